@@ -0,0 +1,163 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use yasbit::block::{Block, BlockHeader};
+use yasbit::config::Network;
+use yasbit::crypto;
+use yasbit::crypto::Hashable;
+use yasbit::message;
+use yasbit::message::block::MessageBlock;
+use yasbit::message::Message;
+use yasbit::transaction::Transaction;
+
+// Mainnet block 100000 and its four transactions
+const BLOCK_100000: &str = concat!(
+    "0100000050120119172a610421a6c3011dd330d9df07b63616c2cc1f1cd00200000000006657a9252aacd5c0b2940996",
+    "ecff952228c3067cc38d4885efb5a4ac4247e9f337221b4d4c86041b0f2b571004010000000100000000000000000000",
+    "00000000000000000000000000000000000000000000ffffffff08044c86041b020602ffffffff0100f2052a01000000",
+    "4341041b0e8c2567c12536aa13357b79a073dc4444acb83c4ec7a0e2f99dd7457516c5817242da796924ca4e99947d08",
+    "7fedf9ce467cb9f7c6287078f801df276fdf84ac000000000100000001032e38e9c0a84c6046d687d10556dcacc41d27",
+    "5ec55fc00779ac88fdf357a187000000008c493046022100c352d3dd993a981beba4a63ad15c209275ca9470abfcd57d",
+    "a93b58e4eb5dce82022100840792bc1f456062819f15d33ee7055cf7b5ee1af1ebcc6028d9cdb1c3af7748014104f46d",
+    "b5e9d61a9dc27b8d64ad23e7383a4e6ca164593c2527c038c0857eb67ee8e825dca65046b82c9331586c82e0fd1f633f",
+    "25f87c161bc6f8a630121df2b3d3ffffffff0200e32321000000001976a914c398efa9c392ba6013c5e04ee729755ef7",
+    "f58b3288ac000fe208010000001976a914948c765a6914d43f2a7ac177da2c2f6b52de3d7c88ac000000000100000001",
+    "c33ebff2a709f13d9f9a7569ab16a32786af7d7e2de09265e41c61d078294ecf010000008a4730440220032d30df5ee6",
+    "f57fa46cddb5eb8d0d9fe8de6b342d27942ae90a3231e0ba333e02203deee8060fdc70230a7f5b4ad7d7bc3e628cbe21",
+    "9a886b84269eaeb81e26b4fe014104ae31c31bf91278d99b8377a35bbce5b27d9fff15456839e919453fc7b3f721f0ba",
+    "403ff96c9deeb680e5fd341c0fc3a7b90da4631ee39560639db462e9cb850fffffffff0240420f00000000001976a914",
+    "b0dcbf97eabf4404e31d952477ce822dadbe7e1088acc060d211000000001976a9146b1281eec25ab4e1e0793ff4e08a",
+    "b1abb3409cd988ac0000000001000000010b6072b386d4a773235237f64c1126ac3b240c84b917a3909ba1c43ded5f51",
+    "f4000000008c493046022100bb1ad26df930a51cce110cf44f7a48c3c561fd977500b1ae5d6b6fd13d0b3f4a022100c5",
+    "b42951acedff14abba2736fd574bdb465f3e6f8da12e2c5303954aca7f78f3014104a7135bfe824c97ecc01ec7d7e336",
+    "185c81e2aa2c41ab175407c09484ce9694b44953fcb751206564a9c24dd094d42fdbfdd5aad3e063ce6af4cfaaea4ea1",
+    "4fbbffffffff0140420f00000000001976a91439aa3d569e06a1d7926dc4be1193c99bf2eb9ee088ac00000000",
+);
+const BLOCK_100000_HASH: &str = "000000000003ba27aa200b1cecaad478d2b00432346c3f1f3986da1afd33e506";
+// Transactions of the generated full block, which is about as large as the
+// mainnet block 502871
+const FULL_BLOCK_TRANSACTIONS: u32 = 4400;
+
+// Unsigned transaction of the native P2WPKH example of BIP143
+const TRANSACTION: &str = "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000";
+const TRANSACTION_COUNT: usize = 1000;
+
+/// Benchmarks the parsing, serialization and hashing of a block
+fn bench_block(c: &mut Criterion, name: &str, bytes: &[u8]) {
+    let block = Block::from_bytes(bytes);
+    assert!(block.check_merkle_root());
+
+    c.bench_function(&format!("{} header from_bytes", name), |b| {
+        b.iter(|| BlockHeader::from_bytes(black_box(bytes)))
+    });
+    c.bench_function(&format!("{} from_bytes", name), |b| {
+        b.iter(|| Block::from_bytes(black_box(bytes)))
+    });
+    c.bench_function(&format!("{} bytes", name), |b| {
+        b.iter(|| black_box(&block).bytes())
+    });
+    c.bench_function(&format!("{} hash32", name), |b| {
+        b.iter(|| crypto::hash32(black_box(bytes)))
+    });
+    c.bench_function(&format!("{} merkle root", name), |b| {
+        b.iter(|| black_box(&block).check_merkle_root())
+    });
+
+    let message = Message::new(message::MAGIC_MAIN, MessageBlock::new(block)).bytes();
+    c.bench_function(&format!("{} message parse", name), |b| {
+        b.iter(|| {
+            message::parse(black_box(&message), message::MAGIC_MAIN)
+                .ok()
+                .unwrap()
+        })
+    });
+}
+
+fn bench_genesis(c: &mut Criterion) {
+    let bytes = Network::Mainnet.genesis().bytes();
+    c.bench_function("genesis from_bytes", |b| {
+        b.iter(|| Block::from_bytes(black_box(&bytes)))
+    });
+    let block = Block::from_bytes(&bytes);
+    c.bench_function("genesis hash", |b| b.iter(|| black_box(&block).hash()));
+}
+
+fn bench_block_100000(c: &mut Criterion) {
+    let bytes = hex::decode(BLOCK_100000).unwrap();
+    assert_eq!(
+        hex::encode(Block::from_bytes(&bytes).hash()),
+        BLOCK_100000_HASH
+    );
+    bench_block(c, "100000", &bytes);
+}
+
+/// Returns a P2PKH script paying to the given public key hash
+fn p2pkh(pubkey_hash: [u8; 20]) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend_from_slice(&pubkey_hash);
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+/// Generates a full block whose transactions spend distinct outputs to two
+/// P2PKH outputs, with a scriptSig as large as a signature and a public key
+fn full_block() -> Block {
+    let mut coinbase = Transaction::new();
+    // Push of the height 502871
+    coinbase.add_input([0; 32], 0xffffffff, vec![0x03, 0x57, 0xac, 0x07]);
+    coinbase.add_output(1_250_000_000, p2pkh([0; 20]));
+    let mut block = Block::new(
+        0x20000000,
+        [0; 32],
+        1516233600,
+        0,
+        0x1761e9f8,
+        Box::new(coinbase),
+    );
+
+    for i in 0..FULL_BLOCK_TRANSACTIONS {
+        let mut prev_tx = [0; 32];
+        prev_tx[..4].copy_from_slice(&i.to_le_bytes());
+        let mut tx = Transaction::new();
+        tx.add_input(prev_tx, i % 4, vec![0x42; 107]);
+        tx.add_output(100_000, p2pkh([1; 20]));
+        tx.add_output(u64::from(i), p2pkh([2; 20]));
+        block.add_tx(Box::new(tx)).unwrap();
+    }
+    block.update_merkle_root();
+    block
+}
+
+fn bench_full_block(c: &mut Criterion) {
+    let bytes = full_block().bytes();
+    bench_block(c, "full block", &bytes);
+}
+
+fn bench_transactions(c: &mut Criterion) {
+    let tx = hex::decode(TRANSACTION).unwrap();
+    let transactions: Vec<Vec<u8>> = (0..TRANSACTION_COUNT).map(|_| tx.clone()).collect();
+    c.bench_function("1000 transactions round trip", |b| {
+        b.iter(|| {
+            for bytes in &transactions {
+                let (tx, _size) = Transaction::from_bytes(black_box(bytes));
+                black_box(tx.bytes());
+            }
+        })
+    });
+    // The txid is cached by the transaction, hence computed once per parsing
+    c.bench_function("1000 transactions txid", |b| {
+        b.iter(|| {
+            for bytes in &transactions {
+                let (tx, _size) = Transaction::from_bytes(black_box(bytes));
+                black_box(tx.hash());
+            }
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_genesis,
+    bench_block_100000,
+    bench_full_block,
+    bench_transactions
+);
+criterion_main!(benches);
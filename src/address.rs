@@ -0,0 +1,261 @@
+use crate::config::Network;
+use crate::crypto;
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+// Prefixes of private keys in Wallet Import Format
+pub const WIF_PREFIX_MAINNET: u8 = 0x80;
+pub const WIF_PREFIX_TESTNET: u8 = 0xef;
+// Suffix of a WIF key whose public key is compressed
+const WIF_COMPRESSED: u8 = 0x01;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    InvalidBase58,
+    InvalidChecksum,
+    InvalidLength,
+    InvalidSecret, // not in the range [1, n - 1] of private keys
+    UnknownPrefix(u8),
+}
+
+/// Encodes bytes in base58, leading zeros being encoded as '1'
+pub fn base58_encode(data: &[u8]) -> String {
+    let zeros = data.iter().take_while(|&&byte| byte == 0).count();
+
+    // Base 58 digits, least significant first
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in &data[zeros..] {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    let mut encoded = String::with_capacity(zeros + digits.len());
+    for _ in 0..zeros {
+        encoded.push(BASE58_ALPHABET[0] as char);
+    }
+    for digit in digits.iter().rev() {
+        encoded.push(BASE58_ALPHABET[*digit as usize] as char);
+    }
+    encoded
+}
+
+pub fn base58_decode(encoded: &str) -> Result<Vec<u8>, Error> {
+    let zeros = encoded
+        .bytes()
+        .take_while(|&c| c == BASE58_ALPHABET[0])
+        .count();
+
+    // Bytes, least significant first
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in encoded.bytes().skip(zeros) {
+        let mut carry = match BASE58_ALPHABET.iter().position(|&x| x == c) {
+            Some(value) => value as u32,
+            None => return Err(Error::InvalidBase58),
+        };
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+
+    let mut decoded = vec![0; zeros];
+    decoded.extend(bytes.iter().rev());
+    Ok(decoded)
+}
+
+/// Encodes bytes in base58 with a 4 bytes checksum
+pub fn base58check_encode(data: &[u8]) -> String {
+    let mut bytes = data.to_vec();
+    bytes.extend_from_slice(&crypto::hash32(data)[..4]);
+    base58_encode(&bytes)
+}
+
+/// Decodes a base58check string, returning the payload without its checksum
+pub fn base58check_decode(encoded: &str) -> Result<Vec<u8>, Error> {
+    let mut bytes = base58_decode(encoded)?;
+    if bytes.len() < 4 {
+        return Err(Error::InvalidLength);
+    }
+    let checksum = bytes.split_off(bytes.len() - 4);
+    if checksum[..] != crypto::hash32(&bytes)[..4] {
+        return Err(Error::InvalidChecksum);
+    }
+    Ok(bytes)
+}
+
+/// A private key in Wallet Import Format
+#[derive(Debug, PartialEq, Clone)]
+pub struct WifKey {
+    secret: [u8; 32],
+    network: Network, // testnet and regtest share the same prefix
+    compressed: bool, // whether the public key is used in compressed form
+}
+
+impl WifKey {
+    pub fn new(secret: [u8; 32], network: Network, compressed: bool) -> Result<Self, Error> {
+        if !crypto::is_valid_secret(&secret) {
+            return Err(Error::InvalidSecret);
+        }
+        Ok(WifKey {
+            secret,
+            network,
+            compressed,
+        })
+    }
+
+    pub fn secret(&self) -> &[u8; 32] {
+        &self.secret
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    pub fn compressed(&self) -> bool {
+        self.compressed
+    }
+
+    pub fn encode(&self) -> String {
+        let mut bytes = Vec::with_capacity(34);
+        bytes.push(match self.network {
+            Network::Mainnet => WIF_PREFIX_MAINNET,
+            _ => WIF_PREFIX_TESTNET,
+        });
+        bytes.extend_from_slice(&self.secret);
+        if self.compressed {
+            bytes.push(WIF_COMPRESSED);
+        }
+        base58check_encode(&bytes)
+    }
+
+    pub fn decode(wif: &str) -> Result<Self, Error> {
+        let bytes = base58check_decode(wif)?;
+        let compressed = match bytes.len() {
+            33 => false,
+            34 if bytes[33] == WIF_COMPRESSED => true,
+            _ => return Err(Error::InvalidLength),
+        };
+        let network = match bytes[0] {
+            WIF_PREFIX_MAINNET => Network::Mainnet,
+            WIF_PREFIX_TESTNET => Network::Testnet,
+            prefix => return Err(Error::UnknownPrefix(prefix)),
+        };
+        let mut secret = [0; 32];
+        secret.copy_from_slice(&bytes[1..33]);
+        WifKey::new(secret, network, compressed)
+    }
+
+    /// Returns the private key in DER format, as expected by `crypto::sign`
+    pub fn private_key(&self) -> Vec<u8> {
+        crypto::private_key_from_secret(&self.secret).expect("secret checked on creation")
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        crypto::public_key_from_secret(&self.secret, self.compressed)
+            .expect("secret checked on creation")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_base58() {
+        assert_eq!(base58_encode(b""), "");
+        assert_eq!(base58_encode(b"hello world"), "StV1DL6CwTryKyV");
+        assert_eq!(base58_encode(&[0, 0, 0x28, 0x7f, 0xb4, 0xcd]), "11233QC4");
+        assert_eq!(base58_decode("StV1DL6CwTryKyV").unwrap(), b"hello world");
+        assert_eq!(
+            base58_decode("11233QC4").unwrap(),
+            vec![0, 0, 0x28, 0x7f, 0xb4, 0xcd]
+        );
+        assert_eq!(base58_decode("0OIl"), Err(Error::InvalidBase58));
+    }
+
+    #[test]
+    fn test_base58check() {
+        // Address of the public key of the genesis block
+        let hash = hex::decode("0062e907b15cbf27d5425399ebf6f0fb50ebb88f18").unwrap();
+        let address = base58check_encode(&hash);
+        assert_eq!(address, "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        assert_eq!(base58check_decode(&address).unwrap(), hash);
+        assert_eq!(
+            base58check_decode("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb"),
+            Err(Error::InvalidChecksum)
+        );
+    }
+
+    #[test]
+    fn test_wif() {
+        let secret =
+            hex::decode("0c28fca386c7a227600b2fe50b7cae11ec86d3bf1fbe471be89827e19d72aa1d")
+                .unwrap();
+        for (wif, network, compressed) in &[
+            (
+                "5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ",
+                Network::Mainnet,
+                false,
+            ),
+            (
+                "KwdMAjGmerYanjeui5SHS7JkmpZvVipYvB2LJGU1ZxJwYvP98617",
+                Network::Mainnet,
+                true,
+            ),
+        ] {
+            let key = WifKey::decode(wif).unwrap();
+            assert_eq!(&key.secret()[..], &secret[..]);
+            assert_eq!(key.network(), network);
+            assert_eq!(key.compressed(), *compressed);
+            assert_eq!(&key.encode(), wif);
+        }
+
+        let key = WifKey::new([0xab; 32], Network::Testnet, true).unwrap();
+        assert!(key.encode().starts_with('c'));
+        assert_eq!(WifKey::decode(&key.encode()).unwrap(), key);
+
+        // Secrets out of the range of private keys
+        assert_eq!(
+            WifKey::new([0xff; 32], Network::Mainnet, true),
+            Err(Error::InvalidSecret)
+        );
+        let mut bytes = vec![WIF_PREFIX_MAINNET];
+        bytes.extend_from_slice(&[0; 32]);
+        assert_eq!(
+            WifKey::decode(&base58check_encode(&bytes)),
+            Err(Error::InvalidSecret)
+        );
+    }
+
+    #[test]
+    fn test_wif_keys() {
+        // Private key 1
+        let mut secret = [0; 32];
+        secret[31] = 1;
+        let key = WifKey::decode("KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgd9M7rFU73sVHnoWn").unwrap();
+        assert_eq!(key.secret(), &secret);
+        assert_eq!(
+            hex::encode(key.public_key()),
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+        );
+
+        // The imported key can sign
+        let data = crypto::hash32(b"BABAR");
+        let sig = crypto::sign(&key.private_key(), &data);
+        assert!(crypto::check_signature(&key.public_key(), &sig, &data).unwrap());
+    }
+}
@@ -1,9 +1,31 @@
+use crate::config::Config;
 use crate::crypto::{bytes_to_hash32, hash32, hash32_to_bytes, Hash32, Hashable};
 use crate::merkle_tree;
-use crate::transaction::Transaction;
+use crate::script::{legacy_sigop_count, push_number, Script, ScriptError};
+use crate::signet;
+use crate::transaction::{money_sum, Transaction, TxOutput, WITNESS_SCALE_FACTOR};
 use crate::utils;
 use crate::variable_integer::VariableInteger;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time;
+
+/// Bounds of the size of the coinbase scriptSig
+const MIN_COINBASE_SCRIPT_SIG_SIZE: usize = 2;
+const MAX_COINBASE_SCRIPT_SIG_SIZE: usize = 100;
+/// Largest weight of a block, a byte of witness data weighing 1 and any
+/// other byte WITNESS_SCALE_FACTOR (BIP141)
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+/// Largest cost of the signature operations of a block (BIP141)
+pub const MAX_BLOCK_SIGOPS_COST: usize = 80_000;
+/// Number of previous blocks whose median timestamp a block must be after
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// How far in the future the timestamp of a block may be, in seconds
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+// Reward of the first blocks, in satoshis
+const INITIAL_SUBSIDY: u64 = 5_000_000_000;
+/// Number of blocks after which the outputs of a coinbase can be spent
+pub const COINBASE_MATURITY: u64 = 100;
 
 /// A block is represented here
 /// See https://en.bitcoin.it/wiki/Block
@@ -13,6 +35,38 @@ pub struct Block {
     pub transactions: Vec<Box<Transaction>>,
 }
 
+/// Reasons for which a block can be rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockValidationError {
+    BadMerkleRoot,
+    BadBip34Height,
+    BadSignetSolution,
+    DoubleSpend,
+    BadProofOfWork,
+    UnknownOutput,    // an input spends an output which is not stored
+    ImmatureCoinbase, // an input spends a coinbase less than COINBASE_MATURITY blocks deep
+    BadScript(ScriptError),
+    BadCoinbaseLength,
+    BadCoinbase, // the first transaction is not a coinbase, or another one is
+    TimeTooNew,  // the timestamp is too far in the future
+    TimeTooOld,  // the timestamp is not after the median time past
+    BadWeight,
+    TooManySigops,
+    BadInputValue,    // a transaction creates more than it spends
+    BadOutputValue,   // an output value or their sum exceeds MAX_MONEY
+    BadCoinbaseValue, // the coinbase claims more than the subsidy and the fees
+    UnknownParent,
+}
+
+impl BlockValidationError {
+    /// Returns whether the block may be valid but altered by the peer which
+    /// has served it, its transactions not matching its header. It is then
+    /// downloaded again.
+    pub fn is_malleation(&self) -> bool {
+        *self == BlockValidationError::BadMerkleRoot
+    }
+}
+
 /// A block header is represented here
 /// See https://en.bitcoin.it/wiki/Block_hashing_algorithm
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -79,10 +133,63 @@ impl BlockHeader {
         }
     }
 
+    pub fn hash_prev_block(&self) -> Hash32 {
+        self.hash_prev_block
+    }
+
+    /// Returns the timestamp of the block
+    pub fn time(&self) -> u32 {
+        self.time
+    }
+
+    /// Returns the timestamp of the block widened to 64 bits, which is how
+    /// timestamps are compared during validation. The field itself rolls
+    /// over in 2106, but the arithmetic on it then cannot wrap around.
+    pub fn timestamp(&self) -> u64 {
+        u64::from(self.time)
+    }
+
     pub fn validate(&self) -> bool {
         // FIXME: Do something
         true
     }
+
+    /// Returns the target encoded in the compact `bits` field, ordered like
+    /// the hashes, or None if it is negative, zero or overflows 256 bits
+    pub fn target(&self) -> Option<Hash32> {
+        let exponent = (self.bits >> 24) as i64;
+        if self.bits & 0x00800000 != 0 {
+            return None;
+        }
+        let mantissa = (self.bits & 0x007fffff).to_be_bytes();
+        let mut target = [0; 32];
+        for (i, byte) in mantissa[1..].iter().enumerate() {
+            let pos = 32 - exponent + i as i64;
+            if pos >= 32 {
+                // Shifted out
+                continue;
+            }
+            if pos < 0 {
+                if *byte != 0 {
+                    return None;
+                }
+                continue;
+            }
+            target[pos as usize] = *byte;
+        }
+        if target == [0; 32] {
+            return None;
+        }
+        Some(target)
+    }
+
+    /// Returns whether the hash of the header does not exceed its target
+    pub fn check_proof_of_work(&self) -> bool {
+        match self.target() {
+            Some(target) => self.hash() <= target,
+            None => false,
+        }
+    }
 }
 
 impl Block {
@@ -113,11 +220,72 @@ impl Block {
         block
     }
 
-    fn update_merkle_root(&mut self) {
-        let mk = merkle_tree::MerkleTree::new(&self.transactions);
+    /// Creates a block like `new`, but fails if the first transaction is
+    /// not a coinbase
+    pub fn new_checked(
+        version: u32,
+        hash_prev_block: Hash32,
+        time: u32,
+        nonce: u32,
+        bits: u32,
+        first_tx: Box<Transaction>,
+    ) -> Result<Self, BlockValidationError> {
+        if !first_tx.is_coinbase() {
+            return Err(BlockValidationError::BadCoinbase);
+        }
+        Ok(Block::new(
+            version,
+            hash_prev_block,
+            time,
+            nonce,
+            bits,
+            first_tx,
+        ))
+    }
+
+    /// Sets the merkle root of the header from the transactions of the
+    /// block
+    pub fn update_merkle_root(&mut self) {
+        let mk =
+            merkle_tree::MerkleTree::from_hashable(self.transactions.iter().map(|tx| tx.as_ref()));
         self.header.hash_merkle_root = mk.root().unwrap()
     }
 
+    /// Returns whether the merkle root of the header matches the
+    /// transactions of the block
+    pub fn check_merkle_root(&self) -> bool {
+        let mk =
+            merkle_tree::MerkleTree::from_hashable(self.transactions.iter().map(|tx| tx.as_ref()));
+        mk.root() == Some(self.header.hash_merkle_root)
+    }
+
+    /// Returns the root of the merkle tree of the wtxids of the
+    /// transactions, the coinbase one being zero (BIP141). The coinbase of
+    /// segwit blocks commits to it.
+    pub fn witness_merkle_root(&self) -> Hash32 {
+        let wtxids = self
+            .transactions
+            .iter()
+            .enumerate()
+            .map(|(index, tx)| if index == 0 { [0; 32] } else { tx.wtxid() })
+            .collect();
+        merkle_tree::MerkleTree::from_hashes(wtxids)
+            .root()
+            .unwrap_or([0; 32])
+    }
+
+    /// Returns whether each outpoint is spent at most once by the
+    /// transactions of the block
+    pub fn check_no_double_spend(&self) -> bool {
+        let mut spent = HashSet::new();
+        // The coinbase does not spend any outpoint
+        self.transactions
+            .iter()
+            .skip(1)
+            .flat_map(|tx| tx.inputs().iter())
+            .all(|input| spent.insert(input.prev_output()))
+    }
+
     /// Returns a bytes array representing the block.
     pub fn bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
@@ -126,12 +294,59 @@ impl Block {
         let tx_count = VariableInteger::new(self.transactions.len() as u64);
         bytes.extend_from_slice(&tx_count.bytes().as_slice());
         for transaction in &self.transactions {
-            bytes.extend_from_slice(&transaction.bytes());
+            bytes.extend_from_slice(&transaction.witness_bytes());
         }
 
         bytes
     }
 
+    /// Returns the size of the block with the witness data of its
+    /// transactions, i.e. the length of `bytes`
+    pub fn size(&self) -> usize {
+        BlockHeader::length()
+            + VariableInteger::new(self.transactions.len() as u64).length()
+            + self
+                .transactions
+                .iter()
+                .map(|tx| tx.witness_size())
+                .sum::<usize>()
+    }
+
+    /// Returns the size of the block without the witness data of its
+    /// transactions
+    pub fn stripped_size(&self) -> usize {
+        BlockHeader::length()
+            + VariableInteger::new(self.transactions.len() as u64).length()
+            + self.transactions.iter().map(|tx| tx.size()).sum::<usize>()
+    }
+
+    /// Returns the weight of the block (BIP141)
+    pub fn weight(&self) -> usize {
+        self.stripped_size() * (WITNESS_SCALE_FACTOR - 1) + self.size()
+    }
+
+    /// Returns the cost of the signature operations of the scripts of the
+    /// block. Only the legacy ones are counted, not those of the redeem
+    /// and witness scripts.
+    pub fn sigop_cost(&self) -> usize {
+        let sigops: usize = self
+            .transactions
+            .iter()
+            .map(|tx| {
+                tx.inputs()
+                    .iter()
+                    .map(|input| legacy_sigop_count(&input.script_sig))
+                    .chain(
+                        tx.outputs()
+                            .iter()
+                            .map(|output| legacy_sigop_count(&output.pubkey())),
+                    )
+                    .sum::<usize>()
+            })
+            .sum();
+        sigops * WITNESS_SCALE_FACTOR
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Self {
         let mut index = 0;
 
@@ -155,19 +370,86 @@ impl Block {
         }
     }
 
-    /// Adds the given transaction to the block
-    pub fn add_tx(&mut self, tr: Box<Transaction>) {
+    /// Returns the transactions of the block, the coinbase being the first one
+    pub fn transactions(&self) -> &[Box<Transaction>] {
+        &self.transactions
+    }
+
+    /// Returns the coinbase transaction, which is the first one
+    pub fn coinbase(&self) -> Option<&Transaction> {
+        self.transactions.first().map(|tx| tx.as_ref())
+    }
+
+    /// Returns whether the block starts with a coinbase whose scriptSig is
+    /// between 2 and 100 bytes, as required by consensus
+    pub fn validate_coinbase_scriptsig(&self) -> bool {
+        match self.coinbase() {
+            Some(coinbase) if coinbase.is_coinbase() => {
+                let size = coinbase.inputs()[0].script_sig.len();
+                size >= MIN_COINBASE_SCRIPT_SIG_SIZE && size <= MAX_COINBASE_SCRIPT_SIG_SIZE
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the height pushed at the start of the coinbase scriptSig
+    /// (BIP34). Only meaningful for blocks from height 227836 on mainnet.
+    pub fn extract_bip34_height(&self) -> Option<u64> {
+        let script_sig = &self.coinbase()?.inputs().first()?.script_sig;
+        let opcode = *script_sig.first()?;
+        match opcode {
+            // OP_0 and OP_1 to OP_16
+            0x00 => Some(0),
+            0x51..=0x60 => Some(u64::from(opcode - 0x50)),
+            // Push of a little endian number, whose last byte holds the sign
+            0x01..=0x08 => {
+                let number = script_sig.get(1..(1 + opcode as usize))?;
+                if number[number.len() - 1] & 0x80 != 0 {
+                    return None;
+                }
+                Some(
+                    number
+                        .iter()
+                        .rev()
+                        .fold(0, |height, byte| (height << 8) | u64::from(*byte)),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    pub fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Adds the given transaction to the block. Fails if it is a coinbase,
+    /// which can only be the first transaction of a block.
+    pub fn add_tx(&mut self, tr: Box<Transaction>) -> Result<(), BlockValidationError> {
+        if tr.is_coinbase() {
+            return Err(BlockValidationError::BadCoinbase);
+        }
         self.transactions.push(tr);
+        Ok(())
+    }
+
+    /// Returns whether the first transaction of the block, and only this
+    /// one, is a coinbase
+    pub fn check_coinbase(&self) -> bool {
+        match self.transactions.split_first() {
+            Some((first, others)) => {
+                first.is_coinbase() && !others.iter().any(|tx| tx.is_coinbase())
+            }
+            None => false,
+        }
     }
 
     /// Returns a boolean whether the block is valid or not.
     pub fn is_valid(&self) -> bool {
-        // TODO
-        false
+        self.header.check_proof_of_work() && self.check_merkle_root()
     }
 
     /// Try to find a valid nonce for the block.
-    fn mine(&mut self) -> u32 {
+    pub fn mine(&mut self) -> u32 {
         for x in 0..u32::max_value() {
             self.header.nonce = x;
             if self.is_valid() {
@@ -196,28 +478,249 @@ impl Hashable for BlockHeader {
     }
 }
 
+/// Returns the reward created by a block at the given height, halved every
+/// `halving_interval` blocks
+pub fn block_subsidy(height: u64, halving_interval: u64) -> u64 {
+    let halvings = height / halving_interval;
+    if halvings >= 64 {
+        return 0;
+    }
+    INITIAL_SUBSIDY >> halvings
+}
+
+/// Returns the median of the timestamps of the previous blocks, or None if
+/// there is none (i.e. for the genesis block)
+pub fn median_time_past(prev_timestamps: &[u64]) -> Option<u64> {
+    let mut timestamps = prev_timestamps.to_vec();
+    timestamps.sort();
+    timestamps.get(timestamps.len() / 2).cloned()
+}
+
+/// Returns the current time as a UNIX timestamp. Unlike the timestamps of
+/// the headers, it does not roll over in 2106.
+pub fn unix_time() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Returns whether a block timestamp is not too far ahead of `now`. Both are
+/// compared as u64 so that adding the allowed drift cannot overflow near
+/// u32::MAX, and so that once the current time has passed the 2106 rollover
+/// every header timestamp is in the past rather than wrapping to the future.
+fn check_block_time(timestamp: u64, now: u64) -> bool {
+    timestamp <= now + MAX_FUTURE_BLOCK_TIME
+}
+
+/// Returns whether the coinbase scriptSig of the block starts with the
+/// minimal push of its height, which is required from the BIP34 activation
+/// height
+fn check_bip34_height(config: &Config, block: &Block, height: u64) -> bool {
+    if height < config.network.bip34_height() {
+        return true;
+    }
+    match block
+        .coinbase()
+        .and_then(|coinbase| coinbase.inputs().first())
+    {
+        Some(input) => input.script_sig.starts_with(&push_number(height as i64)),
+        None => false,
+    }
+}
+
+/// Checks the values spent and created by the transactions of a block, and
+/// runs the scripts of their inputs if `check_scripts` is set. The outputs
+/// of the previous transactions of the block may be spent, except those of
+/// its coinbase.
+fn check_inputs(
+    block: &Block,
+    height: u64,
+    utxo: &dyn Fn((Hash32, u32)) -> Option<(TxOutput, Option<u64>)>,
+    config: &Config,
+    check_scripts: bool,
+) -> Result<(), BlockValidationError> {
+    let output_value = |tx: &Transaction| {
+        tx.output_value()
+            .ok_or(BlockValidationError::BadOutputValue)
+    };
+
+    let mut block_outputs: HashMap<(Hash32, u32), (TxOutput, Option<u64>)> = HashMap::new();
+    let mut fees = 0u64;
+    for tx in block.transactions() {
+        if !tx.is_coinbase() {
+            let mut input_value = 0u64;
+            for (index, input) in tx.inputs().iter().enumerate() {
+                let outpoint = input.prev_output();
+                let (prev_output, coinbase_height) = match block_outputs.get(&outpoint) {
+                    Some(output) => output.clone(),
+                    None => utxo(outpoint).ok_or(BlockValidationError::UnknownOutput)?,
+                };
+                if let Some(coinbase_height) = coinbase_height {
+                    if height - coinbase_height < COINBASE_MATURITY {
+                        return Err(BlockValidationError::ImmatureCoinbase);
+                    }
+                }
+                input_value = money_sum(vec![input_value, prev_output.value()])
+                    .ok_or(BlockValidationError::BadInputValue)?;
+                if check_scripts {
+                    let mut script = Script::new(
+                        tx.clone(),
+                        index,
+                        Box::new(prev_output),
+                        block.header.timestamp(),
+                    );
+                    script.set_segwit(height >= config.network.segwit_height());
+                    if let Err(error) = script.exec().verify() {
+                        return Err(BlockValidationError::BadScript(error));
+                    }
+                }
+            }
+            let fee = input_value
+                .checked_sub(output_value(tx)?)
+                .ok_or(BlockValidationError::BadInputValue)?;
+            fees = money_sum(vec![fees, fee]).ok_or(BlockValidationError::BadInputValue)?;
+        }
+        let txid = tx.hash();
+        let coinbase_height = if tx.is_coinbase() { Some(height) } else { None };
+        for (vout, output) in tx.outputs().iter().enumerate() {
+            block_outputs.insert((txid, vout as u32), ((**output).clone(), coinbase_height));
+        }
+    }
+
+    let subsidy = block_subsidy(height, config.network.subsidy_halving_interval());
+    let claimable = subsidy
+        .checked_add(fees)
+        .ok_or(BlockValidationError::BadCoinbaseValue)?;
+    match block.coinbase() {
+        Some(coinbase) if output_value(coinbase)? <= claimable => Ok(()),
+        _ => Err(BlockValidationError::BadCoinbaseValue),
+    }
+}
+
+/// Runs the consensus checks on a block at `height`, whose previous blocks
+/// have the timestamps `prev_timestamps` (at most MEDIAN_TIME_SPAN of
+/// them). `utxo` gives the outputs spent by its inputs, with the height of
+/// their block for coinbase outputs, except those
+/// created by the block itself. The scripts of the inputs are only run if
+/// `check_scripts` is set. The timestamp of the block may be at most
+/// MAX_FUTURE_BLOCK_TIME after `now`. Returns the reason of the first check
+/// failing.
+pub fn validate_block(
+    block: &Block,
+    prev_timestamps: &[u64],
+    height: u64,
+    utxo: &dyn Fn((Hash32, u32)) -> Option<(TxOutput, Option<u64>)>,
+    config: &Config,
+    check_scripts: bool,
+    now: u64,
+) -> Result<(), BlockValidationError> {
+    // The genesis block is part of the configuration
+    let is_genesis = block.hash() == config.genesis_block.hash();
+    if !is_genesis && !block.header.check_proof_of_work() {
+        return Err(BlockValidationError::BadProofOfWork);
+    }
+    if !check_block_time(block.header.timestamp(), now) {
+        return Err(BlockValidationError::TimeTooNew);
+    }
+    if let Some(median) = median_time_past(prev_timestamps) {
+        if block.header.timestamp() <= median {
+            return Err(BlockValidationError::TimeTooOld);
+        }
+    }
+    if !block.check_merkle_root() {
+        return Err(BlockValidationError::BadMerkleRoot);
+    }
+    if block.weight() > MAX_BLOCK_WEIGHT {
+        return Err(BlockValidationError::BadWeight);
+    }
+    if !block.check_coinbase() {
+        return Err(BlockValidationError::BadCoinbase);
+    }
+    if !block.validate_coinbase_scriptsig() {
+        return Err(BlockValidationError::BadCoinbaseLength);
+    }
+    if !block.check_no_double_spend() {
+        return Err(BlockValidationError::DoubleSpend);
+    }
+    if block
+        .transactions()
+        .iter()
+        .any(|tx| tx.output_value().is_none())
+    {
+        return Err(BlockValidationError::BadOutputValue);
+    }
+    if !check_bip34_height(config, block, height) {
+        return Err(BlockValidationError::BadBip34Height);
+    }
+    if block.sigop_cost() > MAX_BLOCK_SIGOPS_COST {
+        return Err(BlockValidationError::TooManySigops);
+    }
+
+    // Blocks of a signet must be signed, except the genesis block
+    if let Some(challenge) = &config.signet_challenge {
+        if !is_genesis && !signet::check_block_solution(block, challenge) {
+            return Err(BlockValidationError::BadSignetSolution);
+        }
+    }
+
+    check_inputs(block, height, utxo, config, check_scripts)
+}
+
+// Coinbase scriptSig of the genesis blocks of mainnet, testnet and regtest
+const SATOSHI_SCRIPT_SIG: &str = "04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73";
+// Output script of the reward of these genesis blocks
+const SATOSHI_SCRIPT_PUBKEY: &str = "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac";
+
+/// Parameters of the genesis block of a chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenesisSpec {
+    pub version: u32,
+    pub time: u32,
+    pub nonce: u32,
+    pub bits: u32,
+    pub reward: u64,
+    pub script_sig: Vec<u8>,    // scriptSig of the coinbase input
+    pub script_pubkey: Vec<u8>, // output script of the reward
+}
+
+impl GenesisSpec {
+    /// Returns the parameters of a genesis block using the coinbase of
+    /// Satoshi's genesis block
+    pub fn satoshi(version: u32, time: u32, nonce: u32, bits: u32, reward: u64) -> Self {
+        GenesisSpec {
+            version,
+            time,
+            nonce,
+            bits,
+            reward,
+            script_sig: hex::decode(SATOSHI_SCRIPT_SIG).unwrap(),
+            script_pubkey: hex::decode(SATOSHI_SCRIPT_PUBKEY).unwrap(),
+        }
+    }
+
+    pub fn block(&self) -> Block {
+        let mut tx = Transaction::new();
+
+        // Coinbase generation input
+        tx.add_input([0 as u8; 32], 0xffffffff, self.script_sig.clone());
+
+        // Output reward
+        tx.add_output(self.reward, self.script_pubkey.clone());
+
+        Block::new(
+            self.version,
+            [0; 32],    // prev block
+            self.time,  // time
+            self.nonce, // nonce
+            self.bits,  // bits
+            Box::new(tx),
+        )
+    }
+}
+
 pub fn genesis_block(version: u32, time: u32, nonce: u32, bits: u32, reward: u64) -> Block {
-    let mut tx = Transaction::new();
-
-    // Coinbase generation input
-    tx.add_input(
-        [0 as u8; 32],
-        0xffffffff,
-        hex::decode("04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73").unwrap());
-
-    // Output reward
-    tx.add_output(
-        reward,
-        hex::decode("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac").unwrap());
-
-    Block::new(
-        version,
-        [0; 32], // prev block
-        time,    // time
-        nonce,   // nonce
-        bits,    // bits
-        Box::new(tx),
-    )
+    GenesisSpec::satoshi(version, time, nonce, bits, reward).block()
 }
 
 #[cfg(test)]
@@ -225,6 +728,7 @@ mod tests {
 
     use super::*;
     use crate::config;
+    use crate::crypto;
 
     #[test]
     /// The test is based on
@@ -238,6 +742,8 @@ mod tests {
         );
 
         assert_eq!(block, Block::from_bytes(&block.bytes()));
+        assert_eq!(block.size(), 285);
+        assert_eq!(block.size(), block.bytes().len());
         assert_eq!(
             "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
             hex::encode(block.header.hash_merkle_root)
@@ -289,4 +795,590 @@ mod tests {
 
         assert_eq!(block, Block::from_bytes(&block.bytes()));
     }
+
+    #[test]
+    /// Signet reuses the coinbase of the mainnet genesis block
+    fn test_custom_genesis_block() {
+        let spec = GenesisSpec::satoshi(1, 1598918400, 52613770, 0x1e0377ae, 5_000_000_000);
+        assert_eq!(
+            "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+            hex::encode(spec.block().hash())
+        );
+
+        let custom = GenesisSpec {
+            script_sig: b"custom chain".to_vec(),
+            script_pubkey: vec![0x51],
+            ..spec
+        };
+        let block = custom.block();
+        assert_eq!(
+            block.coinbase().unwrap().inputs()[0].script_sig,
+            b"custom chain"
+        );
+        assert_eq!(
+            block.coinbase().unwrap().outputs()[0].value(),
+            5_000_000_000
+        );
+        assert!(block.check_merkle_root());
+        assert_ne!(block.hash(), spec.block().hash());
+        assert_eq!(block, Block::from_bytes(&block.bytes()));
+    }
+
+    #[test]
+    fn test_transactions() {
+        let block = config::main_config().genesis_block;
+        assert_eq!(block.transaction_count(), 1);
+
+        let coinbase = &block.transactions()[0];
+        assert_eq!(
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            hex::encode(coinbase.hash())
+        );
+        assert_eq!(coinbase.inputs().len(), 1);
+        assert_eq!(coinbase.outputs().len(), 1);
+    }
+
+    /// Returns a block whose coinbase has the given scriptSig
+    fn block_with_coinbase(script_sig: Vec<u8>) -> Block {
+        let mut coinbase = Transaction::new();
+        coinbase.add_input([0; 32], 0xffffffff, script_sig);
+        coinbase.add_output(2500000000, vec![0x51]);
+        Block::new(2, [0; 32], 0, 0, 0, Box::new(coinbase))
+    }
+
+    #[test]
+    fn test_extract_bip34_height() {
+        // Coinbase scriptSig of block 227836, the first one enforcing BIP34
+        let block = block_with_coinbase(
+            hex::decode(
+                "03fc7903062f503253482f04ac204f510858029a11000003550d3363646164312f736c7573682f",
+            )
+            .unwrap(),
+        );
+        assert_eq!(block.coinbase(), Some(block.transactions()[0].as_ref()));
+        assert_eq!(block.extract_bip34_height(), Some(227836));
+
+        // Small heights are pushed with OP_N
+        assert_eq!(
+            block_with_coinbase(vec![0x00]).extract_bip34_height(),
+            Some(0)
+        );
+        assert_eq!(
+            block_with_coinbase(vec![0x5a, 0x00]).extract_bip34_height(),
+            Some(10)
+        );
+
+        // Negative, truncated or missing heights
+        assert_eq!(
+            block_with_coinbase(vec![0x01, 0x81]).extract_bip34_height(),
+            None
+        );
+        assert_eq!(
+            block_with_coinbase(vec![0x03, 0xfc, 0x79]).extract_bip34_height(),
+            None
+        );
+        assert_eq!(block_with_coinbase(vec![]).extract_bip34_height(), None);
+        assert_eq!(block_with_coinbase(vec![0x6a]).extract_bip34_height(), None);
+    }
+
+    #[test]
+    fn test_validate_coinbase_scriptsig() {
+        assert!(config::main_config()
+            .genesis_block
+            .validate_coinbase_scriptsig());
+        assert!(block_with_coinbase(vec![0x02, 0xf4, 0x01]).validate_coinbase_scriptsig());
+        assert!(block_with_coinbase(vec![0x51; 2]).validate_coinbase_scriptsig());
+        assert!(block_with_coinbase(vec![0x51; 100]).validate_coinbase_scriptsig());
+        assert!(!block_with_coinbase(vec![0x51]).validate_coinbase_scriptsig());
+        assert!(!block_with_coinbase(vec![]).validate_coinbase_scriptsig());
+        assert!(!block_with_coinbase(vec![0x51; 101]).validate_coinbase_scriptsig());
+
+        // The first transaction must be a coinbase
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, vec![0x51; 2]);
+        let block = Block::new(2, [0; 32], 0, 0, 0, Box::new(tx));
+        assert!(!block.validate_coinbase_scriptsig());
+    }
+
+    #[test]
+    fn test_coinbase_position() {
+        let genesis = config::regtest_config().genesis_block;
+        let coinbase = genesis.transactions()[0].clone();
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, vec![]);
+
+        assert_eq!(
+            Block::new_checked(1, genesis.hash(), 1, 0, 0x207fffff, Box::new(tx.clone())),
+            Err(BlockValidationError::BadCoinbase)
+        );
+        let mut block =
+            Block::new_checked(1, genesis.hash(), 1, 0, 0x207fffff, coinbase.clone()).unwrap();
+        assert!(block.check_coinbase());
+        assert_eq!(
+            block.add_tx(coinbase.clone()),
+            Err(BlockValidationError::BadCoinbase)
+        );
+        assert_eq!(block.add_tx(Box::new(tx.clone())), Ok(()));
+        assert_eq!(block.transaction_count(), 2);
+        assert!(block.check_coinbase());
+
+        // Blocks built otherwise are flagged
+        let not_first = Block::new(1, genesis.hash(), 1, 0, 0x207fffff, Box::new(tx));
+        assert!(!not_first.check_coinbase());
+        block.transactions.push(coinbase);
+        assert!(!block.check_coinbase());
+    }
+
+    #[test]
+    fn test_check_merkle_root() {
+        let mut block = config::main_config().genesis_block;
+        assert!(block.check_merkle_root());
+
+        // add_tx does not update the merkle root
+        block.add_tx(Box::new(Transaction::new())).unwrap();
+        assert!(!block.check_merkle_root());
+    }
+
+    #[test]
+    fn test_proof_of_work() {
+        let block = config::main_config().genesis_block;
+        assert_eq!(
+            hex::encode(block.header.target().unwrap()),
+            "00000000ffff0000000000000000000000000000000000000000000000000000"
+        );
+        assert!(block.header.check_proof_of_work());
+        assert!(block.is_valid());
+
+        let mut header = block.header.clone();
+        header.nonce += 1;
+        assert!(!header.check_proof_of_work());
+
+        // Negative, zero and overflowing targets
+        for bits in &[0x1d80ffff, 0x1d000000, 0x2200ffff] {
+            header.bits = *bits;
+            assert_eq!(header.target(), None);
+            assert!(!header.check_proof_of_work());
+        }
+        // Small exponents shift the mantissa out
+        header.bits = 0x02123456;
+        assert_eq!(header.target().unwrap()[30..], [0x12, 0x34]);
+
+        let mut block = config::regtest_config().genesis_block;
+        block.header.hash_prev_block = block.hash();
+        let nonce = block.mine();
+        assert_eq!(block.header.nonce, nonce);
+        assert!(block.is_valid());
+    }
+
+    #[test]
+    fn test_witness_merkle_root() {
+        let mut block = block_with_coinbase(vec![0x51]);
+        let coinbase = block.transactions()[0].hash();
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, Vec::new());
+        tx.add_output(1000, vec![0x51]);
+        tx.set_witness(0, vec![vec![0x30, 0x44], vec![0x02, 0x79]]);
+        block.add_tx(Box::new(tx.clone())).unwrap();
+        block.update_merkle_root();
+
+        // Nodes are hashed in serialization order, the reverse of the txids
+        let concat = |mut a: Hash32, mut b: Hash32| {
+            a.reverse();
+            b.reverse();
+            let mut parent = hash32(&[a, b].concat());
+            parent.reverse();
+            parent
+        };
+        // The txid root ignores witnesses
+        assert_eq!(block.header.hash_merkle_root, concat(coinbase, tx.hash()));
+        assert!(block.check_merkle_root());
+        // The wtxid root commits to them, the coinbase being zero
+        assert_ne!(tx.wtxid(), tx.hash());
+        assert_eq!(block.witness_merkle_root(), concat([0; 32], tx.wtxid()));
+
+        let mut other = block.clone();
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, Vec::new());
+        tx.add_output(1000, vec![0x51]);
+        tx.set_witness(0, vec![vec![0x30, 0x45]]);
+        other.transactions[1] = Box::new(tx);
+        other.update_merkle_root();
+        assert_eq!(other.header.hash_merkle_root, block.header.hash_merkle_root);
+        assert_ne!(other.witness_merkle_root(), block.witness_merkle_root());
+        assert_eq!(other.size(), other.bytes().len());
+    }
+
+    /// Returns a coinbase paying `reward`, whose scriptSig holds height 1
+    fn coinbase_paying(reward: u64) -> Transaction {
+        let mut coinbase = Transaction::new();
+        coinbase.add_input([0; 32], 0xffffffff, vec![0x51, 0x00]);
+        coinbase.add_output(reward, vec![0x51]);
+        coinbase
+    }
+
+    /// Returns a transaction spending `outpoint` into an output of `value`
+    fn spending(outpoint: (Hash32, u32), value: u64) -> Transaction {
+        let mut tx = Transaction::new();
+        tx.add_input(outpoint.0, outpoint.1, vec![]);
+        tx.add_output(value, vec![0x51]);
+        tx
+    }
+
+    /// Returns a mined regtest block on top of the genesis block
+    fn mined_block(time: u32, coinbase: Transaction, txs: Vec<Transaction>) -> Block {
+        let genesis = config::regtest_config().genesis_block;
+        let mut block = Block::new(1, genesis.hash(), time, 0, 0x207fffff, Box::new(coinbase));
+        for tx in txs {
+            block.add_tx(Box::new(tx)).unwrap();
+        }
+        block.update_merkle_root();
+        block.mine();
+        block
+    }
+
+    #[test]
+    fn test_validate_block() {
+        let config = config::regtest_config();
+        let genesis_time = config.genesis_block.header.timestamp();
+        let time = genesis_time as u32 + 1;
+        let now = genesis_time + 1;
+        let subsidy = block_subsidy(1, config.network.subsidy_halving_interval());
+        let mut utxo = HashMap::new();
+        utxo.insert(
+            ([1; 32], 0),
+            (TxOutput::new(1_000_000_000, vec![0x51]), None),
+        );
+        utxo.insert(
+            ([2; 32], 0),
+            (TxOutput::new(1_000_000_000, vec![0x00]), None),
+        );
+        // Output of the coinbase of block 1
+        utxo.insert(
+            ([4; 32], 0),
+            (TxOutput::new(1_000_000_000, vec![0x51]), Some(1)),
+        );
+        // Pay to the witness script OP_0
+        let mut witness_program = vec![0x00, 0x20];
+        witness_program.extend_from_slice(&crypto::single_hash32(&[0x00]));
+        utxo.insert(
+            ([5; 32], 0),
+            (TxOutput::new(1_000_000_000, witness_program), None),
+        );
+        let lookup = |outpoint| utxo.get(&outpoint).cloned();
+        let validate = |block: &Block, height: u64| {
+            validate_block(block, &[genesis_time], height, &lookup, &config, true, now)
+        };
+
+        // Spends 10 BTC, of which 1 BTC of fees go to the coinbase, and an
+        // output of the block itself
+        let tx = spending(([1; 32], 0), 900_000_000);
+        let chained = spending((tx.hash(), 0), 900_000_000);
+        let block = mined_block(
+            time,
+            coinbase_paying(subsidy + 100_000_000),
+            vec![tx, chained],
+        );
+        assert_eq!(validate(&block, 1), Ok(()));
+        assert_eq!(
+            validate_block(&config.genesis_block, &[], 0, &|_| None, &config, true, now),
+            Ok(())
+        );
+
+        let unmined = (0..)
+            .map(|nonce| {
+                let coinbase = Box::new(coinbase_paying(subsidy));
+                Block::new(
+                    1,
+                    config.genesis_block.hash(),
+                    time,
+                    nonce,
+                    0x207fffff,
+                    coinbase,
+                )
+            })
+            .find(|block| !block.header.check_proof_of_work())
+            .unwrap();
+        assert_eq!(
+            validate(&unmined, 1),
+            Err(BlockValidationError::BadProofOfWork)
+        );
+
+        let future = mined_block(u32::MAX, coinbase_paying(subsidy), vec![]);
+        assert_eq!(validate(&future, 1), Err(BlockValidationError::TimeTooNew));
+        let latest = (now + MAX_FUTURE_BLOCK_TIME) as u32;
+        let at_limit = mined_block(latest, coinbase_paying(subsidy), vec![]);
+        assert_eq!(validate(&at_limit, 1), Ok(()));
+        let past_limit = mined_block(latest + 1, coinbase_paying(subsidy), vec![]);
+        assert_eq!(
+            validate(&past_limit, 1),
+            Err(BlockValidationError::TimeTooNew)
+        );
+        // The median of the previous timestamps is genesis_time + 1
+        let empty = mined_block(time, coinbase_paying(subsidy), vec![]);
+        let prev_timestamps = [genesis_time + 1, genesis_time, genesis_time + 1];
+        assert_eq!(
+            validate_block(&empty, &prev_timestamps, 1, &lookup, &config, true, now),
+            Err(BlockValidationError::TimeTooOld)
+        );
+
+        let mut bad_merkle_root = empty.clone();
+        bad_merkle_root
+            .add_tx(Box::new(spending(([1; 32], 0), 1)))
+            .unwrap();
+        assert_eq!(
+            validate(&bad_merkle_root, 1),
+            Err(BlockValidationError::BadMerkleRoot)
+        );
+
+        let mut heavy = spending(([1; 32], 0), 1);
+        heavy.add_output(0, vec![0x51; MAX_BLOCK_WEIGHT / WITNESS_SCALE_FACTOR]);
+        let heavy = mined_block(time, coinbase_paying(subsidy), vec![heavy]);
+        assert_eq!(validate(&heavy, 1), Err(BlockValidationError::BadWeight));
+
+        let mut short_coinbase = Transaction::new();
+        short_coinbase.add_input([0; 32], 0xffffffff, vec![0x51]);
+        short_coinbase.add_output(subsidy, vec![0x51]);
+        let short_coinbase = mined_block(time, short_coinbase, vec![]);
+        assert_eq!(
+            validate(&short_coinbase, 1),
+            Err(BlockValidationError::BadCoinbaseLength)
+        );
+
+        let mut second_coinbase = mined_block(time, coinbase_paying(subsidy), vec![]);
+        second_coinbase
+            .transactions
+            .push(Box::new(coinbase_paying(0)));
+        second_coinbase.update_merkle_root();
+        second_coinbase.mine();
+        assert_eq!(
+            validate(&second_coinbase, 1),
+            Err(BlockValidationError::BadCoinbase)
+        );
+
+        let double_spend = mined_block(
+            time,
+            coinbase_paying(subsidy),
+            vec![spending(([1; 32], 0), 1), spending(([1; 32], 0), 2)],
+        );
+        assert_eq!(
+            validate(&double_spend, 1),
+            Err(BlockValidationError::DoubleSpend)
+        );
+
+        assert_eq!(
+            validate(&empty, config.network.bip34_height()),
+            Err(BlockValidationError::BadBip34Height)
+        );
+
+        let mut sigops = coinbase_paying(subsidy);
+        sigops.add_output(
+            0,
+            vec![0xac; MAX_BLOCK_SIGOPS_COST / WITNESS_SCALE_FACTOR + 1],
+        );
+        let sigops = mined_block(time, sigops, vec![]);
+        assert_eq!(
+            validate(&sigops, 1),
+            Err(BlockValidationError::TooManySigops)
+        );
+
+        let unknown = mined_block(
+            time,
+            coinbase_paying(subsidy),
+            vec![spending(([3; 32], 0), 1)],
+        );
+        assert_eq!(
+            validate(&unknown, 1),
+            Err(BlockValidationError::UnknownOutput)
+        );
+
+        // Coinbase outputs are spent once COINBASE_MATURITY blocks deep
+        let mature = mined_block(
+            time,
+            coinbase_paying(subsidy),
+            vec![spending(([4; 32], 0), 1)],
+        );
+        assert_eq!(
+            validate(&mature, 1 + COINBASE_MATURITY - 1),
+            Err(BlockValidationError::ImmatureCoinbase)
+        );
+        assert_eq!(validate(&mature, 1 + COINBASE_MATURITY), Ok(()));
+
+        // Witness programs are verified from the segwit height of the network
+        let witness_spend = mined_block(
+            time,
+            coinbase_paying(subsidy),
+            vec![spending(([5; 32], 0), 1)],
+        );
+        assert!(matches!(
+            validate(&witness_spend, 1),
+            Err(BlockValidationError::BadScript(_))
+        ));
+        let mainnet = config::main_config();
+        assert_eq!(
+            validate_block(
+                &witness_spend,
+                &[genesis_time],
+                1,
+                &lookup,
+                &mainnet,
+                true,
+                now
+            ),
+            Ok(())
+        );
+        let coinbase = coinbase_paying(subsidy);
+        let same_block = mined_block(
+            time,
+            coinbase.clone(),
+            vec![spending((coinbase.hash(), 0), 1)],
+        );
+        assert_eq!(
+            validate(&same_block, 1),
+            Err(BlockValidationError::ImmatureCoinbase)
+        );
+
+        let overspending = mined_block(
+            time,
+            coinbase_paying(subsidy),
+            vec![spending(([1; 32], 0), 1_000_000_001)],
+        );
+        assert_eq!(
+            validate(&overspending, 1),
+            Err(BlockValidationError::BadInputValue)
+        );
+
+        // Values which would overflow, or wrap around to a small sum
+        let mut overflowing = spending(([1; 32], 0), u64::MAX - 10);
+        overflowing.add_output(20, vec![0x51]);
+        for tx in vec![spending(([1; 32], 0), u64::MAX), overflowing] {
+            let block = mined_block(time, coinbase_paying(subsidy), vec![tx]);
+            assert_eq!(
+                validate(&block, 1),
+                Err(BlockValidationError::BadOutputValue)
+            );
+        }
+        let block = mined_block(time, coinbase_paying(u64::MAX), vec![]);
+        assert_eq!(
+            validate(&block, 1),
+            Err(BlockValidationError::BadOutputValue)
+        );
+
+        // The output script is OP_0
+        let bad_script = mined_block(
+            time,
+            coinbase_paying(subsidy),
+            vec![spending(([2; 32], 0), 1)],
+        );
+        assert_eq!(
+            validate(&bad_script, 1),
+            Err(BlockValidationError::BadScript(ScriptError::EvalFalse))
+        );
+        assert_eq!(
+            validate_block(
+                &bad_script,
+                &[genesis_time],
+                1,
+                &lookup,
+                &config,
+                false,
+                now
+            ),
+            Ok(())
+        );
+
+        let greedy = mined_block(
+            time,
+            coinbase_paying(subsidy + 100_000_001),
+            vec![spending(([1; 32], 0), 900_000_000)],
+        );
+        assert_eq!(
+            validate(&greedy, 1),
+            Err(BlockValidationError::BadCoinbaseValue)
+        );
+        // The subsidy is halved
+        assert_eq!(
+            validate(&empty, config.network.subsidy_halving_interval()),
+            Err(BlockValidationError::BadCoinbaseValue)
+        );
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        assert_eq!(block_subsidy(0, 150), 5_000_000_000);
+        assert_eq!(block_subsidy(149, 150), 5_000_000_000);
+        assert_eq!(block_subsidy(150, 150), 2_500_000_000);
+        assert_eq!(block_subsidy(210_000 * 4, 210_000), 312_500_000);
+        assert_eq!(block_subsidy(210_000 * 33, 210_000), 0);
+        assert_eq!(block_subsidy(u64::MAX, 150), 0);
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        assert_eq!(median_time_past(&[]), None);
+        assert_eq!(median_time_past(&[5]), Some(5));
+        assert_eq!(median_time_past(&[3, 1, 2]), Some(2));
+        assert_eq!(median_time_past(&[4, 1, 3, 2]), Some(3));
+    }
+
+    #[test]
+    fn test_weight() {
+        let block = config::main_config().genesis_block;
+        assert_eq!(block.stripped_size(), block.size());
+        assert_eq!(block.weight(), 4 * block.size());
+        // The genesis output pays to a public key
+        assert_eq!(block.sigop_cost(), 4);
+
+        let mut tx = spending(([1; 32], 0), 1);
+        tx.set_witness(0, vec![vec![0x30; 10]]);
+        let block = mined_block(0, coinbase_paying(1), vec![tx]);
+        assert_eq!(block.size(), block.stripped_size() + 2 + 1 + 1 + 10);
+        assert_eq!(block.weight(), 3 * block.stripped_size() + block.size());
+    }
+
+    #[test]
+    fn test_check_block_time() {
+        let max = u64::from(u32::MAX);
+        assert!(check_block_time(1296688602, 1296688602));
+        assert!(check_block_time(
+            1296688602 + MAX_FUTURE_BLOCK_TIME,
+            1296688602
+        ));
+        assert!(!check_block_time(
+            1296688603 + MAX_FUTURE_BLOCK_TIME,
+            1296688602
+        ));
+
+        // Near the rollover of the u32 header field
+        assert!(check_block_time(max, max));
+        assert!(check_block_time(max, max - MAX_FUTURE_BLOCK_TIME));
+        assert!(!check_block_time(max, max - MAX_FUTURE_BLOCK_TIME - 1));
+        assert!(check_block_time(max - 1, max));
+        // Past it, the largest header timestamp is in the past
+        assert!(check_block_time(max, max + 1));
+        assert!(check_block_time(0, max + MAX_FUTURE_BLOCK_TIME + 1));
+    }
+
+    #[test]
+    fn test_bip34_height() {
+        let config = config::regtest_config();
+        let bip34_height = config.network.bip34_height();
+        let block_at = |height: u64, script_sig: Vec<u8>| {
+            let mut coinbase = Transaction::new();
+            coinbase.add_input([0; 32], 0xffffffff, script_sig);
+            coinbase.add_output(5_000_000_000, vec![]);
+            let block = Block::new(1, [1; 32], 0, 0, 0x207fffff, Box::new(coinbase));
+            check_bip34_height(&config, &block, height)
+        };
+
+        // Push of 500 (0x01f4)
+        assert!(block_at(bip34_height, vec![0x02, 0xf4, 0x01]));
+        assert!(!block_at(bip34_height, vec![0x02, 0xf5, 0x01]));
+        assert!(!block_at(bip34_height, vec![0x6a]));
+        assert!(!block_at(bip34_height + 1, vec![0x02, 0xf4, 0x01]));
+        // The push must be minimal
+        assert!(!block_at(bip34_height, vec![0x04, 0xf4, 0x01, 0x00, 0x00]));
+        assert!(!block_at(bip34_height, vec![0x4c, 0x02, 0xf4, 0x01]));
+        // Not enforced before the activation height
+        assert!(block_at(bip34_height - 1, vec![0x6a]));
+    }
 }
@@ -0,0 +1,214 @@
+use crate::crypto;
+use crate::crypto::Hashable;
+use crate::script;
+use crate::transaction::Transaction;
+use std::convert::TryInto;
+
+// Limits of the filters loaded by peers (BIP37)
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+/// Bloom filter loaded by a peer to only hear about the transactions it is
+/// interested in (BIP37). The filter is never updated with the outputs of
+/// the matched transactions, so the flags of filterload are ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    data: Vec<u8>,
+    hash_funcs: u32,
+    tweak: u32,
+}
+
+impl BloomFilter {
+    /// Creates a filter, or returns None if it exceeds the limits of BIP37
+    pub fn new(data: Vec<u8>, hash_funcs: u32, tweak: u32) -> Option<Self> {
+        if data.len() > MAX_BLOOM_FILTER_SIZE || hash_funcs > MAX_HASH_FUNCS {
+            return None;
+        }
+        Some(BloomFilter {
+            data,
+            hash_funcs,
+            tweak,
+        })
+    }
+
+    /// Returns the positions of the bits of the filter set for the key
+    fn bits<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        (0..self.hash_funcs).map(move |i| {
+            let seed = i.wrapping_mul(0xfba4c795).wrapping_add(self.tweak);
+            murmur3(seed, key) as usize % (self.data.len() * 8)
+        })
+    }
+
+    /// Adds a key to the filter
+    pub fn insert(&mut self, key: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        let bits: Vec<usize> = self.bits(key).collect();
+        for bit in bits {
+            self.data[bit >> 3] |= 1 << (bit & 7);
+        }
+    }
+
+    /// Returns whether the key may have been inserted in the filter. An
+    /// empty filter matches everything.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        if self.data.is_empty() {
+            return true;
+        }
+        self.bits(key)
+            .all(|bit| self.data[bit >> 3] & (1 << (bit & 7)) != 0)
+    }
+
+    /// Returns whether the filter matches the txid, the outpoints spent by
+    /// the transaction or the data pushed by its scripts
+    pub fn matches_tx(&self, tx: &Transaction) -> bool {
+        if self.contains(&crypto::hash32_to_bytes(&tx.hash())) {
+            return true;
+        }
+        let outputs = tx.outputs().iter().map(|output| output.pubkey());
+        let script_sigs = tx.inputs().iter().map(|input| input.script_sig.clone());
+        if outputs
+            .chain(script_sigs)
+            .any(|script| pushed_data(&script).any(|data| self.contains(data)))
+        {
+            return true;
+        }
+        tx.inputs().iter().any(|input| {
+            let (prev_txid, vout) = input.prev_output();
+            let mut outpoint = crypto::hash32_to_bytes(&prev_txid).to_vec();
+            outpoint.extend_from_slice(&vout.to_le_bytes());
+            self.contains(&outpoint)
+        })
+    }
+}
+
+/// Returns the data pushed by a script, until its end or a truncated push
+fn pushed_data(script: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut index = 0;
+    std::iter::from_fn(move || {
+        while index < script.len() {
+            let opcode = script[index];
+            if opcode > 0x4e {
+                index += 1;
+                continue;
+            }
+            let (start, size) = script::push_data(script, index)?;
+            index = start + size;
+            return Some(&script[start..index]);
+        }
+        None
+    })
+}
+
+/// 32 bits MurmurHash3 of the data
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+
+    let mut hash = seed;
+    let blocks = data.chunks_exact(4);
+    let tail = blocks.remainder();
+    for block in blocks {
+        let k = u32::from_le_bytes(block.try_into().unwrap());
+        hash ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe6546b64);
+    }
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .enumerate()
+            .fold(0u32, |k, (i, byte)| k | (*byte as u32) << (8 * i));
+        hash ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85ebca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2ae35);
+    hash ^= hash >> 16;
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_murmur3() {
+        let vectors: &[(u32, u32, &str)] = &[
+            (0x00000000, 0x00000000, ""),
+            (0x6a396f08, 0xfba4c795, ""),
+            (0x81f16f39, 0xffffffff, ""),
+            (0x514e28b7, 0x00000000, "00"),
+            (0xea3f0b17, 0xfba4c795, "00"),
+            (0xfd6cf10d, 0x00000000, "ff"),
+            (0x16c6b7ab, 0x00000000, "0011"),
+            (0x8eb51c3d, 0x00000000, "001122"),
+            (0xb4471bf8, 0x00000000, "00112233"),
+            (0xe2301fa8, 0x00000000, "0011223344"),
+        ];
+        for (expected, seed, data) in vectors {
+            assert_eq!(murmur3(*seed, &hex::decode(data).unwrap()), *expected);
+        }
+    }
+
+    #[test]
+    fn test_contains() {
+        // Filter of 3 elements with a false positive rate of 1%
+        let filter = BloomFilter::new(hex::decode("614e9b").unwrap(), 5, 0).unwrap();
+        for key in &[
+            "99108ad8ed9bb6274d3980bab5a85c048f0950c8",
+            "b5a2c786d9ef4658287ced5914b37a1b4aa32eee",
+            "b9300670b4c5366e95b2699e8b18bc75e5f729c5",
+        ] {
+            assert!(filter.contains(&hex::decode(key).unwrap()));
+        }
+        assert!(!filter.contains(&hex::decode("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap()));
+
+        // The same filter is built by inserting the keys
+        let mut inserted = BloomFilter::new(vec![0; 3], 5, 0).unwrap();
+        for key in &[
+            "99108ad8ed9bb6274d3980bab5a85c048f0950c8",
+            "b5a2c786d9ef4658287ced5914b37a1b4aa32eee",
+            "b9300670b4c5366e95b2699e8b18bc75e5f729c5",
+        ] {
+            inserted.insert(&hex::decode(key).unwrap());
+        }
+        assert_eq!(inserted, filter);
+
+        assert!(BloomFilter::new(vec![], 0, 0)
+            .unwrap()
+            .contains(b"anything"));
+        assert!(BloomFilter::new(vec![0; MAX_BLOOM_FILTER_SIZE + 1], 1, 0).is_none());
+        assert!(BloomFilter::new(vec![0; 1], MAX_HASH_FUNCS + 1, 0).is_none());
+    }
+
+    #[test]
+    fn test_matches_tx() {
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 3, vec![0x02, 0xaa, 0xbb]);
+        tx.add_output(1000, vec![0x76, 0xa9, 0x03, 0x01, 0x02, 0x03, 0x88, 0xac]);
+
+        let mut outpoint = crypto::hash32_to_bytes(&[1; 32]).to_vec();
+        outpoint.extend_from_slice(&3u32.to_le_bytes());
+        for key in &[
+            crypto::hash32_to_bytes(&tx.hash()).to_vec(),
+            vec![0x01, 0x02, 0x03],
+            vec![0xaa, 0xbb],
+            outpoint,
+        ] {
+            let mut filter = BloomFilter::new(vec![0; 64], 3, 0).unwrap();
+            filter.insert(key);
+            assert!(filter.matches_tx(&tx));
+        }
+        let mut filter = BloomFilter::new(vec![0; 64], 3, 0).unwrap();
+        filter.insert(&[0x01, 0x02]);
+        assert!(!filter.matches_tx(&tx));
+    }
+}
@@ -0,0 +1,389 @@
+use crate::block::Block;
+use crate::crypto;
+use crate::crypto::{Hash32, Hashable};
+use crate::variable_integer::VariableInteger;
+use std::collections::HashSet;
+
+// Compact block filters (BIP158)
+// See https://github.com/bitcoin/bips/blob/master/bip-0158.mediawiki
+
+/// Type of the basic filter, the only one defined by BIP158
+pub const BASIC_FILTER: u8 = 0;
+// Golomb-Rice parameters of the basic filter
+const BASIC_FILTER_P: u8 = 19;
+const BASIC_FILTER_M: u64 = 784_931;
+
+const OP_RETURN: u8 = 0x6a;
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+/// SipHash-2-4 of `data` with the key (k0, k1)
+fn siphash(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = u64::from_le_bytes([
+            chunk[0], chunk[1], chunk[2], chunk[3], chunk[4], chunk[5], chunk[6], chunk[7],
+        ]);
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+    // The last word holds the remaining bytes and the length of the data
+    let mut last = (data.len() as u64 & 0xff) << 56;
+    for (i, byte) in chunks.remainder().iter().enumerate() {
+        last |= u64::from(*byte) << (8 * i);
+    }
+    v[3] ^= last;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= last;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// Writes bits from the most significant one of each byte
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u8, // bits used in the last byte
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            bytes: Vec::new(),
+            used: 8,
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 8 {
+            self.bytes.push(0);
+            self.used = 0;
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used += 1;
+    }
+
+    /// Writes the `count` lowest bits of `value`
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize, // in bits
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.pos / 8)?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, count: u8) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | u64::from(self.read_bit()?);
+        }
+        Some(value)
+    }
+}
+
+/// Golomb-coded set of items, keyed by the hash of the block it describes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    n: u64,        // number of items
+    bits: Vec<u8>, // Golomb-Rice coded deltas of the sorted hashed items
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FilterError {
+    Truncated,
+    Overflow,
+}
+
+/// Returns the header of a filter from its hash and the header of the
+/// filter of the previous block
+pub fn next_filter_header(filter_hash: &Hash32, prev_header: &Hash32) -> Hash32 {
+    let mut bytes = crypto::hash32_to_bytes(filter_hash).to_vec();
+    bytes.extend_from_slice(&crypto::hash32_to_bytes(prev_header));
+    let mut header = crypto::hash32(&bytes);
+    header.reverse();
+    header
+}
+
+/// Returns the SipHash key derived from the hash of a block
+fn filter_key(block_hash: &Hash32) -> (u64, u64) {
+    let bytes = crypto::hash32_to_bytes(block_hash);
+    let k0 = u64::from_le_bytes([
+        bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+    ]);
+    let k1 = u64::from_le_bytes([
+        bytes[8], bytes[9], bytes[10], bytes[11], bytes[12], bytes[13], bytes[14], bytes[15],
+    ]);
+    (k0, k1)
+}
+
+/// Maps an item uniformly onto [0, n * M)
+fn hash_to_range(key: (u64, u64), n: u64, item: &[u8]) -> u64 {
+    let range = n * BASIC_FILTER_M;
+    ((u128::from(siphash(key.0, key.1, item)) * u128::from(range)) >> 64) as u64
+}
+
+impl Filter {
+    /// Builds the filter of the given items for a block
+    pub fn new(block_hash: &Hash32, items: &HashSet<Vec<u8>>) -> Self {
+        let key = filter_key(block_hash);
+        let n = items.len() as u64;
+        let mut values: Vec<u64> = items
+            .iter()
+            .map(|item| hash_to_range(key, n, item))
+            .collect();
+        values.sort();
+
+        let mut writer = BitWriter::new();
+        let mut last = 0;
+        for value in values {
+            let delta = value - last;
+            last = value;
+            // Quotient in unary, then the remainder on P bits
+            for _ in 0..(delta >> BASIC_FILTER_P) {
+                writer.write_bit(true);
+            }
+            writer.write_bit(false);
+            writer.write_bits(delta, BASIC_FILTER_P);
+        }
+        Filter {
+            n,
+            bits: writer.bytes,
+        }
+    }
+
+    /// Builds the basic filter of a block. `prev_scripts` are the scripts of
+    /// the outputs spent by the block, which are not held by the block.
+    pub fn basic(block: &Block, prev_scripts: &[Vec<u8>]) -> Self {
+        let mut items: HashSet<Vec<u8>> = block
+            .transactions()
+            .iter()
+            .flat_map(|tx| tx.outputs().iter())
+            .map(|output| output.pubkey())
+            .filter(|script| !script.is_empty() && script[0] != OP_RETURN)
+            .collect();
+        items.extend(prev_scripts.iter().cloned());
+        Filter::new(&block.hash(), &items)
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = VariableInteger::new(self.n).bytes();
+        bytes.extend_from_slice(&self.bits);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FilterError> {
+        let (n, size) = match VariableInteger::from_bytes(bytes) {
+            Ok(n) => n,
+            Err(_) => return Err(FilterError::Truncated),
+        };
+        Ok(Filter {
+            n,
+            bits: bytes[size..].to_vec(),
+        })
+    }
+
+    /// Returns the hashed items of the filter, in ascending order
+    fn values(&self) -> Result<Vec<u64>, FilterError> {
+        let mut reader = BitReader::new(&self.bits);
+        let mut values = Vec::new();
+        let mut last = 0u64;
+        for _ in 0..self.n {
+            let mut quotient = 0u64;
+            loop {
+                match reader.read_bit() {
+                    Some(true) => quotient += 1,
+                    Some(false) => break,
+                    None => return Err(FilterError::Truncated),
+                }
+            }
+            let remainder = match reader.read_bits(BASIC_FILTER_P) {
+                Some(remainder) => remainder,
+                None => return Err(FilterError::Truncated),
+            };
+            last = match last.checked_add((quotient << BASIC_FILTER_P) | remainder) {
+                Some(value) => value,
+                None => return Err(FilterError::Overflow),
+            };
+            values.push(last);
+        }
+        Ok(values)
+    }
+
+    /// Returns whether any of the scripts may be in the filter of the given
+    /// block. False positives happen with a probability of 1/M.
+    pub fn match_any(&self, block_hash: &Hash32, scripts: &[Vec<u8>]) -> Result<bool, FilterError> {
+        let values = self.values()?;
+        let key = filter_key(block_hash);
+        Ok(scripts.iter().any(|script| {
+            values
+                .binary_search(&hash_to_range(key, self.n, script))
+                .is_ok()
+        }))
+    }
+
+    /// Returns whether the script may be in the filter of the given block
+    pub fn matches(&self, block_hash: &Hash32, script: &[u8]) -> Result<bool, FilterError> {
+        self.match_any(block_hash, &[script.to_vec()])
+    }
+
+    /// Returns the header of the filter, which commits to the previous
+    /// filter headers
+    pub fn header(&self, prev_header: &Hash32) -> Hash32 {
+        next_filter_header(&self.hash(), prev_header)
+    }
+}
+
+impl Hashable for Filter {
+    /// Returns the hash of the serialized filter
+    fn hash(&self) -> Hash32 {
+        let mut hash = crypto::hash32(&self.bytes());
+        hash.reverse();
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn test_siphash() {
+        // Reference vector of SipHash-2-4, with the key 00 01 .. 0f and
+        // the message 00 01 .. 0e
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(
+            siphash(0x0706050403020100, 0x0f0e0d0c0b0a0908, &data),
+            0xa129ca6149be45e5
+        );
+    }
+
+    #[test]
+    fn test_genesis_filter() {
+        // BIP158 test vector of the testnet genesis block
+        let genesis = config::test_config().genesis_block;
+        let filter = Filter::basic(&genesis, &[]);
+        assert_eq!(hex::encode(filter.bytes()), "019dfca8");
+        assert_eq!(
+            hex::encode(filter.header(&[0; 32])),
+            "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"
+        );
+
+        let filter = Filter::from_bytes(&hex::decode("019dfca8").unwrap()).unwrap();
+        let script = genesis.coinbase().unwrap().outputs()[0].pubkey();
+        assert!(filter.matches(&genesis.hash(), &script).unwrap());
+        assert!(!filter.matches(&genesis.hash(), &[0x51]).unwrap());
+    }
+
+    #[test]
+    fn test_bip158_vectors() {
+        // BIP158 test vectors of the testnet blocks 2 and 3, whose filters
+        // chain from the filter header of block 1
+        let vectors = [
+            (
+                "000000006c02c8ea6e4ff69651f7fcde348fb9d557a06e6957b65552002a7820",
+                "0100000006128e87be8b1b4dea47a7247d5528d2702c96826c7a648497e773b800000000e241352e3bec0a95a6217e10c3abb54adfa05abb12c126695595580fb92e222032e7494dffff001d00d235340101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0e0432e7494d010e062f503253482fffffffff0100f2052a010000002321038a7f6ef1c8ca0c588aa53fa860128077c9e6c11e6830f4d7ee4e763a56b7718fac00000000",
+                "d7bdac13a59d745b1add0d2ce852f1a0442e8945fc1bf3848d3cbffd88c24fe1",
+                "0174a170",
+                "186afd11ef2b5e7e3504f2e8cbf8df28a1fd251fe53d60dff8b1467d1b386cf0",
+            ),
+            (
+                "000000008b896e272758da5297bcd98fdc6d97c9b765ecec401e286dc1fdbe10",
+                "0100000020782a005255b657696ea057d5b98f34defcf75196f64f6eeac8026c0000000041ba5afc532aae03151b8aa87b65e1594f97504a768e010c98c0add79216247186e7494dffff001d058dc2b60101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0e0486e7494d0151062f503253482fffffffff0100f2052a01000000232103f6d9ff4c12959445ca5549c811683bf9c88e637b222dd2e0311154c4c85cf423ac00000000",
+                "186afd11ef2b5e7e3504f2e8cbf8df28a1fd251fe53d60dff8b1467d1b386cf0",
+                "016cf7a0",
+                "8d63aadf5ab7257cb6d2316a57b16f517bff1c6388f124ec4c04af1212729d2a",
+            ),
+        ];
+        for (hash, block, prev_header, filter_bytes, header) in vectors.iter() {
+            let block = Block::from_bytes(&hex::decode(block).unwrap());
+            assert_eq!(hex::encode(block.hash()), *hash);
+            let filter = Filter::basic(&block, &[]);
+            assert_eq!(hex::encode(filter.bytes()), *filter_bytes);
+            let prev_header = crate::utils::clone_into_array(&hex::decode(prev_header).unwrap());
+            assert_eq!(hex::encode(filter.header(&prev_header)), *header);
+
+            let script = block.coinbase().unwrap().outputs()[0].pubkey();
+            assert!(filter.matches(&block.hash(), &script).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut block = config::regtest_config().genesis_block;
+        let scripts: Vec<Vec<u8>> = (0..100u8).map(|i| vec![0x51, i, i, i]).collect();
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, vec![]);
+        for script in &scripts[..50] {
+            tx.add_output(1000, script.clone());
+        }
+        // Outputs which can not be spent are not part of the filter
+        tx.add_output(0, vec![OP_RETURN, 0x01]);
+        tx.add_output(0, vec![]);
+        block.add_tx(Box::new(tx)).unwrap();
+        let filter = Filter::basic(&block, &scripts[50..]);
+        // Items of the coinbase and of the transaction
+        assert_eq!(filter.n, 101);
+
+        let decoded = Filter::from_bytes(&filter.bytes()).unwrap();
+        assert_eq!(decoded, filter);
+        assert_eq!(decoded.values().unwrap().len(), 101);
+        for script in &scripts {
+            assert!(decoded.matches(&block.hash(), script).unwrap());
+        }
+        assert!(!decoded
+            .match_any(&block.hash(), &[vec![OP_RETURN, 0x01], vec![0x52]])
+            .unwrap());
+
+        let empty = Filter::new(&block.hash(), &HashSet::new());
+        assert_eq!(empty.bytes(), vec![0]);
+        assert!(!empty.matches(&block.hash(), &scripts[0]).unwrap());
+
+        let truncated = Filter::from_bytes(&filter.bytes()[..20]).unwrap();
+        assert_eq!(truncated.values(), Err(FilterError::Truncated));
+    }
+}
@@ -1,61 +1,702 @@
-use crate::block::{genesis_block, Block};
+use crate::block::{genesis_block, Block, GenesisSpec};
+use crate::crypto::Hash32;
+use crate::message;
+use crate::signet;
+use crate::storage;
 use rand::seq::SliceRandom;
+use std::net;
+use std::path;
+use std::time::Duration;
+
+/// Default number of blocks requested at once from a single node
+pub const DEFAULT_MAX_DOWNLOADING_BLOCKS: usize = 16;
+/// Default number of peers the node connects to
+pub const DEFAULT_PEER_COUNT: usize = 8;
+/// Default directory of the databases and block files
+pub const DEFAULT_DATA_DIR: &str = "/var/tmp/yasbit";
+/// Default minimum fee rate (satoshis per kB) of the relayed transactions
+pub const DEFAULT_MIN_RELAY_FEE: u64 = 1000;
+/// Default number of connection attempts in flight at once
+pub const DEFAULT_MAX_CONNECTING: usize = 4;
+/// Default number of messages per second accepted from a peer
+pub const DEFAULT_MAX_MESSAGE_RATE: u32 = 200;
+/// Default number of messages a peer may send at once above that rate
+pub const DEFAULT_MAX_MESSAGE_BURST: u32 = 1000;
+/// Default time without any data from a peer after which it is pinged, the
+/// connection being closed if it stays silent as long again
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+/// Smallest prune target, leaving room for the blocks kept below the tip
+pub const MIN_PRUNE_TARGET_MIB: u64 = 550;
+
+/// Networks supported by yasbit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+    Signet,
+}
+
+impl Network {
+    /// Returns the magic value used in the header of every message sent on
+    /// this network
+    pub fn magic(&self) -> u32 {
+        match self {
+            Network::Mainnet => message::MAGIC_MAIN,
+            Network::Testnet => message::MAGIC_TESTNET3,
+            Network::Regtest => message::MAGIC_REGTEST,
+            Network::Signet => message::MAGIC_SIGNET,
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Network::Mainnet => 8333,
+            Network::Testnet => 18333,
+            Network::Regtest => 18444,
+            Network::Signet => 38333,
+        }
+    }
+
+    /// Returns the genesis block of the network
+    pub fn genesis(&self) -> Block {
+        match self {
+            Network::Mainnet => genesis_block(
+                1,             // version
+                1231006505,    // time
+                2083236893,    // nonce
+                486604799,     // bits
+                5_000_000_000, // reward
+            ),
+            Network::Testnet => genesis_block(
+                1,             // version
+                1296688602,    // time
+                414098458,     // nonce
+                0x1d00ffff,    // bits
+                5_000_000_000, // reward
+            ),
+            Network::Regtest => genesis_block(
+                1,             // version
+                1296688602,    // time
+                2,             // nonce
+                0x207fffff,    // bits
+                5_000_000_000, // reward
+            ),
+            Network::Signet => genesis_block(
+                1,             // version
+                1598918400,    // time
+                52613770,      // nonce
+                0x1e0377ae,    // bits
+                5_000_000_000, // reward
+            ),
+        }
+    }
+
+    /// Returns the height from which the coinbase must start with the
+    /// height of its block (BIP34)
+    pub fn bip34_height(&self) -> u64 {
+        match self {
+            Network::Mainnet => 227931,
+            Network::Testnet => 21111,
+            Network::Regtest => 500,
+            Network::Signet => 1,
+        }
+    }
+
+    /// Returns the number of blocks after which the block subsidy is halved
+    pub fn subsidy_halving_interval(&self) -> u64 {
+        match self {
+            Network::Regtest => 150,
+            _ => 210_000,
+        }
+    }
+
+    /// Returns the height from which the witness programs are verified
+    /// (BIP141)
+    pub fn segwit_height(&self) -> u64 {
+        match self {
+            Network::Mainnet => 481824,
+            Network::Testnet => 834624,
+            Network::Regtest => 0,
+            Network::Signet => 1,
+        }
+    }
+
+    /// Returns the script which must be satisfied by the signature of each
+    /// block, for signets only (BIP325)
+    pub fn signet_challenge(&self) -> Option<Vec<u8>> {
+        match self {
+            Network::Signet => Some(hex::decode(signet::DEFAULT_CHALLENGE).unwrap()),
+            _ => None,
+        }
+    }
+
+    /// Returns the DNS seeds of the network. Regtest has none.
+    pub fn dns_seeds(&self) -> Vec<String> {
+        let seeds: &[&str] = match self {
+            Network::Mainnet => &[
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "dnsseed.bitcoin.dashjr.org",
+                "seed.bitcoinstats.com",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.org",
+                "seed.bitcoin.sprovoost.nl",
+                "nsseed.emzy.de",
+            ],
+            Network::Testnet => &[
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.org",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+            ],
+            Network::Regtest => &[],
+            Network::Signet => &["seed.signet.bitcoin.sprovoost.nl"],
+        };
+        seeds.iter().map(|seed| seed.to_string()).collect()
+    }
+
+    /// Returns the network using the given magic value, if any
+    pub fn from_magic(magic: u32) -> Option<Network> {
+        [
+            Network::Mainnet,
+            Network::Testnet,
+            Network::Regtest,
+            Network::Signet,
+        ]
+        .iter()
+        .find(|network| network.magic() == magic)
+        .cloned()
+    }
+}
+
+/// Checks run on the blocks before they are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationLevel {
+    Full,        // every consensus check, including the scripts of the inputs
+    HeadersOnly, // proof of work and consistency of the blocks, scripts are assumed valid
+    None,        // blocks are stored unchecked
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
+    pub network: Network,
     pub genesis_block: Block,
     pub magic: u32,
     pub dns_seeds: Vec<String>,
     pub port: u16,
+    pub max_downloading_blocks: usize, // in-flight block window of each node
+    pub data_dir: path::PathBuf,
+    pub peer_count: usize,
+    pub proxy: Option<net::SocketAddr>, // SOCKS proxy for outgoing connections
+    pub compress_blocks: bool,          // deflate blocks written in block files
+    pub max_block_file_size: u64,
+    pub process_alerts: bool, // deprecated alert system, whose keys are public
+    pub signet_challenge: Option<Vec<u8>>, // script signing the blocks of a signet
+    pub min_relay_fee: u64,   // satoshis per kB, announced to peers in feefilter messages
+    pub build_cfilters: bool, // build and serve the compact filters of the blocks (BIP157)
+    pub max_reorg_depth: u64, // deepest fork below the tip a branch may be switched from
+    pub max_message_rate: u32, // messages per second accepted from each peer
+    pub max_message_burst: u32,
+    pub max_connecting: usize,  // connection attempts in flight at once
+    pub peer_timeout: Duration, // read timeout of the connections, detecting silent peers
+    pub external_addr: Option<net::IpAddr>, // advertised to peers, learnt from them if unset
+    pub relay: bool, // ask peers to announce their transactions (BIP37), unset during IBD
+    pub fixed_peers: Vec<net::SocketAddr>, // tried before the discovered peers
+    pub connect_only: bool, // only connect to fixed_peers, without DNS seeds nor addr gossip
+    pub prune_target_mib: Option<u64>, // old block files are deleted above this size
+    pub validation_level: ValidationLevel,
+    pub assume_valid: Option<Hash32>, // scripts of this block and its ancestors are not checked
 }
 
-pub fn main_config() -> Config {
-    let mut dns_seeds = vec![
-        "seed.bitcoin.sipa.be".to_string(),
-        "dnsseed.bluematt.me".to_string(),
-        "dnsseed.bitcoin.dashjr.org".to_string(),
-        "seed.bitcoinstats.com".to_string(),
-        "seed.bitcoin.jonasschnelli.ch".to_string(),
-        "seed.btc.petertodd.org".to_string(),
-        "seed.bitcoin.sprovoost.nl".to_string(),
-        "nsseed.emzy.de".to_string(),
-    ];
-    let mut rng = rand::thread_rng();
-    dns_seeds.shuffle(&mut rng);
-
-    Config {
-        genesis_block: genesis_block(
-            1,             // version
-            1231006505,    // time
-            2083236893,    // nonce
-            486604799,     // bits
-            5_000_000_000, // reward
-        ),
-        magic: 0xD9B4BEF9,
-        dns_seeds,
-        port: 8333,
+impl Config {
+    pub fn new(network: Network) -> Self {
+        let mut dns_seeds = network.dns_seeds();
+        let mut rng = rand::thread_rng();
+        dns_seeds.shuffle(&mut rng);
+
+        Config {
+            network,
+            genesis_block: network.genesis(),
+            magic: network.magic(),
+            dns_seeds,
+            port: network.default_port(),
+            max_downloading_blocks: DEFAULT_MAX_DOWNLOADING_BLOCKS,
+            data_dir: path::PathBuf::from(DEFAULT_DATA_DIR),
+            peer_count: DEFAULT_PEER_COUNT,
+            proxy: None,
+            compress_blocks: false,
+            max_block_file_size: storage::DEFAULT_MAX_BLOCK_FILE_SIZE,
+            process_alerts: false,
+            signet_challenge: network.signet_challenge(),
+            min_relay_fee: DEFAULT_MIN_RELAY_FEE,
+            build_cfilters: false,
+            max_reorg_depth: storage::DEFAULT_MAX_REORG_DEPTH,
+            max_message_rate: DEFAULT_MAX_MESSAGE_RATE,
+            max_message_burst: DEFAULT_MAX_MESSAGE_BURST,
+            max_connecting: DEFAULT_MAX_CONNECTING,
+            peer_timeout: DEFAULT_PEER_TIMEOUT,
+            external_addr: None,
+            relay: true,
+            fixed_peers: Vec::new(),
+            connect_only: false,
+            prune_target_mib: None,
+            validation_level: ValidationLevel::Full,
+            assume_valid: None,
+        }
+    }
+
+    /// Returns a builder of a testnet configuration
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    NoPeer,
+    EmptyDownloadWindow,
+    NoConnectionSlot,
+    NoPeerTimeout,
+    EmptyDataDir,
+    NoFixedPeer,
+    PruneTargetTooLow,
+}
+
+/// Builds a `Config`, checking the consistency of its fields
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    network: Network,
+    data_dir: path::PathBuf,
+    peer_count: usize,
+    max_downloading_blocks: usize,
+    proxy: Option<net::SocketAddr>,
+    compress_blocks: bool,
+    max_block_file_size: u64,
+    process_alerts: bool,
+    genesis: Option<GenesisSpec>,
+    signet_challenge: Option<Vec<u8>>,
+    min_relay_fee: u64,
+    build_cfilters: bool,
+    max_reorg_depth: u64,
+    max_message_rate: u32,
+    max_message_burst: u32,
+    max_connecting: usize,
+    peer_timeout: Duration,
+    external_addr: Option<net::IpAddr>,
+    fixed_peers: Vec<net::SocketAddr>,
+    connect_only: bool,
+    prune_target_mib: Option<u64>,
+    validation_level: ValidationLevel,
+    assume_valid: Option<Hash32>,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        ConfigBuilder {
+            network: Network::Testnet,
+            data_dir: path::PathBuf::from(DEFAULT_DATA_DIR),
+            peer_count: DEFAULT_PEER_COUNT,
+            max_downloading_blocks: DEFAULT_MAX_DOWNLOADING_BLOCKS,
+            proxy: None,
+            compress_blocks: false,
+            max_block_file_size: storage::DEFAULT_MAX_BLOCK_FILE_SIZE,
+            process_alerts: false,
+            genesis: None,
+            signet_challenge: None,
+            min_relay_fee: DEFAULT_MIN_RELAY_FEE,
+            build_cfilters: false,
+            max_reorg_depth: storage::DEFAULT_MAX_REORG_DEPTH,
+            max_message_rate: DEFAULT_MAX_MESSAGE_RATE,
+            max_message_burst: DEFAULT_MAX_MESSAGE_BURST,
+            max_connecting: DEFAULT_MAX_CONNECTING,
+            peer_timeout: DEFAULT_PEER_TIMEOUT,
+            external_addr: None,
+            fixed_peers: Vec::new(),
+            connect_only: false,
+            prune_target_mib: None,
+            validation_level: ValidationLevel::Full,
+            assume_valid: None,
+        }
+    }
+
+    pub fn network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub fn data_dir<P: Into<path::PathBuf>>(mut self, data_dir: P) -> Self {
+        self.data_dir = data_dir.into();
+        self
+    }
+
+    pub fn peer_count(mut self, peer_count: usize) -> Self {
+        self.peer_count = peer_count;
+        self
+    }
+
+    pub fn max_downloading_blocks(mut self, max_downloading_blocks: usize) -> Self {
+        self.max_downloading_blocks = max_downloading_blocks;
+        self
+    }
+
+    pub fn proxy(mut self, addr: net::SocketAddr) -> Self {
+        self.proxy = Some(addr);
+        self
+    }
+
+    pub fn compress_blocks(mut self, compress_blocks: bool) -> Self {
+        self.compress_blocks = compress_blocks;
+        self
+    }
+
+    pub fn max_block_file_size(mut self, max_block_file_size: u64) -> Self {
+        self.max_block_file_size = max_block_file_size;
+        self
+    }
+
+    pub fn process_alerts(mut self, process_alerts: bool) -> Self {
+        self.process_alerts = process_alerts;
+        self
+    }
+
+    /// Overrides the genesis block of the network, for custom chains
+    pub fn genesis(mut self, genesis: GenesisSpec) -> Self {
+        self.genesis = Some(genesis);
+        self
+    }
+
+    /// Overrides the challenge of a signet, for custom signets
+    pub fn signet_challenge(mut self, challenge: Vec<u8>) -> Self {
+        self.signet_challenge = Some(challenge);
+        self
+    }
+
+    /// Sets the minimum fee rate, in satoshis per kB, of the transactions
+    /// accepted from peers
+    pub fn min_relay_fee(mut self, min_relay_fee: u64) -> Self {
+        self.min_relay_fee = min_relay_fee;
+        self
+    }
+
+    pub fn build_cfilters(mut self, build_cfilters: bool) -> Self {
+        self.build_cfilters = build_cfilters;
+        self
+    }
+
+    /// Sets the maximum number of blocks of the active chain which may be
+    /// disconnected by a reorganization
+    pub fn max_reorg_depth(mut self, max_reorg_depth: u64) -> Self {
+        self.max_reorg_depth = max_reorg_depth;
+        self
+    }
+
+    /// Limits the messages received from each peer to `rate` per second,
+    /// `burst` messages being accepted at once
+    pub fn message_rate_limit(mut self, rate: u32, burst: u32) -> Self {
+        self.max_message_rate = rate;
+        self.max_message_burst = burst;
+        self
+    }
+
+    /// Sets the number of connection attempts in flight at once
+    pub fn max_connecting(mut self, max_connecting: usize) -> Self {
+        self.max_connecting = max_connecting;
+        self
+    }
+
+    /// Sets the time without any data from a peer after which it is pinged.
+    /// Peers which stay silent as long again are disconnected.
+    pub fn peer_timeout(mut self, peer_timeout: Duration) -> Self {
+        self.peer_timeout = peer_timeout;
+        self
+    }
+
+    /// Sets the address advertised to peers instead of learning it from
+    /// them
+    pub fn external_addr(mut self, addr: net::IpAddr) -> Self {
+        self.external_addr = Some(addr);
+        self
+    }
+
+    /// Adds a peer to connect to before the ones found from the DNS seeds
+    /// and the addr messages
+    pub fn fixed_peer(mut self, addr: net::SocketAddr) -> Self {
+        self.fixed_peers.push(addr);
+        self
+    }
+
+    /// Restricts the connections to the fixed peers, like the `-connect`
+    /// option of Bitcoin Core
+    pub fn connect_only(mut self, connect_only: bool) -> Self {
+        self.connect_only = connect_only;
+        self
+    }
+
+    /// Deletes the oldest block files once they exceed the given size,
+    /// like the `-prune` option of Bitcoin Core
+    pub fn prune_target_mib(mut self, prune_target_mib: u64) -> Self {
+        self.prune_target_mib = Some(prune_target_mib);
+        self
+    }
+
+    /// Skips some checks of the blocks, e.g. for a faster bootstrap from a
+    /// trusted peer
+    pub fn validation_level(mut self, validation_level: ValidationLevel) -> Self {
+        self.validation_level = validation_level;
+        self
+    }
+
+    /// Skips the scripts of the given block and of its ancestors, like the
+    /// `-assumevalid` option of Bitcoin Core
+    pub fn assume_valid(mut self, hash: Hash32) -> Self {
+        self.assume_valid = Some(hash);
+        self
+    }
+
+    pub fn build(self) -> Result<Config, ConfigError> {
+        if self.peer_count == 0 {
+            return Err(ConfigError::NoPeer);
+        }
+        if self.max_downloading_blocks == 0 {
+            return Err(ConfigError::EmptyDownloadWindow);
+        }
+        if self.max_connecting == 0 {
+            return Err(ConfigError::NoConnectionSlot);
+        }
+        if self.peer_timeout.as_nanos() == 0 {
+            return Err(ConfigError::NoPeerTimeout);
+        }
+        if self.data_dir.as_os_str().is_empty() {
+            return Err(ConfigError::EmptyDataDir);
+        }
+        if self.connect_only && self.fixed_peers.is_empty() {
+            return Err(ConfigError::NoFixedPeer);
+        }
+        if let Some(prune_target_mib) = self.prune_target_mib {
+            if prune_target_mib < MIN_PRUNE_TARGET_MIB {
+                return Err(ConfigError::PruneTargetTooLow);
+            }
+        }
+
+        let mut config = Config::new(self.network);
+        config.data_dir = self.data_dir;
+        config.peer_count = self.peer_count;
+        config.max_downloading_blocks = self.max_downloading_blocks;
+        config.proxy = self.proxy;
+        config.compress_blocks = self.compress_blocks;
+        config.max_block_file_size = self.max_block_file_size;
+        config.process_alerts = self.process_alerts;
+        config.min_relay_fee = self.min_relay_fee;
+        config.build_cfilters = self.build_cfilters;
+        config.max_reorg_depth = self.max_reorg_depth;
+        config.max_message_rate = self.max_message_rate;
+        config.max_message_burst = self.max_message_burst;
+        config.max_connecting = self.max_connecting;
+        config.peer_timeout = self.peer_timeout;
+        config.external_addr = self.external_addr;
+        config.fixed_peers = self.fixed_peers;
+        config.connect_only = self.connect_only;
+        config.prune_target_mib = self.prune_target_mib;
+        config.validation_level = self.validation_level;
+        config.assume_valid = self.assume_valid;
+        if let Some(genesis) = self.genesis {
+            config.genesis_block = genesis.block();
+        }
+        if self.signet_challenge.is_some() {
+            config.signet_challenge = self.signet_challenge;
+        }
+        Ok(config)
     }
 }
 
+pub fn main_config() -> Config {
+    Config::new(Network::Mainnet)
+}
+
 pub fn test_config() -> Config {
-    let mut dns_seeds = vec![
-        "testnet-seed.bitcoin.jonasschnelli.ch".to_string(),
-        "seed.tbtc.petertodd.org".to_string(),
-        "seed.testnet.bitcoin.sprovoost.nl".to_string(),
-        "testnet-seed.bluematt.me".to_string(),
-    ];
-    let mut rng = rand::thread_rng();
-    dns_seeds.shuffle(&mut rng);
-    Config {
-        genesis_block: genesis_block(
-            1,             // version
-            1296688602,    // time
-            414098458,     // nonce
-            0x1d00ffff,    // bits
-            5_000_000_000, // reward
-        ),
-        magic: 0x0709110B,
-        dns_seeds,
-        port: 18333,
+    Config::new(Network::Testnet)
+}
+
+pub fn regtest_config() -> Config {
+    Config::new(Network::Regtest)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::crypto::Hashable;
+
+    #[test]
+    fn test_network_magic() {
+        assert_eq!(Network::Mainnet.magic(), 0xD9B4BEF9);
+        assert_eq!(Network::Testnet.magic(), 0x0709110B);
+        assert_eq!(Network::Regtest.magic(), 0xDAB5BFFA);
+
+        assert_eq!(Network::from_magic(0xD9B4BEF9), Some(Network::Mainnet));
+        assert_eq!(Network::from_magic(0x0709110B), Some(Network::Testnet));
+        assert_eq!(Network::from_magic(0xDAB5BFFA), Some(Network::Regtest));
+        assert_eq!(Network::from_magic(0x40CF030A), Some(Network::Signet));
+        assert_eq!(Network::from_magic(message::MAGIC_NAMECOIN), None);
+    }
+
+    #[test]
+    fn test_network_genesis() {
+        assert_eq!(
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            hex::encode(Network::Mainnet.genesis().hash())
+        );
+        assert_eq!(
+            "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+            hex::encode(Network::Testnet.genesis().hash())
+        );
+        assert_eq!(
+            "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+            hex::encode(Network::Regtest.genesis().hash())
+        );
+        assert_eq!(
+            "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+            hex::encode(Network::Signet.genesis().hash())
+        );
+    }
+
+    #[test]
+    fn test_config_from_network() {
+        let config = regtest_config();
+        assert_eq!(config.network, Network::Regtest);
+        assert_eq!(config.magic, Network::Regtest.magic());
+        assert_eq!(config.port, 18444);
+        assert!(config.dns_seeds.is_empty());
+        assert_eq!(config.genesis_block, Network::Regtest.genesis());
+        assert_eq!(config.max_downloading_blocks, 16);
+
+        assert_eq!(config.signet_challenge, None);
+
+        let config = main_config();
+        assert_eq!(config.dns_seeds.len(), Network::Mainnet.dns_seeds().len());
+
+        let config = Config::new(Network::Signet);
+        assert_eq!(config.port, 38333);
+        assert_eq!(
+            config.signet_challenge,
+            Some(hex::decode(signet::DEFAULT_CHALLENGE).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_builder_defaults() {
+        let config = Config::builder().build().unwrap();
+        assert_eq!(config.network, Network::Testnet);
+        assert_eq!(config.magic, Network::Testnet.magic());
+        assert_eq!(config.data_dir, path::PathBuf::from("/var/tmp/yasbit"));
+        assert_eq!(config.peer_count, 8);
+        assert_eq!(config.max_downloading_blocks, 16);
+        assert_eq!(config.proxy, None);
+        assert!(!config.compress_blocks);
+        assert_eq!(config.max_block_file_size, 128 * 1024 * 1024);
+        assert!(!config.process_alerts);
+        assert_eq!(config.min_relay_fee, 1000);
+        assert!(!config.build_cfilters);
+        assert_eq!(config.max_reorg_depth, 100);
+        assert_eq!(config.max_message_rate, 200);
+        assert_eq!(config.max_message_burst, 1000);
+        assert_eq!(config.max_connecting, 4);
+        assert_eq!(config.peer_timeout, Duration::from_secs(180));
+        assert_eq!(config.external_addr, None);
+        assert!(config.relay);
+        assert!(config.fixed_peers.is_empty());
+        assert!(!config.connect_only);
+        assert_eq!(config.validation_level, ValidationLevel::Full);
+        assert_eq!(config.assume_valid, None);
+    }
+
+    #[test]
+    fn test_builder_overrides() {
+        let proxy: net::SocketAddr = "127.0.0.1:9050".parse().unwrap();
+        let config = Config::builder()
+            .network(Network::Regtest)
+            .data_dir("/tmp/yasbit")
+            .peer_count(2)
+            .max_downloading_blocks(4)
+            .proxy(proxy)
+            .compress_blocks(true)
+            .max_block_file_size(1024)
+            .process_alerts(true)
+            .min_relay_fee(5000)
+            .build_cfilters(true)
+            .max_reorg_depth(6)
+            .message_rate_limit(10, 50)
+            .max_connecting(2)
+            .peer_timeout(Duration::from_secs(30))
+            .external_addr("203.0.113.7".parse().unwrap())
+            .fixed_peer(proxy)
+            .connect_only(true)
+            .validation_level(ValidationLevel::HeadersOnly)
+            .assume_valid([1; 32])
+            .build()
+            .unwrap();
+        assert_eq!(config.network, Network::Regtest);
+        assert_eq!(config.genesis_block, Network::Regtest.genesis());
+        assert_eq!(config.port, 18444);
+        assert_eq!(config.data_dir, path::PathBuf::from("/tmp/yasbit"));
+        assert_eq!(config.peer_count, 2);
+        assert_eq!(config.max_downloading_blocks, 4);
+        assert_eq!(config.proxy, Some(proxy));
+        assert!(config.compress_blocks);
+        assert_eq!(config.max_block_file_size, 1024);
+        assert!(config.process_alerts);
+        assert_eq!(config.min_relay_fee, 5000);
+        assert!(config.build_cfilters);
+        assert_eq!(config.max_reorg_depth, 6);
+        assert_eq!(config.max_message_rate, 10);
+        assert_eq!(config.max_message_burst, 50);
+        assert_eq!(config.max_connecting, 2);
+        assert_eq!(config.peer_timeout, Duration::from_secs(30));
+        assert_eq!(config.external_addr, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(config.fixed_peers, vec![proxy]);
+        assert!(config.connect_only);
+        assert_eq!(config.validation_level, ValidationLevel::HeadersOnly);
+        assert_eq!(config.assume_valid, Some([1; 32]));
+
+        let genesis = GenesisSpec::satoshi(1, 1598918400, 52613770, 0x1e0377ae, 5_000_000_000);
+        let config = Config::builder().genesis(genesis.clone()).build().unwrap();
+        assert_eq!(config.genesis_block, genesis.block());
+
+        assert_eq!(
+            Config::builder().peer_count(0).build().unwrap_err(),
+            ConfigError::NoPeer
+        );
+        assert_eq!(
+            Config::builder().max_connecting(0).build().unwrap_err(),
+            ConfigError::NoConnectionSlot
+        );
+        assert_eq!(
+            Config::builder()
+                .peer_timeout(Duration::from_secs(0))
+                .build()
+                .unwrap_err(),
+            ConfigError::NoPeerTimeout
+        );
+        assert_eq!(
+            Config::builder().data_dir("").build().unwrap_err(),
+            ConfigError::EmptyDataDir
+        );
+        assert_eq!(
+            Config::builder().connect_only(true).build().unwrap_err(),
+            ConfigError::NoFixedPeer
+        );
+        assert_eq!(
+            Config::builder().prune_target_mib(500).build().unwrap_err(),
+            ConfigError::PruneTargetTooLow
+        );
+        assert_eq!(
+            Config::builder()
+                .prune_target_mib(MIN_PRUNE_TARGET_MIB)
+                .build()
+                .unwrap()
+                .prune_target_mib,
+            Some(MIN_PRUNE_TARGET_MIB)
+        );
     }
 }
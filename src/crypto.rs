@@ -2,12 +2,12 @@ extern crate openssl;
 
 use std::error::Error;
 
-use openssl::bn::BigNumContext;
+use openssl::bn::{BigNum, BigNumContext};
 use openssl::ec::*;
 use openssl::ecdsa::EcdsaSig;
 use openssl::hash::{hash, MessageDigest};
 use openssl::nid::Nid;
-use openssl::sha::sha256;
+use openssl::sha::{sha256, Sha256};
 
 pub type Hash32 = [u8; 32];
 pub type Hash20 = [u8; 20];
@@ -16,6 +16,36 @@ pub fn hash32(data: &[u8]) -> Hash32 {
     sha256(&sha256(data))
 }
 
+/// Double SHA256 of data given in chunks, which do not have to be gathered
+/// in a single buffer first
+pub struct Hasher32(Sha256);
+
+impl Default for Hasher32 {
+    fn default() -> Self {
+        Hasher32(Sha256::new())
+    }
+}
+
+impl Hasher32 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    /// Returns the same hash as `hash32` of the concatenated chunks
+    pub fn finalize(self) -> Hash32 {
+        sha256(&self.0.finish())
+    }
+}
+
+/// Single SHA256, as used by pay to witness script hash programs
+pub fn single_hash32(data: &[u8]) -> Hash32 {
+    sha256(data)
+}
+
 pub fn hash20(data: &[u8]) -> Hash20 {
     let mut array = [0; 20];
     for (i, byte) in hash(MessageDigest::ripemd160(), &sha256(data))
@@ -29,6 +59,11 @@ pub fn hash20(data: &[u8]) -> Hash20 {
     array
 }
 
+/// RIPEMD160(SHA256(data)), as used by OP_HASH160 and addresses
+pub fn hash160(data: &[u8]) -> Hash20 {
+    hash20(data)
+}
+
 pub fn bytes_to_hash32(data: &[u8]) -> Result<Hash32, &'static str> {
     if data.len() != 32 {
         return Err("Invalid length");
@@ -54,6 +89,74 @@ pub trait Hashable {
     fn hash(&self) -> Hash32;
 }
 
+/// Generates a new secp256k1 key pair. Returns the private key in DER format
+/// and the uncompressed public key.
+pub fn generate_keypair() -> (Vec<u8>, Vec<u8>) {
+    let group = EcGroup::from_curve_name(Nid::SECP256K1).unwrap();
+    let mut ctx = BigNumContext::new().unwrap();
+    let key = EcKey::generate(&group).unwrap();
+    let pub_key = key
+        .public_key()
+        .to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx)
+        .unwrap();
+    (key.private_key_to_der().unwrap(), pub_key)
+}
+
+/// Returns whether a 32 bytes secret is a private key, that is whether it
+/// is in the range [1, n - 1], n being the order of the curve
+pub fn is_valid_secret(secret: &[u8; 32]) -> bool {
+    let group = EcGroup::from_curve_name(Nid::SECP256K1).unwrap();
+    let mut ctx = BigNumContext::new().unwrap();
+    let mut order = BigNum::new().unwrap();
+    group.order(&mut order, &mut ctx).unwrap();
+    let secret = BigNum::from_slice(secret).unwrap();
+    secret.num_bits() > 0 && secret < order
+}
+
+fn key_from_secret(secret: &[u8; 32]) -> Result<EcKey<openssl::pkey::Private>, Box<dyn Error>> {
+    if !is_valid_secret(secret) {
+        return Err("secret out of the range of private keys".into());
+    }
+    let group = EcGroup::from_curve_name(Nid::SECP256K1)?;
+    let mut ctx = BigNumContext::new()?;
+    let priv_key = BigNum::from_slice(secret)?;
+    let mut pub_key = EcPoint::new(&group)?;
+    pub_key.mul_generator2(&group, &priv_key, &mut ctx)?;
+    Ok(EcKey::from_private_components(&group, &priv_key, &pub_key)?)
+}
+
+/// Returns the private key in DER format from its 32 bytes secret
+pub fn private_key_from_secret(secret: &[u8; 32]) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(key_from_secret(secret)?.private_key_to_der()?)
+}
+
+/// Returns the 32 bytes secret of a private key in DER format
+pub fn secret_from_private_key(priv_key: &[u8]) -> [u8; 32] {
+    let key = EcKey::private_key_from_der(priv_key).unwrap();
+    let mut secret = [0; 32];
+    let bytes = key.private_key().to_vec();
+    // Big endian, without leading zeros
+    secret[(32 - bytes.len())..].copy_from_slice(&bytes);
+    secret
+}
+
+/// Returns the public key matching a 32 bytes secret
+pub fn public_key_from_secret(
+    secret: &[u8; 32],
+    compressed: bool,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let group = EcGroup::from_curve_name(Nid::SECP256K1)?;
+    let mut ctx = BigNumContext::new()?;
+    let form = if compressed {
+        PointConversionForm::COMPRESSED
+    } else {
+        PointConversionForm::UNCOMPRESSED
+    };
+    Ok(key_from_secret(secret)?
+        .public_key()
+        .to_bytes(&group, form, &mut ctx)?)
+}
+
 pub fn sign(priv_key: &[u8], data: &Hash32) -> Vec<u8> {
     let key = EcKey::private_key_from_der(priv_key).unwrap();
     let sig = EcdsaSig::sign(data, &key).unwrap();
@@ -92,6 +195,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hasher32() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        for chunk_size in &[1, 7, 64, 1000, 10_000] {
+            let mut hasher = Hasher32::new();
+            for chunk in data.chunks(*chunk_size) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), hash32(&data));
+        }
+
+        let mut hasher = Hasher32::new();
+        hasher.update("ba".as_bytes());
+        hasher.update(&[]);
+        hasher.update("bar".as_bytes());
+        assert_eq!(hasher.finalize(), hash32("babar".as_bytes()));
+        assert_eq!(Hasher32::new().finalize(), hash32(&[]));
+    }
+
     #[test]
     fn test_hash20() {
         let data = "babar".as_bytes();
@@ -99,6 +221,46 @@ mod tests {
         assert_eq!("7bf35740091d766c45e3c052aa173fa4af80027d", hex::encode(h));
     }
 
+    #[test]
+    fn test_hash160() {
+        // Public key of the private key 1, see BIP173
+        let data =
+            hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap();
+        let h = hash160(&data);
+        assert_eq!("751e76e8199196d454941c45d1b3a323f1433bd6", hex::encode(h));
+        assert_eq!(hash20(&data), h);
+    }
+
+    #[test]
+    fn test_generate_keypair() {
+        let (priv_key, pub_key) = generate_keypair();
+        assert_eq!(pub_key.len(), 65);
+        let secret = secret_from_private_key(&priv_key);
+        assert_eq!(public_key_from_secret(&secret, false).unwrap(), pub_key);
+        assert_eq!(
+            secret_from_private_key(&private_key_from_secret(&secret).unwrap()),
+            secret
+        );
+
+        // Out of the range [1, n - 1]
+        let order = hex::decode("fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141")
+            .unwrap();
+        let mut secret = [0; 32];
+        assert!(!is_valid_secret(&secret));
+        assert!(private_key_from_secret(&secret).is_err());
+        secret.copy_from_slice(&order);
+        assert!(!is_valid_secret(&secret));
+        assert!(public_key_from_secret(&secret, true).is_err());
+        secret[31] -= 1;
+        assert!(is_valid_secret(&secret));
+        assert!(public_key_from_secret(&secret, true).is_ok());
+
+        let data = hash32("BABAR".as_bytes());
+        let sig = sign(&priv_key, &data);
+        assert!(check_signature(&pub_key, &sig, &data).unwrap());
+    }
+
     #[test]
     fn test_generate_keys_sign_verify() {
         let mut ctx = BigNumContext::new().unwrap();
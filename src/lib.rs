@@ -1,28 +1,72 @@
 extern crate hex;
 extern crate rand;
-mod block;
-mod config;
-mod crypto;
+pub mod address;
+pub mod block;
+mod bloom;
+mod cfilter;
+pub mod config;
+pub mod crypto;
+mod mempool;
 mod merkle_tree;
-mod message;
+pub mod message;
 mod network;
 mod node;
-mod script;
+pub mod psbt;
+pub mod script;
+mod signet;
 mod storage;
-mod transaction;
+pub mod transaction;
 mod utils;
 mod valider;
 mod variable_integer;
+pub mod wallet;
 
 use crate::crypto::Hashable;
 use dns_lookup::lookup_host;
+use rand::seq::IteratorRandom;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::net;
-use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
+use std::time;
 
-const PEERS_NUMBER: usize = 8;
-const MAX_HEADERS: usize = 2000;
+const MAX_HEADERS: usize = message::headers::MAX_HEADERS;
+// Number of relayed transactions remembered to avoid announcing them twice
+const MAX_KNOWN_TXS: usize = 50_000;
+// Maximum number of transactions kept to be served to peers, the ones of
+// lowest fee rate are evicted first
+const MAX_MEMPOOL_TXS: usize = 5000;
+// A peer whose ban score reaches this threshold is banned
+const BAN_THRESHOLD: u32 = 100;
+const BAN_DURATION: time::Duration = time::Duration::from_secs(24 * 60 * 60);
+const DNS_RETRY_ROUNDS: u32 = 4;
+const DNS_RETRY_DELAY: time::Duration = time::Duration::from_millis(500);
+// The sync node is replaced if it does not send headers within this delay
+const SYNC_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+// Known peers which have not been seen active for this long are forgotten
+const ADDR_MAX_AGE: time::Duration = time::Duration::from_secs(3 * 60 * 60);
+const ADDR_PRUNE_INTERVAL: time::Duration = time::Duration::from_secs(10 * 60);
+// Announced transactions may be asked to another peer after this delay
+const INVENTORY_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+// Connected peers are pinged at this interval to measure their latency
+const PING_INTERVAL: time::Duration = time::Duration::from_secs(2 * 60);
+// Maximum number of addresses sent in response to a getaddr message
+const MAX_ADDR_RESPONSE: usize = 1000;
+// Maximum number of compact filters kept to be matched against scripts
+const MAX_FILTERS: usize = 2000;
+// Maximum number of filters requested by a getcfilters message (BIP157)
+const MAX_GETCFILTERS_SIZE: u64 = 1000;
+// Maximum number of filter hashes requested by a getcfheaders message
+const MAX_GETCFHEADERS_SIZE: u64 = 2000;
+// Interval between the filter headers sent in a cfcheckpt message
+const CFCHECKPT_INTERVAL: u64 = 1000;
+// Number of headers responses kept for the peers asking for the same ones
+const HEADERS_CACHE_SIZE: usize = 16;
+// Number of distinct peers which must see us at the same address before
+// it is advertised
+const EXTERNAL_ADDR_VOTES: usize = 3;
+// Log target of the sync events, so that they can be filtered
+const SYNC_LOG_TARGET: &str = "yasbit::sync";
 
 #[derive(Debug)]
 struct GlobalState {
@@ -30,43 +74,482 @@ struct GlobalState {
     known_active_nodes: HashSet<network::NetAddr>,
     sync_node_id: Option<node::NodeId>,
     download_queue: VecDeque<crypto::Hash32>,
+    block_sources: HashMap<crypto::Hash32, node::NodeId>, // node which served each block
+    ban_scores: HashMap<node::NodeId, u32>,
+    banned: HashMap<net::Ipv6Addr, time::SystemTime>, // banned addresses and the end of their ban
+    last_header: crypto::Hash32,                      // locator of the next getheaders message
+    header_locator: Vec<crypto::Hash32>, // ancestors sent after last_header, set after a reorg
+    headers_request: u64,                // incremented each time headers are requested
+    headers_in_flight: Option<node::NodeId>, // node asked for headers, until it answers
+    known_txs: HashSet<crypto::Hash32>,  // transactions already relayed
+    mempool: mempool::Mempool,           // transactions accepted, served on getdata
+    known_headers: HashSet<crypto::Hash32>, // headers already queued for download
+    header_parents: HashMap<crypto::Hash32, crypto::Hash32>, // parent of each queued block, until it is validated
+    invalid_blocks: HashSet<crypto::Hash32>, // blocks found invalid, and their descendants
+    inventory_in_flight: HashMap<crypto::Hash32, (node::NodeId, time::Instant)>, // announced transactions asked for, to which node and when
+    blocks_in_flight: HashSet<crypto::Hash32>, // blocks asked to a download node, until received
+    headers_cache: HeadersCache,               // headers recently served to peers
+    storage: Option<Arc<storage::Storage>>,    // blocks which do not need to be downloaded
+    event_subscriber: Option<mpsc::Sender<SyncEvent>>, // receives the sync events
+    filters: HashMap<crypto::Hash32, cfilter::Filter>, // compact filters received from peers
+    connect_limiter: Arc<ConnectLimiter>,      // shared by the threads connecting to peers
+    external_addr: Option<net::IpAddr>,        // our address, as agreed by several peers
+    external_addr_votes: HashMap<net::IpAddr, HashSet<net::IpAddr>>, // peers seeing each address
+    initial_block_download: bool, // until the blocks of the whole header chain are stored
+    headers_synced: bool,         // the sync node has no more headers
+    stored_blocks: u64,           // blocks validated and stored since the start
+}
+
+/// Bounds the number of connection attempts in flight at once, so that
+/// replacing many peers does not open a burst of connections
+#[derive(Debug)]
+struct ConnectLimiter {
+    max: usize,
+    in_flight: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A connection attempt in flight, ended when dropped
+struct ConnectPermit<'a>(&'a ConnectLimiter);
+
+impl ConnectLimiter {
+    fn new(max: usize) -> Self {
+        ConnectLimiter {
+            max,
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits until fewer than `max` connection attempts are in flight
+    fn acquire(&self) -> ConnectPermit<'_> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight >= self.max {
+            in_flight = self.released.wait(in_flight).unwrap();
+        }
+        *in_flight += 1;
+        ConnectPermit(self)
+    }
+}
+
+impl Drop for ConnectPermit<'_> {
+    fn drop(&mut self) {
+        *self.0.in_flight.lock().unwrap() -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Headers messages recently sent in response to getheaders messages, the
+/// least recently used first. They are keyed on the first hash of the
+/// locator and the stop hash, and only valid until the active chain changes.
+#[derive(Debug)]
+struct HeadersCache {
+    capacity: usize,
+    entries: VecDeque<(
+        (crypto::Hash32, crypto::Hash32),
+        message::headers::MessageHeaders,
+    )>,
+    storage_reads: u64, // responses which had to be read from the storage
+}
+
+impl HeadersCache {
+    fn new(capacity: usize) -> Self {
+        HeadersCache {
+            capacity,
+            entries: VecDeque::new(),
+            storage_reads: 0,
+        }
+    }
+
+    fn get(
+        &mut self,
+        key: &(crypto::Hash32, crypto::Hash32),
+    ) -> Option<message::headers::MessageHeaders> {
+        let index = self.entries.iter().position(|(k, _)| k == key)?;
+        let entry = self.entries.remove(index)?;
+        let headers = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(headers)
+    }
+
+    fn insert(
+        &mut self,
+        key: (crypto::Hash32, crypto::Hash32),
+        headers: message::headers::MessageHeaders,
+    ) {
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, headers));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Number of outbound slots in each state
+#[derive(Debug, PartialEq)]
+struct ConnectionCounts {
+    connected: usize,
+    connecting: usize,
+    dead: usize,
+}
+
+impl ConnectionCounts {
+    /// Returns the number of slots which hold a peer
+    fn live(&self) -> usize {
+        self.connected + self.connecting
+    }
+}
+
+impl GlobalState {
+    fn new(last_header: crypto::Hash32) -> Self {
+        GlobalState {
+            nodes: vec![],
+            known_active_nodes: HashSet::new(),
+            sync_node_id: None,
+            download_queue: VecDeque::new(),
+            block_sources: HashMap::new(),
+            ban_scores: HashMap::new(),
+            banned: HashMap::new(),
+            last_header,
+            header_locator: Vec::new(),
+            headers_request: 0,
+            headers_in_flight: None,
+            known_txs: HashSet::new(),
+            mempool: mempool::Mempool::new(),
+            known_headers: [last_header].iter().cloned().collect(),
+            header_parents: HashMap::new(),
+            invalid_blocks: HashSet::new(),
+            inventory_in_flight: HashMap::new(),
+            blocks_in_flight: HashSet::new(),
+            headers_cache: HeadersCache::new(HEADERS_CACHE_SIZE),
+            storage: None,
+            event_subscriber: None,
+            filters: HashMap::new(),
+            connect_limiter: Arc::new(ConnectLimiter::new(config::DEFAULT_MAX_CONNECTING)),
+            external_addr: None,
+            external_addr_votes: HashMap::new(),
+            initial_block_download: true,
+            headers_synced: false,
+            stored_blocks: 0,
+        }
+    }
+
+    /// Logs a sync event and sends it to the subscriber, if any. The
+    /// subscriber is forgotten once it has hung up.
+    fn emit(&mut self, event: SyncEvent) {
+        log::info!(target: SYNC_LOG_TARGET, "{:?}", event);
+        let hung_up = match &self.event_subscriber {
+            Some(subscriber) => subscriber.send(event).is_err(),
+            None => false,
+        };
+        if hung_up {
+            self.event_subscriber = None;
+        }
+    }
+
+    fn connection_counts(&self) -> ConnectionCounts {
+        let mut counts = ConnectionCounts {
+            connected: 0,
+            connecting: 0,
+            dead: 0,
+        };
+        for node in &self.nodes {
+            match node.state() {
+                node::NodeState::DISCONNECTED => counts.dead += 1,
+                node::NodeState::CONNECTING(_) => counts.connecting += 1,
+                _ => counts.connected += 1,
+            }
+        }
+        counts
+    }
+
+    /// Returns whether the block has already been stored, e.g. before a
+    /// restart
+    fn has_block(&self, hash: crypto::Hash32) -> bool {
+        match &self.storage {
+            Some(storage) => storage.has_block(hash).unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Ends the initial block download once the whole header chain is known
+    /// and `tip`, the last block stored, is its last header. Returns whether
+    /// it has just ended.
+    fn update_initial_block_download(&mut self, tip: crypto::Hash32) -> bool {
+        if self.initial_block_download && self.headers_synced && tip == self.last_header {
+            log::info!(
+                "Initial block download is over after {} blocks, ask peers for transactions",
+                self.stored_blocks
+            );
+            self.initial_block_download = false;
+            return true;
+        }
+        false
+    }
+
+    /// Returns whether the given address is currently banned
+    fn is_banned(&self, ip: &net::IpAddr) -> bool {
+        match self.banned.get(&network::to_ipv6(ip)) {
+            Some(until) => *until > time::SystemTime::now(),
+            None => false,
+        }
+    }
+
+    /// Bans the given address for BAN_DURATION
+    fn ban(&mut self, ip: &net::IpAddr) {
+        let now = time::SystemTime::now();
+        // Forget about expired bans
+        self.banned.retain(|_, until| *until > now);
+        self.banned.insert(network::to_ipv6(ip), now + BAN_DURATION);
+    }
 }
 
 pub enum ControllerMessage {
     NodeResponse(node::NodeResponse),
     ValiderResponse(valider::ValiderMessage),
+    SyncTimeout(u64), // headers request which has not been answered in time
+    PruneAddrs,       // forget the known peers which have not been seen recently
+    BroadcastTx(transaction::Transaction), // locally created transaction to announce
+    Shutdown,         // stop the nodes and flush the storage
+    PingPeers,        // measure the latency of the connected peers
+    RequestFilters(u32, crypto::Hash32), // start height and stop hash of the filters to ask for
+    MatchFilters(Vec<Vec<u8>>, mpsc::Sender<Vec<crypto::Hash32>>), // blocks matching the scripts
+    ScanWallet(Vec<crypto::Hash20>, mpsc::Sender<Vec<wallet::Utxo>>), // outputs paying the hashes
+}
+
+/// Key events of the synchronization, sent to the subscriber given to
+/// `run_with_subscriber`
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncEvent {
+    PeerConnected(node::NodeId),
+    SyncNodeChosen(node::NodeId),
+    HeadersReceived(node::NodeId, usize), // number of new headers
+    BlockValidated(crypto::Hash32, u64),  // hash and height
+    Reorg(crypto::Hash32, crypto::Hash32), // previous and new tip
+    PeerBanned(net::IpAddr),
+}
+
+/// Resolves the addresses of peers from a DNS seed
+pub trait PeerResolver {
+    /// Returns the addresses found for the given seed. An empty vector is
+    /// returned if the resolution failed.
+    fn resolve(&self, seed: &str) -> Vec<net::IpAddr>;
 }
 
-fn get_peers_from_dns(config: &config::Config, size: usize) -> Vec<std::net::IpAddr> {
-    // Load peers
-    let mut addrs = Vec::new();
-    for seed in &config.dns_seeds {
+pub struct DnsResolver;
+
+impl PeerResolver for DnsResolver {
+    fn resolve(&self, seed: &str) -> Vec<net::IpAddr> {
         log::debug!("Resolve {}", seed);
-        match lookup_host(&seed) {
-            Ok(ips) => {
-                if !ips.is_empty() {
-                    addrs = ips;
-                    break;
-                }
+        lookup_host(seed).unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub enum PeerDiscoveryError {
+    NoPeerFound,
+}
+
+fn get_peers_from_dns(
+    config: &config::Config,
+    resolver: &dyn PeerResolver,
+    size: usize,
+) -> Result<Vec<net::IpAddr>, PeerDiscoveryError> {
+    resolve_peers(
+        &config.dns_seeds,
+        resolver,
+        size,
+        DNS_RETRY_ROUNDS,
+        DNS_RETRY_DELAY,
+    )
+}
+
+/// Returns the peers to connect to at startup: the fixed peers first, then
+/// the ones found from the DNS seeds unless in connect-only mode
+fn initial_peers(
+    config: &config::Config,
+    resolver: &dyn PeerResolver,
+) -> Result<Vec<net::SocketAddr>, PeerDiscoveryError> {
+    let mut addrs = config.fixed_peers.clone();
+    if !config.connect_only && addrs.len() < config.peer_count {
+        match get_peers_from_dns(config, resolver, config.peer_count - addrs.len()) {
+            Ok(found) => addrs.extend(
+                found
+                    .into_iter()
+                    .map(|addr| net::SocketAddr::new(addr, config.port)),
+            ),
+            Err(err) if addrs.is_empty() => return Err(err),
+            Err(_) => log::warn!("Could not find any peer from DNS, use the fixed peers"),
+        }
+    }
+    if addrs.is_empty() {
+        return Err(PeerDiscoveryError::NoPeerFound);
+    }
+    Ok(addrs)
+}
+
+/// Tries each seed in turn until one of them gives some peers. If all of
+/// them fail, retries `rounds` times, doubling the delay between each round.
+fn resolve_peers(
+    seeds: &[String],
+    resolver: &dyn PeerResolver,
+    size: usize,
+    rounds: u32,
+    delay: time::Duration,
+) -> Result<Vec<net::IpAddr>, PeerDiscoveryError> {
+    if seeds.is_empty() {
+        return Err(PeerDiscoveryError::NoPeerFound);
+    }
+
+    let mut delay = delay;
+    for round in 0..rounds {
+        if round > 0 {
+            log::warn!(
+                "Could not resolve any DNS seed. Retry in {} ms",
+                delay.as_millis()
+            );
+            thread::sleep(delay);
+            delay *= 2;
+        }
+
+        for seed in seeds {
+            let mut addrs = resolver.resolve(seed);
+            if !addrs.is_empty() {
+                addrs.truncate(size);
+                log::info!("Peers: {:?}", addrs);
+                return Ok(addrs);
             }
-            _ => (),
         }
     }
-    addrs.truncate(size);
-    log::info!("Peers: {:?}", addrs);
-    addrs
+    Err(PeerDiscoveryError::NoPeerFound)
+}
+
+/// Handle to a node started with `start`, through which it is driven from
+/// other threads
+#[derive(Debug, Clone)]
+pub struct ControllerHandle {
+    sender: mpsc::Sender<ControllerMessage>,
+}
+
+impl ControllerHandle {
+    /// Stops the nodes and flushes the storage. The thread of the node
+    /// returns once it is done.
+    pub fn shutdown(&self) {
+        self.sender
+            .send(ControllerMessage::Shutdown)
+            .unwrap_or_default();
+    }
+
+    /// Announces a locally created transaction to the peers
+    pub fn broadcast_transaction(&self, tx: transaction::Transaction) {
+        self.sender
+            .send(ControllerMessage::BroadcastTx(tx))
+            .unwrap_or_default();
+    }
+
+    /// Asks the sync node for the basic compact filters of the blocks from
+    /// `start_height` up to `stop_hash` (BIP157)
+    pub fn request_filters(&self, start_height: u32, stop_hash: crypto::Hash32) {
+        self.sender
+            .send(ControllerMessage::RequestFilters(start_height, stop_hash))
+            .unwrap_or_default();
+    }
+
+    /// Returns the blocks whose received filter matches any of the scripts,
+    /// or None if the node has stopped
+    pub fn blocks_matching(&self, scripts: Vec<Vec<u8>>) -> Option<Vec<crypto::Hash32>> {
+        let (sender, receiver) = mpsc::channel();
+        self.sender
+            .send(ControllerMessage::MatchFilters(scripts, sender))
+            .ok()?;
+        receiver.recv().ok()
+    }
+
+    /// Returns the unspent outputs of the active chain paying any of the
+    /// public key hashes, or None if the node has stopped
+    pub fn unspent_outputs(&self, pubkey_hashes: Vec<crypto::Hash20>) -> Option<Vec<wallet::Utxo>> {
+        let (sender, receiver) = mpsc::channel();
+        self.sender
+            .send(ControllerMessage::ScanWallet(pubkey_hashes, sender))
+            .ok()?;
+        receiver.recv().ok()
+    }
+}
+
+pub fn run(config: config::Config) {
+    let (controller_sender, controller_receiver) = mpsc::channel();
+    run_with_events(config, None, controller_sender, controller_receiver)
+}
+
+/// Runs the node like `run`, sending the sync events to `subscriber`
+pub fn run_with_subscriber(config: config::Config, subscriber: mpsc::Sender<SyncEvent>) {
+    let (controller_sender, controller_receiver) = mpsc::channel();
+    run_with_events(
+        config,
+        Some(subscriber),
+        controller_sender,
+        controller_receiver,
+    )
 }
 
-pub fn run() {
-    let config = config::test_config();
+/// Runs the node in its own thread, sending the sync events to
+/// `subscriber` if any. Returns the handle driving the node and the handle
+/// of its thread, which returns once the node is shut down.
+pub fn start(
+    config: config::Config,
+    subscriber: Option<mpsc::Sender<SyncEvent>>,
+) -> (ControllerHandle, thread::JoinHandle<()>) {
+    let (controller_sender, controller_receiver) = mpsc::channel();
+    let handle = ControllerHandle {
+        sender: controller_sender.clone(),
+    };
+    let thread = thread::spawn(move || {
+        run_with_events(config, subscriber, controller_sender, controller_receiver)
+    });
+    (handle, thread)
+}
 
+fn run_with_events(
+    config: config::Config,
+    event_subscriber: Option<mpsc::Sender<SyncEvent>>,
+    controller_sender: mpsc::Sender<ControllerMessage>,
+    controller_receiver: mpsc::Receiver<ControllerMessage>,
+) {
     // Initialize DBs
-    let mut storage = storage::Storage::new(
-        "/var/tmp/yasbit/blocks.db",
-        "/var/tmp/yasbit/transactions.db",
-        "/var/tmp/yasbit/chain.db",
-        "/var/tmp/yasbit/blocks/",
-    );
+    let data_path = |name: &str| config.data_dir.join(name).to_str().unwrap().to_string();
+    if let Err(err) = std::fs::create_dir_all(config.data_dir.join("blocks")) {
+        log::error!("Could not create data directory: {:?}", err);
+        return;
+    }
+    let mut storage = match storage::Storage::new(
+        &data_path("blocks.db"),
+        &data_path("transactions.db"),
+        &data_path("chain.db"),
+        &data_path("blocks"),
+    ) {
+        Ok(storage) => storage,
+        Err(storage::Error::IncompatibleVersion(version)) => {
+            log::error!(
+                "The data directory has been written with the storage format {}, \
+                 which is incompatible with the current format {}",
+                version,
+                storage::STORAGE_VERSION
+            );
+            return;
+        }
+        Err(err) => {
+            log::error!("Could not open the storage: {:?}", err);
+            return;
+        }
+    };
+    storage.set_compress_blocks(config.compress_blocks);
+    storage.set_max_block_file_size(config.max_block_file_size);
+    storage.set_build_cfilters(config.build_cfilters);
+    storage.set_max_reorg_depth(config.max_reorg_depth);
+    storage.set_prune_target(config.prune_target_mib.map(|mib| mib * 1024 * 1024));
 
     match storage.has_block(config.genesis_block.hash()) {
         Ok(true) => log::info!(
@@ -86,44 +569,69 @@ pub fn run() {
         }
     }
 
-    let addrs = get_peers_from_dns(&config, PEERS_NUMBER);
+    // Resume the sync from the last validated block
+    let tip = match storage.tip() {
+        Ok(Some((hash, height))) => {
+            log::info!(
+                "Resume from block {} at height {}",
+                hex::encode(hash),
+                height
+            );
+            hash
+        }
+        Ok(None) => config.genesis_block.hash(),
+        Err(err) => {
+            log::error!("Storage error: {:?}.", err);
+            return;
+        }
+    };
 
-    let mut state = GlobalState {
-        nodes: vec![],
-        known_active_nodes: HashSet::new(),
-        sync_node_id: None,
-        download_queue: VecDeque::new(),
+    let addrs = match initial_peers(&config, &DnsResolver) {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            log::error!("Could not find any peer: {:?}", err);
+            return;
+        }
     };
 
-    let (controller_sender, controller_receiver) = mpsc::channel();
+    let mut state = GlobalState::new(tip);
+    state.event_subscriber = event_subscriber;
+    state.connect_limiter = Arc::new(ConnectLimiter::new(config.max_connecting));
 
-    for addr in &addrs {
+    // Only outbound connections are supported: one slot per peer
+    for node_id in 0..config.peer_count {
         let (command_sender, command_receiver) = mpsc::channel();
-        let node_id = state.nodes.len();
-        state
-            .nodes
-            .push(node::NodeHandle::new(node_id, command_sender));
-        let node_controller_sender = controller_sender.clone();
-        let node_sock_addr = net::SocketAddr::new(*addr, config.port);
-        let node_config = config.clone();
-        thread::spawn(move || {
-            start_node(
+        let mut node_handle = node::NodeHandle::new(node_id, command_sender);
+        node_handle
+            .set_state(node::NodeState::DISCONNECTED)
+            .unwrap_or_default();
+        state.nodes.push(node_handle);
+        if let Some(addr) = addrs.get(node_id) {
+            spawn_node(
+                &mut state,
+                &config,
+                &controller_sender,
                 node_id,
-                node_sock_addr,
+                *addr,
                 command_receiver,
-                node_controller_sender,
-                node_config,
-            )
-        });
+            );
+        }
     }
 
+    // Storage is shared between the controller and the valider thread
+    let storage = Arc::new(storage);
+    state.storage = Some(Arc::clone(&storage));
+
     // Spawn valider thread
     let (mut valider_sender, valider_receiver) = mpsc::channel();
     let valider_sender_timeout = valider_sender.clone();
     let valider_controller_sender = controller_sender.clone();
+    let valider_storage = Arc::clone(&storage);
+    let valider_config = config.clone();
     thread::spawn(move || {
         valider::run(
-            storage,
+            valider_config,
+            valider_storage,
             valider_sender_timeout.clone(),
             valider_receiver,
             valider_controller_sender,
@@ -131,22 +639,107 @@ pub fn run() {
     });
     log::info!("Valider thread spawned");
 
+    // Periodically forget stale peers
+    let prune_sender = controller_sender.clone();
+    thread::spawn(move || loop {
+        thread::sleep(ADDR_PRUNE_INTERVAL);
+        if prune_sender.send(ControllerMessage::PruneAddrs).is_err() {
+            break;
+        }
+    });
+
+    // Periodically ping the peers
+    let ping_sender = controller_sender.clone();
+    thread::spawn(move || loop {
+        thread::sleep(PING_INTERVAL);
+        if ping_sender.send(ControllerMessage::PingPeers).is_err() {
+            break;
+        }
+    });
+
     loop {
         log::trace!("Global State: {:?}", state);
         let message = controller_receiver.recv().unwrap();
+        if !handle_controller_message(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            message,
+        ) {
+            return;
+        }
+    }
+}
 
-        match message {
-            ControllerMessage::NodeResponse(response) => handle_node_response(
-                &mut state,
-                &config,
-                &mut valider_sender,
-                &controller_sender,
-                response,
-            ),
-            ControllerMessage::ValiderResponse(valider_message) => {
-                handle_valider_message(&mut state, &config, valider_message, &controller_sender)
-            }
-        };
+/// Handles a message received by the controller. Returns false once the
+/// node has been shut down.
+fn handle_controller_message(
+    state: &mut GlobalState,
+    config: &config::Config,
+    valider_sender: &mut mpsc::Sender<valider::Message>,
+    controller_sender: &mpsc::Sender<ControllerMessage>,
+    message: ControllerMessage,
+) -> bool {
+    match message {
+        ControllerMessage::NodeResponse(response) => {
+            handle_node_response(state, config, valider_sender, controller_sender, response)
+        }
+        ControllerMessage::ValiderResponse(valider_message) => handle_valider_message(
+            state,
+            config,
+            valider_sender,
+            valider_message,
+            controller_sender,
+        ),
+        ControllerMessage::SyncTimeout(request) => {
+            handle_sync_timeout(state, config, controller_sender, request)
+        }
+        ControllerMessage::PruneAddrs => prune_known_peers(state, unix_time()),
+        ControllerMessage::BroadcastTx(tx) => accept_transaction(state, config, None, tx),
+        ControllerMessage::PingPeers => ping_peers(state),
+        ControllerMessage::RequestFilters(start_height, stop_hash) => match state.sync_node_id {
+            Some(node_id) => request_filters(state, config, node_id, start_height, stop_hash),
+            None => log::warn!("No node to ask for filters"),
+        },
+        ControllerMessage::MatchFilters(scripts, sender) => sender
+            .send(blocks_matching(state, &scripts))
+            .unwrap_or_default(),
+        ControllerMessage::ScanWallet(pubkey_hashes, sender) => sender
+            .send(scan_wallet(state, pubkey_hashes))
+            .unwrap_or_default(),
+        ControllerMessage::Shutdown => {
+            shutdown(state);
+            return false;
+        }
+    };
+    true
+}
+
+/// Sends a ping to the nodes which completed the handshake
+fn ping_peers(state: &GlobalState) {
+    for node_handle in state.nodes.iter() {
+        match node_handle.state() {
+            node::NodeState::UPDATING_PEERS | node::NodeState::UPDATING_BLOCKS => node_handle
+                .send(node::NodeCommand::Ping)
+                .unwrap_or_default(),
+            _ => (),
+        }
+    }
+}
+
+/// Stops every node and flushes the storage before the controller returns
+fn shutdown(state: &GlobalState) {
+    log::info!("Shutting down");
+    for node_handle in state.nodes.iter() {
+        node_handle
+            .send(node::NodeCommand::Kill)
+            .unwrap_or_default();
+    }
+    if let Some(storage) = &state.storage {
+        if let Err(err) = storage.flush() {
+            log::error!("Could not flush the storage: {:?}", err);
+        }
     }
 }
 
@@ -158,13 +751,70 @@ fn node_restart_with_new_peer(
 ) {
     log::info!("[{}] Restart node", node_id);
 
-    let node_handle = match get_node_handle(&mut state.nodes, &node_id) {
-        Some(handle) => handle,
+    let (command_receiver, old_addr) = match reset_node(state, node_id) {
+        Some(reset) => reset,
         None => {
             log::warn!("Can not get node_handle: {}", node_id);
             return;
         }
     };
+
+    // Restart node with a new peer
+    state.ban_scores.remove(&node_id);
+
+    if state.connection_counts().live() >= config.peer_count {
+        log::info!("[{}] Outbound limit reached, leave the slot empty", node_id);
+        return;
+    }
+
+    let node_sock_addr = match next_known_peer(state, config, old_addr) {
+        Some(addr) => addr,
+        None if config.connect_only => {
+            log::error!("[{}] No fixed peer to connect to", node_id);
+            return;
+        }
+        None => {
+            let addrs: Vec<net::IpAddr> =
+                get_peers_from_dns(config, &DnsResolver, config.peer_count)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|addr| !state.is_banned(addr))
+                    .collect();
+            if addrs.len() < 1 {
+                log::error!("Could not find another peer from DNS");
+                return;
+            }
+
+            net::SocketAddr::new(addrs[0], config.port)
+        }
+    };
+
+    spawn_node(
+        state,
+        config,
+        controller_sender,
+        node_id,
+        node_sock_addr,
+        command_receiver,
+    );
+
+    // Send a download message to all nodes
+    send_download_message(state, config);
+}
+
+/// Kills a node and resets its handle, giving the blocks and transactions
+/// asked to it back to the other nodes. Returns the receiver of the commands
+/// for the next peer of the slot, and the address of the old one.
+fn reset_node(
+    state: &mut GlobalState,
+    node_id: node::NodeId,
+) -> Option<(mpsc::Receiver<node::NodeCommand>, Option<net::SocketAddr>)> {
+    // The transactions asked to the old node may be asked to other nodes
+    state
+        .inventory_in_flight
+        .retain(|_, (asked_node_id, _)| *asked_node_id != node_id);
+
+    let node_handle = get_node_handle(&mut state.nodes, &node_id)?;
     // Kill this node
     node_handle
         .send(node::NodeCommand::Kill)
@@ -175,6 +825,7 @@ fn node_restart_with_new_peer(
     // these blocks
     loop {
         if let Some(hash) = node_handle.download_current_pop() {
+            state.blocks_in_flight.remove(&hash);
             state.download_queue.push_front(hash);
         } else {
             break;
@@ -185,30 +836,129 @@ fn node_restart_with_new_peer(
     let (command_sender, command_receiver) = mpsc::channel();
 
     // Reset node handle
+    let old_addr = node_handle.addr();
     node_handle.reset(command_sender);
+    node_handle
+        .set_state(node::NodeState::DISCONNECTED)
+        .unwrap_or_default();
+    Some((command_receiver, old_addr))
+}
 
-    // Restart node with a new peer
-    let node_id = node_handle.id();
+/// Reconnects to the peers connected during the initial block download,
+/// which have been asked not to announce transactions (BIP37)
+fn reconnect_for_relay(
+    state: &mut GlobalState,
+    config: &config::Config,
+    controller_sender: &mpsc::Sender<ControllerMessage>,
+) {
+    if !config.relay {
+        return;
+    }
+    let peers: Vec<(node::NodeId, net::SocketAddr)> = state
+        .nodes
+        .iter()
+        .filter(|node| node.state() != &node::NodeState::DISCONNECTED)
+        .filter_map(|node| Some((node.id(), node.addr()?)))
+        .collect();
+    for (node_id, addr) in peers {
+        log::info!(
+            "[{}] Reconnect to the peer to hear about transactions",
+            node_id
+        );
+        if let Some((command_receiver, _)) = reset_node(state, node_id) {
+            spawn_node(
+                state,
+                config,
+                controller_sender,
+                node_id,
+                addr,
+                command_receiver,
+            );
+        }
+    }
+}
 
-    let (addr, port) = match state.known_active_nodes.iter().nth(0) {
-        Some(active_node) => (
-            net::IpAddr::from(active_node.net_addr_version.ip),
-            active_node.net_addr_version.port,
-        ),
-        None => {
-            let addrs = get_peers_from_dns(config, 1);
-            if addrs.len() < 1 {
-                log::error!("Could not find another peer from DNS");
-                return;
-            }
+/// Returns a known peer which is neither banned nor already connected. The
+/// fixed peers come first and, unlike the gossiped ones, are never excluded.
+fn next_known_peer(
+    state: &GlobalState,
+    config: &config::Config,
+    exclude: Option<net::SocketAddr>,
+) -> Option<net::SocketAddr> {
+    let gossiped = state
+        .known_active_nodes
+        .iter()
+        .map(network::NetAddr::to_socket_addr)
+        .filter(|addr| Some(*addr) != exclude);
+    config
+        .fixed_peers
+        .iter()
+        .cloned()
+        .chain(gossiped)
+        .find(|addr| {
+            !state.is_banned(&addr.ip())
+                && !state.nodes.iter().any(|node| node.addr() == Some(*addr))
+        })
+}
 
-            (addrs[0], config.port)
-        }
-    };
+/// Returns the current time as a UNIX timestamp, as found in addresses
+fn unix_time() -> u32 {
+    time::SystemTime::now()
+        .duration_since(time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
+
+/// Returns whether a known peer has been seen active recently enough to be
+/// advertised
+fn is_fresh(addr: &network::NetAddr, now: u32) -> bool {
+    u64::from(addr.time()) + ADDR_MAX_AGE.as_secs() >= u64::from(now)
+}
 
-    let node_sock_addr = net::SocketAddr::new(addr, port);
-    let node_config = config.clone();
+/// Forgets the known peers which have not been seen active for ADDR_MAX_AGE
+fn prune_known_peers(state: &mut GlobalState, now: u32) {
+    let before = state.known_active_nodes.len();
+    state.known_active_nodes.retain(|addr| is_fresh(addr, now));
+    log::debug!(
+        "Pruned {} stale peers",
+        before - state.known_active_nodes.len()
+    );
+}
+
+/// Returns at most `count` fresh known peers, picked randomly
+fn sample_known_peers(state: &GlobalState, now: u32, count: usize) -> Vec<network::NetAddr> {
+    let mut rng = rand::thread_rng();
+    state
+        .known_active_nodes
+        .iter()
+        .filter(|addr| is_fresh(addr, now))
+        .cloned()
+        .choose_multiple(&mut rng, count)
+}
+
+/// Starts the thread talking to a peer on behalf of the given node handle
+fn spawn_node(
+    state: &mut GlobalState,
+    config: &config::Config,
+    controller_sender: &mpsc::Sender<ControllerMessage>,
+    node_id: node::NodeId,
+    node_sock_addr: net::SocketAddr,
+    command_receiver: mpsc::Receiver<node::NodeCommand>,
+) {
+    if let Some(node_handle) = get_node_handle(&mut state.nodes, &node_id) {
+        node_handle.set_addr(node_sock_addr);
+        node_handle
+            .set_state(node::NodeState::CONNECTING(node::ConnectionState::CLOSED))
+            .unwrap_or_default();
+    }
+    let mut node_config = config.clone();
+    if node_config.external_addr.is_none() {
+        node_config.external_addr = state.external_addr;
+    }
+    // Transactions are useless until the chain is synchronized
+    node_config.relay = config.relay && !state.initial_block_download;
     let node_controller_sender = controller_sender.clone();
+    let connect_limiter = Arc::clone(&state.connect_limiter);
     log::info!(
         "[{}] Start communicating with a new peer: {:?}",
         node_id,
@@ -221,16 +971,112 @@ fn node_restart_with_new_peer(
             command_receiver,
             node_controller_sender,
             node_config,
+            &connect_limiter,
         )
     });
-
-    // Send a download message to all nodes
-    send_download_message(state, config);
+}
+
+/// Records the address at which a peer sees this node. Once enough distinct
+/// peers agree on an address, it is advertised to the next peers.
+fn learn_external_addr(
+    state: &mut GlobalState,
+    peer_addr: Option<net::SocketAddr>,
+    addr: net::IpAddr,
+) {
+    if addr.is_unspecified() || addr.is_loopback() || state.external_addr == Some(addr) {
+        return;
+    }
+    let peer_ip = match peer_addr {
+        Some(peer_addr) => peer_addr.ip(),
+        None => return,
+    };
+    let peers = state
+        .external_addr_votes
+        .entry(addr)
+        .or_insert_with(HashSet::new);
+    peers.insert(peer_ip);
+    if peers.len() >= EXTERNAL_ADDR_VOTES {
+        log::info!("External address is now {}", addr);
+        state.external_addr = Some(addr);
+        state.external_addr_votes.clear();
+    }
+}
+
+/// Fills the empty slots with known peers until the outbound target is
+/// reached
+fn top_up_connections(
+    state: &mut GlobalState,
+    config: &config::Config,
+    controller_sender: &mpsc::Sender<ControllerMessage>,
+) {
+    while state.connection_counts().live() < config.peer_count {
+        let node_id = match state
+            .nodes
+            .iter()
+            .find(|node| *node.state() == node::NodeState::DISCONNECTED)
+        {
+            Some(node) => node.id(),
+            None => return,
+        };
+        let node_sock_addr = match next_known_peer(state, config, None) {
+            Some(addr) => addr,
+            None => return,
+        };
+
+        let (command_sender, command_receiver) = mpsc::channel();
+        if let Some(node_handle) = get_node_handle(&mut state.nodes, &node_id) {
+            node_handle.reset(command_sender);
+        }
+        spawn_node(
+            state,
+            config,
+            controller_sender,
+            node_id,
+            node_sock_addr,
+            command_receiver,
+        );
+    }
+}
+
+/// Increases the ban score of a node. Once the score reaches
+/// BAN_THRESHOLD, the peer is banned and the node is restarted with a new peer.
+/// Returns whether the peer has been banned.
+fn misbehaving(
+    state: &mut GlobalState,
+    config: &config::Config,
+    controller_sender: &mpsc::Sender<ControllerMessage>,
+    node_id: node::NodeId,
+    misbehavior: node::Misbehavior,
+) -> bool {
+    let score = state.ban_scores.entry(node_id).or_insert(0);
+    *score += misbehavior.score();
+    log::warn!(
+        "[{}] Misbehavior {:?}, ban score is now {}",
+        node_id,
+        misbehavior,
+        score
+    );
+    if *score < BAN_THRESHOLD {
+        return false;
+    }
+
+    let addr = get_node_handle(&mut state.nodes, &node_id).and_then(|handle| handle.addr());
+    match addr {
+        Some(addr) => {
+            log::warn!("[{}] Ban peer {}", node_id, addr.ip());
+            state.ban(&addr.ip());
+            state.emit(SyncEvent::PeerBanned(addr.ip()));
+        }
+        None => log::warn!("[{}] Address of the peer is unknown", node_id),
+    }
+    node_restart_with_new_peer(state, config, controller_sender, node_id);
+    true
 }
 
 fn handle_valider_message(
     state: &mut GlobalState,
     config: &config::Config,
+    valider_sender: &mpsc::Sender<valider::Message>,
     valider_message: valider::ValiderMessage,
     controller_sender: &mpsc::Sender<ControllerMessage>,
 ) {
@@ -257,6 +1103,75 @@ fn handle_valider_message(
             };
             node_restart_with_new_peer(state, config, controller_sender, node_handle.id());
         }
+        valider::ValiderMessage::Invalid(hash, reason) => {
+            log::warn!("Block {} is invalid: {:?}", hex::encode(hash), reason);
+
+            if reason.is_malleation() {
+                // The block must be downloaded again, hopefully from an
+                // honest peer
+                state.download_queue.push_front(hash);
+            } else {
+                forget_invalid_block(state, valider_sender, hash);
+            }
+
+            match state.block_sources.remove(&hash) {
+                Some(node_id) => {
+                    log::info!("[{}] Node has served an invalid block", node_id);
+                    misbehaving(
+                        state,
+                        config,
+                        controller_sender,
+                        node_id,
+                        node::Misbehavior::InvalidBlock,
+                    );
+                }
+                None => {
+                    log::error!(
+                        "Could not find which node served block {}",
+                        hex::encode(hash)
+                    );
+                    send_download_message(state, config);
+                }
+            }
+        }
+        valider::ValiderMessage::Validated(hash, height) => {
+            state.block_sources.remove(&hash);
+            state.header_parents.remove(&hash);
+            state.stored_blocks += 1;
+            state.headers_cache.clear();
+            state.emit(SyncEvent::BlockValidated(hash, height));
+            if state.update_initial_block_download(hash) {
+                reconnect_for_relay(state, config, controller_sender);
+            }
+            remove_confirmed_transactions(state);
+        }
+        valider::ValiderMessage::Reorg(old_tip, new_tip) => {
+            log::warn!(
+                "Reorganization from {} to {}",
+                hex::encode(old_tip),
+                hex::encode(new_tip)
+            );
+            state.emit(SyncEvent::Reorg(old_tip, new_tip));
+            state.headers_cache.clear();
+
+            // Headers are requested again from the new tip, so that the
+            // header sync follows the branch which has been switched to
+            let locator = match &state.storage {
+                Some(storage) => storage.block_locator(),
+                None => return,
+            };
+            match locator {
+                Ok(locator) if locator.first() == Some(&new_tip) => {
+                    state.last_header = new_tip;
+                    state.header_locator = locator[1..].to_vec();
+                    // The request in flight, if any, is superseded
+                    state.headers_in_flight = None;
+                    request_headers(state, config, controller_sender);
+                }
+                Ok(_) => log::debug!("Block {} is not the tip anymore", hex::encode(new_tip)),
+                Err(err) => log::warn!("Could not build the block locator: {:?}", err),
+            }
+        }
     }
 }
 
@@ -286,40 +1201,75 @@ fn handle_node_response(
                         message::getaddr::MessageGetAddr::new(),
                     )),
                 ));
-                node_handle.set_state(node::NodeState::UPDATING_PEERS);
+                // Peers should not announce transactions we would not relay
+                node_handle
+                    .send(node::NodeCommand::SendMessage(
+                        message::MessageType::FeeFilter(message::Message::new(
+                            config.magic,
+                            message::feefilter::MessageFeeFilter::new(config.min_relay_fee),
+                        )),
+                    ))
+                    .unwrap_or_default();
+                node_handle
+                    .send(node::NodeCommand::Ping)
+                    .unwrap_or_default();
+                node_handle
+                    .set_state(node::NodeState::UPDATING_PEERS)
+                    .unwrap_or_default();
+                state.emit(SyncEvent::PeerConnected(response.node_id));
             } else {
                 log::warn!("Unexpected Connected message");
             }
         }
         node::NodeResponseContent::Addrs(addrs) => {
-            for addr in &addrs {
-                state.known_active_nodes.insert(addr.clone());
+            // In connect-only mode, gossiped peers are never dialed
+            if !config.connect_only {
+                for addr in &addrs {
+                    state.known_active_nodes.insert(addr.clone());
+                }
             }
+            // New peers may fill the empty slots
+            top_up_connections(state, config, controller_sender);
+            let node_handle = match get_node_handle(&mut state.nodes, &response.node_id) {
+                Some(handle) => handle,
+                None => return,
+            };
 
             if let node::NodeState::UPDATING_PEERS = node_handle.state() {
-                node_handle.set_state(node::NodeState::UPDATING_BLOCKS);
+                node_handle
+                    .set_state(node::NodeState::UPDATING_BLOCKS)
+                    .unwrap_or_default();
                 if state.sync_node_id.is_none() {
                     state.sync_node_id = Some(response.node_id.clone());
                     log::info!("Node {} becomes the sync node", response.node_id);
-                    node_handle.send(node::NodeCommand::SendMessage(
-                        message::MessageType::GetHeaders(message::Message::new(
-                            config.magic,
-                            message::getheaders::MessageGetHeaders::new(
-                                70013,
-                                vec![config.genesis_block.hash()], // TODO
-                                [0; 32], // Get at most headers as possible
-                            ),
-                        )),
-                    ));
+                    state.emit(SyncEvent::SyncNodeChosen(response.node_id));
+                    request_headers(state, config, controller_sender);
                 } else {
                     // Node is not the sync node. Try to download
                     log::info!("Node {} becomes a download node", response.node_id);
-                    node_handle.download_next(&config, &mut state.download_queue);
+                    node_handle.download_next(
+                        config,
+                        &mut state.download_queue,
+                        &mut state.blocks_in_flight,
+                    );
                 }
             } else {
                 log::warn!("Unexpected Addrs message");
             }
         }
+        node::NodeResponseContent::GetAddr => {
+            let addrs = sample_known_peers(state, unix_time(), MAX_ADDR_RESPONSE);
+            let node_handle = match get_node_handle(&mut state.nodes, &response.node_id) {
+                Some(handle) => handle,
+                None => return,
+            };
+            log::debug!("[{}] Advertise {} peers", node_handle.id(), addrs.len());
+            node_handle
+                .send(node::NodeCommand::SendMessage(message::MessageType::Addr(
+                    message::Message::new(config.magic, message::addr::MessageAddr::new(addrs)),
+                )))
+                .unwrap_or_default();
+        }
         node::NodeResponseContent::Headers(headers) => {
             if node_handle.id() != state.sync_node_id.unwrap() {
                 log::warn!(
@@ -328,60 +1278,215 @@ fn handle_node_response(
                 );
                 return;
             }
+            // The headers request has been answered
+            state.headers_in_flight = None;
+
+            if headers.len() > MAX_HEADERS {
+                log::warn!(
+                    "[{}] Received {} headers, more than allowed",
+                    response.node_id,
+                    headers.len()
+                );
+                misbehaving(
+                    state,
+                    config,
+                    controller_sender,
+                    response.node_id,
+                    node::Misbehavior::OversizedMessage,
+                );
+                return;
+            }
+
+            if !headers_connect(&state.known_headers, &headers) {
+                log::warn!(
+                    "[{}] Headers do not connect to a known header",
+                    response.node_id
+                );
+                misbehaving(
+                    state,
+                    config,
+                    controller_sender,
+                    response.node_id,
+                    node::Misbehavior::InvalidHeader,
+                );
+                return;
+            }
 
             log::debug!(
                 "Push headers to download queue. Original lenth: {}",
                 state.download_queue.len()
             );
+            let mut invalid_header = false;
+            let mut new_hashes = Vec::with_capacity(headers.len());
             for header in &headers {
+                if state.invalid_blocks.contains(&header.hash())
+                    || state.invalid_blocks.contains(&header.hash_prev_block())
+                {
+                    log::warn!("Header builds on an invalid block: {:?}", header);
+                    state.invalid_blocks.insert(header.hash());
+                    invalid_header = true;
+                    continue;
+                }
+                if state.known_headers.contains(&header.hash()) {
+                    // Already received in an overlapping batch
+                    continue;
+                }
                 if header.validate() {
+                    // Only valid headers are known, so that no later batch
+                    // can connect to an invalid one
+                    state.known_headers.insert(header.hash());
+                    new_hashes.push(header.hash());
+                    if state.has_block(header.hash()) {
+                        continue;
+                    }
+                    state
+                        .header_parents
+                        .insert(header.hash(), header.hash_prev_block());
                     state.download_queue.push_back(header.hash());
                 // log::debug!("Add {:?} to download queue", header.hash());
                 } else {
                     // TODO ???
                     log::warn!("Header is invalid: {:?}", header);
+                    invalid_header = true;
                 }
             }
+            if invalid_header {
+                misbehaving(
+                    state,
+                    config,
+                    controller_sender,
+                    response.node_id,
+                    node::Misbehavior::InvalidHeader,
+                );
+            }
             log::debug!(
                 "Final length of download queue: {}",
                 state.download_queue.len()
             );
 
+            state.emit(SyncEvent::HeadersReceived(
+                response.node_id,
+                new_hashes.len(),
+            ));
+
             log::debug!("Send waiting message to valider thread.");
             valider_sender
-                .send(valider::Message::Wait(
-                    headers.iter().map(|header| header.hash()).collect(),
-                ))
+                .send(valider::Message::Wait(new_hashes.clone()))
                 .unwrap();
 
             send_download_message(state, config);
 
+            // Headers are contiguous: the new ones are at the end
+            if let Some(hash) = new_hashes.last() {
+                state.last_header = *hash;
+            }
             if headers.len() == MAX_HEADERS {
-                let last_hash = headers.last().unwrap().hash();
-                log::debug!("Send another GetHeaders message from: {:?}", last_hash);
-                let sync_node =
-                    get_node_handle(&mut state.nodes, &state.sync_node_id.unwrap()).unwrap();
-                sync_node.send(node::NodeCommand::SendMessage(
-                    message::MessageType::GetHeaders(message::Message::new(
-                        config.magic,
-                        message::getheaders::MessageGetHeaders::new(
-                            70013,
-                            vec![last_hash],
-                            [0; 32], // Get at most headers as possible
-                        ),
-                    )),
-                ));
+                log::debug!(
+                    "Send another GetHeaders message from: {:?}",
+                    state.last_header
+                );
+                request_headers(state, config, controller_sender);
             } else {
                 log::debug!("{:?} headers received. The end?", headers.len());
+                state.headers_synced = true;
+                // The blocks may all be stored already, e.g. after a restart
+                if state.has_block(state.last_header)
+                    && state.update_initial_block_download(state.last_header)
+                {
+                    reconnect_for_relay(state, config, controller_sender);
+                }
+                // Headers request has been answered, cancel its timeout
+                state.headers_request += 1;
             }
         }
         node::NodeResponseContent::Block(block) => {
             log::debug!("Send validate message to validate thread.");
-            node_handle.mark_downloaded(&block);
+            if node_handle.mark_downloaded(&block) {
+                state.blocks_in_flight.remove(&block.hash());
+            }
+            state.block_sources.insert(block.hash(), node_handle.id());
             valider_sender
                 .send(valider::Message::Validate(block))
                 .unwrap();
-            node_handle.download_next(&config, &mut state.download_queue);
+            node_handle.download_next(
+                config,
+                &mut state.download_queue,
+                &mut state.blocks_in_flight,
+            );
+        }
+        node::NodeResponseContent::FeeFilter(fee_filter) => {
+            node_handle.set_fee_filter(fee_filter);
+        }
+        node::NodeResponseContent::Filter(block_hash, filter) => {
+            if state.filters.len() >= MAX_FILTERS {
+                state.filters.clear();
+            }
+            state.filters.insert(block_hash, filter);
+        }
+        node::NodeResponseContent::GetHeaders(locator, stop_hash) => {
+            serve_headers(state, config, response.node_id, &locator, stop_hash)
+        }
+        node::NodeResponseContent::GetCFilters(filter_type, start_height, stop_hash) => {
+            serve_filters(
+                state,
+                config,
+                response.node_id,
+                filter_type,
+                u64::from(start_height),
+                stop_hash,
+            )
+        }
+        node::NodeResponseContent::GetCFHeaders(filter_type, start_height, stop_hash) => {
+            serve_filter_headers(
+                state,
+                config,
+                response.node_id,
+                filter_type,
+                u64::from(start_height),
+                stop_hash,
+            )
+        }
+        node::NodeResponseContent::GetCFCheckpt(filter_type, stop_hash) => {
+            serve_filter_checkpoints(state, config, response.node_id, filter_type, stop_hash)
+        }
+        node::NodeResponseContent::Transaction(tx) => {
+            state.inventory_in_flight.remove(&tx.hash());
+            accept_transaction(state, config, Some(response.node_id), tx)
+        }
+        node::NodeResponseContent::Relay(relay) => {
+            node_handle.set_relay(relay);
+        }
+        node::NodeResponseContent::FilterLoad(bloom_filter) => {
+            // Loading a filter turns relaying on (BIP37)
+            node_handle.set_bloom_filter(bloom_filter);
+            node_handle.set_relay(true);
+        }
+        node::NodeResponseContent::Latency(latency) => {
+            node_handle.set_latency(latency);
+        }
+        node::NodeResponseContent::LocalAddr(addr) => {
+            let peer_addr = node_handle.addr();
+            learn_external_addr(state, peer_addr, addr)
+        }
+        node::NodeResponseContent::GetData(inventory) => {
+            serve_data(state, config, response.node_id, &inventory)
+        }
+        node::NodeResponseContent::Inv(inventory) => request_inventory(
+            state,
+            config,
+            controller_sender,
+            response.node_id,
+            &inventory,
+        ),
+        node::NodeResponseContent::NotFound(inventory) => {
+            // The items may be asked to the other peers announcing them
+            for inv_vect in inventory {
+                if let Some((node_id, _)) = state.inventory_in_flight.get(&inv_vect.hash) {
+                    if *node_id == response.node_id {
+                        state.inventory_in_flight.remove(&inv_vect.hash);
+                    }
+                }
+            }
         }
         node::NodeResponseContent::ConnectionClosed => {
             log::debug!(
@@ -391,75 +1496,2947 @@ fn handle_node_response(
             let node_id = node_handle.id();
             node_restart_with_new_peer(state, config, controller_sender, node_id);
         }
+        node::NodeResponseContent::Misbehavior(misbehavior) => {
+            let node_id = node_handle.id();
+            misbehaving(state, config, controller_sender, node_id, misbehavior);
+        }
         _ => log::warn!("Unknown message from thread"),
     };
 }
 
-fn send_download_message(state: &mut GlobalState, config: &config::Config) {
-    log::debug!("Send download message to nodes");
-    let mut download_nodes = if state.nodes.len() > 1 {
-        state
-            .nodes
-            .iter()
-            .filter(|elt| elt.id() != state.sync_node_id.unwrap())
-            .cloned()
-            .collect()
-    } else {
-        state.nodes.clone() // FIXME Find a way to avoid cloning here
-    };
-    for node in download_nodes.iter_mut() {
-        node.download_next(&config, &mut state.download_queue);
+/// Forgets a block found invalid and the queued blocks descending from it,
+/// which are neither downloaded nor validated anymore. The headers building
+/// on them are rejected.
+fn forget_invalid_block(
+    state: &mut GlobalState,
+    valider_sender: &mpsc::Sender<valider::Message>,
+    hash: crypto::Hash32,
+) {
+    let mut children: HashMap<crypto::Hash32, Vec<crypto::Hash32>> = HashMap::new();
+    for (child, parent) in &state.header_parents {
+        children.entry(*parent).or_default().push(*child);
+    }
+    let mut invalid = vec![hash];
+    let mut index = 0;
+    while index < invalid.len() {
+        if let Some(hashes) = children.get(&invalid[index]) {
+            invalid.extend_from_slice(hashes);
+        }
+        index += 1;
+    }
+
+    // Headers are requested again from the last valid one
+    if invalid.contains(&state.last_header) {
+        if let Some(parent) = state.header_parents.get(&hash) {
+            state.last_header = *parent;
+            state.header_locator.clear();
+        }
     }
+    for hash in &invalid {
+        state.header_parents.remove(hash);
+        state.invalid_blocks.insert(*hash);
+    }
+    let invalid_blocks = &state.invalid_blocks;
+    state
+        .download_queue
+        .retain(|hash| !invalid_blocks.contains(hash));
+    valider_sender
+        .send(valider::Message::Forget(invalid))
+        .unwrap_or_default();
 }
 
-fn get_node_handle<'a>(
-    nodes: &'a mut Vec<node::NodeHandle>,
-    node_id: &node::NodeId,
-) -> Option<&'a mut node::NodeHandle> {
-    // FIXME
-    // This is a dumb implementation. Maybe node_id should not be
-    // the index of the node in nodes...
-    nodes.iter_mut().nth(*node_id)
+/// Returns whether the headers form a chain on top of a known header
+fn headers_connect(
+    known_headers: &HashSet<crypto::Hash32>,
+    headers: &[block::BlockHeader],
+) -> bool {
+    let mut prev = match headers.first() {
+        Some(header) if known_headers.contains(&header.hash_prev_block()) => {
+            header.hash_prev_block()
+        }
+        Some(_) => return false,
+        None => return true,
+    };
+    for header in headers {
+        if header.hash_prev_block() != prev {
+            return false;
+        }
+        prev = header.hash();
+    }
+    true
 }
 
-fn start_node(
-    node_id: usize,
-    socket_addr: net::SocketAddr,
-    command_receiver: mpsc::Receiver<node::NodeCommand>,
-    response_sender: mpsc::Sender<ControllerMessage>,
-    config: config::Config,
+/// Sends a getheaders message to the sync node, starting from the last
+/// known header. The sync node is replaced if it does not answer within
+/// SYNC_TIMEOUT. Nothing is sent while another request is in flight: the
+/// next one is sent once it is answered or has timed out.
+fn request_headers(
+    state: &mut GlobalState,
+    config: &config::Config,
+    controller_sender: &mpsc::Sender<ControllerMessage>,
 ) {
-    log::info!(
-        "[{}] Trying to connect to {}:{}",
-        node_id,
-        socket_addr.ip(),
-        socket_addr.port()
-    );
-    let stream = match net::TcpStream::connect(socket_addr) {
-        Ok(value) => value,
-        Err(_) => {
-            log::error!(
-                "[{}] Could not connect to {}:{}",
-                node_id,
-                socket_addr.ip(),
-                socket_addr.port()
-            );
+    if let Some(node_id) = state.headers_in_flight {
+        log::debug!("Headers have already been requested from node {}", node_id);
+        return;
+    }
+    let sync_node_id = match state.sync_node_id {
+        Some(id) => id,
+        None => {
+            log::warn!("There is no sync node to request headers from");
+            return;
+        }
+    };
+    let sync_node = match get_node_handle(&mut state.nodes, &sync_node_id) {
+        Some(handle) => handle,
+        None => {
+            log::warn!("Can not get node_handle: {}", sync_node_id);
+            return;
+        }
+    };
+    sync_node
+        .send(node::NodeCommand::SendMessage(
+            message::MessageType::GetHeaders(message::Message::new(
+                config.magic,
+                message::getheaders::MessageGetHeaders::new(
+                    70013,
+                    std::iter::once(state.last_header)
+                        .chain(state.header_locator.iter().cloned())
+                        .collect(),
+                    [0; 32], // Get at most headers as possible
+                ),
+            )),
+        ))
+        .unwrap_or_default();
 
-            response_sender.send(ControllerMessage::NodeResponse(node::NodeResponse {
-                node_id: node_id,
-                content: node::NodeResponseContent::ConnectionClosed,
-            }));
+    state.headers_in_flight = Some(sync_node_id);
+    state.headers_request += 1;
+    let request = state.headers_request;
+    let timeout_sender = controller_sender.clone();
+    thread::spawn(move || {
+        thread::sleep(SYNC_TIMEOUT);
+        timeout_sender
+            .send(ControllerMessage::SyncTimeout(request))
+            .unwrap_or_default();
+    });
+}
+
+/// Asks a peer for the basic compact filters of the blocks from
+/// `start_height` up to `stop_hash`
+fn request_filters(
+    state: &mut GlobalState,
+    config: &config::Config,
+    node_id: node::NodeId,
+    start_height: u32,
+    stop_hash: crypto::Hash32,
+) {
+    let node_handle = match get_node_handle(&mut state.nodes, &node_id) {
+        Some(handle) => handle,
+        None => {
+            log::warn!("Can not get node_handle: {}", node_id);
             return;
         }
     };
+    node_handle
+        .send(node::NodeCommand::SendMessage(
+            message::MessageType::GetCFilters(message::Message::new(
+                config.magic,
+                message::getcfilters::MessageGetCFilters::new(
+                    cfilter::BASIC_FILTER,
+                    start_height,
+                    stop_hash,
+                ),
+            )),
+        ))
+        .unwrap_or_default();
+}
 
-    log::info!(
-        "[{}] Connected to {} on port {}",
-        node_id,
-        socket_addr.ip(),
-        socket_addr.port()
-    );
+/// Sends to a peer the headers of the active chain following its locator,
+/// from the cache if they have already been sent for the same request
+fn serve_headers(
+    state: &mut GlobalState,
+    config: &config::Config,
+    node_id: node::NodeId,
+    locator: &[crypto::Hash32],
+    stop_hash: crypto::Hash32,
+) {
+    let storage = match &state.storage {
+        Some(storage) => Arc::clone(storage),
+        None => return,
+    };
+    let key = locator.first().map(|tip| (*tip, stop_hash));
+    let cached = match &key {
+        Some(key) => state.headers_cache.get(key),
+        None => None,
+    };
+    let headers = match cached {
+        Some(headers) => headers,
+        None => {
+            state.headers_cache.storage_reads += 1;
+            let (fork, headers) = match storage.headers_after(locator, stop_hash, MAX_HEADERS) {
+                Ok(result) => result,
+                Err(err) => {
+                    log::warn!(
+                        "[{}] Can not read the requested headers: {:?}",
+                        node_id,
+                        err
+                    );
+                    return;
+                }
+            };
+            let headers = message::headers::MessageHeaders::from_headers(headers);
+            // Otherwise the response depends on the rest of the locator
+            if let Some(key) = key.filter(|(tip, _)| *tip == fork) {
+                state.headers_cache.insert(key, headers.clone());
+            }
+            headers
+        }
+    };
+    let node_handle = match get_node_handle(&mut state.nodes, &node_id) {
+        Some(handle) => handle,
+        None => return,
+    };
+    node_handle
+        .send(node::NodeCommand::SendMessage(
+            message::MessageType::Headers(message::Message::new(config.magic, headers)),
+        ))
+        .unwrap_or_default();
+}
 
-    let mut node = node::Node::new(node_id, stream, command_receiver, response_sender);
-    node.run(&config);
+/// Sends to a peer the basic filters of the blocks from `start_height` up to
+/// `stop_hash`, if they are built
+fn serve_filters(
+    state: &mut GlobalState,
+    config: &config::Config,
+    node_id: node::NodeId,
+    filter_type: u8,
+    start_height: u64,
+    stop_hash: crypto::Hash32,
+) {
+    if !config.build_cfilters || filter_type != cfilter::BASIC_FILTER {
+        log::debug!(
+            "[{}] Filters of type {} are not served",
+            node_id,
+            filter_type
+        );
+        return;
+    }
+    let storage = match &state.storage {
+        Some(storage) => Arc::clone(storage),
+        None => return,
+    };
+    let hashes = match storage.height(stop_hash) {
+        Ok(Some(stop_height))
+            if stop_height >= start_height && stop_height - start_height < MAX_GETCFILTERS_SIZE =>
+        {
+            storage.blocks_range(start_height, stop_hash)
+        }
+        _ => {
+            log::debug!("[{}] Invalid getcfilters range", node_id);
+            return;
+        }
+    };
+    let node_handle = match get_node_handle(&mut state.nodes, &node_id) {
+        Some(handle) => handle,
+        None => return,
+    };
+    for hash in hashes.unwrap_or_default() {
+        let filter = match storage.get_filter(hash) {
+            Ok(Some(filter)) => filter,
+            _ => {
+                log::warn!("Filter of block {} is not available", hex::encode(hash));
+                return;
+            }
+        };
+        node_handle
+            .send(node::NodeCommand::SendMessage(
+                message::MessageType::CFilter(message::Message::new(
+                    config.magic,
+                    message::cfilter::MessageCFilter::new(
+                        cfilter::BASIC_FILTER,
+                        hash,
+                        filter.bytes(),
+                    ),
+                )),
+            ))
+            .unwrap_or_default();
+    }
+}
+
+/// Sends a peer the hashes of the basic filters of the blocks from
+/// `start_height` up to `stop_hash`, with the filter header of the block
+/// before them
+fn serve_filter_headers(
+    state: &mut GlobalState,
+    config: &config::Config,
+    node_id: node::NodeId,
+    filter_type: u8,
+    start_height: u64,
+    stop_hash: crypto::Hash32,
+) {
+    if !config.build_cfilters || filter_type != cfilter::BASIC_FILTER {
+        log::debug!(
+            "[{}] Filter headers of type {} are not served",
+            node_id,
+            filter_type
+        );
+        return;
+    }
+    let storage = match &state.storage {
+        Some(storage) => Arc::clone(storage),
+        None => return,
+    };
+    // The block before the range gives the previous filter header
+    let hashes = match storage.height(stop_hash) {
+        Ok(Some(stop_height))
+            if stop_height >= start_height
+                && stop_height - start_height < MAX_GETCFHEADERS_SIZE =>
+        {
+            storage.blocks_range(start_height.saturating_sub(1), stop_hash)
+        }
+        _ => {
+            log::debug!("[{}] Invalid getcfheaders range", node_id);
+            return;
+        }
+    };
+    let mut hashes = hashes.unwrap_or_default();
+    let prev_filter_header = if start_height == 0 {
+        Some([0; 32])
+    } else if hashes.is_empty() {
+        None
+    } else {
+        storage
+            .get_filter_header(hashes.remove(0))
+            .unwrap_or_default()
+    };
+    let prev_filter_header = match prev_filter_header {
+        Some(header) => header,
+        None => {
+            log::warn!(
+                "Filter headers before block {} are not available",
+                start_height
+            );
+            return;
+        }
+    };
+    let mut filter_hashes = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+        match storage.get_filter(hash) {
+            Ok(Some(filter)) => filter_hashes.push(filter.hash()),
+            _ => {
+                log::warn!("Filter of block {} is not available", hex::encode(hash));
+                return;
+            }
+        }
+    }
+    if let Some(node_handle) = get_node_handle(&mut state.nodes, &node_id) {
+        node_handle
+            .send(node::NodeCommand::SendMessage(
+                message::MessageType::CFHeaders(message::Message::new(
+                    config.magic,
+                    message::cfheaders::MessageCFHeaders::new(
+                        cfilter::BASIC_FILTER,
+                        stop_hash,
+                        prev_filter_header,
+                        filter_hashes,
+                    ),
+                )),
+            ))
+            .unwrap_or_default();
+    }
+}
+
+/// Sends a peer the basic filter headers of every `CFCHECKPT_INTERVAL`th
+/// block of the active chain up to `stop_hash`
+fn serve_filter_checkpoints(
+    state: &mut GlobalState,
+    config: &config::Config,
+    node_id: node::NodeId,
+    filter_type: u8,
+    stop_hash: crypto::Hash32,
+) {
+    if !config.build_cfilters || filter_type != cfilter::BASIC_FILTER {
+        log::debug!(
+            "[{}] Filter checkpoints of type {} are not served",
+            node_id,
+            filter_type
+        );
+        return;
+    }
+    let storage = match &state.storage {
+        Some(storage) => Arc::clone(storage),
+        None => return,
+    };
+    let stop_height = match storage.height(stop_hash) {
+        Ok(Some(height)) if storage.active_hash(height).ok().flatten() == Some(stop_hash) => height,
+        _ => {
+            log::debug!(
+                "[{}] Block {} of getcfcheckpt is not on the active chain",
+                node_id,
+                hex::encode(stop_hash)
+            );
+            return;
+        }
+    };
+    let mut filter_headers = Vec::new();
+    for height in (1..=stop_height / CFCHECKPT_INTERVAL).map(|index| index * CFCHECKPT_INTERVAL) {
+        let header = match storage.active_hash(height) {
+            Ok(Some(hash)) => storage.get_filter_header(hash),
+            _ => Ok(None),
+        };
+        match header {
+            Ok(Some(header)) => filter_headers.push(header),
+            _ => {
+                log::warn!("Filter header at height {} is not available", height);
+                return;
+            }
+        }
+    }
+    if let Some(node_handle) = get_node_handle(&mut state.nodes, &node_id) {
+        node_handle
+            .send(node::NodeCommand::SendMessage(
+                message::MessageType::CFCheckpt(message::Message::new(
+                    config.magic,
+                    message::cfcheckpt::MessageCFCheckpt::new(
+                        cfilter::BASIC_FILTER,
+                        stop_hash,
+                        filter_headers,
+                    ),
+                )),
+            ))
+            .unwrap_or_default();
+    }
+}
+
+/// Returns the unspent outputs of the active chain paying any of the
+/// public key hashes
+fn scan_wallet(state: &GlobalState, pubkey_hashes: Vec<crypto::Hash20>) -> Vec<wallet::Utxo> {
+    let storage = match &state.storage {
+        Some(storage) => storage,
+        None => return Vec::new(),
+    };
+    let mut wallet = wallet::Wallet::new();
+    for pubkey_hash in pubkey_hashes {
+        wallet.watch(pubkey_hash);
+    }
+    if let Err(err) = wallet.scan(storage) {
+        log::error!("Could not scan the active chain: {:?}", err);
+        return Vec::new();
+    }
+    wallet.utxos().into_iter().cloned().collect()
+}
+
+/// Returns the blocks whose received filter matches any of the scripts
+fn blocks_matching(state: &GlobalState, scripts: &[Vec<u8>]) -> Vec<crypto::Hash32> {
+    state
+        .filters
+        .iter()
+        .filter(
+            |(block_hash, filter)| match filter.match_any(block_hash, scripts) {
+                Ok(matched) => matched,
+                Err(err) => {
+                    log::warn!(
+                        "Filter of block {} is invalid: {:?}",
+                        hex::encode(block_hash),
+                        err
+                    );
+                    false
+                }
+            },
+        )
+        .map(|(block_hash, _)| *block_hash)
+        .collect()
+}
+
+/// Hands the sync over to another connected node if the headers request
+/// has not been answered
+fn handle_sync_timeout(
+    state: &mut GlobalState,
+    config: &config::Config,
+    controller_sender: &mpsc::Sender<ControllerMessage>,
+    request: u64,
+) {
+    if request != state.headers_request {
+        // Headers have been received since
+        return;
+    }
+    state.headers_in_flight = None;
+
+    let new_sync_node = state
+        .nodes
+        .iter()
+        .filter(|node| Some(node.id()) != state.sync_node_id)
+        .find(|node| *node.state() == node::NodeState::UPDATING_BLOCKS)
+        .map(|node| node.id());
+    match new_sync_node {
+        Some(node_id) => {
+            log::warn!(
+                "Sync node {:?} is stalled, node {} becomes the sync node",
+                state.sync_node_id,
+                node_id
+            );
+            state.sync_node_id = Some(node_id);
+            state.emit(SyncEvent::SyncNodeChosen(node_id));
+        }
+        None => log::warn!(
+            "Sync node {:?} is stalled but no other node is connected",
+            state.sync_node_id
+        ),
+    }
+    request_headers(state, config, controller_sender);
+}
+
+/// Adds a transaction received from a peer or created locally (no origin)
+/// to the mempool and announces it to the other peers
+fn accept_transaction(
+    state: &mut GlobalState,
+    config: &config::Config,
+    origin: Option<node::NodeId>,
+    tx: transaction::Transaction,
+) {
+    let txid = tx.hash();
+    if state.known_txs.contains(&txid) {
+        return;
+    }
+    if tx.inputs().is_empty() || tx.outputs().is_empty() || tx.output_value().is_none() {
+        log::warn!("Transaction {} is invalid", hex::encode(txid));
+        return;
+    }
+    if !tx.is_standard_size() {
+        log::debug!(
+            "Transaction {} is too heavy to be relayed",
+            hex::encode(txid)
+        );
+        return;
+    }
+    // The inputs are checked against the outputs of the active chain
+    let storage = match &state.storage {
+        Some(storage) => Arc::clone(storage),
+        None => {
+            log::debug!(
+                "No storage to check transaction {}, drop it",
+                hex::encode(txid)
+            );
+            return;
+        }
+    };
+    let height = match storage.tip() {
+        Ok(tip) => tip.map_or(0, |(_, height)| height) + 1,
+        Err(err) => {
+            log::error!("Storage error: {:?}.", err);
+            return;
+        }
+    };
+    let utxo = |outpoint| match storage.get_output(outpoint) {
+        Ok(output) => output,
+        Err(err) => {
+            log::error!("Storage error: {:?}.", err);
+            None
+        }
+    };
+    let fee = match state
+        .mempool
+        .check_inputs(&tx, &utxo, height, u64::from(unix_time()))
+    {
+        Ok(fee) => fee,
+        Err(err) => {
+            log::debug!(
+                "Transaction {} is not accepted to the mempool: {:?}",
+                hex::encode(txid),
+                err
+            );
+            return;
+        }
+    };
+    let fee_rate = fee * 1000 / tx.size() as u64;
+    if origin.is_some() && fee_rate < config.min_relay_fee {
+        log::debug!(
+            "Transaction {} is below the minimum relay fee",
+            hex::encode(txid)
+        );
+        return;
+    }
+    if state.mempool.len() >= MAX_MEMPOOL_TXS {
+        // The transactions of lowest fee rate make room, unless the
+        // transaction spends them
+        let evicted = match state.mempool.lowest_fee_rate() {
+            Some((lowest, lowest_fee_rate)) if lowest_fee_rate < fee_rate => {
+                state.mempool.descendants(lowest)
+            }
+            _ => Vec::new(),
+        };
+        if evicted.is_empty()
+            || tx
+                .inputs()
+                .iter()
+                .any(|input| evicted.contains(&input.prev_output().0))
+        {
+            log::debug!("Mempool is full, drop transaction {}", hex::encode(txid));
+            return;
+        }
+        for evicted_txid in evicted {
+            log::debug!(
+                "Evict transaction {} from the mempool",
+                hex::encode(evicted_txid)
+            );
+            state.mempool.remove(&evicted_txid);
+        }
+    }
+    if state.known_txs.len() >= MAX_KNOWN_TXS {
+        state.known_txs.clear();
+    }
+    state.known_txs.insert(txid);
+    state.mempool.insert(tx, fee_rate);
+    relay_transaction(state, config, origin, txid, fee_rate);
+}
+
+/// Removes from the mempool the transactions confirmed by the active chain,
+/// and the ones conflicting with it
+fn remove_confirmed_transactions(state: &mut GlobalState) {
+    if state.mempool.is_empty() {
+        return;
+    }
+    let storage = match &state.storage {
+        Some(storage) => Arc::clone(storage),
+        None => return,
+    };
+    let spending_tx = |outpoint| match storage.spending_tx(outpoint) {
+        Ok(txid) => txid,
+        Err(err) => {
+            log::error!("Storage error: {:?}.", err);
+            None
+        }
+    };
+    for txid in state.mempool.remove_confirmed(&spending_tx) {
+        log::debug!(
+            "Transaction {} is confirmed or conflicts with a block, remove it from the mempool",
+            hex::encode(txid)
+        );
+    }
+}
+
+/// Asks a peer for the transactions it has announced, unless they are
+/// already known or asked for, or it has announced them before. Unknown
+/// announced blocks are found from the headers, which are requested from
+/// the sync node. Announcements are ignored during the initial block
+/// download.
+fn request_inventory(
+    state: &mut GlobalState,
+    config: &config::Config,
+    controller_sender: &mpsc::Sender<ControllerMessage>,
+    node_id: node::NodeId,
+    inventory: &[message::inv_base::InvVect],
+) {
+    if state.initial_block_download {
+        return;
+    }
+    // Transactions not received in time may be asked to another peer
+    state
+        .inventory_in_flight
+        .retain(|_, (_, asked)| asked.elapsed() < INVENTORY_TIMEOUT);
+    let announced: Vec<&message::inv_base::InvVect> =
+        match get_node_handle(&mut state.nodes, &node_id) {
+            Some(handle) => inventory
+                .iter()
+                .filter(|inv_vect| handle.add_known_inventory(inv_vect.hash))
+                .collect(),
+            None => return,
+        };
+
+    let mut wanted = Vec::new();
+    let mut new_block = false;
+    for inv_vect in announced {
+        match inv_vect.hash_type {
+            message::inv_base::MSG_BLOCK | message::inv_base::MSG_WITNESS_BLOCK => {
+                if !state.known_headers.contains(&inv_vect.hash) && !state.has_block(inv_vect.hash)
+                {
+                    new_block = true;
+                }
+            }
+            message::inv_base::MSG_TX | message::inv_base::MSG_WITNESS_TX => {
+                if state.known_txs.contains(&inv_vect.hash)
+                    || state.inventory_in_flight.contains_key(&inv_vect.hash)
+                {
+                    continue;
+                }
+                state
+                    .inventory_in_flight
+                    .insert(inv_vect.hash, (node_id, time::Instant::now()));
+                wanted.push(message::inv_base::InvVect {
+                    hash_type: message::inv_base::MSG_WITNESS_TX,
+                    hash: inv_vect.hash,
+                });
+            }
+            _ => (),
+        }
+    }
+    if new_block {
+        log::debug!("[{}] Announced a new block, ask for headers", node_id);
+        request_headers(state, config, controller_sender);
+    }
+    if wanted.is_empty() {
+        return;
+    }
+
+    log::debug!("[{}] Ask {} announced items", node_id, wanted.len());
+    if let Some(node_handle) = get_node_handle(&mut state.nodes, &node_id) {
+        node_handle
+            .send(node::NodeCommand::SendMessage(
+                message::MessageType::GetData(message::Message::new(
+                    config.magic,
+                    message::getdata::MessageGetData::new(wanted),
+                )),
+            ))
+            .unwrap_or_default();
+    }
+}
+
+/// Sends the requested items to a peer: transactions from the mempool,
+/// blocks from the storage, and filtered blocks as a merkleblock followed
+/// by the matched transactions if the peer loaded a bloom filter (BIP37).
+/// Compact blocks are never negotiated. The items which can not be served
+/// are listed in a notfound message.
+fn serve_data(
+    state: &mut GlobalState,
+    config: &config::Config,
+    node_id: node::NodeId,
+    inventory: &[message::inv_base::InvVect],
+) {
+    let storage = state.storage.clone();
+    let stored_block = |hash| match &storage {
+        Some(storage) => storage.get_block(hash).unwrap_or(None),
+        None => None,
+    };
+    let node_handle = match get_node_handle(&mut state.nodes, &node_id) {
+        Some(handle) => handle,
+        None => return,
+    };
+    let mut not_found = Vec::new();
+    for inv_vect in inventory {
+        let messages = match inv_vect.hash_type {
+            message::inv_base::MSG_TX | message::inv_base::MSG_WITNESS_TX => {
+                state.mempool.get(&inv_vect.hash).map(|tx| {
+                    vec![message::MessageType::Tx(message::Message::new(
+                        config.magic,
+                        message::tx::MessageTx::new(tx.clone()),
+                    ))]
+                })
+            }
+            message::inv_base::MSG_BLOCK | message::inv_base::MSG_WITNESS_BLOCK => {
+                stored_block(inv_vect.hash).map(|block| {
+                    vec![message::MessageType::Block(message::Message::new(
+                        config.magic,
+                        message::block::MessageBlock::new(block),
+                    ))]
+                })
+            }
+            message::inv_base::MSG_FILTERED_BLOCK
+            | message::inv_base::MSG_FILTERED_WITNESS_BLOCK => {
+                match (node_handle.bloom_filter(), stored_block(inv_vect.hash)) {
+                    (Some(bloom_filter), Some(block)) => {
+                        Some(filtered_block_messages(config, bloom_filter, &block))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match messages {
+            Some(messages) => {
+                for message in messages {
+                    node_handle
+                        .send(node::NodeCommand::SendMessage(message))
+                        .unwrap_or_default();
+                }
+            }
+            None => not_found.push(inv_vect.clone()),
+        }
+    }
+    if !not_found.is_empty() {
+        node_handle
+            .send(node::NodeCommand::SendMessage(
+                message::MessageType::NotFound(message::Message::new(
+                    config.magic,
+                    message::notfound::MessageNotFound::new(not_found),
+                )),
+            ))
+            .unwrap_or_default();
+    }
+}
+
+/// Returns the merkleblock message of a block for a bloom filter, followed
+/// by the messages of the transactions matching it
+fn filtered_block_messages(
+    config: &config::Config,
+    bloom_filter: &bloom::BloomFilter,
+    block: &block::Block,
+) -> Vec<message::MessageType> {
+    let matches: Vec<bool> = block
+        .transactions()
+        .iter()
+        .map(|tx| bloom_filter.matches_tx(tx))
+        .collect();
+    let mut messages = vec![message::MessageType::MerkleBlock(message::Message::new(
+        config.magic,
+        message::merkleblock::MessageMerkleBlock::new(block, &matches),
+    ))];
+    for (tx, _) in block
+        .transactions()
+        .iter()
+        .zip(matches)
+        .filter(|(_, matched)| *matched)
+    {
+        messages.push(message::MessageType::Tx(message::Message::new(
+            config.magic,
+            message::tx::MessageTx::new(tx.as_ref().clone()),
+        )));
+    }
+    messages
+}
+
+/// Announces a transaction to the connected peers, except the one it comes
+/// from, those which do not want transactions and those whose fee filter is
+/// above its fee rate
+fn relay_transaction(
+    state: &GlobalState,
+    config: &config::Config,
+    origin: Option<node::NodeId>,
+    txid: crypto::Hash32,
+    fee_rate: u64,
+) {
+    for node in &state.nodes {
+        let connected = match node.state() {
+            node::NodeState::UPDATING_PEERS | node::NodeState::UPDATING_BLOCKS => true,
+            _ => false,
+        };
+        if Some(node.id()) == origin || !connected || !node.relay() || fee_rate < node.fee_filter()
+        {
+            continue;
+        }
+        log::debug!("[{}] Announce transaction {}", node.id(), hex::encode(txid));
+        node.send(node::NodeCommand::SendMessage(message::MessageType::Inv(
+            message::Message::new(
+                config.magic,
+                message::inv::MessageInv::new(vec![message::inv_base::InvVect {
+                    hash_type: message::inv_base::MSG_TX,
+                    hash: txid,
+                }]),
+            ),
+        )))
+        .unwrap_or_default();
+    }
+}
+
+fn send_download_message(state: &mut GlobalState, config: &config::Config) {
+    log::debug!("Send download message to nodes");
+    // The handles are updated in place, so that the blocks they are asked
+    // for can be found in case of a timeout
+    let mut download_nodes: Vec<usize> = if state.nodes.len() > 1 {
+        (0..state.nodes.len())
+            .filter(|index| Some(state.nodes[*index].id()) != state.sync_node_id)
+            .collect()
+    } else {
+        (0..state.nodes.len()).collect()
+    };
+    // The fastest peers are served first
+    download_nodes
+        .sort_by_key(|index| state.nodes[*index].latency().unwrap_or(time::Duration::MAX));
+    for index in download_nodes {
+        state.nodes[index].download_next(
+            config,
+            &mut state.download_queue,
+            &mut state.blocks_in_flight,
+        );
+    }
+}
+
+fn get_node_handle<'a>(
+    nodes: &'a mut Vec<node::NodeHandle>,
+    node_id: &node::NodeId,
+) -> Option<&'a mut node::NodeHandle> {
+    // FIXME
+    // This is a dumb implementation. Maybe node_id should not be
+    // the index of the node in nodes...
+    nodes.iter_mut().nth(*node_id)
+}
+
+fn start_node(
+    node_id: usize,
+    socket_addr: net::SocketAddr,
+    command_receiver: mpsc::Receiver<node::NodeCommand>,
+    response_sender: mpsc::Sender<ControllerMessage>,
+    config: config::Config,
+    connect_limiter: &ConnectLimiter,
+) {
+    let permit = connect_limiter.acquire();
+    log::info!(
+        "[{}] Trying to connect to {}:{}",
+        node_id,
+        socket_addr.ip(),
+        socket_addr.port()
+    );
+    let connection = match config.proxy {
+        Some(proxy) => network::socks5_connect(proxy, socket_addr),
+        None => net::TcpStream::connect(socket_addr),
+    };
+    drop(permit);
+    let stream = match connection {
+        Ok(value) => value,
+        Err(_) => {
+            log::error!(
+                "[{}] Could not connect to {}:{}",
+                node_id,
+                socket_addr.ip(),
+                socket_addr.port()
+            );
+
+            response_sender.send(ControllerMessage::NodeResponse(node::NodeResponse {
+                node_id: node_id,
+                content: node::NodeResponseContent::ConnectionClosed,
+            }));
+            return;
+        }
+    };
+
+    log::info!(
+        "[{}] Connected to {} on port {}",
+        node_id,
+        socket_addr.ip(),
+        socket_addr.port()
+    );
+
+    let mut node = node::Node::new(node_id, stream, &config, command_receiver, response_sender);
+    node.run(&config);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::cell::Cell;
+
+    /// Resolver failing a given number of times before succeeding
+    struct MockResolver {
+        failures: Cell<u32>,
+        addrs: Vec<net::IpAddr>,
+    }
+
+    impl PeerResolver for MockResolver {
+        fn resolve(&self, _seed: &str) -> Vec<net::IpAddr> {
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return vec![];
+            }
+            self.addrs.clone()
+        }
+    }
+
+    fn new_test_state() -> (GlobalState, mpsc::Receiver<node::NodeCommand>) {
+        let mut state = GlobalState::new([0; 32]);
+        let (command_sender, command_receiver) = mpsc::channel();
+        let mut node_handle = node::NodeHandle::new(0, command_sender);
+        node_handle
+            .set_state(node::NodeState::UPDATING_PEERS)
+            .unwrap();
+        node_handle
+            .set_state(node::NodeState::UPDATING_BLOCKS)
+            .unwrap();
+        state.nodes.push(node_handle);
+        (state, command_receiver)
+    }
+
+    /// Gives the state a storage whose block 1 confirms a transaction with
+    /// OP_TRUE outputs of the given values, and returns this transaction
+    fn fund_state(state: &mut GlobalState, name: &str, values: &[u64]) -> transaction::Transaction {
+        let config = config::regtest_config();
+        let mut funding = transaction::Transaction::new();
+        funding.add_input([1; 32], 0, vec![]);
+        for value in values {
+            funding.add_output(*value, vec![0x51]);
+        }
+        let mut block1 = storage::next_block(&config.genesis_block, 1);
+        block1.add_tx(Box::new(funding.clone())).unwrap();
+        let storage = storage::temp_storage(name);
+        storage.store_block(&config.genesis_block).unwrap();
+        storage.store_block(&block1).unwrap();
+        state.storage = Some(Arc::new(storage));
+        funding
+    }
+
+    #[test]
+    fn test_blocks_asked_once() {
+        let config = config::regtest_config();
+        let (mut state, command_receiver) = new_test_state();
+        let (command_sender, other_receiver) = mpsc::channel();
+        let mut node_handle = node::NodeHandle::new(1, command_sender);
+        node_handle
+            .set_state(node::NodeState::UPDATING_PEERS)
+            .unwrap();
+        node_handle
+            .set_state(node::NodeState::UPDATING_BLOCKS)
+            .unwrap();
+        state.nodes.push(node_handle);
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        let block2 = storage::next_block(&block1, 1);
+
+        state.download_queue = vec![block1.hash(), block2.hash()].into_iter().collect();
+        send_download_message(&mut state, &config);
+        assert!(state.nodes[0].is_downloading(&block1.hash()));
+        assert!(state.nodes[0].is_downloading(&block2.hash()));
+        assert!(matches!(
+            command_receiver.try_recv(),
+            Ok(node::NodeCommand::SendMessage(
+                message::MessageType::GetData(_)
+            ))
+        ));
+
+        // A block queued again while it is still downloaded, e.g. after a
+        // timeout, is not asked to another node
+        state.download_queue.push_front(block1.hash());
+        send_download_message(&mut state, &config);
+        assert!(state.download_queue.is_empty());
+        assert!(!state.nodes[1].is_downloading(&block1.hash()));
+        assert!(other_receiver.try_recv().is_err());
+
+        // Once delivered, it can be asked again
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Block(block1.clone()),
+            },
+        );
+        assert_eq!(
+            state.blocks_in_flight,
+            [block2.hash()].iter().cloned().collect()
+        );
+        // Its source is forgotten once it is validated
+        assert_eq!(state.block_sources.get(&block1.hash()), Some(&0));
+        handle_valider_message(
+            &mut state,
+            &config,
+            &valider_sender,
+            valider::ValiderMessage::Validated(block1.hash(), 1),
+            &controller_sender,
+        );
+        assert!(state.block_sources.is_empty());
+        state.download_queue.push_front(block1.hash());
+        send_download_message(&mut state, &config);
+        assert!(state.nodes[1].is_downloading(&block1.hash()));
+        assert!(matches!(
+            other_receiver.try_recv(),
+            Ok(node::NodeCommand::SendMessage(
+                message::MessageType::GetData(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_block_restarts_serving_node() {
+        let config = config::regtest_config();
+        let (mut state, command_receiver) = new_test_state();
+        let (mut valider_sender, valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        // This block does not match its merkle root
+        let mut block = config.genesis_block.clone();
+        block
+            .add_tx(Box::new(transaction::Transaction::new()))
+            .unwrap();
+
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Block(block.clone()),
+            },
+        );
+        match valider_receiver.try_recv() {
+            Ok(valider::Message::Validate(received)) => assert_eq!(received, block),
+            _ => panic!("Block should have been sent to the valider"),
+        }
+
+        handle_valider_message(
+            &mut state,
+            &config,
+            &valider_sender,
+            valider::ValiderMessage::Invalid(
+                block.hash(),
+                block::BlockValidationError::BadMerkleRoot,
+            ),
+            &controller_sender,
+        );
+
+        // The serving node has been killed and its handle reset. No other
+        // peer is known so the slot stays empty.
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::Kill) => (),
+            _ => panic!("Serving node should have been killed"),
+        }
+        assert_eq!(state.nodes[0].state(), &node::NodeState::DISCONNECTED);
+        assert!(state.block_sources.is_empty());
+        assert_eq!(state.download_queue.front(), Some(&block.hash()));
+    }
+
+    #[test]
+    fn test_invalid_block_forgotten() {
+        let config = config::regtest_config();
+        let mut state = GlobalState::new(config.genesis_block.hash());
+        state
+            .nodes
+            .push(node::NodeHandle::new(0, mpsc::channel().0));
+        state.sync_node_id = Some(0);
+        let (valider_sender, valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        let send_headers = |state: &mut GlobalState, headers| {
+            handle_node_response(
+                state,
+                &config,
+                &mut valider_sender.clone(),
+                &controller_sender,
+                node::NodeResponse {
+                    node_id: 0,
+                    content: node::NodeResponseContent::Headers(headers),
+                },
+            )
+        };
+
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        let block2 = storage::next_block(&block1, 2);
+        let block3 = storage::next_block(&block2, 3);
+        let block4 = storage::next_block(&block3, 4);
+        send_headers(
+            &mut state,
+            vec![
+                block1.header.clone(),
+                block2.header.clone(),
+                block3.header.clone(),
+            ],
+        );
+        assert_eq!(
+            state.download_queue,
+            vec![block1.hash(), block2.hash(), block3.hash()]
+        );
+
+        // block2 is not downloaded again, nor its descendants
+        handle_valider_message(
+            &mut state,
+            &config,
+            &valider_sender,
+            valider::ValiderMessage::Invalid(
+                block2.hash(),
+                block::BlockValidationError::DoubleSpend,
+            ),
+            &controller_sender,
+        );
+        assert_eq!(state.download_queue, vec![block1.hash()]);
+        assert_eq!(state.last_header, block1.hash());
+        let forgotten = valider_receiver
+            .try_iter()
+            .find_map(|message| match message {
+                valider::Message::Forget(hashes) => Some(hashes),
+                _ => None,
+            });
+        assert_eq!(forgotten, Some(vec![block2.hash(), block3.hash()]));
+
+        // Headers building on them are invalid
+        assert!(state.ban_scores.get(&0).is_none());
+        send_headers(&mut state, vec![block4.header.clone()]);
+        assert_eq!(state.download_queue, vec![block1.hash()]);
+        assert!(state.invalid_blocks.contains(&block4.hash()));
+        assert_eq!(
+            state.ban_scores.get(&0),
+            Some(&node::Misbehavior::InvalidHeader.score())
+        );
+    }
+
+    #[test]
+    fn test_partial_delivery_requeued() {
+        let config = config::regtest_config();
+        let (mut state, command_receiver) = new_test_state();
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        let mut blocks = vec![storage::next_block(&config.genesis_block, 1)];
+        for time in 2..6 {
+            let block = storage::next_block(blocks.last().unwrap(), time);
+            blocks.push(block);
+        }
+        let hashes: Vec<crypto::Hash32> = blocks.iter().map(|block| block.hash()).collect();
+
+        // All the blocks are asked for in a single getdata message
+        state.download_queue = hashes.iter().cloned().collect();
+        assert!(state.nodes[0].download_next(
+            &config,
+            &mut state.download_queue,
+            &mut state.blocks_in_flight
+        ));
+        assert!(state.download_queue.is_empty());
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::GetData(_))) => (),
+            other => panic!("Unexpected command: {:?}", other),
+        }
+
+        // Only some of them are delivered before the peer goes away
+        for block in &[&blocks[0], &blocks[2]] {
+            handle_node_response(
+                &mut state,
+                &config,
+                &mut valider_sender,
+                &controller_sender,
+                node::NodeResponse {
+                    node_id: 0,
+                    content: node::NodeResponseContent::Block((*block).clone()),
+                },
+            );
+        }
+        assert!(!state.nodes[0].is_downloading(&hashes[0]));
+        assert!(state.nodes[0].is_downloading(&hashes[1]));
+        node_restart_with_new_peer(&mut state, &config, &controller_sender, 0);
+
+        // The undelivered blocks are downloaded first, in their order
+        assert_eq!(state.download_queue, vec![hashes[1], hashes[3], hashes[4]]);
+        assert!(hashes
+            .iter()
+            .all(|hash| !state.nodes[0].is_downloading(hash)));
+        assert_eq!(state.nodes[0].download_current_pop(), None);
+    }
+
+    #[test]
+    fn test_disconnected_headers_rejected() {
+        let config = config::regtest_config();
+        let mut state = GlobalState::new(config.genesis_block.hash());
+        state
+            .nodes
+            .push(node::NodeHandle::new(0, mpsc::channel().0));
+        state.sync_node_id = Some(0);
+        let (mut valider_sender, valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        let block2 = storage::next_block(&block1, 2);
+        let block3 = storage::next_block(&block2, 3);
+        assert!(headers_connect(
+            &state.known_headers,
+            &[block1.header.clone(), block2.header.clone()]
+        ));
+
+        // block2 is missing
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Headers(vec![
+                    block1.header.clone(),
+                    block3.header.clone(),
+                ]),
+            },
+        );
+        assert!(state.download_queue.is_empty());
+        assert_eq!(state.last_header, config.genesis_block.hash());
+        assert!(valider_receiver.try_recv().is_err());
+        assert_eq!(
+            state.ban_scores.get(&0),
+            Some(&node::Misbehavior::InvalidHeader.score())
+        );
+
+        // Headers which do not start from a known header
+        assert!(!headers_connect(
+            &state.known_headers,
+            &[block2.header.clone(), block3.header.clone()]
+        ));
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Headers(vec![
+                    block2.header.clone(),
+                    block3.header.clone(),
+                ]),
+            },
+        );
+        assert!(state.download_queue.is_empty());
+        assert!(!state.known_headers.contains(&block2.hash()));
+        assert_eq!(state.last_header, config.genesis_block.hash());
+        assert!(valider_receiver.try_recv().is_err());
+        assert_eq!(
+            state.ban_scores.get(&0),
+            Some(&(2 * node::Misbehavior::InvalidHeader.score()))
+        );
+    }
+
+    #[test]
+    fn test_oversized_headers_rejected() {
+        let config = config::regtest_config();
+        let mut state = GlobalState::new(config.genesis_block.hash());
+        state
+            .nodes
+            .push(node::NodeHandle::new(0, mpsc::channel().0));
+        state.sync_node_id = Some(0);
+        let (mut valider_sender, valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        let mut headers = Vec::with_capacity(MAX_HEADERS + 1);
+        let mut block = config.genesis_block.clone();
+        for _ in 0..=MAX_HEADERS {
+            block = storage::next_block(&block, 0);
+            headers.push(block.header.clone());
+        }
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Headers(headers),
+            },
+        );
+        assert!(state.download_queue.is_empty());
+        assert!(valider_receiver.try_recv().is_err());
+        assert_eq!(
+            state.ban_scores.get(&0),
+            Some(&node::Misbehavior::OversizedMessage.score())
+        );
+    }
+
+    #[test]
+    fn test_overlapping_headers_queued_once() {
+        let config = config::regtest_config();
+        let mut state = GlobalState::new(config.genesis_block.hash());
+        state
+            .nodes
+            .push(node::NodeHandle::new(0, mpsc::channel().0));
+        state.sync_node_id = Some(0);
+        let (mut valider_sender, valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        let block2 = storage::next_block(&block1, 2);
+        let block3 = storage::next_block(&block2, 3);
+        let batches = vec![
+            vec![block1.header.clone(), block2.header.clone()],
+            vec![block2.header.clone(), block3.header.clone()],
+            vec![block1.header.clone()],
+        ];
+        for batch in batches {
+            handle_node_response(
+                &mut state,
+                &config,
+                &mut valider_sender,
+                &controller_sender,
+                node::NodeResponse {
+                    node_id: 0,
+                    content: node::NodeResponseContent::Headers(batch),
+                },
+            );
+        }
+
+        assert_eq!(
+            state.download_queue,
+            vec![block1.hash(), block2.hash(), block3.hash()]
+        );
+        assert_eq!(state.last_header, block3.hash());
+        let mut waiting = Vec::new();
+        while let Ok(valider::Message::Wait(hashes)) = valider_receiver.try_recv() {
+            waiting.extend(hashes);
+        }
+        assert_eq!(waiting, vec![block1.hash(), block2.hash(), block3.hash()]);
+        assert_eq!(state.ban_scores.get(&0), None);
+    }
+
+    #[test]
+    fn test_stored_blocks_not_queued() {
+        let config = config::regtest_config();
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        let block2 = storage::next_block(&block1, 2);
+        let block3 = storage::next_block(&block2, 3);
+        let storage = storage::temp_storage("controller_stored_blocks");
+        for block in &[&config.genesis_block, &block1, &block2] {
+            storage.store_block(block).unwrap();
+        }
+
+        let mut state = GlobalState::new(config.genesis_block.hash());
+        state
+            .nodes
+            .push(node::NodeHandle::new(0, mpsc::channel().0));
+        state.sync_node_id = Some(0);
+        state.storage = Some(Arc::new(storage));
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Headers(vec![
+                    block1.header.clone(),
+                    block2.header.clone(),
+                    block3.header.clone(),
+                ]),
+            },
+        );
+
+        assert_eq!(state.download_queue, vec![block3.hash()]);
+        assert_eq!(state.last_header, block3.hash());
+    }
+
+    #[test]
+    fn test_getheaders_after_reorg() {
+        let config = config::regtest_config();
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        let fork1 = storage::next_block(&config.genesis_block, 101);
+        let fork2 = storage::next_block(&fork1, 102);
+        let storage = storage::temp_storage("controller_reorg_locator");
+        for block in &[&config.genesis_block, &block1, &fork1, &fork2] {
+            storage.store_block(block).unwrap();
+        }
+
+        let (mut state, command_receiver) = new_test_state();
+        state.sync_node_id = Some(0);
+        state.last_header = block1.hash();
+        state.storage = Some(Arc::new(storage));
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        let (valider_sender, _valider_receiver) = mpsc::channel();
+        handle_valider_message(
+            &mut state,
+            &config,
+            &valider_sender,
+            valider::ValiderMessage::Reorg(block1.hash(), fork2.hash()),
+            &controller_sender,
+        );
+
+        // The locator starts from the new tip, down to the genesis block
+        let locator = vec![fork2.hash(), fork1.hash(), config.genesis_block.hash()];
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::GetHeaders(message))) => {
+                assert_eq!(
+                    message.command,
+                    message::getheaders::MessageGetHeaders::new(70013, locator, [0; 32])
+                )
+            }
+            other => panic!("Unexpected command: {:?}", other),
+        }
+        assert_eq!(state.last_header, fork2.hash());
+    }
+
+    #[test]
+    fn test_ban_after_threshold() {
+        let config = config::regtest_config();
+        let (mut state, command_receiver) = new_test_state();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        let peer_addr: net::SocketAddr = "10.0.0.1:18444".parse().unwrap();
+        state.nodes[0].set_addr(peer_addr);
+
+        let violations = BAN_THRESHOLD / node::Misbehavior::InvalidChecksum.score();
+        for _ in 0..(violations - 1) {
+            assert!(!misbehaving(
+                &mut state,
+                &config,
+                &controller_sender,
+                0,
+                node::Misbehavior::InvalidChecksum,
+            ));
+        }
+        assert!(!state.is_banned(&peer_addr.ip()));
+        assert!(command_receiver.try_recv().is_err());
+
+        assert!(misbehaving(
+            &mut state,
+            &config,
+            &controller_sender,
+            0,
+            node::Misbehavior::InvalidChecksum,
+        ));
+        assert!(state.is_banned(&peer_addr.ip()));
+        assert!(state.is_banned(&net::IpAddr::from(network::to_ipv6(&peer_addr.ip()))));
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::Kill) => (),
+            _ => panic!("Banned node should have been killed"),
+        }
+        // The score of the new peer starts from scratch
+        assert_eq!(state.ban_scores.get(&0), None);
+    }
+
+    #[test]
+    fn test_resolve_peers_retry() {
+        let seeds = vec!["seed.example.org".to_string()];
+        let addrs: Vec<net::IpAddr> = vec!["10.0.0.1".parse().unwrap()];
+        let resolver = MockResolver {
+            failures: Cell::new(2),
+            addrs: addrs.clone(),
+        };
+
+        let peers = resolve_peers(
+            &seeds,
+            &resolver,
+            config::DEFAULT_PEER_COUNT,
+            3,
+            time::Duration::from_millis(1),
+        )
+        .unwrap();
+        assert_eq!(peers, addrs);
+
+        // Not enough rounds
+        let resolver = MockResolver {
+            failures: Cell::new(2),
+            addrs,
+        };
+        assert!(resolve_peers(
+            &seeds,
+            &resolver,
+            config::DEFAULT_PEER_COUNT,
+            2,
+            time::Duration::from_millis(1)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_get_peers_from_dns_truncated() {
+        let mut config = config::regtest_config();
+        config.dns_seeds = vec!["seed.example.org".to_string()];
+        let addrs: Vec<net::IpAddr> = (1..=5)
+            .map(|i| net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, i)))
+            .collect();
+        let resolver = MockResolver {
+            failures: Cell::new(0),
+            addrs: addrs.clone(),
+        };
+        assert_eq!(
+            get_peers_from_dns(&config, &resolver, 2).unwrap(),
+            addrs[..2].to_vec()
+        );
+        // Fewer peers than asked for are all kept
+        assert_eq!(get_peers_from_dns(&config, &resolver, 8).unwrap(), addrs);
+
+        config.dns_seeds = vec![];
+        assert!(get_peers_from_dns(&config, &resolver, 2).is_err());
+    }
+
+    #[test]
+    fn test_stalled_sync_node_rotation() {
+        let config = config::regtest_config();
+        let (mut state, sync_receiver) = new_test_state();
+        let (command_sender, command_receiver) = mpsc::channel();
+        let mut node_handle = node::NodeHandle::new(1, command_sender);
+        node_handle
+            .set_state(node::NodeState::UPDATING_PEERS)
+            .unwrap();
+        node_handle
+            .set_state(node::NodeState::UPDATING_BLOCKS)
+            .unwrap();
+        state.nodes.push(node_handle);
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        state.sync_node_id = Some(0);
+        request_headers(&mut state, &config, &controller_sender);
+        match sync_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::GetHeaders(_))) => (),
+            _ => panic!("Headers should have been requested from the sync node"),
+        }
+
+        // A timeout of an older request is ignored
+        let request = state.headers_request;
+        handle_sync_timeout(&mut state, &config, &controller_sender, request - 1);
+        assert_eq!(state.sync_node_id, Some(0));
+
+        // The sync node stays silent
+        handle_sync_timeout(&mut state, &config, &controller_sender, request);
+        assert_eq!(state.sync_node_id, Some(1));
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::GetHeaders(_))) => (),
+            _ => panic!("Headers should have been requested from the new sync node"),
+        }
+        assert!(sync_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_single_headers_request_in_flight() {
+        let config = config::regtest_config();
+        let (mut state, old_receiver) = new_test_state();
+        let (command_sender, command_receiver) = mpsc::channel();
+        let mut node_handle = node::NodeHandle::new(1, command_sender);
+        node_handle
+            .set_state(node::NodeState::UPDATING_PEERS)
+            .unwrap();
+        node_handle
+            .set_state(node::NodeState::UPDATING_BLOCKS)
+            .unwrap();
+        state.nodes.push(node_handle);
+        state.last_header = config.genesis_block.hash();
+        state.known_headers.insert(config.genesis_block.hash());
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        state.sync_node_id = Some(0);
+        request_headers(&mut state, &config, &controller_sender);
+        assert_eq!(state.headers_in_flight, Some(0));
+        // Not sent again until answered
+        request_headers(&mut state, &config, &controller_sender);
+        match old_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::GetHeaders(_))) => (),
+            _ => panic!("Headers should have been requested from the sync node"),
+        }
+        assert!(old_receiver.try_recv().is_err());
+
+        // The sync node is rotated after a timeout
+        let request = state.headers_request;
+        handle_sync_timeout(&mut state, &config, &controller_sender, request);
+        assert_eq!(state.sync_node_id, Some(1));
+        assert_eq!(state.headers_in_flight, Some(1));
+        assert!(command_receiver.try_recv().is_ok());
+
+        // The late answer of the old sync node is ignored
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Headers(vec![block1.header.clone()]),
+            },
+        );
+        assert!(state.download_queue.is_empty());
+        assert_eq!(state.headers_in_flight, Some(1));
+        request_headers(&mut state, &config, &controller_sender);
+        assert!(command_receiver.try_recv().is_err());
+        assert!(old_receiver.try_recv().is_err());
+
+        // Until the new sync node answers
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 1,
+                content: node::NodeResponseContent::Headers(vec![block1.header.clone()]),
+            },
+        );
+        assert_eq!(state.headers_in_flight, None);
+        assert_eq!(state.last_header, block1.hash());
+        request_headers(&mut state, &config, &controller_sender);
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::GetHeaders(_))) => (),
+            _ => panic!("Headers should have been requested from the new sync node"),
+        }
+    }
+
+    /// Returns an address on which connections are refused
+    fn known_peer(port: u16) -> network::NetAddr {
+        network::NetAddr::new(
+            0,
+            message::NODE_NETWORK,
+            net::Ipv4Addr::LOCALHOST.to_ipv6_mapped(),
+            port,
+        )
+    }
+
+    #[test]
+    fn test_connection_slots() {
+        let mut config = config::regtest_config();
+        config.peer_count = 3;
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        let mut state = GlobalState::new([0; 32]);
+        let mut command_receivers = vec![];
+        for node_id in 0..3 {
+            let (command_sender, command_receiver) = mpsc::channel();
+            let mut node_handle = node::NodeHandle::new(node_id, command_sender);
+            node_handle
+                .set_state(node::NodeState::UPDATING_PEERS)
+                .unwrap();
+            node_handle
+                .set_state(node::NodeState::UPDATING_BLOCKS)
+                .unwrap();
+            node_handle.set_addr(net::SocketAddr::new(
+                net::IpAddr::from(net::Ipv4Addr::LOCALHOST),
+                1 + node_id as u16,
+            ));
+            state.nodes.push(node_handle);
+            command_receivers.push(command_receiver);
+        }
+        state.known_active_nodes.insert(known_peer(3)); // already connected
+        state.known_active_nodes.insert(known_peer(4));
+        state.known_active_nodes.insert(known_peer(5));
+
+        // Disconnected nodes are replaced by known peers
+        for node_id in 0..2 {
+            node_restart_with_new_peer(&mut state, &config, &controller_sender, node_id);
+            assert_eq!(
+                state.connection_counts(),
+                ConnectionCounts {
+                    connected: 2 - node_id,
+                    connecting: 1 + node_id,
+                    dead: 0,
+                }
+            );
+        }
+        let addrs: HashSet<Option<net::SocketAddr>> =
+            state.nodes.iter().map(|node| node.addr()).collect();
+        assert_eq!(addrs.len(), 3);
+
+        // No more known peer but the one which has just been dropped: the
+        // slot stays empty
+        node_restart_with_new_peer(&mut state, &config, &controller_sender, 2);
+        assert_eq!(state.connection_counts().live(), 2);
+        assert_eq!(state.connection_counts().dead, 1);
+
+        // Newly advertised peers fill the empty slot, and only this one
+        state.nodes[0]
+            .set_state(node::NodeState::UPDATING_PEERS)
+            .unwrap();
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Addrs(vec![known_peer(6), known_peer(7)]),
+            },
+        );
+        assert_eq!(state.connection_counts().live(), 3);
+        assert_eq!(state.connection_counts().dead, 0);
+
+        // The outbound limit is never exceeded
+        config.peer_count = 2;
+        node_restart_with_new_peer(&mut state, &config, &controller_sender, 1);
+        assert_eq!(state.connection_counts().live(), 2);
+        assert_eq!(state.nodes[1].state(), &node::NodeState::DISCONNECTED);
+    }
+
+    #[test]
+    fn test_connect_only() {
+        let fixed_peer = known_peer(1).to_socket_addr();
+        let mut config = config::regtest_config();
+        config.peer_count = 2;
+        config.dns_seeds = vec!["seed.example.org".to_string()];
+        config.fixed_peers = vec![fixed_peer];
+        let dns_peer: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let resolver = MockResolver {
+            failures: Cell::new(0),
+            addrs: vec![dns_peer],
+        };
+        assert_eq!(
+            initial_peers(&config, &resolver).unwrap(),
+            vec![fixed_peer, net::SocketAddr::new(dns_peer, config.port)]
+        );
+
+        // The DNS seeds are not even resolved: a lookup would use the failure
+        config.connect_only = true;
+        let resolver = MockResolver {
+            failures: Cell::new(1),
+            addrs: vec![dns_peer],
+        };
+        assert_eq!(initial_peers(&config, &resolver).unwrap(), vec![fixed_peer]);
+        assert_eq!(resolver.failures.get(), 1);
+
+        // Gossiped peers are neither remembered nor dialed
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let mut state = GlobalState::new([0; 32]);
+        let mut node_handle = node::NodeHandle::new(0, mpsc::channel().0);
+        node_handle
+            .set_state(node::NodeState::UPDATING_PEERS)
+            .unwrap();
+        node_handle.set_addr(fixed_peer);
+        state.nodes.push(node_handle);
+        let mut node_handle = node::NodeHandle::new(1, mpsc::channel().0);
+        node_handle
+            .set_state(node::NodeState::DISCONNECTED)
+            .unwrap();
+        state.nodes.push(node_handle);
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Addrs(vec![known_peer(2), known_peer(3)]),
+            },
+        );
+        assert!(state.known_active_nodes.is_empty());
+        assert_eq!(state.nodes[1].state(), &node::NodeState::DISCONNECTED);
+
+        // A lost fixed peer is connected again
+        node_restart_with_new_peer(&mut state, &config, &controller_sender, 0);
+        assert_eq!(state.nodes[0].addr(), Some(fixed_peer));
+        assert_eq!(state.connection_counts().connecting, 1);
+    }
+
+    #[test]
+    fn test_transaction_relay() {
+        let config = config::regtest_config();
+        let (mut state, origin_receiver) = new_test_state();
+        let (command_sender, peer_receiver) = mpsc::channel();
+        let mut node_handle = node::NodeHandle::new(1, command_sender);
+        node_handle
+            .set_state(node::NodeState::UPDATING_PEERS)
+            .unwrap();
+        node_handle
+            .set_state(node::NodeState::UPDATING_BLOCKS)
+            .unwrap();
+        state.nodes.push(node_handle);
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        let funding = fund_state(&mut state, "controller_transaction_relay", &[2000]);
+        let mut tx = transaction::Transaction::new();
+        tx.add_input(funding.hash(), 0, vec![0x51]);
+        tx.add_output(1000, vec![0x51]);
+        for node_id in &[0, 1] {
+            handle_node_response(
+                &mut state,
+                &config,
+                &mut valider_sender,
+                &controller_sender,
+                node::NodeResponse {
+                    node_id: *node_id,
+                    content: node::NodeResponseContent::Transaction(tx.clone()),
+                },
+            );
+        }
+
+        // Announced once, to the other peer only
+        match peer_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::Inv(inv))) => {
+                assert_eq!(
+                    inv,
+                    message::Message::new(
+                        config.magic,
+                        message::inv::MessageInv::new(vec![message::inv_base::InvVect {
+                            hash_type: message::inv_base::MSG_TX,
+                            hash: tx.hash(),
+                        }])
+                    )
+                )
+            }
+            _ => panic!("Transaction should have been announced"),
+        }
+        assert!(peer_receiver.try_recv().is_err());
+        assert!(origin_receiver.try_recv().is_err());
+
+        // The fee filter of the peer is respected
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 1,
+                content: node::NodeResponseContent::FeeFilter(1000),
+            },
+        );
+        relay_transaction(&state, &config, Some(0), tx.hash(), 999);
+        assert!(peer_receiver.try_recv().is_err());
+        relay_transaction(&state, &config, Some(0), tx.hash(), 1000);
+        assert!(peer_receiver.try_recv().is_ok());
+
+        // So is its relay flag
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 1,
+                content: node::NodeResponseContent::Relay(false),
+            },
+        );
+        relay_transaction(&state, &config, Some(0), tx.hash(), 1000);
+        assert!(peer_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_fee_filter_sent_after_handshake() {
+        let mut config = config::regtest_config();
+        config.min_relay_fee = 5000;
+        let mut state = GlobalState::new(config.genesis_block.hash());
+        let (command_sender, command_receiver) = mpsc::channel();
+        state.nodes.push(node::NodeHandle::new(0, command_sender));
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Connected,
+            },
+        );
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::GetAddr(_))) => (),
+            other => panic!("Unexpected command: {:?}", other),
+        }
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::FeeFilter(feefilter))) => {
+                assert_eq!(
+                    feefilter,
+                    message::Message::new(
+                        config.magic,
+                        message::feefilter::MessageFeeFilter::new(5000)
+                    )
+                )
+            }
+            other => panic!("Unexpected command: {:?}", other),
+        }
+
+        // Transactions of peers below the minimum fee rate are dropped
+        let funding = fund_state(&mut state, "controller_fee_filter", &[30_000]);
+        let mut parent = transaction::Transaction::new();
+        parent.add_input(funding.hash(), 0, vec![0x51]);
+        parent.add_output(10_000, vec![0x51]);
+        parent.add_output(10_000, vec![0x51]);
+        accept_transaction(&mut state, &config, None, parent.clone());
+        let mut low_fee = transaction::Transaction::new();
+        low_fee.add_input(parent.hash(), 0, vec![0x51]);
+        low_fee.add_output(9_990, vec![0x51]);
+        accept_transaction(&mut state, &config, Some(0), low_fee.clone());
+        assert!(state.mempool.get(&low_fee.hash()).is_none());
+        let mut high_fee = transaction::Transaction::new();
+        high_fee.add_input(parent.hash(), 1, vec![0x51]);
+        high_fee.add_output(5_000, vec![0x51]);
+        accept_transaction(&mut state, &config, Some(0), high_fee.clone());
+        assert!(state.mempool.get(&high_fee.hash()).is_some());
+    }
+
+    #[test]
+    fn test_compact_filters() {
+        let config = config::test_config();
+        let (mut state, command_receiver) = new_test_state();
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        let genesis = config.genesis_block.clone();
+
+        // The filters are asked to the sync node
+        state.sync_node_id = Some(0);
+        assert!(handle_controller_message(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            ControllerMessage::RequestFilters(0, genesis.hash()),
+        ));
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::GetCFilters(getcfilters))) => {
+                assert_eq!(
+                    getcfilters,
+                    message::Message::new(
+                        config.magic,
+                        message::getcfilters::MessageGetCFilters::new(0, 0, genesis.hash())
+                    )
+                )
+            }
+            other => panic!("Unexpected command: {:?}", other),
+        }
+
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Filter(
+                    genesis.hash(),
+                    cfilter::Filter::basic(&genesis, &[]),
+                ),
+            },
+        );
+        let script = genesis.coinbase().unwrap().outputs()[0].pubkey();
+        let (matches_sender, matches_receiver) = mpsc::channel();
+        for scripts in vec![vec![script], vec![vec![0x51]]] {
+            assert!(handle_controller_message(
+                &mut state,
+                &config,
+                &mut valider_sender,
+                &controller_sender,
+                ControllerMessage::MatchFilters(scripts, matches_sender.clone()),
+            ));
+        }
+        assert_eq!(matches_receiver.try_recv(), Ok(vec![genesis.hash()]));
+        assert_eq!(matches_receiver.try_recv(), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_scan_wallet() {
+        let config = config::regtest_config();
+        let genesis = config.genesis_block.clone();
+        let storage = storage::temp_storage("controller_wallet");
+        storage.store_block(&genesis).unwrap();
+        let (mut state, _command_receiver) = new_test_state();
+        state.storage = Some(Arc::new(storage));
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        // The genesis coinbase pays to a public key
+        let coinbase = genesis.coinbase().unwrap();
+        let script = coinbase.outputs()[0].pubkey();
+        let pubkey_hash = crypto::hash160(&script[1..(script.len() - 1)]);
+        let (utxos_sender, utxos_receiver) = mpsc::channel();
+        for pubkey_hashes in vec![vec![pubkey_hash], vec![[7; 20]]] {
+            assert!(handle_controller_message(
+                &mut state,
+                &config,
+                &mut valider_sender,
+                &controller_sender,
+                ControllerMessage::ScanWallet(pubkey_hashes, utxos_sender.clone()),
+            ));
+        }
+        assert_eq!(
+            utxos_receiver.try_recv(),
+            Ok(vec![wallet::Utxo {
+                tx: coinbase.hash(),
+                index: 0,
+                value: 5_000_000_000,
+                pubkey_hash,
+            }])
+        );
+        assert_eq!(utxos_receiver.try_recv(), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_serve_filters() {
+        let mut config = config::regtest_config();
+        config.build_cfilters = true;
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        let mut storage = storage::temp_storage("controller_filters");
+        storage.set_build_cfilters(true);
+        storage.store_block(&config.genesis_block).unwrap();
+        storage.store_block(&block1).unwrap();
+        let (mut state, command_receiver) = new_test_state();
+        state.storage = Some(Arc::new(storage));
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::GetCFilters(0, 0, block1.hash()),
+            },
+        );
+        for block in &[&config.genesis_block, &block1] {
+            match command_receiver.try_recv() {
+                Ok(node::NodeCommand::SendMessage(message::MessageType::CFilter(filter))) => {
+                    assert_eq!(
+                        filter,
+                        message::Message::new(
+                            config.magic,
+                            message::cfilter::MessageCFilter::new(
+                                0,
+                                block.hash(),
+                                cfilter::Filter::basic(block, &[]).bytes()
+                            )
+                        )
+                    )
+                }
+                other => panic!("Unexpected command: {:?}", other),
+            }
+        }
+        assert!(command_receiver.try_recv().is_err());
+
+        // So are the filter headers, from the one of the block before the
+        // range
+        let genesis_filter = cfilter::Filter::basic(&config.genesis_block, &[]);
+        let filter1 = cfilter::Filter::basic(&block1, &[]);
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::GetCFHeaders(0, 1, block1.hash()),
+            },
+        );
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::CFHeaders(cfheaders))) => {
+                assert_eq!(
+                    cfheaders.command,
+                    message::cfheaders::MessageCFHeaders::new(
+                        0,
+                        block1.hash(),
+                        genesis_filter.header(&[0; 32]),
+                        vec![filter1.hash()]
+                    )
+                );
+                assert_eq!(
+                    cfheaders.command.filter_headers(),
+                    vec![filter1.header(&genesis_filter.header(&[0; 32]))]
+                );
+            }
+            other => panic!("Unexpected command: {:?}", other),
+        }
+
+        // There is no checkpoint below height 1000
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::GetCFCheckpt(0, block1.hash()),
+            },
+        );
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::CFCheckpt(cfcheckpt))) => {
+                assert_eq!(
+                    cfcheckpt.command,
+                    message::cfcheckpt::MessageCFCheckpt::new(0, block1.hash(), vec![])
+                );
+            }
+            other => panic!("Unexpected command: {:?}", other),
+        }
+        assert!(command_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_inventory_requested_once() {
+        let config = config::regtest_config();
+        let (mut state, receiver_0) = new_test_state();
+        let mut receivers = Vec::new();
+        for node_id in 1..4 {
+            let (command_sender, receiver) = mpsc::channel();
+            state
+                .nodes
+                .push(node::NodeHandle::new(node_id, command_sender));
+            receivers.push(receiver);
+        }
+        let receiver_1 = &receivers[0];
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        let block = storage::next_block(&config.genesis_block, 1);
+        let block_inv = message::inv_base::InvVect {
+            hash_type: message::inv_base::MSG_BLOCK,
+            hash: block.hash(),
+        };
+        let mut respond = |state: &mut GlobalState, node_id, content| {
+            handle_node_response(
+                state,
+                &config,
+                &mut valider_sender,
+                &controller_sender,
+                node::NodeResponse { node_id, content },
+            )
+        };
+
+        // Ignored until the chain is synchronized
+        state.sync_node_id = Some(0);
+        respond(
+            &mut state,
+            1,
+            node::NodeResponseContent::Inv(vec![block_inv.clone()]),
+        );
+        assert!(receiver_0.try_recv().is_err());
+        assert!(receiver_1.try_recv().is_err());
+
+        // New blocks are found from the headers of the sync node
+        state.initial_block_download = false;
+        respond(
+            &mut state,
+            1,
+            node::NodeResponseContent::Inv(vec![block_inv.clone()]),
+        );
+        respond(
+            &mut state,
+            1,
+            node::NodeResponseContent::Inv(vec![block_inv.clone()]),
+        );
+        assert!(matches!(
+            receiver_0.try_recv(),
+            Ok(node::NodeCommand::SendMessage(
+                message::MessageType::GetHeaders(_)
+            ))
+        ));
+        assert!(receiver_0.try_recv().is_err());
+        assert!(receiver_1.try_recv().is_err());
+        assert!(state.inventory_in_flight.is_empty());
+
+        let mut tx = transaction::Transaction::new();
+        tx.add_input([1; 32], 0, vec![0x51]);
+        tx.add_output(1000, vec![0x51]);
+        let tx_inv = message::inv_base::InvVect {
+            hash_type: message::inv_base::MSG_TX,
+            hash: tx.hash(),
+        };
+        let asked = |receiver: &mpsc::Receiver<node::NodeCommand>| match receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::GetData(message))) => {
+                assert_eq!(
+                    message.command,
+                    message::getdata::MessageGetData::new(vec![message::inv_base::InvVect {
+                        hash_type: message::inv_base::MSG_WITNESS_TX,
+                        hash: tx.hash(),
+                    }])
+                );
+                true
+            }
+            Err(_) => false,
+            other => panic!("Unexpected command: {:?}", other),
+        };
+        respond(
+            &mut state,
+            0,
+            node::NodeResponseContent::Inv(vec![tx_inv.clone()]),
+        );
+        assert!(asked(&receiver_0));
+        assert_eq!(state.inventory_in_flight.get(&tx.hash()).unwrap().0, 0);
+
+        // Already asked to the first peer
+        respond(
+            &mut state,
+            1,
+            node::NodeResponseContent::Inv(vec![tx_inv.clone()]),
+        );
+        assert!(!asked(&receiver_1));
+
+        // Until it does not find it, or times out
+        respond(
+            &mut state,
+            0,
+            node::NodeResponseContent::NotFound(vec![tx_inv.clone()]),
+        );
+        assert!(state.inventory_in_flight.is_empty());
+        respond(
+            &mut state,
+            2,
+            node::NodeResponseContent::Inv(vec![tx_inv.clone()]),
+        );
+        assert!(asked(&receivers[1]));
+        let expired = time::Instant::now().checked_sub(INVENTORY_TIMEOUT).unwrap();
+        state.inventory_in_flight.insert(tx.hash(), (2, expired));
+        respond(
+            &mut state,
+            3,
+            node::NodeResponseContent::Inv(vec![tx_inv.clone()]),
+        );
+        assert!(asked(&receivers[2]));
+
+        // Or its node is restarted
+        node_restart_with_new_peer(&mut state, &config, &controller_sender, 3);
+        assert!(state.inventory_in_flight.is_empty());
+
+        // Known transactions are not asked for
+        let mut known_tx = transaction::Transaction::new();
+        known_tx.add_input([2; 32], 0, vec![0x51]);
+        known_tx.add_output(1000, vec![0x51]);
+        state.known_txs.insert(known_tx.hash());
+        respond(
+            &mut state,
+            1,
+            node::NodeResponseContent::Inv(vec![message::inv_base::InvVect {
+                hash_type: message::inv_base::MSG_TX,
+                hash: known_tx.hash(),
+            }]),
+        );
+        assert!(receiver_1.try_recv().is_err());
+        assert!(state.inventory_in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_confirmed_transactions_removed() {
+        let config = config::regtest_config();
+        let (mut state, _command_receiver) = new_test_state();
+        let (valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        let funding = fund_state(&mut state, "controller_confirmed_txs", &[2000, 2000]);
+        let storage = Arc::clone(state.storage.as_ref().unwrap());
+        let block1 = storage
+            .get_block(storage.tip().unwrap().unwrap().0)
+            .unwrap()
+            .unwrap();
+
+        let mut confirmed = transaction::Transaction::new();
+        confirmed.add_input(funding.hash(), 0, vec![]);
+        confirmed.add_output(1000, vec![0x51]);
+        let mut conflicting = transaction::Transaction::new();
+        conflicting.add_input(funding.hash(), 1, vec![]);
+        conflicting.add_output(1000, vec![0x51]);
+        let mut kept = transaction::Transaction::new();
+        kept.add_input(confirmed.hash(), 0, vec![]);
+        kept.add_output(500, vec![0x51]);
+        for tx in &[&confirmed, &conflicting, &kept] {
+            accept_transaction(&mut state, &config, None, (*tx).clone());
+        }
+        assert_eq!(state.mempool.len(), 3);
+
+        // Block 2 confirms a transaction and spends the input of another one
+        let mut double_spend = transaction::Transaction::new();
+        double_spend.add_input(funding.hash(), 1, vec![]);
+        double_spend.add_output(1500, vec![0x51]);
+        let mut block2 = storage::next_block(&block1, 1);
+        block2.add_tx(Box::new(confirmed.clone())).unwrap();
+        block2.add_tx(Box::new(double_spend)).unwrap();
+        storage.store_block(&block2).unwrap();
+        handle_valider_message(
+            &mut state,
+            &config,
+            &valider_sender,
+            valider::ValiderMessage::Validated(block2.hash(), 2),
+            &controller_sender,
+        );
+        assert_eq!(state.mempool.len(), 1);
+        assert!(state.mempool.get(&kept.hash()).is_some());
+    }
+
+    #[test]
+    fn test_broadcast_transaction() {
+        let config = config::regtest_config();
+        let (mut state, receiver_0) = new_test_state();
+        let (command_sender, receiver_1) = mpsc::channel();
+        let mut node_handle = node::NodeHandle::new(1, command_sender);
+        node_handle
+            .set_state(node::NodeState::UPDATING_PEERS)
+            .unwrap();
+        state.nodes.push(node_handle);
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        let funding = fund_state(&mut state, "controller_broadcast_tx", &[2000]);
+        let mut tx = transaction::Transaction::new();
+        tx.add_input(funding.hash(), 0, vec![0x51]);
+        tx.add_output(1000, vec![0x51]);
+        accept_transaction(&mut state, &config, None, tx.clone());
+
+        // Announced to every connected peer
+        for receiver in &[&receiver_0, &receiver_1] {
+            match receiver.try_recv() {
+                Ok(node::NodeCommand::SendMessage(message::MessageType::Inv(_))) => (),
+                _ => panic!("Transaction should have been announced"),
+            }
+        }
+
+        // Served on request, unknown transactions are reported as such
+        let unknown = message::inv_base::InvVect {
+            hash_type: message::inv_base::MSG_TX,
+            hash: [2; 32],
+        };
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 1,
+                content: node::NodeResponseContent::GetData(vec![
+                    message::inv_base::InvVect {
+                        hash_type: message::inv_base::MSG_WITNESS_TX,
+                        hash: tx.hash(),
+                    },
+                    unknown.clone(),
+                ]),
+            },
+        );
+        match receiver_1.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::Tx(message))) => {
+                assert_eq!(message.command, message::tx::MessageTx::new(tx))
+            }
+            _ => panic!("Transaction should have been served"),
+        }
+        match receiver_1.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::NotFound(message))) => {
+                assert_eq!(
+                    message.command,
+                    message::notfound::MessageNotFound::new(vec![unknown])
+                )
+            }
+            _ => panic!("Unknown transaction should have been reported"),
+        }
+        assert!(receiver_0.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_serve_headers() {
+        let config = config::regtest_config();
+        let (mut state, command_receiver) = new_test_state();
+        let (valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        let block2 = storage::next_block(&block1, 1);
+        let block3 = storage::next_block(&block2, 1);
+        let storage = Arc::new(storage::temp_storage("controller_serve_headers"));
+        for block in &[&config.genesis_block, &block1, &block2] {
+            storage.store_block(block).unwrap();
+        }
+        state.storage = Some(Arc::clone(&storage));
+
+        let mut get_headers = |state: &mut GlobalState, locator: Vec<crypto::Hash32>| {
+            handle_node_response(
+                state,
+                &config,
+                &mut valider_sender.clone(),
+                &controller_sender,
+                node::NodeResponse {
+                    node_id: 0,
+                    content: node::NodeResponseContent::GetHeaders(locator, [0; 32]),
+                },
+            );
+            match command_receiver.try_recv() {
+                Ok(node::NodeCommand::SendMessage(message::MessageType::Headers(message))) => {
+                    message.command
+                }
+                other => panic!("Unexpected command: {:?}", other),
+            }
+        };
+        let headers = |blocks: &[&block::Block]| {
+            message::headers::MessageHeaders::from_headers(
+                blocks.iter().map(|block| block.header.clone()).collect(),
+            )
+        };
+
+        // The same request is only read once from the storage
+        for _ in 0..2 {
+            assert_eq!(
+                get_headers(&mut state, vec![block1.hash()]),
+                headers(&[&block2])
+            );
+        }
+        assert_eq!(state.headers_cache.storage_reads, 1);
+
+        // Responses not following the locator tip are not cached
+        for _ in 0..2 {
+            assert_eq!(
+                get_headers(&mut state, vec![[1; 32], block1.hash()]),
+                headers(&[&block2])
+            );
+        }
+        assert_eq!(state.headers_cache.storage_reads, 3);
+
+        // The cache is invalidated when the chain is extended
+        storage.store_block(&block3).unwrap();
+        handle_valider_message(
+            &mut state,
+            &config,
+            &valider_sender,
+            valider::ValiderMessage::Validated(block3.hash(), 3),
+            &controller_sender,
+        );
+        assert_eq!(
+            get_headers(&mut state, vec![block1.hash()]),
+            headers(&[&block2, &block3])
+        );
+        assert_eq!(state.headers_cache.storage_reads, 4);
+    }
+
+    #[test]
+    fn test_headers_cache_eviction() {
+        let mut cache = HeadersCache::new(2);
+        let headers = |count| {
+            message::headers::MessageHeaders::from_headers(vec![
+                config::regtest_config()
+                    .genesis_block
+                    .header;
+                count
+            ])
+        };
+        cache.insert(([1; 32], [0; 32]), headers(1));
+        cache.insert(([2; 32], [0; 32]), headers(2));
+        // The least recently used entry is evicted
+        assert_eq!(cache.get(&([1; 32], [0; 32])), Some(headers(1)));
+        cache.insert(([3; 32], [0; 32]), headers(3));
+        assert_eq!(cache.get(&([2; 32], [0; 32])), None);
+        assert_eq!(cache.get(&([1; 32], [0; 32])), Some(headers(1)));
+        assert_eq!(cache.get(&([3; 32], [0; 32])), Some(headers(3)));
+        cache.clear();
+        assert_eq!(cache.get(&([3; 32], [0; 32])), None);
+    }
+
+    #[test]
+    fn test_serve_blocks() {
+        let config = config::regtest_config();
+        let (mut state, command_receiver) = new_test_state();
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        let mut tx = transaction::Transaction::new();
+        tx.add_input([1; 32], 0, vec![0x51]);
+        tx.add_output(1000, vec![0x03, 0xaa, 0xbb, 0xcc]);
+        let mut block = storage::next_block(&config.genesis_block, 1);
+        block.add_tx(Box::new(tx.clone())).unwrap();
+        block.update_merkle_root();
+        let storage = storage::temp_storage("controller_serve_blocks");
+        storage.store_block(&config.genesis_block).unwrap();
+        storage.store_block(&block).unwrap();
+        state.storage = Some(Arc::new(storage));
+
+        let mut respond = |state: &mut GlobalState, content| {
+            handle_node_response(
+                state,
+                &config,
+                &mut valider_sender,
+                &controller_sender,
+                node::NodeResponse {
+                    node_id: 0,
+                    content,
+                },
+            )
+        };
+        let block_hash = block.hash();
+        let inv = |hash_type| message::inv_base::InvVect {
+            hash_type,
+            hash: block_hash,
+        };
+        let get_data = |inv_vect: &message::inv_base::InvVect| {
+            node::NodeResponseContent::GetData(vec![inv_vect.clone()])
+        };
+        let expect_not_found = |inv_vect| match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::NotFound(message))) => {
+                assert_eq!(
+                    message.command,
+                    message::notfound::MessageNotFound::new(vec![inv_vect])
+                )
+            }
+            _ => panic!("Expected a notfound message"),
+        };
+
+        respond(
+            &mut state,
+            get_data(&inv(message::inv_base::MSG_WITNESS_BLOCK)),
+        );
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::Block(message))) => {
+                assert_eq!(
+                    message.command,
+                    message::block::MessageBlock::new(block.clone())
+                )
+            }
+            _ => panic!("Block should have been served"),
+        }
+
+        // Filtered blocks are only served once a bloom filter is loaded
+        let inv_vect = inv(message::inv_base::MSG_FILTERED_BLOCK);
+        respond(&mut state, get_data(&inv_vect));
+        expect_not_found(inv_vect.clone());
+        let mut bloom_filter = bloom::BloomFilter::new(vec![0; 64], 3, 0).unwrap();
+        bloom_filter.insert(&[0xaa, 0xbb, 0xcc]);
+        state.nodes[0].set_relay(false);
+        respond(
+            &mut state,
+            node::NodeResponseContent::FilterLoad(bloom_filter),
+        );
+        assert!(state.nodes[0].relay());
+
+        respond(&mut state, get_data(&inv_vect));
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::MerkleBlock(message))) => {
+                assert_eq!(
+                    message.command,
+                    message::merkleblock::MessageMerkleBlock::new(&block, &[false, true])
+                )
+            }
+            _ => panic!("Filtered block should have been served"),
+        }
+        // Followed by the matched transaction only
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::Tx(message))) => {
+                assert_eq!(message.command, message::tx::MessageTx::new(tx))
+            }
+            _ => panic!("Matched transaction should have been served"),
+        }
+        assert!(command_receiver.try_recv().is_err());
+
+        // Compact blocks are never negotiated
+        let inv_vect = inv(message::inv_base::MSG_CMPCT_BLOCK);
+        respond(&mut state, get_data(&inv_vect));
+        expect_not_found(inv_vect);
+        let inv_vect = message::inv_base::InvVect {
+            hash_type: message::inv_base::MSG_BLOCK,
+            hash: [1; 32],
+        };
+        respond(&mut state, get_data(&inv_vect));
+        expect_not_found(inv_vect);
+    }
+
+    #[test]
+    fn test_sample_fresh_peers() {
+        let (mut state, command_receiver) = new_test_state();
+        let config = config::regtest_config();
+        let now = 1_600_000_000;
+        let peer = |port: u16, age: u64| {
+            network::NetAddr::new(
+                now - age as u32,
+                message::NODE_NETWORK,
+                net::Ipv4Addr::LOCALHOST.to_ipv6_mapped(),
+                port,
+            )
+        };
+        let max_age = ADDR_MAX_AGE.as_secs();
+        let fresh = vec![peer(1, 0), peer(2, 60), peer(3, max_age)];
+        let stale = vec![peer(4, max_age + 1), peer(5, 24 * 60 * 60)];
+        for addr in fresh.iter().chain(stale.iter()) {
+            state.known_active_nodes.insert(addr.clone());
+        }
+
+        let mut sample = sample_known_peers(&state, now, MAX_ADDR_RESPONSE);
+        sample.sort_by_key(|addr| addr.net_addr_version.port());
+        assert_eq!(sample, fresh);
+        assert_eq!(sample_known_peers(&state, now, 2).len(), 2);
+
+        prune_known_peers(&mut state, now);
+        assert_eq!(state.known_active_nodes.len(), fresh.len());
+        assert!(stale
+            .iter()
+            .all(|addr| !state.known_active_nodes.contains(addr)));
+
+        // getaddr is answered with an addr message
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::GetAddr,
+            },
+        );
+        match command_receiver.try_recv() {
+            Ok(node::NodeCommand::SendMessage(message::MessageType::Addr(_))) => (),
+            other => panic!("Unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sync_events() {
+        let config = config::regtest_config();
+        let mut state = GlobalState::new(config.genesis_block.hash());
+        let (event_sender, event_receiver) = mpsc::channel();
+        state.event_subscriber = Some(event_sender);
+        let (command_sender, _command_receiver) = mpsc::channel();
+        let mut node_handle = node::NodeHandle::new(0, command_sender);
+        let peer_addr: net::SocketAddr = "10.0.0.1:18444".parse().unwrap();
+        node_handle.set_addr(peer_addr);
+        state.nodes.push(node_handle);
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        let block2 = storage::next_block(&block1, 2);
+        let responses = vec![
+            node::NodeResponseContent::Connected,
+            node::NodeResponseContent::Addrs(vec![]),
+            node::NodeResponseContent::Headers(vec![block1.header.clone(), block2.header.clone()]),
+        ];
+        for content in responses {
+            handle_node_response(
+                &mut state,
+                &config,
+                &mut valider_sender,
+                &controller_sender,
+                node::NodeResponse {
+                    node_id: 0,
+                    content,
+                },
+            );
+        }
+        for valider_message in vec![
+            valider::ValiderMessage::Validated(block1.hash(), 1),
+            valider::ValiderMessage::Validated(block2.hash(), 2),
+            valider::ValiderMessage::Reorg(block1.hash(), block2.hash()),
+        ] {
+            handle_valider_message(
+                &mut state,
+                &config,
+                &valider_sender,
+                valider_message,
+                &controller_sender,
+            );
+        }
+        misbehaving(
+            &mut state,
+            &config,
+            &controller_sender,
+            0,
+            node::Misbehavior::InvalidBlock,
+        );
+
+        assert_eq!(
+            event_receiver.try_iter().collect::<Vec<SyncEvent>>(),
+            vec![
+                SyncEvent::PeerConnected(0),
+                SyncEvent::SyncNodeChosen(0),
+                SyncEvent::HeadersReceived(0, 2),
+                SyncEvent::BlockValidated(block1.hash(), 1),
+                SyncEvent::BlockValidated(block2.hash(), 2),
+                SyncEvent::Reorg(block1.hash(), block2.hash()),
+                SyncEvent::PeerBanned(peer_addr.ip()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_connect_limiter() {
+        let limiter = Arc::new(ConnectLimiter::new(2));
+        let mut permits = vec![limiter.acquire(), limiter.acquire()];
+        assert_eq!(*limiter.in_flight.lock().unwrap(), 2);
+
+        // A third attempt waits until a permit is released
+        let (started_sender, started_receiver) = mpsc::channel();
+        let (acquired_sender, acquired_receiver) = mpsc::channel();
+        let waiting_limiter = Arc::clone(&limiter);
+        let waiting = thread::spawn(move || {
+            started_sender.send(()).unwrap();
+            let _permit = waiting_limiter.acquire();
+            acquired_sender.send(()).unwrap();
+        });
+        started_receiver.recv().unwrap();
+        assert!(acquired_receiver.try_recv().is_err());
+
+        permits.pop();
+        acquired_receiver.recv().unwrap();
+        waiting.join().unwrap();
+        assert_eq!(*limiter.in_flight.lock().unwrap(), 1);
+
+        drop(permits);
+        assert_eq!(*limiter.in_flight.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_start_node_waits_for_permit() {
+        let config = config::regtest_config();
+        // Nothing listens on this address: the connection is refused
+        let peer_addr = net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        // Every connection slot is taken
+        let limiter = Arc::new(ConnectLimiter::new(1));
+        let permit = limiter.acquire();
+
+        let (response_sender, response_receiver) = mpsc::channel();
+        let (_command_sender, command_receiver) = mpsc::channel();
+        let (started_sender, started_receiver) = mpsc::channel();
+        let node_limiter = Arc::clone(&limiter);
+        thread::spawn(move || {
+            started_sender.send(()).unwrap();
+            start_node(
+                0,
+                peer_addr,
+                command_receiver,
+                response_sender,
+                config,
+                &node_limiter,
+            )
+        });
+        started_receiver.recv().unwrap();
+        assert!(response_receiver.try_recv().is_err());
+
+        // The attempt fails once it gets the permit, and gives it back
+        drop(permit);
+        match response_receiver.recv() {
+            Ok(ControllerMessage::NodeResponse(node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::ConnectionClosed,
+            })) => (),
+            _ => panic!("Expected a ConnectionClosed response"),
+        }
+        assert_eq!(*limiter.in_flight.lock().unwrap(), 0);
+    }
+
+    /// Accepts a connection from a node and returns its version message
+    fn accept_version(
+        listener: &net::TcpListener,
+        magic: u32,
+    ) -> message::Message<message::version::MessageVersion> {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut bytes = Vec::new();
+        loop {
+            let mut buffer = [0; 256];
+            let size = std::io::Read::read(&mut stream, &mut buffer).unwrap();
+            bytes.extend_from_slice(&buffer[..size]);
+            if let Ok((message::MessageType::Version(version), _)) = message::parse(&bytes, magic) {
+                return version;
+            }
+        }
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        drop(storage::temp_storage("controller_shutdown"));
+        let config = config::Config::builder()
+            .network(config::Network::Regtest)
+            .data_dir(storage::temp_storage_root("controller_shutdown"))
+            .peer_count(1)
+            .fixed_peer(listener.local_addr().unwrap())
+            .connect_only(true)
+            .build()
+            .unwrap();
+        let genesis = config.genesis_block.hash();
+
+        let (controller, thread) = start(config, None);
+        let (mut peer, _) = listener.accept().unwrap();
+        controller.shutdown();
+        thread.join().unwrap();
+
+        // The node has been stopped and the storage flushed
+        peer.set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let mut bytes = Vec::new();
+        assert!(std::io::Read::read_to_end(&mut peer, &mut bytes).is_ok());
+        let storage = storage::reopen_temp_storage("controller_shutdown");
+        assert!(storage.has_block(genesis).unwrap());
+        // Commands sent once the node is stopped are dropped
+        controller.shutdown();
+    }
+
+    #[test]
+    fn test_learn_external_addr() {
+        let config = config::regtest_config();
+        let mut state = GlobalState::new(config.genesis_block.hash());
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        for node_id in 0..4 {
+            let (command_sender, _command_receiver) = mpsc::channel();
+            let mut node_handle = node::NodeHandle::new(node_id, command_sender);
+            node_handle.set_addr(net::SocketAddr::new(
+                net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, node_id as u8)),
+                18444,
+            ));
+            state.nodes.push(node_handle);
+        }
+        let external_addr: net::IpAddr = "203.0.113.7".parse().unwrap();
+        let mut report = |state: &mut GlobalState, node_id, addr| {
+            handle_node_response(
+                state,
+                &config,
+                &mut valider_sender,
+                &controller_sender,
+                node::NodeResponse {
+                    node_id,
+                    content: node::NodeResponseContent::LocalAddr(addr),
+                },
+            )
+        };
+
+        // A single peer, even repeating itself, is not enough
+        report(&mut state, 0, external_addr);
+        report(&mut state, 0, external_addr);
+        report(&mut state, 1, "198.51.100.1".parse().unwrap());
+        report(&mut state, 2, "0.0.0.0".parse().unwrap());
+        report(&mut state, 3, external_addr);
+        assert_eq!(state.external_addr, None);
+        report(&mut state, 2, external_addr);
+        assert_eq!(state.external_addr, Some(external_addr));
+
+        // The next version messages advertise it
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (_command_sender, command_receiver) = mpsc::channel();
+        spawn_node(
+            &mut state,
+            &config,
+            &controller_sender,
+            0,
+            listener.local_addr().unwrap(),
+            command_receiver,
+        );
+        let version = accept_version(&listener, config.magic);
+        let addr_from = version.command.addr_from();
+        assert_eq!(
+            addr_from.to_socket_addr(),
+            net::SocketAddr::new(external_addr, config.port)
+        );
+    }
+
+    #[test]
+    fn test_no_relay_during_initial_block_download() {
+        let config = config::regtest_config();
+        let (mut state, _command_receiver) = new_test_state();
+        state.sync_node_id = Some(0);
+        state.known_headers.insert(config.genesis_block.hash());
+        let (controller_sender, _controller_receiver) = mpsc::channel();
+        let (mut valider_sender, _valider_receiver) = mpsc::channel();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut connect = |state: &mut GlobalState| {
+            let (_command_sender, command_receiver) = mpsc::channel();
+            state
+                .nodes
+                .push(node::NodeHandle::new(1, mpsc::channel().0));
+            spawn_node(
+                state,
+                &config,
+                &controller_sender,
+                1,
+                listener.local_addr().unwrap(),
+                command_receiver,
+            );
+            state.nodes.pop();
+            accept_version(&listener, config.magic).command.relay()
+        };
+        assert!(!connect(&mut state));
+
+        // A peer connected during the download, then reconnected once it is
+        // over to hear about transactions
+        let (command_sender, command_receiver) = mpsc::channel();
+        state.nodes.push(node::NodeHandle::new(1, command_sender));
+        spawn_node(
+            &mut state,
+            &config,
+            &controller_sender,
+            1,
+            listener.local_addr().unwrap(),
+            command_receiver,
+        );
+        assert!(!accept_version(&listener, config.magic).command.relay());
+
+        // The sync node has no more headers
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        handle_node_response(
+            &mut state,
+            &config,
+            &mut valider_sender,
+            &controller_sender,
+            node::NodeResponse {
+                node_id: 0,
+                content: node::NodeResponseContent::Headers(vec![block1.header.clone()]),
+            },
+        );
+        assert!(state.initial_block_download);
+        assert!(!connect(&mut state));
+
+        // Nor blocks to download
+        handle_valider_message(
+            &mut state,
+            &config,
+            &valider_sender,
+            valider::ValiderMessage::Validated(block1.hash(), 1),
+            &controller_sender,
+        );
+        assert_eq!(state.stored_blocks, 1);
+        assert!(!state.initial_block_download);
+        assert!(accept_version(&listener, config.magic).command.relay());
+        assert_eq!(state.nodes[1].addr(), Some(listener.local_addr().unwrap()));
+        state.nodes.pop();
+        assert!(connect(&mut state));
+    }
 }
@@ -3,9 +3,203 @@
 extern crate log;
 extern crate simple_logger;
 
+use std::env;
+use std::process;
+use std::time::Duration;
+use yasbit::config::{Config, Network, ValidationLevel};
+
+const USAGE: &str = "Usage: yasbit [OPTIONS]
+
+Options:
+    --network <mainnet|testnet|regtest|signet>  Network to connect to (default: testnet)
+    --mainnet, --testnet, --regtest, --signet   Shorthands for --network
+    --log-level <error|warn|info|debug|trace>   Verbosity of the logs (default: debug)
+    --datadir <PATH>                            Directory of the databases and block files
+    --peers <COUNT>                             Number of peers to connect to
+    --max-connecting <COUNT>                    Connection attempts in flight at once
+    --peer-timeout <SECONDS>                    Ping, then drop, peers silent this long
+    --proxy <ADDR:PORT>                         Connect to peers through this SOCKS proxy
+    --external-ip <ADDR>                        Address advertised to peers (default: learnt)
+    --connect <ADDR:PORT>                       Only connect to this peer (may be repeated)
+    --compress-blocks                           Compress blocks written on disk
+    --process-alerts                            Display the deprecated alert messages
+    --min-relay-fee <SAT/KB>                    Minimum fee rate of the relayed transactions
+    --build-cfilters                            Build and serve compact block filters
+    --max-reorg-depth <BLOCKS>                  Deepest reorganization accepted (default: 100)
+    --prune <MIB>                               Delete old blocks above this size (min: 550)
+    --validation <full|headers-only|none>       Checks run on the blocks (default: full)
+    --assume-valid <HASH>                       Skip the scripts of this block and its ancestors
+    --help                                      Print this message";
+
+fn exit_with_usage(error: &str) -> ! {
+    eprintln!("{}\n\n{}", error, USAGE);
+    process::exit(1);
+}
+
+fn parse_network(value: &str) -> Result<Network, String> {
+    match value {
+        "mainnet" => Ok(Network::Mainnet),
+        "testnet" => Ok(Network::Testnet),
+        "regtest" => Ok(Network::Regtest),
+        "signet" => Ok(Network::Signet),
+        _ => Err(format!("Unknown network {}", value)),
+    }
+}
+
+fn parse_validation_level(value: &str) -> Result<ValidationLevel, String> {
+    match value {
+        "full" => Ok(ValidationLevel::Full),
+        "headers-only" => Ok(ValidationLevel::HeadersOnly),
+        "none" => Ok(ValidationLevel::None),
+        _ => Err(format!("Unknown validation level {}", value)),
+    }
+}
+
+/// Returns the configuration and the log level given on the command line
+fn parse_args(args: Vec<String>) -> Result<(Config, log::Level), String> {
+    let mut builder = Config::builder();
+    let mut log_level = log::Level::Debug;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--help" {
+            println!("{}", USAGE);
+            process::exit(0);
+        }
+        if arg == "--compress-blocks" {
+            builder = builder.compress_blocks(true);
+            continue;
+        }
+        if arg == "--process-alerts" {
+            builder = builder.process_alerts(true);
+            continue;
+        }
+        if arg == "--build-cfilters" {
+            builder = builder.build_cfilters(true);
+            continue;
+        }
+        if let "--mainnet" | "--testnet" | "--regtest" | "--signet" = arg.as_str() {
+            builder = builder.network(parse_network(&arg[2..])?);
+            continue;
+        }
+        let value = match args.next() {
+            Some(value) => value,
+            None => return Err(format!("Missing value for {}", arg)),
+        };
+        let invalid = || format!("Invalid value for {}: {}", arg, value);
+        builder = match arg.as_str() {
+            "--network" => builder.network(parse_network(&value)?),
+            "--log-level" => {
+                log_level = value.parse().map_err(|_| invalid())?;
+                builder
+            }
+            "--datadir" => builder.data_dir(value.as_str()),
+            "--peers" => builder.peer_count(value.parse().map_err(|_| invalid())?),
+            "--max-connecting" => builder.max_connecting(value.parse().map_err(|_| invalid())?),
+            "--peer-timeout" => {
+                builder.peer_timeout(Duration::from_secs(value.parse().map_err(|_| invalid())?))
+            }
+            "--proxy" => builder.proxy(value.parse().map_err(|_| invalid())?),
+            "--connect" => builder
+                .fixed_peer(value.parse().map_err(|_| invalid())?)
+                .connect_only(true),
+            "--external-ip" => builder.external_addr(value.parse().map_err(|_| invalid())?),
+            "--min-relay-fee" => builder.min_relay_fee(value.parse().map_err(|_| invalid())?),
+            "--max-reorg-depth" => builder.max_reorg_depth(value.parse().map_err(|_| invalid())?),
+            "--prune" => builder.prune_target_mib(value.parse().map_err(|_| invalid())?),
+            "--validation" => builder.validation_level(parse_validation_level(&value)?),
+            "--assume-valid" => match hex::decode(&value) {
+                Ok(hash) if hash.len() == 32 => {
+                    let mut assume_valid = [0; 32];
+                    assume_valid.copy_from_slice(&hash);
+                    builder.assume_valid(assume_valid)
+                }
+                _ => return Err(invalid()),
+            },
+            _ => return Err(format!("Unknown option {}", arg)),
+        };
+    }
+
+    match builder.build() {
+        Ok(config) => Ok((config, log_level)),
+        Err(err) => Err(format!("Invalid configuration: {:?}", err)),
+    }
+}
+
 fn main() {
+    let (config, log_level) = match parse_args(env::args().skip(1).collect()) {
+        Ok(parsed) => parsed,
+        Err(error) => exit_with_usage(&error),
+    };
+
     // Initialize logger
-    simple_logger::init_with_level(log::Level::Debug).unwrap();
+    simple_logger::init_with_level(log_level).unwrap();
+
+    let (controller, thread) = yasbit::start(config, None);
+    // The storage is flushed before exiting on SIGINT and SIGTERM
+    if let Err(err) = ctrlc::set_handler(move || controller.shutdown()) {
+        log::error!("Could not install the signal handler: {}", err);
+    }
+    thread.join().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use yasbit::config;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_network() {
+        let expected = config::main_config();
+        for mainnet in &[args(&["--network", "mainnet"]), args(&["--mainnet"])] {
+            let (config, _) = parse_args(mainnet.clone()).unwrap();
+            assert_eq!(config.network, expected.network);
+            assert_eq!(config.magic, expected.magic);
+            assert_eq!(config.port, expected.port);
+            assert_eq!(config.genesis_block, expected.genesis_block);
+        }
+
+        let (config, log_level) = parse_args(args(&[])).unwrap();
+        assert_eq!(config.network, Network::Testnet);
+        assert_eq!(log_level, log::Level::Debug);
+
+        assert!(parse_network("mainnet").is_ok());
+        assert!(parse_network("moonnet").is_err());
+        assert!(parse_args(args(&["--network", "moonnet"])).is_err());
+        assert!(parse_args(args(&["--network"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_log_level() {
+        let (_, log_level) = parse_args(args(&["--log-level", "info"])).unwrap();
+        assert_eq!(log_level, log::Level::Info);
+        let (_, log_level) = parse_args(args(&["--regtest", "--log-level", "WARN"])).unwrap();
+        assert_eq!(log_level, log::Level::Warn);
+        assert!(parse_args(args(&["--log-level", "loud"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_validation_level() {
+        let (config, _) = parse_args(args(&[])).unwrap();
+        assert_eq!(config.validation_level, ValidationLevel::Full);
+        let (config, _) = parse_args(args(&["--validation", "headers-only"])).unwrap();
+        assert_eq!(config.validation_level, ValidationLevel::HeadersOnly);
+        let (config, _) = parse_args(args(&["--validation", "none"])).unwrap();
+        assert_eq!(config.validation_level, ValidationLevel::None);
+        assert!(parse_args(args(&["--validation", "some"])).is_err());
+    }
 
-    yasbit::run();
+    #[test]
+    fn test_parse_assume_valid() {
+        let hash = "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206";
+        let (config, _) = parse_args(args(&["--regtest", "--assume-valid", hash])).unwrap();
+        assert_eq!(config.assume_valid.map(hex::encode), Some(hash.to_string()));
+        assert!(parse_args(args(&["--assume-valid", "0f9188"])).is_err());
+        assert!(parse_args(args(&["--assume-valid", "genesis"])).is_err());
+    }
 }
@@ -0,0 +1,316 @@
+use crate::block::COINBASE_MATURITY;
+use crate::crypto::{Hash32, Hashable};
+use crate::script::{Script, ScriptError};
+use crate::transaction::{money_sum, Transaction, TxOutput};
+use std::collections::{HashMap, HashSet};
+
+/// Reasons for which a transaction is not accepted to the mempool
+#[derive(Debug, Clone, PartialEq)]
+pub enum MempoolError {
+    UnknownOutput,  // an input spends an output which is neither stored nor in the mempool
+    Conflict,       // an input spends an output already spent by the mempool
+    DuplicateInput, // two inputs spend the same output
+    ImmatureCoinbase, // an input spends a coinbase less than COINBASE_MATURITY blocks deep
+    BadInputValue,  // the transaction creates more than it spends
+    BadScript(ScriptError),
+}
+
+/// Transactions waiting to be confirmed, served to peers on getdata
+#[derive(Debug, Default)]
+pub struct Mempool {
+    txs: HashMap<Hash32, Transaction>,
+    fee_rates: HashMap<Hash32, u64>, // satoshis per kB of the transactions
+    spends: HashMap<(Hash32, u32), Hash32>, // outpoints spent by the transactions
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    pub fn get(&self, txid: &Hash32) -> Option<&Transaction> {
+        self.txs.get(txid)
+    }
+
+    /// Returns an output of a transaction of the mempool
+    fn output(&self, outpoint: (Hash32, u32)) -> Option<TxOutput> {
+        let (txid, vout) = outpoint;
+        let output = self.txs.get(&txid)?.outputs().get(vout as usize)?;
+        Some((**output).clone())
+    }
+
+    /// Checks that the inputs of a transaction spend outputs of the active
+    /// chain, given by `utxo` with the height of their block for coinbase
+    /// outputs, or of the mempool, which are not spent by the mempool yet.
+    /// Their scripts are run with minimal pushes required and witness
+    /// programs verified. `height` is the height of the next block. Returns
+    /// the fee of the transaction.
+    pub fn check_inputs(
+        &self,
+        tx: &Transaction,
+        utxo: &dyn Fn((Hash32, u32)) -> Option<(TxOutput, Option<u64>)>,
+        height: u64,
+        timestamp: u64,
+    ) -> Result<u64, MempoolError> {
+        let mut input_value = 0u64;
+        let mut spent = HashSet::new();
+        for (index, input) in tx.inputs().iter().enumerate() {
+            let outpoint = input.prev_output();
+            if !spent.insert(outpoint) {
+                return Err(MempoolError::DuplicateInput);
+            }
+            if self.spends.contains_key(&outpoint) {
+                return Err(MempoolError::Conflict);
+            }
+            let (prev_output, coinbase_height) = match self.output(outpoint) {
+                Some(output) => (output, None),
+                None => utxo(outpoint).ok_or(MempoolError::UnknownOutput)?,
+            };
+            if let Some(coinbase_height) = coinbase_height {
+                let depth = height.checked_sub(coinbase_height);
+                if depth.map_or(true, |depth| depth < COINBASE_MATURITY) {
+                    return Err(MempoolError::ImmatureCoinbase);
+                }
+            }
+            input_value = money_sum(vec![input_value, prev_output.value()])
+                .ok_or(MempoolError::BadInputValue)?;
+
+            let mut script = Script::new(
+                Box::new(tx.clone()),
+                index,
+                Box::new(prev_output),
+                timestamp,
+            );
+            script.set_require_minimal(true);
+            script.set_segwit(true);
+            if let Err(error) = script.exec().verify() {
+                return Err(MempoolError::BadScript(error));
+            }
+        }
+        tx.output_value()
+            .and_then(|output_value| input_value.checked_sub(output_value))
+            .ok_or(MempoolError::BadInputValue)
+    }
+
+    /// Adds a transaction whose inputs have been checked, with its fee rate
+    pub fn insert(&mut self, tx: Transaction, fee_rate: u64) {
+        let txid = tx.hash();
+        for input in tx.inputs() {
+            self.spends.insert(input.prev_output(), txid);
+        }
+        self.txs.insert(txid, tx);
+        self.fee_rates.insert(txid, fee_rate);
+    }
+
+    /// Removes a transaction, but not the ones spending its outputs
+    pub fn remove(&mut self, txid: &Hash32) -> Option<Transaction> {
+        let tx = self.txs.remove(txid)?;
+        self.fee_rates.remove(txid);
+        for input in tx.inputs() {
+            if self.spends.get(&input.prev_output()) == Some(txid) {
+                self.spends.remove(&input.prev_output());
+            }
+        }
+        Some(tx)
+    }
+
+    /// Returns a transaction and the transactions of the mempool spending
+    /// its outputs, directly or not
+    pub fn descendants(&self, txid: Hash32) -> Vec<Hash32> {
+        let mut descendants = vec![txid];
+        let mut index = 0;
+        while index < descendants.len() {
+            if let Some(tx) = self.txs.get(&descendants[index]) {
+                for vout in 0..tx.outputs().len() {
+                    if let Some(spender) = self.spends.get(&(descendants[index], vout as u32)) {
+                        if !descendants.contains(spender) {
+                            descendants.push(*spender);
+                        }
+                    }
+                }
+            }
+            index += 1;
+        }
+        descendants
+    }
+
+    /// Returns the transaction of lowest fee rate, with its fee rate
+    pub fn lowest_fee_rate(&self) -> Option<(Hash32, u64)> {
+        self.fee_rates
+            .iter()
+            .min_by_key(|(_, fee_rate)| **fee_rate)
+            .map(|(txid, fee_rate)| (*txid, *fee_rate))
+    }
+
+    /// Removes the transactions confirmed by the active chain, and the ones
+    /// conflicting with it with their descendants. `spending_tx` returns the
+    /// transaction of the active chain spending an outpoint, if any. Returns
+    /// the removed transactions.
+    pub fn remove_confirmed(
+        &mut self,
+        spending_tx: &dyn Fn((Hash32, u32)) -> Option<Hash32>,
+    ) -> Vec<Hash32> {
+        let mut removed = Vec::new();
+        let txids: Vec<Hash32> = self.txs.keys().cloned().collect();
+        for txid in txids {
+            // Already removed as the descendant of a conflicting transaction
+            let tx = match self.txs.get(&txid) {
+                Some(tx) => tx,
+                None => continue,
+            };
+            let spenders: Vec<Hash32> = tx
+                .inputs()
+                .iter()
+                .filter_map(|input| spending_tx(input.prev_output()))
+                .collect();
+            if spenders.is_empty() {
+                continue;
+            }
+            // The children of a confirmed transaction stay, as they now
+            // spend outputs of the chain
+            let evicted = if spenders.iter().all(|spender| *spender == txid) {
+                vec![txid]
+            } else {
+                self.descendants(txid)
+            };
+            for txid in evicted {
+                if self.remove(&txid).is_some() {
+                    removed.push(txid);
+                }
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Returns a transaction spending `outpoint` into an OP_TRUE output
+    fn spending(outpoint: (Hash32, u32), value: u64, script_sig: Vec<u8>) -> Transaction {
+        let mut tx = Transaction::new();
+        tx.add_input(outpoint.0, outpoint.1, script_sig);
+        tx.add_output(value, vec![0x51]);
+        tx
+    }
+
+    #[test]
+    fn test_check_inputs() {
+        let mut utxo = HashMap::new();
+        utxo.insert(([1; 32], 0), (TxOutput::new(10_000, vec![0x51]), None));
+        // Output of the coinbase of block 1
+        utxo.insert(([2; 32], 0), (TxOutput::new(10_000, vec![0x51]), Some(1)));
+        let lookup = |outpoint| utxo.get(&outpoint).cloned();
+        let mut mempool = Mempool::new();
+        let check = |mempool: &Mempool, tx: &Transaction, height: u64| {
+            mempool.check_inputs(tx, &lookup, height, 0)
+        };
+
+        let tx = spending(([1; 32], 0), 9_000, vec![]);
+        assert_eq!(check(&mempool, &tx, 2), Ok(1_000));
+        mempool.insert(tx.clone(), 1_000);
+        assert!(mempool.get(&tx.hash()).is_some());
+
+        // Outputs of the mempool may be spent, but only once
+        let child = spending((tx.hash(), 0), 8_000, vec![]);
+        assert_eq!(check(&mempool, &child, 2), Ok(1_000));
+        let conflict = spending(([1; 32], 0), 8_000, vec![]);
+        assert_eq!(check(&mempool, &conflict, 2), Err(MempoolError::Conflict));
+
+        assert_eq!(
+            check(&mempool, &spending(([3; 32], 0), 1, vec![]), 2),
+            Err(MempoolError::UnknownOutput)
+        );
+        assert_eq!(
+            check(&mempool, &spending((tx.hash(), 0), 9_001, vec![]), 2),
+            Err(MempoolError::BadInputValue)
+        );
+        // OP_0 OP_VERIFY
+        assert_eq!(
+            check(&mempool, &spending((tx.hash(), 0), 1, vec![0x00, 0x69]), 2),
+            Err(MempoolError::BadScript(ScriptError::Verify))
+        );
+        // OP_PUSHDATA1 of a single byte
+        assert_eq!(
+            check(
+                &mempool,
+                &spending((tx.hash(), 0), 1, vec![0x4c, 0x01, 0x01]),
+                2
+            ),
+            Err(MempoolError::BadScript(ScriptError::MinimalData))
+        );
+
+        // The same output spent twice by a transaction counts once
+        let mut duplicate = spending(([1; 32], 0), 19_000, vec![]);
+        duplicate.add_input([1; 32], 0, vec![]);
+        assert_eq!(
+            check(&Mempool::new(), &duplicate, 2),
+            Err(MempoolError::DuplicateInput)
+        );
+
+        let coinbase_spend = spending(([2; 32], 0), 9_000, vec![]);
+        assert_eq!(
+            check(&mempool, &coinbase_spend, COINBASE_MATURITY),
+            Err(MempoolError::ImmatureCoinbase)
+        );
+        assert_eq!(
+            check(&mempool, &coinbase_spend, 0),
+            Err(MempoolError::ImmatureCoinbase)
+        );
+        assert_eq!(
+            check(&mempool, &coinbase_spend, 1 + COINBASE_MATURITY),
+            Ok(1_000)
+        );
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut mempool = Mempool::new();
+        let parent = spending(([1; 32], 0), 9_000, vec![]);
+        let child = spending((parent.hash(), 0), 8_000, vec![]);
+        let grandchild = spending((child.hash(), 0), 7_000, vec![]);
+        let other = spending(([2; 32], 0), 9_000, vec![]);
+        mempool.insert(parent.clone(), 5_000);
+        mempool.insert(child.clone(), 2_000);
+        mempool.insert(grandchild.clone(), 3_000);
+        mempool.insert(other.clone(), 4_000);
+
+        assert_eq!(mempool.lowest_fee_rate(), Some((child.hash(), 2_000)));
+        assert_eq!(
+            mempool.descendants(parent.hash()),
+            vec![parent.hash(), child.hash(), grandchild.hash()]
+        );
+        assert_eq!(mempool.descendants(other.hash()), vec![other.hash()]);
+
+        // The outpoint of a removed transaction may be spent again
+        assert_eq!(mempool.remove(&other.hash()), Some(other.clone()));
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.lowest_fee_rate(), Some((child.hash(), 2_000)));
+        let lookup = |_| Some((TxOutput::new(10_000, vec![0x51]), None));
+        assert_eq!(mempool.check_inputs(&other, &lookup, 2, 0), Ok(1_000));
+
+        // The chain confirms the parent, and spends the output of the child
+        // with another transaction, which conflicts with the grandchild
+        let mut chain_spends = HashMap::new();
+        chain_spends.insert(([1; 32], 0), parent.hash());
+        chain_spends.insert((child.hash(), 0), [3; 32]);
+        let spending_tx = |outpoint| chain_spends.get(&outpoint).cloned();
+        let mut removed = mempool.remove_confirmed(&spending_tx);
+        removed.sort();
+        let mut expected = vec![parent.hash(), grandchild.hash()];
+        expected.sort();
+        assert_eq!(removed, expected);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get(&child.hash()).is_some());
+    }
+}
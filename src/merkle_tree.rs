@@ -14,17 +14,32 @@ pub struct MerkleTreeLayer {
 impl MerkleTree {
     /// Creates a MerkleTree from a vector of Hashable elements
     pub fn new<T: crypto::Hashable>(elts: &Vec<Box<T>>) -> Self {
-        let mut elements = Vec::with_capacity(elts.len());
-        for elt in elts.iter() {
-            elements.push((*elt).hash());
-        }
+        MerkleTree::from_hashable(elts.iter().map(|elt| elt.as_ref()))
+    }
+
+    /// Creates a MerkleTree from borrowed Hashable elements
+    pub fn from_hashable<'a, T, I>(elts: I) -> Self
+    where
+        T: crypto::Hashable + 'a,
+        I: IntoIterator<Item = &'a T>,
+    {
+        let elements = elts.into_iter().map(|elt| elt.hash()).collect();
+        MerkleTree { elements }
+    }
+
+    /// Creates a MerkleTree from the hashes of its leaves
+    pub fn from_hashes(elements: Vec<crypto::Hash32>) -> Self {
         MerkleTree { elements }
     }
 
+    /// Returns the parent of two nodes. Like the txids, nodes are in the
+    /// reverse order of their serialization.
     fn concat(a: &MerkleTreeNode, b: &MerkleTreeNode) -> MerkleTreeNode {
-        let mut con = a.to_vec();
-        con.extend_from_slice(b);
-        crypto::hash32(con.as_slice())
+        let mut con = crypto::hash32_to_bytes(a).to_vec();
+        con.extend_from_slice(&crypto::hash32_to_bytes(b));
+        let mut hash = crypto::hash32(con.as_slice());
+        hash.reverse();
+        hash
     }
 
     fn layer_up(elements: Vec<MerkleTreeNode>) -> Vec<MerkleTreeNode> {
@@ -33,7 +48,7 @@ impl MerkleTree {
         let odd = (elements_len % 2) == 1;
         let mut new_elements = Vec::with_capacity(end);
         for i in 0..end {
-            new_elements.push(MerkleTree::concat(&elements[i], &elements[i + 1]));
+            new_elements.push(MerkleTree::concat(&elements[2 * i], &elements[2 * i + 1]));
         }
         if odd {
             new_elements.push(MerkleTree::concat(
@@ -63,10 +78,71 @@ impl MerkleTree {
     }
 
     /// Returns the height of the MerkleTree (layers numbers)
+    #[cfg(test)]
     pub fn height(&self) -> usize {
         (self.elements.len() as f32).log2().ceil() as usize + 1
     }
 
+    /// Returns the number of nodes of the layer at the given height, the
+    /// leaves being at height 0
+    fn layer_width(&self, height: usize) -> usize {
+        (self.elements.len() + (1 << height) - 1) >> height
+    }
+
+    /// Returns the hash of the node at the given height and position in its
+    /// layer
+    fn node_hash(&self, height: usize, pos: usize) -> MerkleTreeNode {
+        if height == 0 {
+            return self.elements[pos];
+        }
+        let left = self.node_hash(height - 1, pos * 2);
+        let right = if pos * 2 + 1 < self.layer_width(height - 1) {
+            self.node_hash(height - 1, pos * 2 + 1)
+        } else {
+            left
+        };
+        MerkleTree::concat(&left, &right)
+    }
+
+    /// Returns the hashes and the flag bits of the partial merkle tree
+    /// proving which leaves match (BIP37). Nodes are visited depth first,
+    /// and the hash of a node is given instead of its children if none of
+    /// its leaves match.
+    pub fn partial(&self, matches: &[bool]) -> (Vec<crypto::Hash32>, Vec<bool>) {
+        let mut hashes = Vec::new();
+        let mut flags = Vec::new();
+        if self.elements.is_empty() {
+            return (hashes, flags);
+        }
+        let mut height = 0;
+        while self.layer_width(height) > 1 {
+            height += 1;
+        }
+        self.partial_rec(height, 0, matches, &mut hashes, &mut flags);
+        (hashes, flags)
+    }
+
+    fn partial_rec(
+        &self,
+        height: usize,
+        pos: usize,
+        matches: &[bool],
+        hashes: &mut Vec<crypto::Hash32>,
+        flags: &mut Vec<bool>,
+    ) {
+        let end = std::cmp::min((pos + 1) << height, self.elements.len());
+        let parent_of_match = ((pos << height)..end).any(|leaf| matches.get(leaf) == Some(&true));
+        flags.push(parent_of_match);
+        if height == 0 || !parent_of_match {
+            hashes.push(self.node_hash(height, pos));
+            return;
+        }
+        self.partial_rec(height - 1, pos * 2, matches, hashes, flags);
+        if pos * 2 + 1 < self.layer_width(height - 1) {
+            self.partial_rec(height - 1, pos * 2 + 1, matches, hashes, flags);
+        }
+    }
+
     fn dump_rec(
         elements: Vec<MerkleTreeNode>,
         acc: &mut Vec<MerkleTreeLayer>,
@@ -92,10 +168,51 @@ impl MerkleTree {
     /// All vectors are ordered.
     pub fn layers(&self) -> Vec<MerkleTreeLayer> {
         let mut vect = Vec::new();
+        if self.elements.is_empty() {
+            return vect;
+        }
         let elements = self.elements.clone();
         MerkleTree::dump_rec(elements, &mut vect);
         vect
     }
+
+    /// Returns the position of the nodes from the leaf at the given index to
+    /// the root, one per layer. Empty if there is no such leaf.
+    #[cfg(test)]
+    pub fn path_to_root(&self, index: usize) -> Vec<usize> {
+        if index >= self.elements.len() {
+            return Vec::new();
+        }
+        let mut path = vec![index];
+        let mut height = 0;
+        while self.layer_width(height) > 1 {
+            height += 1;
+            path.push(index >> height);
+        }
+        path
+    }
+
+    /// Returns the hashes needed with the leaf at the given index to compute
+    /// the root, from the sibling of the leaf to the children of the root
+    #[cfg(test)]
+    pub fn proof(&self, index: usize) -> Option<Vec<crypto::Hash32>> {
+        let path = self.path_to_root(index);
+        if path.is_empty() {
+            return None;
+        }
+        let layers = self.layers();
+        let proof = path
+            .iter()
+            .zip(layers.iter())
+            .take(path.len() - 1)
+            .map(|(pos, layer)| {
+                // The last node of an odd layer is paired with itself
+                let sibling = std::cmp::min(pos ^ 1, layer.elements.len() - 1);
+                layer.elements[sibling]
+            })
+            .collect();
+        Some(proof)
+    }
 }
 
 impl std::fmt::Display for MerkleTree {
@@ -159,7 +276,7 @@ mod tests {
         if let Some(hash) = mk.root() {
             assert_eq!(
                 hash,
-                hex::decode("494c9c623bffa28edd2211dc1a9d364fd298f2906c85c5f8947e4a6396cf6472")
+                hex::decode("5242fa5db949be0e588a96976bf82e8486024352a99326e876a1aafd8f4f84ef")
                     .unwrap()
                     .as_slice()
             );
@@ -174,7 +291,7 @@ mod tests {
         if let Some(hash) = mk.root() {
             assert_eq!(
                 hash,
-                hex::decode("1225b763f8a06c508bd7c0551c09d090d1e50944ee4bab5b78b7ccd0fa9e4c9f")
+                hex::decode("0dad060d6868d397f9abae7652b7aa8450b77aa3ddbfe6f5de62a100c33df99c")
                     .unwrap()
                     .as_slice()
             );
@@ -207,13 +324,13 @@ mod tests {
         assert_eq!(layers[1].elements.len(), 2);
         assert_eq!(
             layers[1].elements[0],
-            hex::decode("494c9c623bffa28edd2211dc1a9d364fd298f2906c85c5f8947e4a6396cf6472")
+            hex::decode("5242fa5db949be0e588a96976bf82e8486024352a99326e876a1aafd8f4f84ef")
                 .unwrap()
                 .as_slice()
         );
         assert_eq!(
             layers[1].elements[1],
-            hex::decode("b1609f84ed2489e94bc3eddc66875f8a38b7aab83c5f9a09875fe41f29132350")
+            hex::decode("6bb9fb559e0fd78dd944ea6af359d65bd1c025a8e555403749a7fe6cd9f0d8a1")
                 .unwrap()
                 .as_slice()
         );
@@ -221,12 +338,33 @@ mod tests {
         assert_eq!(layers[2].elements.len(), 1);
         assert_eq!(
             layers[2].elements[0],
-            hex::decode("1225b763f8a06c508bd7c0551c09d090d1e50944ee4bab5b78b7ccd0fa9e4c9f")
+            hex::decode("0dad060d6868d397f9abae7652b7aa8450b77aa3ddbfe6f5de62a100c33df99c")
                 .unwrap()
                 .as_slice()
         );
     }
 
+    #[test]
+    fn test_from_hashable() {
+        let transactions: Vec<crate::transaction::Transaction> = (0..5u8)
+            .map(|i| {
+                let mut tx = crate::transaction::Transaction::new();
+                tx.add_input([i; 32], 0, vec![]);
+                tx
+            })
+            .collect();
+        let boxed: Vec<Box<crate::transaction::Transaction>> =
+            transactions.iter().cloned().map(Box::new).collect();
+
+        let mk = MerkleTree::from_hashable(&transactions);
+        assert_eq!(mk.root(), MerkleTree::new(&boxed).root());
+        assert!(mk.root().is_some());
+        assert_eq!(
+            MerkleTree::from_hashable(&[1u32, 2, 3]).root(),
+            MerkleTree::new(&vec![Box::new(1u32), Box::new(2), Box::new(3)]).root()
+        );
+    }
+
     #[test]
     fn test_height() {
         let mk1 = MerkleTree::new(&vec![
@@ -263,4 +401,108 @@ mod tests {
         ]);
         assert_eq!(mk3.height(), 5);
     }
+
+    #[test]
+    fn test_partial() {
+        let leaves: Vec<Box<u32>> = vec![Box::new(1), Box::new(2), Box::new(3)];
+        let mk = MerkleTree::new(&leaves);
+        let hash = |i: usize| leaves[i].hash();
+        assert_eq!(mk.node_hash(2, 0), mk.root().unwrap());
+
+        // Only the path to the second leaf is expanded
+        let (hashes, flags) = mk.partial(&[false, true, false]);
+        assert_eq!(flags, vec![true, true, false, true, false]);
+        assert_eq!(
+            hashes,
+            vec![hash(0), hash(1), MerkleTree::concat(&hash(2), &hash(2))]
+        );
+
+        // Without any match, the root is enough
+        let (hashes, flags) = mk.partial(&[false, false, false]);
+        assert_eq!(flags, vec![false]);
+        assert_eq!(hashes, vec![mk.root().unwrap()]);
+
+        let (hashes, flags) = MerkleTree::new(&vec![Box::new(1)]).partial(&[true]);
+        assert_eq!(flags, vec![true]);
+        assert_eq!(hashes, vec![hash(0)]);
+    }
+
+    #[test]
+    fn test_layers_and_path_to_root() {
+        let leaves: Vec<Box<u32>> = (1..=7).map(Box::new).collect();
+        let mk = MerkleTree::new(&leaves);
+        let hash = |i: usize| leaves[i].hash();
+
+        let layers = mk.layers();
+        let widths: Vec<usize> = layers.iter().map(|layer| layer.elements.len()).collect();
+        assert_eq!(widths, vec![7, 4, 2, 1]);
+        // Nodes are made of consecutive pairs, the last node of an odd
+        // layer being paired with itself
+        assert_eq!(
+            layers[1].elements[1],
+            MerkleTree::concat(&hash(2), &hash(3))
+        );
+        assert_eq!(
+            layers[1].elements[3],
+            MerkleTree::concat(&hash(6), &hash(6))
+        );
+        assert_eq!(layers[3].elements[0], mk.root().unwrap());
+        assert_eq!(mk.node_hash(3, 0), mk.root().unwrap());
+
+        assert_eq!(mk.path_to_root(5), vec![5, 2, 1, 0]);
+        assert_eq!(mk.path_to_root(6), vec![6, 3, 1, 0]);
+        assert_eq!(mk.path_to_root(7), Vec::<usize>::new());
+
+        assert_eq!(
+            mk.proof(5),
+            Some(vec![hash(4), layers[1].elements[3], layers[2].elements[0]])
+        );
+        assert_eq!(
+            mk.proof(6).unwrap()[0],
+            hash(6),
+            "the last leaf is its own sibling"
+        );
+        assert_eq!(mk.proof(7), None);
+        assert_eq!(
+            MerkleTree::new(&vec![Box::new(1u32)]).proof(0),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn test_block_100000() {
+        // Mainnet block 100000 and the txids of its 4 transactions
+        let header = crate::block::BlockHeader::from_bytes(
+            &hex::decode(
+                "0100000050120119172a610421a6c3011dd330d9df07b63616c2cc1f1cd002000000\
+                 00006657a9252aacd5c0b2940996ecff952228c3067cc38d4885efb5a4ac4247e9f3\
+                 37221b4d4c86041b0f2b5710",
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            hex::encode(header.hash()),
+            "000000000003ba27aa200b1cecaad478d2b00432346c3f1f3986da1afd33e506"
+        );
+        let txids = [
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ];
+        let mk = MerkleTree::from_hashes(
+            txids
+                .iter()
+                .map(|txid| crate::utils::clone_into_array(&hex::decode(txid).unwrap()))
+                .collect(),
+        );
+        assert_eq!(
+            hex::encode(mk.root().unwrap()),
+            "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766"
+        );
+        assert_eq!(
+            header.bytes()[36..68],
+            crypto::hash32_to_bytes(&mk.root().unwrap())
+        );
+    }
 }
@@ -55,6 +55,10 @@ impl message::MessageCommand for MessageAddr {
         MessageAddr { addr_list }
     }
 
+    fn summary(&self) -> String {
+        format!("addr ({} addresses)", self.addr_list.len())
+    }
+
     fn handle(&self, node: &mut node::Node, config: &config::Config) {
         node.send_response(node::NodeResponseContent::Addrs(self.addr_list.clone()))
             .unwrap();
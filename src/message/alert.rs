@@ -194,7 +194,15 @@ impl message::MessageCommand for MessageAlert {
         }
     }
 
-    fn handle(&self, node: &mut node::Node, config: &config::Config) {}
+    /// Alerts are never relayed. They are only displayed when enabled in
+    /// the configuration and signed with a trusted key.
+    fn handle(&self, node: &mut node::Node, config: &config::Config) {
+        if self.is_accepted(config) {
+            log::warn!("Alert {}: {}", self.id, self.status_bar);
+        } else {
+            log::debug!("Ignore alert {}", self.id);
+        }
+    }
 }
 
 impl MessageAlert {
@@ -232,6 +240,12 @@ impl MessageAlert {
         }
     }
 
+    /// Returns whether the alert should be processed. As the alert system
+    /// is deprecated, alerts are ignored unless `process_alerts` is set.
+    pub fn is_accepted(&self, config: &config::Config) -> bool {
+        config.process_alerts && self.trusted
+    }
+
     fn payload_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.version.to_le_bytes());
@@ -344,6 +358,19 @@ mod tests {
         assert_eq!(alert, expected);
     }
 
+    #[test]
+    fn test_alert_ignored_by_default() {
+        let bytes = hex::decode("73010000003766404f00000000b305434f00000000f2030000f1030000001027000048ee00000064000000004653656520626974636f696e2e6f72672f666562323020696620796f7520686176652074726f75626c6520636f6e6e656374696e67206166746572203230204665627275617279004730450221008389df45f0703f39ec8c1cc42c13810ffcae14995bb648340219e353b63b53eb022009ec65e1c1aaeec1fd334c6b684bde2b3f573060d5b70c3a46723326e4e8a4f1").unwrap();
+        let alert = MessageAlert::from_bytes(&bytes);
+        assert!(alert.trusted);
+
+        let mut config = config::main_config();
+        assert!(!alert.is_accepted(&config));
+
+        config.process_alerts = true;
+        assert!(alert.is_accepted(&config));
+    }
+
     #[test]
     fn test_message_alert_serialize_deserialize() {
         let alert = MessageAlert::new(
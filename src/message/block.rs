@@ -36,6 +36,14 @@ impl message::MessageCommand for MessageBlock {
         }
     }
 
+    fn summary(&self) -> String {
+        format!(
+            "block {} ({} txs)",
+            hex::encode(self.block.hash()),
+            self.block.transaction_count()
+        )
+    }
+
     fn handle(&self, node: &mut node::Node, config: &config::Config) {
         log::debug!("[{:?}] Received block {:?}", node.id(), self.block.hash());
         node.send_response(node::NodeResponseContent::Block(self.block.clone()))
@@ -0,0 +1,114 @@
+use crate::config;
+use crate::crypto;
+use crate::message;
+use crate::node;
+use crate::variable_integer::VariableInteger;
+
+const NAME: &str = "cfcheckpt";
+
+/// Filter headers of every 1000th block, sent in response to getcfcheckpt
+/// (BIP157)
+#[derive(Debug, PartialEq, Clone)]
+pub struct MessageCFCheckpt {
+    filter_type: u8,
+    stop_hash: crypto::Hash32,
+    filter_headers: Vec<crypto::Hash32>,
+}
+
+impl message::MessageCommand for MessageCFCheckpt {
+    fn name(&self) -> [u8; 12] {
+        let mut command = [0; 12];
+        for (i, c) in NAME.char_indices() {
+            command[i] = c as u8;
+        }
+        command
+    }
+
+    fn length(&self) -> u32 {
+        let headers_len_size = VariableInteger::new(self.filter_headers.len() as u64)
+            .bytes()
+            .len();
+        (1 + 32 + headers_len_size + 32 * self.filter_headers.len()) as u32
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length() as usize);
+        bytes.push(self.filter_type);
+        bytes.extend_from_slice(&crypto::hash32_to_bytes(&self.stop_hash));
+        let headers_len = VariableInteger::new(self.filter_headers.len() as u64);
+        bytes.extend_from_slice(headers_len.bytes().as_slice());
+        for header in &self.filter_headers {
+            bytes.extend_from_slice(&crypto::hash32_to_bytes(header));
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let filter_type = bytes[0];
+        let stop_hash = crypto::bytes_to_hash32(&bytes[1..33]).unwrap();
+        let (headers_len, headers_len_size) = VariableInteger::from_bytes(&bytes[33..]).unwrap();
+        let mut index = 33 + headers_len_size;
+        let mut filter_headers = Vec::with_capacity(headers_len as usize);
+        for _ in 0..headers_len {
+            filter_headers.push(crypto::bytes_to_hash32(&bytes[index..(index + 32)]).unwrap());
+            index += 32;
+        }
+        MessageCFCheckpt {
+            filter_type,
+            stop_hash,
+            filter_headers,
+        }
+    }
+
+    fn handle(&self, node: &mut node::Node, _config: &config::Config) {
+        log::debug!(
+            "[{}] Received {} filter checkpoints up to block {}",
+            node.id(),
+            self.filter_headers.len(),
+            hex::encode(self.stop_hash)
+        );
+    }
+}
+
+impl MessageCFCheckpt {
+    pub fn new(
+        filter_type: u8,
+        stop_hash: crypto::Hash32,
+        filter_headers: Vec<crypto::Hash32>,
+    ) -> Self {
+        MessageCFCheckpt {
+            filter_type,
+            stop_hash,
+            filter_headers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::message::MessageCommand;
+
+    #[test]
+    fn test_message_cfcheckpt() {
+        let cfcheckpt = MessageCFCheckpt::new(
+            0,
+            crypto::hash32("babar".as_bytes()),
+            vec![
+                crypto::hash32("toto".as_bytes()),
+                crypto::hash32("tata".as_bytes()),
+            ],
+        );
+        assert_eq!(
+            cfcheckpt.name(),
+            [
+                'c' as u8, 'f' as u8, 'c' as u8, 'h' as u8, 'e' as u8, 'c' as u8, 'k' as u8,
+                'p' as u8, 't' as u8, 0, 0, 0
+            ]
+        );
+        assert_eq!(cfcheckpt.length() as usize, 1 + 32 + 1 + 32 * 2);
+        assert_eq!(cfcheckpt.length() as usize, cfcheckpt.bytes().len());
+        assert_eq!(cfcheckpt, MessageCFCheckpt::from_bytes(&cfcheckpt.bytes()));
+    }
+}
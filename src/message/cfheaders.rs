@@ -0,0 +1,146 @@
+use crate::cfilter;
+use crate::config;
+use crate::crypto;
+use crate::message;
+use crate::node;
+use crate::variable_integer::VariableInteger;
+
+const NAME: &str = "cfheaders";
+
+/// Hashes of the compact filters of a range of blocks, sent in response to
+/// getcfheaders (BIP157)
+#[derive(Debug, PartialEq, Clone)]
+pub struct MessageCFHeaders {
+    filter_type: u8,
+    stop_hash: crypto::Hash32,
+    prev_filter_header: crypto::Hash32, // header of the filter before the range
+    filter_hashes: Vec<crypto::Hash32>,
+}
+
+impl message::MessageCommand for MessageCFHeaders {
+    fn name(&self) -> [u8; 12] {
+        let mut command = [0; 12];
+        for (i, c) in NAME.char_indices() {
+            command[i] = c as u8;
+        }
+        command
+    }
+
+    fn length(&self) -> u32 {
+        let hashes_len_size = VariableInteger::new(self.filter_hashes.len() as u64)
+            .bytes()
+            .len();
+        (1 + 32 + 32 + hashes_len_size + 32 * self.filter_hashes.len()) as u32
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length() as usize);
+        bytes.push(self.filter_type);
+        bytes.extend_from_slice(&crypto::hash32_to_bytes(&self.stop_hash));
+        bytes.extend_from_slice(&crypto::hash32_to_bytes(&self.prev_filter_header));
+        let hashes_len = VariableInteger::new(self.filter_hashes.len() as u64);
+        bytes.extend_from_slice(hashes_len.bytes().as_slice());
+        for hash in &self.filter_hashes {
+            bytes.extend_from_slice(&crypto::hash32_to_bytes(hash));
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let filter_type = bytes[0];
+        let stop_hash = crypto::bytes_to_hash32(&bytes[1..33]).unwrap();
+        let prev_filter_header = crypto::bytes_to_hash32(&bytes[33..65]).unwrap();
+        let (hashes_len, hashes_len_size) = VariableInteger::from_bytes(&bytes[65..]).unwrap();
+        let mut index = 65 + hashes_len_size;
+        let mut filter_hashes = Vec::with_capacity(hashes_len as usize);
+        for _ in 0..hashes_len {
+            filter_hashes.push(crypto::bytes_to_hash32(&bytes[index..(index + 32)]).unwrap());
+            index += 32;
+        }
+        MessageCFHeaders {
+            filter_type,
+            stop_hash,
+            prev_filter_header,
+            filter_hashes,
+        }
+    }
+
+    fn handle(&self, node: &mut node::Node, _config: &config::Config) {
+        log::debug!(
+            "[{}] Received {} filter headers up to block {}",
+            node.id(),
+            self.filter_hashes.len(),
+            hex::encode(self.stop_hash)
+        );
+    }
+}
+
+impl MessageCFHeaders {
+    pub fn new(
+        filter_type: u8,
+        stop_hash: crypto::Hash32,
+        prev_filter_header: crypto::Hash32,
+        filter_hashes: Vec<crypto::Hash32>,
+    ) -> Self {
+        MessageCFHeaders {
+            filter_type,
+            stop_hash,
+            prev_filter_header,
+            filter_hashes,
+        }
+    }
+
+    /// Returns the headers of the filters of the range, which chain from
+    /// the previous filter header
+    pub fn filter_headers(&self) -> Vec<crypto::Hash32> {
+        let mut prev = self.prev_filter_header;
+        self.filter_hashes
+            .iter()
+            .map(|hash| {
+                prev = cfilter::next_filter_header(hash, &prev);
+                prev
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::crypto::Hashable;
+    use crate::message::MessageCommand;
+
+    #[test]
+    fn test_message_cfheaders() {
+        let genesis_filter =
+            cfilter::Filter::from_bytes(&hex::decode("019dfca8").unwrap()).unwrap();
+        let cfheaders = MessageCFHeaders::new(
+            0,
+            crypto::hash32("babar".as_bytes()),
+            [0; 32],
+            vec![genesis_filter.hash(), crypto::hash32("toto".as_bytes())],
+        );
+        assert_eq!(
+            cfheaders.name(),
+            [
+                'c' as u8, 'f' as u8, 'h' as u8, 'e' as u8, 'a' as u8, 'd' as u8, 'e' as u8,
+                'r' as u8, 's' as u8, 0, 0, 0
+            ]
+        );
+        assert_eq!(cfheaders.length() as usize, 1 + 32 + 32 + 1 + 32 * 2);
+        assert_eq!(cfheaders.length() as usize, cfheaders.bytes().len());
+        assert_eq!(cfheaders, MessageCFHeaders::from_bytes(&cfheaders.bytes()));
+
+        // BIP158 test vector of the testnet genesis block
+        let headers = cfheaders.filter_headers();
+        assert_eq!(
+            hex::encode(headers[0]),
+            "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"
+        );
+        assert_eq!(
+            headers[1],
+            cfilter::next_filter_header(&crypto::hash32("toto".as_bytes()), &headers[0])
+        );
+    }
+}
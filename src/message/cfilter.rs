@@ -0,0 +1,111 @@
+use crate::cfilter;
+use crate::config;
+use crate::crypto;
+use crate::message;
+use crate::node;
+use crate::variable_integer::VariableInteger;
+
+const NAME: &str = "cfilter";
+
+/// Compact filter of a block, sent in response to getcfilters (BIP157)
+#[derive(Debug, PartialEq, Clone)]
+pub struct MessageCFilter {
+    filter_type: u8,
+    block_hash: crypto::Hash32,
+    filter: Vec<u8>,
+}
+
+impl message::MessageCommand for MessageCFilter {
+    fn name(&self) -> [u8; 12] {
+        let mut command = [0; 12];
+        for (i, c) in NAME.char_indices() {
+            command[i] = c as u8;
+        }
+        command
+    }
+
+    fn length(&self) -> u32 {
+        let filter_len_size = VariableInteger::new(self.filter.len() as u64).bytes().len();
+        (1 + 32 + filter_len_size + self.filter.len()) as u32
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length() as usize);
+        bytes.push(self.filter_type);
+        bytes.extend_from_slice(&crypto::hash32_to_bytes(&self.block_hash));
+        let filter_len = VariableInteger::new(self.filter.len() as u64);
+        bytes.extend_from_slice(filter_len.bytes().as_slice());
+        bytes.extend_from_slice(&self.filter);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let filter_type = bytes[0];
+        let block_hash = crypto::bytes_to_hash32(&bytes[1..33]).unwrap();
+        let (filter_len, filter_len_size) = VariableInteger::from_bytes(&bytes[33..]).unwrap();
+        let index = 33 + filter_len_size;
+        MessageCFilter {
+            filter_type,
+            block_hash,
+            filter: bytes[index..(index + filter_len as usize)].to_vec(),
+        }
+    }
+
+    fn handle(&self, node: &mut node::Node, _config: &config::Config) {
+        if self.filter_type != cfilter::BASIC_FILTER {
+            log::debug!(
+                "[{}] Ignore filter of unknown type {}",
+                node.id(),
+                self.filter_type
+            );
+            return;
+        }
+        match cfilter::Filter::from_bytes(&self.filter) {
+            Ok(filter) => node
+                .send_response(node::NodeResponseContent::Filter(self.block_hash, filter))
+                .unwrap(),
+            Err(err) => log::warn!(
+                "[{}] Invalid filter of block {}: {:?}",
+                node.id(),
+                hex::encode(self.block_hash),
+                err
+            ),
+        }
+    }
+}
+
+impl MessageCFilter {
+    pub fn new(filter_type: u8, block_hash: crypto::Hash32, filter: Vec<u8>) -> Self {
+        MessageCFilter {
+            filter_type,
+            block_hash,
+            filter,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::message::MessageCommand;
+
+    #[test]
+    fn test_message_cfilter() {
+        let cfilter = MessageCFilter::new(
+            0,
+            crypto::hash32("babar".as_bytes()),
+            hex::decode("019dfca8").unwrap(),
+        );
+        assert_eq!(
+            cfilter.name(),
+            [
+                'c' as u8, 'f' as u8, 'i' as u8, 'l' as u8, 't' as u8, 'e' as u8, 'r' as u8, 0, 0,
+                0, 0, 0
+            ]
+        );
+        assert_eq!(cfilter.length() as usize, 1 + 32 + 1 + 4);
+        assert_eq!(cfilter.length() as usize, cfilter.bytes().len());
+        assert_eq!(cfilter, MessageCFilter::from_bytes(&cfilter.bytes()));
+    }
+}
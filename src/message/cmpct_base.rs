@@ -0,0 +1,201 @@
+use crate::block;
+use crate::transaction::Transaction;
+use crate::utils;
+use crate::variable_integer::VariableInteger;
+
+// Short transaction ids are the 6 low bytes of a SipHash (BIP152)
+pub const SHORT_ID_SIZE: usize = 6;
+pub const SHORT_ID_MASK: u64 = (1 << (8 * SHORT_ID_SIZE)) - 1;
+
+/// Transaction sent in full along with the short ids of a compact block,
+/// with its position in the block
+#[derive(Debug, PartialEq, Clone)]
+pub struct PrefilledTransaction {
+    pub index: usize,
+    pub tx: Transaction,
+}
+
+/// Header of a block with the short ids of its transactions, the ones the
+/// receiver is not expected to have being prefilled (BIP152). Backs the
+/// cmpctblock message.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HeaderAndShortIds {
+    pub header: block::BlockHeader,
+    pub nonce: u64,
+    pub short_ids: Vec<u64>, // only the SHORT_ID_SIZE low bytes are sent
+    pub prefilled_txs: Vec<PrefilledTransaction>, // sorted by index
+}
+
+impl HeaderAndShortIds {
+    pub fn length(&self) -> usize {
+        let prefilled_size: usize = self
+            .prefilled_index_deltas()
+            .zip(self.prefilled_txs.iter())
+            .map(|(delta, prefilled)| {
+                VariableInteger::new(delta as u64).length() + prefilled.tx.witness_size()
+            })
+            .sum();
+        block::BlockHeader::length()
+            + 8
+            + VariableInteger::new(self.short_ids.len() as u64).length()
+            + SHORT_ID_SIZE * self.short_ids.len()
+            + VariableInteger::new(self.prefilled_txs.len() as u64).length()
+            + prefilled_size
+    }
+
+    /// Returns the indexes of the prefilled transactions as they are sent,
+    /// i.e. each one relative to the previous one plus one
+    fn prefilled_index_deltas(&self) -> impl Iterator<Item = usize> + '_ {
+        let mut next = 0;
+        self.prefilled_txs.iter().map(move |prefilled| {
+            let delta = prefilled.index - next;
+            next = prefilled.index + 1;
+            delta
+        })
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length());
+        bytes.extend_from_slice(&self.header.bytes());
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+
+        bytes.extend_from_slice(&VariableInteger::new(self.short_ids.len() as u64).bytes());
+        for short_id in &self.short_ids {
+            bytes.extend_from_slice(&short_id.to_le_bytes()[..SHORT_ID_SIZE]);
+        }
+
+        bytes.extend_from_slice(&VariableInteger::new(self.prefilled_txs.len() as u64).bytes());
+        for (delta, prefilled) in self.prefilled_index_deltas().zip(self.prefilled_txs.iter()) {
+            bytes.extend_from_slice(&VariableInteger::new(delta as u64).bytes());
+            bytes.extend_from_slice(&prefilled.tx.witness_bytes());
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut index = block::BlockHeader::length();
+        let header = block::BlockHeader::from_bytes(&bytes[..index]);
+        let nonce = u64::from_le_bytes(utils::clone_into_array(&bytes[index..(index + 8)]));
+        index += 8;
+
+        let (short_ids_len, short_ids_len_size) =
+            VariableInteger::from_bytes(&bytes[index..]).unwrap();
+        index += short_ids_len_size;
+        let mut short_ids = Vec::with_capacity(short_ids_len as usize);
+        for _ in 0..short_ids_len {
+            let mut short_id = [0; 8];
+            short_id[..SHORT_ID_SIZE].copy_from_slice(&bytes[index..(index + SHORT_ID_SIZE)]);
+            short_ids.push(u64::from_le_bytes(short_id));
+            index += SHORT_ID_SIZE;
+        }
+
+        let (prefilled_len, prefilled_len_size) =
+            VariableInteger::from_bytes(&bytes[index..]).unwrap();
+        index += prefilled_len_size;
+        let mut prefilled_txs = Vec::with_capacity(prefilled_len as usize);
+        let mut next = 0;
+        for _ in 0..prefilled_len {
+            let (delta, delta_size) = VariableInteger::from_bytes(&bytes[index..]).unwrap();
+            index += delta_size;
+            let (tx, tx_size) = Transaction::from_bytes(&bytes[index..]);
+            index += tx_size;
+            prefilled_txs.push(PrefilledTransaction {
+                index: next + delta as usize,
+                tx,
+            });
+            next += delta as usize + 1;
+        }
+
+        HeaderAndShortIds {
+            header,
+            nonce,
+            short_ids,
+            prefilled_txs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config;
+
+    fn header_and_short_ids(
+        short_ids: Vec<u64>,
+        prefilled_txs: Vec<PrefilledTransaction>,
+    ) -> HeaderAndShortIds {
+        HeaderAndShortIds {
+            header: config::main_config().genesis_block.header,
+            nonce: 0x0102030405060708,
+            short_ids,
+            prefilled_txs,
+        }
+    }
+
+    #[test]
+    fn test_short_ids() {
+        let cmpct = header_and_short_ids(vec![0x0000_a1b2_c3d4_e5f6, 1, SHORT_ID_MASK], vec![]);
+        let bytes = cmpct.bytes();
+        assert_eq!(bytes.len(), cmpct.length());
+        assert_eq!(bytes.len(), 80 + 8 + 1 + 3 * 6 + 1);
+        assert_eq!(&bytes[80..88], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(bytes[88], 3);
+        // Little endian, truncated to 6 bytes
+        assert_eq!(&bytes[89..95], &[0xf6, 0xe5, 0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&bytes[95..101], &[1, 0, 0, 0, 0, 0]);
+        assert_eq!(&bytes[101..107], &[0xff; 6]);
+        assert_eq!(bytes[107], 0);
+        assert_eq!(HeaderAndShortIds::from_bytes(&bytes), cmpct);
+
+        // The high bytes are not sent
+        let truncated = header_and_short_ids(vec![0xffff_0000_0000_0001], vec![]);
+        assert_eq!(
+            HeaderAndShortIds::from_bytes(&truncated.bytes()).short_ids,
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_prefilled_index_deltas() {
+        let coinbase = config::main_config().genesis_block.transactions()[0]
+            .as_ref()
+            .clone();
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, vec![0x51]);
+        tx.add_output(1000, vec![0x51]);
+        tx.set_witness(0, vec![vec![0xaa; 3]]);
+        let prefilled = |index: usize, tx: &Transaction| PrefilledTransaction {
+            index,
+            tx: tx.clone(),
+        };
+
+        let cmpct = header_and_short_ids(
+            vec![1, 2, 3],
+            vec![
+                prefilled(0, &coinbase),
+                prefilled(1, &tx),
+                prefilled(4, &tx),
+            ],
+        );
+        assert_eq!(
+            cmpct.prefilled_index_deltas().collect::<Vec<usize>>(),
+            vec![0, 0, 2]
+        );
+        let bytes = cmpct.bytes();
+        assert_eq!(bytes.len(), cmpct.length());
+        let prefilled_start = 80 + 8 + 1 + 3 * 6 + 1;
+        assert_eq!(bytes[prefilled_start - 1], 3);
+        assert_eq!(bytes[prefilled_start], 0);
+        let second = prefilled_start + 1 + coinbase.size();
+        assert_eq!(bytes[second], 0);
+        assert_eq!(bytes[second + 1 + tx.witness_size()], 2);
+        // The witness data is sent along with the transactions
+        assert_eq!(HeaderAndShortIds::from_bytes(&bytes), cmpct);
+
+        let empty = header_and_short_ids(vec![], vec![]);
+        assert_eq!(empty.length(), 80 + 8 + 1 + 1);
+        assert_eq!(HeaderAndShortIds::from_bytes(&empty.bytes()), empty);
+    }
+}
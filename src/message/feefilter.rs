@@ -1,6 +1,5 @@
 use crate::config;
 use crate::message;
-use crate::message::MessageCommand;
 use crate::node;
 use crate::utils;
 
@@ -34,7 +33,10 @@ impl message::MessageCommand for MessageFeeFilter {
         MessageFeeFilter { feerate }
     }
 
-    fn handle(&self, node: &mut node::Node, config: &config::Config) {}
+    fn handle(&self, node: &mut node::Node, _config: &config::Config) {
+        node.send_response(node::NodeResponseContent::FeeFilter(self.feerate))
+            .unwrap();
+    }
 }
 
 impl MessageFeeFilter {
@@ -47,6 +49,7 @@ impl MessageFeeFilter {
 mod tests {
 
     use super::*;
+    use crate::message::MessageCommand;
 
     #[test]
     fn test_message_feefilter() {
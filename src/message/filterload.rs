@@ -0,0 +1,121 @@
+use crate::bloom;
+use crate::config;
+use crate::message;
+use crate::node;
+use crate::utils;
+use crate::variable_integer::VariableInteger;
+
+const NAME: &str = "filterload";
+
+/// Bloom filter of the transactions a peer wants to hear about (BIP37)
+#[derive(Debug, PartialEq, Clone)]
+pub struct MessageFilterLoad {
+    data: Vec<u8>,
+    hash_funcs: u32,
+    tweak: u32,
+    flags: u8, // how the filter is updated with the matched outputs
+}
+
+impl message::MessageCommand for MessageFilterLoad {
+    fn name(&self) -> [u8; 12] {
+        let mut command = [0; 12];
+        for (i, c) in NAME.char_indices() {
+            command[i] = c as u8;
+        }
+        command
+    }
+
+    fn length(&self) -> u32 {
+        let data_len_size = VariableInteger::new(self.data.len() as u64).bytes().len();
+        (data_len_size + self.data.len() + 4 + 4 + 1) as u32
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length() as usize);
+        bytes.extend_from_slice(&VariableInteger::new(self.data.len() as u64).bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes.extend_from_slice(&self.hash_funcs.to_le_bytes());
+        bytes.extend_from_slice(&self.tweak.to_le_bytes());
+        bytes.push(self.flags);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let (data_len, data_len_size) = VariableInteger::from_bytes(bytes).unwrap();
+        let mut index = data_len_size;
+        let data = bytes[index..(index + data_len as usize)].to_vec();
+        index += data_len as usize;
+        let hash_funcs = u32::from_le_bytes(utils::clone_into_array(&bytes[index..(index + 4)]));
+        index += 4;
+        let tweak = u32::from_le_bytes(utils::clone_into_array(&bytes[index..(index + 4)]));
+        index += 4;
+        MessageFilterLoad {
+            data,
+            hash_funcs,
+            tweak,
+            flags: bytes[index],
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!("filterload ({} bytes)", self.data.len())
+    }
+
+    fn handle(&self, node: &mut node::Node, _config: &config::Config) {
+        match self.filter() {
+            Some(filter) => node
+                .send_response(node::NodeResponseContent::FilterLoad(filter))
+                .unwrap(),
+            None => {
+                log::warn!("[{}] Received a bloom filter above the limits", node.id());
+                node.send_response(node::NodeResponseContent::Misbehavior(
+                    node::Misbehavior::OversizedMessage,
+                ))
+                .unwrap()
+            }
+        }
+    }
+}
+
+impl MessageFilterLoad {
+    pub fn new(data: Vec<u8>, hash_funcs: u32, tweak: u32, flags: u8) -> Self {
+        MessageFilterLoad {
+            data,
+            hash_funcs,
+            tweak,
+            flags,
+        }
+    }
+
+    /// Returns the filter to match transactions against, or None if it
+    /// exceeds the limits of BIP37
+    pub fn filter(&self) -> Option<bloom::BloomFilter> {
+        bloom::BloomFilter::new(self.data.clone(), self.hash_funcs, self.tweak)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::message::MessageCommand;
+
+    #[test]
+    fn test_message_filterload() {
+        let filterload = MessageFilterLoad::new(hex::decode("614e9b").unwrap(), 5, 0, 1);
+        assert_eq!(&filterload.name()[..10], b"filterload");
+        assert_eq!(
+            filterload.bytes(),
+            hex::decode("03614e9b050000000000000001").unwrap()
+        );
+        assert_eq!(filterload.length() as usize, filterload.bytes().len());
+        assert_eq!(
+            filterload,
+            MessageFilterLoad::from_bytes(&filterload.bytes())
+        );
+
+        assert!(filterload.filter().is_some());
+        let oversized = MessageFilterLoad::new(vec![0; bloom::MAX_BLOOM_FILTER_SIZE + 1], 1, 0, 0);
+        assert!(oversized.filter().is_none());
+    }
+}
@@ -32,7 +32,10 @@ impl message::MessageCommand for MessageGetAddr {
         MessageGetAddr {}
     }
 
-    fn handle(&self, node: &mut node::Node, config: &config::Config) {}
+    fn handle(&self, node: &mut node::Node, config: &config::Config) {
+        node.send_response(node::NodeResponseContent::GetAddr)
+            .unwrap();
+    }
 }
 
 impl MessageGetAddr {
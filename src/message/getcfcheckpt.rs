@@ -0,0 +1,83 @@
+use crate::config;
+use crate::crypto;
+use crate::message;
+use crate::node;
+
+const NAME: &str = "getcfcheckpt";
+
+/// Requests the filter headers of every 1000th block up to a block (BIP157)
+#[derive(Debug, PartialEq, Clone)]
+pub struct MessageGetCFCheckpt {
+    filter_type: u8,
+    stop_hash: crypto::Hash32,
+}
+
+impl message::MessageCommand for MessageGetCFCheckpt {
+    fn name(&self) -> [u8; 12] {
+        let mut command = [0; 12];
+        for (i, c) in NAME.char_indices() {
+            command[i] = c as u8;
+        }
+        command
+    }
+
+    fn length(&self) -> u32 {
+        1 + 32
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length() as usize);
+        bytes.push(self.filter_type);
+        bytes.extend_from_slice(&crypto::hash32_to_bytes(&self.stop_hash));
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        MessageGetCFCheckpt {
+            filter_type: bytes[0],
+            stop_hash: crypto::bytes_to_hash32(&bytes[1..33]).unwrap(),
+        }
+    }
+
+    fn handle(&self, node: &mut node::Node, _config: &config::Config) {
+        node.send_response(node::NodeResponseContent::GetCFCheckpt(
+            self.filter_type,
+            self.stop_hash,
+        ))
+        .unwrap();
+    }
+}
+
+impl MessageGetCFCheckpt {
+    pub fn new(filter_type: u8, stop_hash: crypto::Hash32) -> Self {
+        MessageGetCFCheckpt {
+            filter_type,
+            stop_hash,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::message::MessageCommand;
+
+    #[test]
+    fn test_message_getcfcheckpt() {
+        let getcfcheckpt = MessageGetCFCheckpt::new(0, crypto::hash32("babar".as_bytes()));
+        assert_eq!(
+            getcfcheckpt.name(),
+            [
+                'g' as u8, 'e' as u8, 't' as u8, 'c' as u8, 'f' as u8, 'c' as u8, 'h' as u8,
+                'e' as u8, 'c' as u8, 'k' as u8, 'p' as u8, 't' as u8
+            ]
+        );
+        assert_eq!(getcfcheckpt.length() as usize, 33);
+        assert_eq!(getcfcheckpt.length() as usize, getcfcheckpt.bytes().len());
+        assert_eq!(
+            getcfcheckpt,
+            MessageGetCFCheckpt::from_bytes(&getcfcheckpt.bytes())
+        );
+    }
+}
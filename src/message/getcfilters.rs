@@ -0,0 +1,89 @@
+use crate::config;
+use crate::crypto;
+use crate::message;
+use crate::node;
+use crate::utils;
+
+const NAME: &str = "getcfilters";
+
+/// Requests the compact filters of a range of blocks (BIP157)
+#[derive(Debug, PartialEq, Clone)]
+pub struct MessageGetCFilters {
+    filter_type: u8,
+    start_height: u32,         // height of the first block of the range
+    stop_hash: crypto::Hash32, // hash of the last block of the range
+}
+
+impl message::MessageCommand for MessageGetCFilters {
+    fn name(&self) -> [u8; 12] {
+        let mut command = [0; 12];
+        for (i, c) in NAME.char_indices() {
+            command[i] = c as u8;
+        }
+        command
+    }
+
+    fn length(&self) -> u32 {
+        1 + 4 + 32
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length() as usize);
+        bytes.push(self.filter_type);
+        bytes.extend_from_slice(&self.start_height.to_le_bytes());
+        bytes.extend_from_slice(&crypto::hash32_to_bytes(&self.stop_hash));
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        MessageGetCFilters {
+            filter_type: bytes[0],
+            start_height: u32::from_le_bytes(utils::clone_into_array(&bytes[1..5])),
+            stop_hash: crypto::bytes_to_hash32(&bytes[5..37]).unwrap(),
+        }
+    }
+
+    fn handle(&self, node: &mut node::Node, _config: &config::Config) {
+        node.send_response(node::NodeResponseContent::GetCFilters(
+            self.filter_type,
+            self.start_height,
+            self.stop_hash,
+        ))
+        .unwrap();
+    }
+}
+
+impl MessageGetCFilters {
+    pub fn new(filter_type: u8, start_height: u32, stop_hash: crypto::Hash32) -> Self {
+        MessageGetCFilters {
+            filter_type,
+            start_height,
+            stop_hash,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::message::MessageCommand;
+
+    #[test]
+    fn test_message_getcfilters() {
+        let getcfilters = MessageGetCFilters::new(0, 1000, crypto::hash32("babar".as_bytes()));
+        assert_eq!(
+            getcfilters.name(),
+            [
+                'g' as u8, 'e' as u8, 't' as u8, 'c' as u8, 'f' as u8, 'i' as u8, 'l' as u8,
+                't' as u8, 'e' as u8, 'r' as u8, 's' as u8, 0
+            ]
+        );
+        assert_eq!(getcfilters.length() as usize, 37);
+        assert_eq!(getcfilters.length() as usize, getcfilters.bytes().len());
+        assert_eq!(
+            getcfilters,
+            MessageGetCFilters::from_bytes(&getcfilters.bytes())
+        );
+    }
+}
@@ -39,6 +39,10 @@ impl message::MessageCommand for MessageGetData {
         }
     }
 
+    fn summary(&self) -> String {
+        format!("getdata ({} items)", self.base.inventory.len())
+    }
+
     fn handle(&self, node: &mut node::Node, config: &config::Config) {
         for inv_vect in self.base.inventory.iter() {
             log::trace!(
@@ -47,6 +51,10 @@ impl message::MessageCommand for MessageGetData {
                 hex::encode(inv_vect.hash)
             );
         }
+        node.send_response(node::NodeResponseContent::GetData(
+            self.base.inventory.clone(),
+        ))
+        .unwrap();
     }
 }
 
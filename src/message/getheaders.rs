@@ -76,7 +76,13 @@ impl message::MessageCommand for MessageGetHeaders {
         }
     }
 
-    fn handle(&self, node: &mut node::Node, config: &config::Config) {}
+    fn handle(&self, node: &mut node::Node, config: &config::Config) {
+        node.send_response(node::NodeResponseContent::GetHeaders(
+            self.block_locator_hashes.clone(),
+            self.hash_stop,
+        ))
+        .unwrap();
+    }
 }
 
 impl MessageGetHeaders {
@@ -6,10 +6,13 @@ use crate::node;
 use crate::variable_integer::VariableInteger;
 
 const NAME: &str = "headers";
+// Maximum number of headers of a headers message
+pub const MAX_HEADERS: usize = 2000;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct MessageHeaders {
     headers: Vec<MessageBlockHeader>,
+    count: u64, // announced number of headers, more than `headers` if oversized or truncated
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -58,10 +61,20 @@ impl message::MessageCommand for MessageHeaders {
         let (headers_len, headers_len_size) = VariableInteger::from_bytes(&bytes).unwrap();
         index += headers_len_size;
 
-        let mut headers = Vec::with_capacity(headers_len as usize);
-
+        // The announced count is not trusted: oversized messages are not
+        // parsed, and truncated ones end with the last complete header
+        let mut headers = Vec::new();
+        if headers_len > MAX_HEADERS as u64 {
+            return Self {
+                headers,
+                count: headers_len,
+            };
+        }
         for _ in 0..headers_len {
             let next_size = block::BlockHeader::length();
+            if index + next_size >= bytes.len() {
+                break;
+            }
             let header = block::BlockHeader::from_bytes(&bytes[index..(index + next_size)]);
             index += next_size;
             let (txn_count, txn_count_size) = VariableInteger::from_bytes(&bytes[index..]).unwrap();
@@ -70,10 +83,29 @@ impl message::MessageCommand for MessageHeaders {
             headers.push(MessageBlockHeader { header, txn_count });
         }
 
-        Self { headers }
+        Self {
+            headers,
+            count: headers_len,
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!("headers ({})", self.headers.len())
     }
 
     fn handle(&self, node: &mut node::Node, config: &config::Config) {
+        if self.count > MAX_HEADERS as u64 || self.count != self.headers.len() as u64 {
+            log::warn!(
+                "[{}] Received a headers message announcing {} headers",
+                node.id(),
+                self.count
+            );
+            node.send_response(node::NodeResponseContent::Misbehavior(
+                node::Misbehavior::OversizedMessage,
+            ))
+            .unwrap_or_default();
+            return;
+        }
         node.send_response(node::NodeResponseContent::Headers(
             self.headers.iter().map(|x| x.header.clone()).collect(),
         ))
@@ -83,7 +115,22 @@ impl message::MessageCommand for MessageHeaders {
 
 impl MessageHeaders {
     pub fn new(headers: Vec<MessageBlockHeader>) -> Self {
-        Self { headers }
+        let count = headers.len() as u64;
+        Self { headers, count }
+    }
+
+    /// Creates the message sending the given headers, without their
+    /// transactions
+    pub fn from_headers(headers: Vec<block::BlockHeader>) -> Self {
+        Self::new(
+            headers
+                .into_iter()
+                .map(|header| MessageBlockHeader {
+                    header,
+                    txn_count: 0,
+                })
+                .collect(),
+        )
     }
 }
 
@@ -135,4 +182,24 @@ mod tests {
             MessageHeaders::from_bytes(&messageHeaders.bytes())
         );
     }
+
+    #[test]
+    fn test_message_headers_oversized() {
+        let header = config::regtest_config().genesis_block.header.bytes();
+        let mut bytes = VariableInteger::new(100_000).bytes();
+        bytes.extend_from_slice(&header);
+        bytes.push(0);
+        let message = MessageHeaders::from_bytes(&bytes);
+        assert!(message.headers.is_empty());
+        assert_eq!(message.count, 100_000);
+
+        // Truncated after the first header
+        let mut bytes = VariableInteger::new(2).bytes();
+        bytes.extend_from_slice(&header);
+        bytes.push(0);
+        bytes.extend_from_slice(&header[..40]);
+        let message = MessageHeaders::from_bytes(&bytes);
+        assert_eq!(message.headers.len(), 1);
+        assert_eq!(message.count, 2);
+    }
 }
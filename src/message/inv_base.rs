@@ -14,6 +14,11 @@ pub const MSG_TX: u32 = 1;
 pub const MSG_BLOCK: u32 = 2;
 pub const MSG_FILTERED_BLOCK: u32 = 3;
 pub const MSG_CMPCT_BLOCK: u32 = 4;
+// Set on a type to request the witness data along with it (BIP144)
+pub const MSG_WITNESS_FLAG: u32 = 1 << 30;
+pub const MSG_WITNESS_TX: u32 = MSG_TX | MSG_WITNESS_FLAG;
+pub const MSG_WITNESS_BLOCK: u32 = MSG_BLOCK | MSG_WITNESS_FLAG;
+pub const MSG_FILTERED_WITNESS_BLOCK: u32 = MSG_FILTERED_BLOCK | MSG_WITNESS_FLAG;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct InvVect {
@@ -22,7 +27,10 @@ pub struct InvVect {
 }
 
 pub fn hash_type_is_valid(hash_type: u32) -> bool {
-    hash_type <= 4
+    match hash_type {
+        MSG_WITNESS_TX | MSG_WITNESS_BLOCK | MSG_FILTERED_WITNESS_BLOCK => true,
+        _ => hash_type <= 4,
+    }
 }
 
 pub fn hash_type_to_str(hash_type: u32) -> &'static str {
@@ -32,6 +40,9 @@ pub fn hash_type_to_str(hash_type: u32) -> &'static str {
         MSG_BLOCK => "MSG_BLOCK",
         MSG_FILTERED_BLOCK => "MSG_FILTERED_BLOCK",
         MSG_CMPCT_BLOCK => "MSG_CMPCT_BLOCK",
+        MSG_WITNESS_TX => "MSG_WITNESS_TX",
+        MSG_WITNESS_BLOCK => "MSG_WITNESS_BLOCK",
+        MSG_FILTERED_WITNESS_BLOCK => "MSG_FILTERED_WITNESS_BLOCK",
         _ => "UNKNOWN",
     }
 }
@@ -115,4 +126,32 @@ mod tests {
         assert_eq!(inv_base.length() as usize, inv_base.bytes().len());
         assert_eq!(inv_base, MessageInvBase::from_bytes(&inv_base.bytes()));
     }
+
+    #[test]
+    fn test_witness_inv_vect() {
+        assert_eq!(MSG_WITNESS_TX, 0x40000001);
+        assert_eq!(MSG_WITNESS_BLOCK, 0x40000002);
+        assert!(hash_type_is_valid(MSG_WITNESS_TX));
+        assert!(hash_type_is_valid(MSG_WITNESS_BLOCK));
+        assert!(hash_type_is_valid(MSG_FILTERED_WITNESS_BLOCK));
+        assert!(!hash_type_is_valid(MSG_CMPCT_BLOCK | MSG_WITNESS_FLAG));
+        assert!(!hash_type_is_valid(5));
+        assert_eq!(hash_type_to_str(MSG_WITNESS_BLOCK), "MSG_WITNESS_BLOCK");
+
+        let inv_base = MessageInvBase {
+            inventory: vec![
+                InvVect {
+                    hash_type: MSG_WITNESS_TX,
+                    hash: crypto::hash32("babar".as_bytes()),
+                },
+                InvVect {
+                    hash_type: MSG_WITNESS_BLOCK,
+                    hash: crypto::hash32("toto".as_bytes()),
+                },
+            ],
+        };
+        let bytes = inv_base.bytes();
+        assert_eq!(&bytes[1..5], &[0x01, 0x00, 0x00, 0x40]);
+        assert_eq!(inv_base, MessageInvBase::from_bytes(&bytes));
+    }
 }
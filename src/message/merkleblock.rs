@@ -0,0 +1,156 @@
+use crate::block;
+use crate::config;
+use crate::crypto;
+use crate::crypto::Hashable;
+use crate::merkle_tree::MerkleTree;
+use crate::message;
+use crate::node;
+use crate::utils;
+use crate::variable_integer::VariableInteger;
+
+const NAME: &str = "merkleblock";
+
+/// Header of a block with the partial merkle tree of the transactions
+/// matching the bloom filter of a peer, sent in response to a getdata for a
+/// filtered block (BIP37)
+#[derive(Debug, PartialEq, Clone)]
+pub struct MessageMerkleBlock {
+    header: block::BlockHeader,
+    total_transactions: u32,
+    hashes: Vec<crypto::Hash32>,
+    flags: Vec<u8>, // bits of the depth first traversal of the tree
+}
+
+impl message::MessageCommand for MessageMerkleBlock {
+    fn name(&self) -> [u8; 12] {
+        let mut command = [0; 12];
+        for (i, c) in NAME.char_indices() {
+            command[i] = c as u8;
+        }
+        command
+    }
+
+    fn length(&self) -> u32 {
+        let hashes_len_size = VariableInteger::new(self.hashes.len() as u64).bytes().len();
+        let flags_len_size = VariableInteger::new(self.flags.len() as u64).bytes().len();
+        (block::BlockHeader::length()
+            + 4
+            + hashes_len_size
+            + 32 * self.hashes.len()
+            + flags_len_size
+            + self.flags.len()) as u32
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length() as usize);
+        bytes.extend_from_slice(&self.header.bytes());
+        bytes.extend_from_slice(&self.total_transactions.to_le_bytes());
+        bytes.extend_from_slice(&VariableInteger::new(self.hashes.len() as u64).bytes());
+        for hash in &self.hashes {
+            bytes.extend_from_slice(&crypto::hash32_to_bytes(hash));
+        }
+        bytes.extend_from_slice(&VariableInteger::new(self.flags.len() as u64).bytes());
+        bytes.extend_from_slice(&self.flags);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut index = block::BlockHeader::length();
+        let header = block::BlockHeader::from_bytes(&bytes[..index]);
+        let total_transactions =
+            u32::from_le_bytes(utils::clone_into_array(&bytes[index..(index + 4)]));
+        index += 4;
+        let (hashes_len, hashes_len_size) = VariableInteger::from_bytes(&bytes[index..]).unwrap();
+        index += hashes_len_size;
+        let mut hashes = Vec::with_capacity(hashes_len as usize);
+        for _ in 0..hashes_len {
+            hashes.push(crypto::bytes_to_hash32(&bytes[index..(index + 32)]).unwrap());
+            index += 32;
+        }
+        let (flags_len, flags_len_size) = VariableInteger::from_bytes(&bytes[index..]).unwrap();
+        index += flags_len_size;
+        let flags = bytes[index..(index + flags_len as usize)].to_vec();
+        MessageMerkleBlock {
+            header,
+            total_transactions,
+            hashes,
+            flags,
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "merkleblock {} ({} hashes)",
+            hex::encode(self.header.hash()),
+            self.hashes.len()
+        )
+    }
+
+    fn handle(&self, node: &mut node::Node, _config: &config::Config) {
+        log::debug!(
+            "[{}] Received filtered block {}",
+            node.id(),
+            hex::encode(self.header.hash())
+        );
+    }
+}
+
+impl MessageMerkleBlock {
+    /// Creates the message proving which transactions of the block match,
+    /// `matches` giving for each transaction whether it does
+    pub fn new(block: &block::Block, matches: &[bool]) -> Self {
+        let tree = MerkleTree::from_hashable(block.transactions().iter().map(|tx| tx.as_ref()));
+        let (hashes, bits) = tree.partial(matches);
+        let mut flags = vec![0; bits.len().div_ceil(8)];
+        for (i, bit) in bits.iter().enumerate() {
+            flags[i / 8] |= (*bit as u8) << (i % 8);
+        }
+        MessageMerkleBlock {
+            header: block.header.clone(),
+            total_transactions: block.transaction_count() as u32,
+            hashes,
+            flags,
+        }
+    }
+
+    pub fn hashes(&self) -> &[crypto::Hash32] {
+        &self.hashes
+    }
+
+    pub fn flags(&self) -> &[u8] {
+        &self.flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::message::MessageCommand;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn test_message_merkleblock() {
+        let genesis = config::regtest_config().genesis_block;
+        let mut block = crate::storage::next_block(&genesis, 1);
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, vec![0x51]);
+        tx.add_output(1000, vec![0x51]);
+        block.add_tx(Box::new(tx.clone())).unwrap();
+        block.update_merkle_root();
+
+        let merkleblock = MessageMerkleBlock::new(&block, &[false, true]);
+        assert_eq!(&merkleblock.name()[..11], b"merkleblock");
+        assert_eq!(
+            merkleblock.hashes(),
+            &[block.transactions()[0].hash(), tx.hash()]
+        );
+        // Root, unmatched coinbase then matched transaction
+        assert_eq!(merkleblock.flags(), &[0b101]);
+        assert_eq!(merkleblock.length() as usize, merkleblock.bytes().len());
+        assert_eq!(
+            merkleblock,
+            MessageMerkleBlock::from_bytes(&merkleblock.bytes())
+        );
+    }
+}
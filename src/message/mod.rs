@@ -2,34 +2,51 @@ use crate::config;
 use crate::crypto;
 use crate::node;
 use crate::utils;
+use std::fmt;
 
 pub mod addr;
 pub mod alert;
 pub mod block;
+pub mod cfcheckpt;
+pub mod cfheaders;
+pub mod cfilter;
+pub mod cmpct_base;
 pub mod feefilter;
+pub mod filterload;
 pub mod getaddr;
 pub mod getblocks;
+pub mod getcfcheckpt;
+pub mod getcfheaders;
+pub mod getcfilters;
 pub mod getdata;
 pub mod getheaders;
 pub mod headers;
 pub mod inv;
 pub mod inv_base;
+pub mod merkleblock;
 pub mod notfound;
 pub mod ping;
 pub mod pong;
 pub mod sendheaders;
+pub mod tx;
 pub mod verack;
 pub mod version;
 
 pub const MAGIC_MAIN: u32 = 0xD9B4BEF9;
 pub const MAGIC_TESTNET: u32 = 0xDAB5BFFA;
 pub const MAGIC_TESTNET3: u32 = 0x0709110B;
+pub const MAGIC_REGTEST: u32 = 0xDAB5BFFA;
+pub const MAGIC_SIGNET: u32 = 0x40CF030A;
 pub const MAGIC_NAMECOIN: u32 = 0xFEB4BEF9;
 
+// Maximum length of a message payload
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1000 * 1000;
+
 pub const NODE_NETWORK: u64 = 1;
 pub const NODE_GETUTXO: u64 = 2;
 pub const NODE_BLOOM: u64 = 4;
 pub const NODE_WITNESS: u64 = 8;
+pub const NODE_COMPACT_FILTERS: u64 = 64;
 pub const NODE_NETWORK_LIMITED: u64 = 1024;
 
 #[derive(Debug, Clone)]
@@ -50,6 +67,15 @@ pub enum MessageType {
     NotFound(Message<notfound::MessageNotFound>),
     Headers(Message<headers::MessageHeaders>),
     Block(Message<block::MessageBlock>),
+    Tx(Message<tx::MessageTx>),
+    GetCFilters(Message<getcfilters::MessageGetCFilters>),
+    CFilter(Message<cfilter::MessageCFilter>),
+    GetCFHeaders(Message<getcfheaders::MessageGetCFHeaders>),
+    CFHeaders(Message<cfheaders::MessageCFHeaders>),
+    GetCFCheckpt(Message<getcfcheckpt::MessageGetCFCheckpt>),
+    CFCheckpt(Message<cfcheckpt::MessageCFCheckpt>),
+    FilterLoad(Message<filterload::MessageFilterLoad>),
+    MerkleBlock(Message<merkleblock::MessageMerkleBlock>),
 }
 
 impl MessageType {
@@ -71,15 +97,64 @@ impl MessageType {
             MessageType::NotFound(message) => message.bytes(),
             MessageType::Headers(message) => message.bytes(),
             MessageType::Block(message) => message.bytes(),
+            MessageType::Tx(message) => message.bytes(),
+            MessageType::GetCFilters(message) => message.bytes(),
+            MessageType::CFilter(message) => message.bytes(),
+            MessageType::GetCFHeaders(message) => message.bytes(),
+            MessageType::CFHeaders(message) => message.bytes(),
+            MessageType::GetCFCheckpt(message) => message.bytes(),
+            MessageType::CFCheckpt(message) => message.bytes(),
+            MessageType::FilterLoad(message) => message.bytes(),
+            MessageType::MerkleBlock(message) => message.bytes(),
         }
     }
 }
 
+/// Short description of a message, without its whole payload
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let summary = match self {
+            MessageType::Version(message) => message.command.summary(),
+            MessageType::Alert(message) => message.command.summary(),
+            MessageType::Verack(message) => message.command.summary(),
+            MessageType::Addr(message) => message.command.summary(),
+            MessageType::GetAddr(message) => message.command.summary(),
+            MessageType::Ping(message) => message.command.summary(),
+            MessageType::Pong(message) => message.command.summary(),
+            MessageType::GetHeaders(message) => message.command.summary(),
+            MessageType::FeeFilter(message) => message.command.summary(),
+            MessageType::SendHeaders(message) => message.command.summary(),
+            MessageType::Inv(message) => message.command.summary(),
+            MessageType::GetData(message) => message.command.summary(),
+            MessageType::GetBlocks(message) => message.command.summary(),
+            MessageType::NotFound(message) => message.command.summary(),
+            MessageType::Headers(message) => message.command.summary(),
+            MessageType::Block(message) => message.command.summary(),
+            MessageType::Tx(message) => message.command.summary(),
+            MessageType::GetCFilters(message) => message.command.summary(),
+            MessageType::CFilter(message) => message.command.summary(),
+            MessageType::GetCFHeaders(message) => message.command.summary(),
+            MessageType::CFHeaders(message) => message.command.summary(),
+            MessageType::GetCFCheckpt(message) => message.command.summary(),
+            MessageType::CFCheckpt(message) => message.command.summary(),
+            MessageType::FilterLoad(message) => message.command.summary(),
+            MessageType::MerkleBlock(message) => message.command.summary(),
+        };
+        write!(f, "{}", summary)
+    }
+}
+
 pub trait MessageCommand {
     fn bytes(&self) -> Vec<u8>;
     fn from_bytes(_: &[u8]) -> Self;
     fn length(&self) -> u32;
     fn name(&self) -> [u8; 12];
+    /// Returns a short description of the message, used in the logs
+    fn summary(&self) -> String {
+        String::from_utf8_lossy(&self.name())
+            .trim_end_matches('\0')
+            .to_string()
+    }
     fn handle(&self, node: &mut node::Node, config: &config::Config);
 }
 
@@ -125,8 +200,9 @@ where
 #[derive(Debug)]
 pub enum ParseError {
     InvalidMagicBytes,
-    InvalidChecksum,
-    UnknownMessage(String),
+    InvalidChecksum(usize), // Length of the whole frame, which can be skipped
+    UnknownMessage(String, usize), // Name of the command and length of the whole frame
+    OversizedMessage(usize),
     Partial(usize),
 }
 
@@ -134,7 +210,9 @@ fn check_size(bytes: &[u8], length: usize) -> bool {
     bytes.len() >= length
 }
 
-pub fn parse(bytes: &[u8]) -> Result<(MessageType, usize), ParseError> {
+/// Parses the first message of the bytes. Messages of another network than
+/// the one of the expected magic are rejected.
+pub fn parse(bytes: &[u8], expected_magic: u32) -> Result<(MessageType, usize), ParseError> {
     let mut to_read = 24;
     let mut index = 0;
 
@@ -146,7 +224,7 @@ pub fn parse(bytes: &[u8]) -> Result<(MessageType, usize), ParseError> {
     index += next_size;
 
     // Check magic
-    if !(magic == MAGIC_MAIN || magic == MAGIC_TESTNET || magic == MAGIC_TESTNET3) {
+    if magic != expected_magic {
         return Err(ParseError::InvalidMagicBytes);
     }
 
@@ -173,6 +251,10 @@ pub fn parse(bytes: &[u8]) -> Result<(MessageType, usize), ParseError> {
     let length = u32::from_le_bytes(utils::clone_into_array(&bytes[index..(index + next_size)]));
     index += next_size;
 
+    if length as usize > MAX_MESSAGE_SIZE {
+        return Err(ParseError::OversizedMessage(length as usize));
+    }
+
     // Now we know how many bytes have to be read
     to_read += length as usize;
 
@@ -191,7 +273,7 @@ pub fn parse(bytes: &[u8]) -> Result<(MessageType, usize), ParseError> {
 
     // Check checksum
     if &crypto::hash32(payload)[0..4] != checksum {
-        return Err(ParseError::InvalidChecksum);
+        return Err(ParseError::InvalidChecksum(24 + length as usize));
     }
 
     log::trace!("payload: {:?}", payload);
@@ -244,8 +326,38 @@ pub fn parse(bytes: &[u8]) -> Result<(MessageType, usize), ParseError> {
     } else if name == "block" {
         let command = block::MessageBlock::from_bytes(&payload);
         message = MessageType::Block(Message { magic, command });
+    } else if name == "tx" {
+        let command = tx::MessageTx::from_bytes(&payload);
+        message = MessageType::Tx(Message { magic, command });
+    } else if name == "getcfilters" {
+        let command = getcfilters::MessageGetCFilters::from_bytes(&payload);
+        message = MessageType::GetCFilters(Message { magic, command });
+    } else if name == "cfilter" {
+        let command = cfilter::MessageCFilter::from_bytes(&payload);
+        message = MessageType::CFilter(Message { magic, command });
+    } else if name == "getcfheaders" {
+        let command = getcfheaders::MessageGetCFHeaders::from_bytes(&payload);
+        message = MessageType::GetCFHeaders(Message { magic, command });
+    } else if name == "cfheaders" {
+        let command = cfheaders::MessageCFHeaders::from_bytes(&payload);
+        message = MessageType::CFHeaders(Message { magic, command });
+    } else if name == "getcfcheckpt" {
+        let command = getcfcheckpt::MessageGetCFCheckpt::from_bytes(&payload);
+        message = MessageType::GetCFCheckpt(Message { magic, command });
+    } else if name == "cfcheckpt" {
+        let command = cfcheckpt::MessageCFCheckpt::from_bytes(&payload);
+        message = MessageType::CFCheckpt(Message { magic, command });
+    } else if name == "filterload" {
+        let command = filterload::MessageFilterLoad::from_bytes(&payload);
+        message = MessageType::FilterLoad(Message { magic, command });
+    } else if name == "merkleblock" {
+        let command = merkleblock::MessageMerkleBlock::from_bytes(&payload);
+        message = MessageType::MerkleBlock(Message { magic, command });
     } else {
-        return Err(ParseError::UnknownMessage(name.clone()));
+        return Err(ParseError::UnknownMessage(
+            name.clone(),
+            24 + length as usize,
+        ));
     }
 
     Ok((message, 24 + length as usize))
@@ -255,6 +367,7 @@ pub fn parse(bytes: &[u8]) -> Result<(MessageType, usize), ParseError> {
 mod tests {
 
     use super::*;
+    use crate::crypto::Hashable;
 
     struct MessageMock {
         name: [u8; 12],
@@ -322,7 +435,7 @@ mod tests {
         );
 
         let bytes = message.bytes();
-        let (parsed_message, length) = parse(&bytes).unwrap();
+        let (parsed_message, length) = parse(&bytes, MAGIC_MAIN).unwrap();
 
         if let MessageType::Version(version) = parsed_message {
             assert_eq!(bytes, version.bytes());
@@ -331,41 +444,138 @@ mod tests {
 
         let mut inv_checksum_bytes = bytes.clone();
         inv_checksum_bytes[35] = inv_checksum_bytes[35] + 1;
-        match parse(&inv_checksum_bytes) {
-            Err(ParseError::InvalidChecksum) => assert!(true),
+        match parse(&inv_checksum_bytes, MAGIC_MAIN) {
+            Err(ParseError::InvalidChecksum(length)) => assert_eq!(length, 125),
             _ => assert!(false),
         }
 
         let mut inv_magic_bytes = bytes.clone();
         inv_magic_bytes[0] = inv_magic_bytes[0] + 1;
-        match parse(&inv_magic_bytes) {
+        match parse(&inv_magic_bytes, MAGIC_MAIN) {
             Err(ParseError::InvalidMagicBytes) => assert!(true),
             _ => assert!(false),
         }
 
-        match parse(&bytes[..5]) {
+        match parse(&bytes[..5], MAGIC_MAIN) {
             Err(ParseError::Partial(nb)) => assert_eq!(nb, 19),
             _ => assert!(false),
         }
 
-        match parse(&bytes[..19]) {
+        match parse(&bytes[..19], MAGIC_MAIN) {
             Err(ParseError::Partial(nb)) => assert_eq!(nb, 5),
             _ => assert!(false),
         }
 
-        match parse(&bytes[..20]) {
+        match parse(&bytes[..20], MAGIC_MAIN) {
             Err(ParseError::Partial(nb)) => assert_eq!(nb, 105),
             _ => assert!(false),
         }
 
-        match parse(&bytes[..24]) {
+        match parse(&bytes[..24], MAGIC_MAIN) {
             Err(ParseError::Partial(nb)) => assert_eq!(nb, 101),
             _ => assert!(false),
         }
 
-        match parse(&bytes[..122]) {
+        match parse(&bytes[..122], MAGIC_MAIN) {
             Err(ParseError::Partial(nb)) => assert_eq!(nb, 3),
             _ => assert!(false),
         }
     }
+
+    #[test]
+    fn test_unknown_message() {
+        let name = [
+            'm' as u8, 'o' as u8, 'c' as u8, 'k' as u8, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let mut bytes = Message::new(MAGIC_MAIN, verack::MessageVerack::new()).bytes();
+        let first_len = bytes.len();
+        bytes.extend(Message::new(MAGIC_MAIN, MessageMock::new(name, vec![1, 2, 3])).bytes());
+        bytes.extend(Message::new(MAGIC_MAIN, verack::MessageVerack::new()).bytes());
+
+        let mut index = 0;
+        match parse(&bytes, MAGIC_MAIN) {
+            Ok((MessageType::Verack(_), length)) => index += length,
+            _ => assert!(false),
+        }
+        assert_eq!(index, first_len);
+        match parse(&bytes[index..], MAGIC_MAIN) {
+            Err(ParseError::UnknownMessage(name, length)) => {
+                assert_eq!(name, "mock");
+                assert_eq!(length, 24 + 3);
+                index += length;
+            }
+            _ => assert!(false),
+        }
+        match parse(&bytes[index..], MAGIC_MAIN) {
+            Ok((MessageType::Verack(_), length)) => assert_eq!(index + length, bytes.len()),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_oversized_message() {
+        let name = [
+            'm' as u8, 'o' as u8, 'c' as u8, 'k' as u8, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let mut bytes = Message::new(MAGIC_MAIN, MessageMock::new(name, vec![])).bytes();
+        // Only the header is sent: the length field is enough to reject it
+        bytes[16..20].copy_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+        match parse(&bytes, MAGIC_MAIN) {
+            Err(ParseError::OversizedMessage(length)) => assert_eq!(length, MAX_MESSAGE_SIZE + 1),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_other_network_magic() {
+        let testnet_magic = config::test_config().magic;
+        let bytes = Message::new(MAGIC_MAIN, verack::MessageVerack::new()).bytes();
+        match parse(&bytes, testnet_magic) {
+            Err(ParseError::InvalidMagicBytes) => (),
+            _ => panic!("A mainnet message should be rejected on testnet"),
+        }
+
+        let bytes = Message::new(testnet_magic, verack::MessageVerack::new()).bytes();
+        match parse(&bytes, testnet_magic) {
+            Ok((MessageType::Verack(_), length)) => assert_eq!(length, bytes.len()),
+            _ => panic!("A testnet message should be parsed on testnet"),
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        let genesis = config::main_config().genesis_block;
+        let hash = hex::encode(genesis.hash());
+        let message = MessageType::Block(Message::new(
+            MAGIC_MAIN,
+            block::MessageBlock::new(genesis.clone()),
+        ));
+        assert_eq!(message.to_string(), format!("block {} (1 txs)", hash));
+
+        // Two headers without transaction count
+        let mut bytes = vec![2];
+        for _ in 0..2 {
+            bytes.extend_from_slice(&genesis.header.bytes());
+            bytes.push(0);
+        }
+        let message = MessageType::Headers(Message::new(
+            MAGIC_MAIN,
+            headers::MessageHeaders::from_bytes(&bytes),
+        ));
+        assert_eq!(message.to_string(), "headers (2)");
+
+        let inv_vect = inv_base::InvVect {
+            hash_type: inv_base::MSG_BLOCK,
+            hash: genesis.hash(),
+        };
+        let message = MessageType::Inv(Message::new(
+            MAGIC_MAIN,
+            inv::MessageInv::new(vec![inv_vect; 500]),
+        ));
+        assert_eq!(message.to_string(), "inv (500 items)");
+
+        // Other messages are only named
+        let message = MessageType::Ping(Message::new(MAGIC_MAIN, ping::MessagePing::new(1)));
+        assert_eq!(message.to_string(), "ping");
+    }
 }
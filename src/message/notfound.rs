@@ -39,7 +39,11 @@ impl message::MessageCommand for MessageNotFound {
         }
     }
 
-    fn handle(&self, node: &mut node::Node, config: &config::Config) {
+    fn summary(&self) -> String {
+        format!("notfound ({} items)", self.base.inventory.len())
+    }
+
+    fn handle(&self, node: &mut node::Node, _config: &config::Config) {
         for inv_vect in self.base.inventory.iter() {
             log::trace!(
                 "{} {}",
@@ -47,6 +51,10 @@ impl message::MessageCommand for MessageNotFound {
                 hex::encode(inv_vect.hash)
             );
         }
+        node.send_response(node::NodeResponseContent::NotFound(
+            self.base.inventory.clone(),
+        ))
+        .unwrap();
     }
 }
 
@@ -41,7 +41,7 @@ impl message::MessageCommand for MessagePing {
         log::debug!("[{}] Sending pong message: {:?}", node.id(), pong);
         let message = message::Message::new(config.magic, pong);
         let stream = node.stream();
-        stream.write(&message.bytes()).unwrap();
+        stream.write_all(&message.bytes()).unwrap();
         stream.flush().unwrap();
     }
 }
@@ -34,7 +34,16 @@ impl message::MessageCommand for MessagePong {
         MessagePong { nonce }
     }
 
-    fn handle(&self, node: &mut node::Node, config: &config::Config) {}
+    fn handle(&self, node: &mut node::Node, _config: &config::Config) {
+        match node.pong_received(self.nonce) {
+            Some(latency) => {
+                log::debug!("[{}] Latency: {:?}", node.id(), latency);
+                node.send_response(node::NodeResponseContent::Latency(latency))
+                    .unwrap_or_default();
+            }
+            None => log::debug!("[{}] Unexpected pong: {}", node.id(), self.nonce),
+        }
+    }
 }
 
 impl MessagePong {
@@ -0,0 +1,77 @@
+use crate::config;
+use crate::crypto::Hashable;
+use crate::message;
+use crate::node;
+use crate::transaction;
+use std::convert::TryInto;
+
+const NAME: &str = "tx";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MessageTx {
+    tx: transaction::Transaction,
+}
+
+impl message::MessageCommand for MessageTx {
+    fn name(&self) -> [u8; 12] {
+        let mut command = [0; 12];
+        for (i, c) in NAME.char_indices() {
+            command[i] = c as u8;
+        }
+        command
+    }
+
+    fn length(&self) -> u32 {
+        self.bytes().len().try_into().unwrap()
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        self.tx.witness_bytes()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        MessageTx {
+            tx: transaction::Transaction::from_bytes(bytes).0,
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!("tx {}", hex::encode(self.tx.hash()))
+    }
+
+    fn handle(&self, node: &mut node::Node, _config: &config::Config) {
+        log::debug!(
+            "[{:?}] Received transaction {}",
+            node.id(),
+            hex::encode(self.tx.hash())
+        );
+        node.send_response(node::NodeResponseContent::Transaction(self.tx.clone()))
+            .unwrap();
+    }
+}
+
+impl MessageTx {
+    pub fn new(tx: transaction::Transaction) -> Self {
+        MessageTx { tx }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageCommand;
+
+    #[test]
+    fn test_message_tx() {
+        let config = config::main_config();
+        let tx = (*config.genesis_block.transactions()[0]).clone();
+        let message_tx = MessageTx::new(tx.clone());
+
+        assert_eq!(
+            message_tx.name(),
+            ['t' as u8, 'x' as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(message_tx.length() as usize, tx.bytes().len());
+        assert_eq!(message_tx, MessageTx::from_bytes(&message_tx.bytes()));
+    }
+}
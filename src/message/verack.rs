@@ -38,7 +38,11 @@ impl message::MessageCommand for MessageVerack {
                     .unwrap();
                 node::ConnectionState::ESTABLISHED
             }
-            _ => panic!("Received unexpected verack message"),
+            _ => {
+                log::warn!("[{}] Received unexpected verack message", node.id());
+                node.disconnect(node::Misbehavior::UnexpectedHandshake);
+                return;
+            }
         };
         node.set_connection_state(new_state);
     }
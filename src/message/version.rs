@@ -101,10 +101,8 @@ impl message::MessageCommand for MessageVersion {
         let start_height = u32::from_le_bytes(utils::clone_into_array(&bytes[index..(index + 4)]));
         index += 4;
 
-        let relay = bytes[index] != 0;
-        index += 1;
-
-        assert_eq!(index, bytes.len());
+        // Missing before BIP37, when transactions were always announced
+        let relay = bytes.get(index).map_or(true, |relay| *relay != 0);
 
         MessageVersion {
             version,
@@ -120,26 +118,36 @@ impl message::MessageCommand for MessageVersion {
     }
 
     fn handle(&self, node: &mut node::Node, config: &config::Config) {
+        let new_state = match node.connection_state() {
+            node::ConnectionState::VER_SENT => node::ConnectionState::VER_RECEIVED,
+            node::ConnectionState::VERACK_RECEIVED => node::ConnectionState::ESTABLISHED,
+            _ => {
+                log::warn!("[{}] Received unexpected version message", node.id());
+                node.disconnect(node::Misbehavior::UnexpectedHandshake);
+                return;
+            }
+        };
+
+        // The peer tells how it sees us, which helps finding our address
+        node.send_response(node::NodeResponseContent::LocalAddr(network::from_ipv6(
+            &self.addr_recv.ip(),
+        )))
+        .unwrap_or_default();
+        node.send_response(node::NodeResponseContent::Relay(self.relay))
+            .unwrap_or_default();
+
         // TODO: Verify validity of this message before sending ack
         let verack = message::verack::MessageVerack::new();
         log::debug!("[{}] Sending verak message: {:?}", node.id(), verack);
         let message = message::Message::new(config.magic, verack);
         let stream = node.stream();
-        stream.write(&message.bytes()).unwrap();
+        stream.write_all(&message.bytes()).unwrap();
         stream.flush().unwrap();
 
-        let new_state = match node.connection_state() {
-            node::ConnectionState::VER_SENT => node::ConnectionState::VER_RECEIVED,
-            node::ConnectionState::VERACK_RECEIVED => {
-                node.send_response(node::NodeResponseContent::Connected)
-                    .unwrap();
-                node::ConnectionState::ESTABLISHED
-            }
-            _ => {
-                log::warn!("Received unexpected version message");
-                return;
-            }
-        };
+        if new_state == node::ConnectionState::ESTABLISHED {
+            node.send_response(node::NodeResponseContent::Connected)
+                .unwrap();
+        }
         node.set_connection_state(new_state);
     }
 }
@@ -168,6 +176,16 @@ impl MessageVersion {
             relay,
         }
     }
+
+    /// Returns the address of the node emitting this message
+    pub fn addr_from(&self) -> &network::NetAddrVersion {
+        &self.addr_from
+    }
+
+    /// Returns whether the emitting node wants transactions to be announced
+    pub fn relay(&self) -> bool {
+        self.relay
+    }
 }
 
 #[cfg(test)]
@@ -201,5 +219,10 @@ mod tests {
             hex::encode(message.bytes())
         );
         assert_eq!(message, MessageVersion::from_bytes(&message.bytes()));
+
+        // Peers older than BIP37 do not send the relay flag
+        let bytes = message.bytes();
+        let message = MessageVersion::from_bytes(&bytes[..(bytes.len() - 1)]);
+        assert!(message.relay());
     }
 }
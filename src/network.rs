@@ -1,13 +1,71 @@
 use crate::message;
 use crate::utils;
 
+use std::io;
+use std::io::prelude::*;
 use std::net;
 
+// SOCKS protocol version 5 (RFC 1928)
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTHENTICATION: u8 = 0;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_IPV6: u8 = 4;
+const SOCKS_SUCCEEDED: u8 = 0;
+
 pub trait NetAddrBase {
     fn bytes(&self) -> Vec<u8>;
     fn from_bytes(_: &[u8]) -> Self;
 }
 
+/// Returns the IPv6 representation of an address, IPv4 addresses being
+/// mapped into the IPv6 space as done on the wire
+pub fn to_ipv6(ip: &net::IpAddr) -> net::Ipv6Addr {
+    match ip {
+        net::IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        net::IpAddr::V6(addr) => *addr,
+    }
+}
+
+/// TCP port of a peer. Unlike every other integer of the protocol, a port
+/// is serialized in network byte order (big endian): converting only
+/// through this type keeps the conversion in a single place.
+#[derive(PartialEq, Debug, Clone, Copy, Eq, Hash)]
+pub struct Port(u16);
+
+impl Port {
+    pub fn new(port: u16) -> Self {
+        Port(port)
+    }
+
+    /// Returns the port in host byte order
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+
+    pub fn bytes(&self) -> [u8; 2] {
+        self.0.to_be_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Port(u16::from_be_bytes(utils::clone_into_array(&bytes[..2])))
+    }
+}
+
+/// Returns the address represented by an IPv6 address received on the wire,
+/// IPv4-mapped addresses being converted back to IPv4
+pub fn from_ipv6(ip: &net::Ipv6Addr) -> net::IpAddr {
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, high, low] => net::IpAddr::V4(net::Ipv4Addr::new(
+            (high >> 8) as u8,
+            high as u8,
+            (low >> 8) as u8,
+            low as u8,
+        )),
+        _ => net::IpAddr::V6(*ip),
+    }
+}
+
 pub const NET_ADDR_VERSION_SIZE: usize = 26;
 pub const NET_ADDR_SIZE: usize = NET_ADDR_VERSION_SIZE + 4;
 
@@ -44,13 +102,31 @@ impl NetAddr {
             net_addr_version: NetAddrVersion::new(services, ip, port),
         }
     }
+
+    /// Returns the address of a peer reachable at a socket address
+    pub fn from_socket_addr(time: u32, services: u64, addr: &net::SocketAddr) -> Self {
+        NetAddr {
+            time,
+            net_addr_version: NetAddrVersion::from_socket_addr(services, addr),
+        }
+    }
+
+    /// Returns the last time (UNIX timestamp) the peer was seen active
+    pub fn time(&self) -> u32 {
+        self.time
+    }
+
+    /// Returns the socket address to dial to reach the peer
+    pub fn to_socket_addr(&self) -> net::SocketAddr {
+        self.net_addr_version.to_socket_addr()
+    }
 }
 
 #[derive(PartialEq, Debug, Clone, Eq, Hash)]
 pub struct NetAddrVersion {
     services: u64,
-    pub ip: net::Ipv6Addr,
-    pub port: u16,
+    ip: net::Ipv6Addr,
+    port: Port,
 }
 
 impl NetAddrBase for NetAddrVersion {
@@ -58,8 +134,7 @@ impl NetAddrBase for NetAddrVersion {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.services.to_le_bytes());
         bytes.extend_from_slice(&self.ip.octets());
-        // Port is in network format: big endian
-        bytes.extend_from_slice(&self.port.to_be_bytes());
+        bytes.extend_from_slice(&self.port.bytes());
         bytes
     }
 
@@ -71,7 +146,7 @@ impl NetAddrBase for NetAddrVersion {
             &bytes[index..(index + 16)],
         ));
         index += 16;
-        let port = u16::from_be_bytes(utils::clone_into_array(&bytes[index..(index + 2)]));
+        let port = Port::from_bytes(&bytes[index..(index + 2)]);
 
         NetAddrVersion { services, ip, port }
     }
@@ -79,14 +154,170 @@ impl NetAddrBase for NetAddrVersion {
 
 impl NetAddrVersion {
     pub fn new(services: u64, ip: net::Ipv6Addr, port: u16) -> Self {
-        NetAddrVersion { services, ip, port }
+        NetAddrVersion {
+            services,
+            ip,
+            port: Port::new(port),
+        }
+    }
+
+    pub fn from_socket_addr(services: u64, addr: &net::SocketAddr) -> Self {
+        NetAddrVersion::new(services, to_ipv6(&addr.ip()), addr.port())
+    }
+
+    pub fn services(&self) -> u64 {
+        self.services
+    }
+
+    /// Returns the address as sent on the wire, IPv4 addresses being mapped
+    pub fn ip(&self) -> net::Ipv6Addr {
+        self.ip
+    }
+
+    /// Returns the port in host byte order
+    pub fn port(&self) -> u16 {
+        self.port.value()
+    }
+
+    /// Returns the socket address to dial to reach the peer
+    pub fn to_socket_addr(&self) -> net::SocketAddr {
+        net::SocketAddr::new(from_ipv6(&self.ip), self.port.value())
     }
 }
 
+/// Connects to a peer through a SOCKS5 proxy (RFC 1928), without
+/// authentication. The returned stream is relayed to the peer.
+pub fn socks5_connect(
+    proxy: net::SocketAddr,
+    target: net::SocketAddr,
+) -> io::Result<net::TcpStream> {
+    let failure = |reason: &str| io::Error::other(reason.to_string());
+    let mut stream = net::TcpStream::connect(proxy)?;
+
+    stream.write_all(&[SOCKS_VERSION, 1, SOCKS_NO_AUTHENTICATION])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [SOCKS_VERSION, SOCKS_NO_AUTHENTICATION] {
+        return Err(failure("SOCKS proxy requires an authentication"));
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+    match target.ip() {
+        net::IpAddr::V4(ip) => {
+            request.push(SOCKS_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        net::IpAddr::V6(ip) => {
+            request.push(SOCKS_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+    request.extend_from_slice(&Port::new(target.port()).bytes());
+    stream.write_all(&request)?;
+
+    // Version, status, reserved byte and type of the bound address
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION || reply[1] != SOCKS_SUCCEEDED {
+        return Err(failure(&format!(
+            "SOCKS proxy failed with status {}",
+            reply[1]
+        )));
+    }
+    // The bound address and port are not needed
+    let bound_size = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        _ => return Err(failure("SOCKS proxy bound an unsupported address")),
+    };
+    let mut bound = vec![0u8; bound_size + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(stream)
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use rand::Rng;
+    use std::thread;
+
+    /// Runs a SOCKS5 proxy accepting a connection to `target`, answering
+    /// with `status` and then writing `data` to the client
+    fn mock_proxy(target: net::SocketAddr, status: u8, data: &'static [u8]) -> net::SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).unwrap();
+
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            let mut expected = vec![5, 1, 0, 1];
+            if let net::IpAddr::V4(ip) = target.ip() {
+                expected.extend_from_slice(&ip.octets());
+            }
+            expected.extend_from_slice(&target.port().to_be_bytes());
+            assert_eq!(request.to_vec(), expected);
+            stream
+                .write_all(&[5, status, 0, 1, 127, 0, 0, 1, 0x20, 0x8d])
+                .unwrap();
+            stream.write_all(data).unwrap();
+        });
+        proxy
+    }
+
+    #[test]
+    fn test_socks5_connect() {
+        let target: net::SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let proxy = mock_proxy(target, 0, b"version");
+        let mut stream = socks5_connect(proxy, target).unwrap();
+        let mut data = [0u8; 7];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"version");
+
+        // Connection refused by the proxy
+        let proxy = mock_proxy(target, 5, b"");
+        assert!(socks5_connect(proxy, target).is_err());
+    }
+
+    #[test]
+    fn test_from_ipv6() {
+        let ipv4: net::IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(from_ipv6(&to_ipv6(&ipv4)), ipv4);
+        let ipv6: net::IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(from_ipv6(&to_ipv6(&ipv6)), ipv6);
+    }
+
+    #[test]
+    fn test_port() {
+        let port = Port::new(8333);
+        assert_eq!(port.bytes(), [0x20, 0x8d]);
+        assert_eq!(Port::from_bytes(&[0x20, 0x8d]), port);
+        assert_eq!(port.value(), 8333);
+    }
+
+    #[test]
+    fn test_net_addr_version_round_trip() {
+        let mut rng = rand::thread_rng();
+        let ports = [0, 1, 255, 256, u16::max_value()];
+        for i in 0..1000 {
+            let port = match ports.get(i) {
+                Some(port) => *port,
+                None => rng.gen(),
+            };
+            let ip: [u8; 16] = rng.gen();
+            let net_addr_version = NetAddrVersion::new(rng.gen(), net::Ipv6Addr::from(ip), port);
+            let bytes = net_addr_version.bytes();
+            assert_eq!(bytes.len(), NET_ADDR_VERSION_SIZE);
+            assert_eq!(&bytes[24..], &port.to_be_bytes());
+            assert_eq!(net_addr_version, NetAddrVersion::from_bytes(&bytes));
+            assert_eq!(net_addr_version.port(), port);
+        }
+    }
 
     #[test]
     fn test_net_addr_version() {
@@ -148,4 +379,30 @@ mod tests {
         );
         assert_eq!(net_addr, NetAddr::from_bytes(&net_addr.bytes()));
     }
+
+    #[test]
+    fn test_net_addr_to_socket_addr() {
+        let net_addr = NetAddr::new(
+            1563472788, // time
+            message::NODE_NETWORK,
+            net::Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped(),
+            8333,
+        );
+        let socket_addr: net::SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        assert_eq!(net_addr.to_socket_addr(), socket_addr);
+        assert_eq!(
+            NetAddr::from_socket_addr(1563472788, message::NODE_NETWORK, &socket_addr),
+            net_addr
+        );
+        assert_eq!(net_addr.net_addr_version.services(), message::NODE_NETWORK);
+        assert_eq!(net_addr.net_addr_version.port(), 8333);
+
+        let socket_addr: net::SocketAddr = "[2001:db8::1]:18333".parse().unwrap();
+        let net_addr = NetAddr::from_socket_addr(0, message::NODE_NETWORK, &socket_addr);
+        assert_eq!(
+            net_addr.net_addr_version.ip(),
+            "2001:db8::1".parse::<net::Ipv6Addr>().unwrap()
+        );
+        assert_eq!(net_addr.to_socket_addr(), socket_addr);
+    }
 }
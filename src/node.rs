@@ -1,26 +1,34 @@
 use crate::block;
+use crate::bloom;
+use crate::cfilter;
+use crate::config;
 use crate::config::Config;
 use crate::crypto;
 use crate::message;
-use crate::message::inv_base::{InvVect, MSG_BLOCK};
+use crate::message::inv_base::{InvVect, MSG_WITNESS_BLOCK};
 use crate::message::MessageCommand;
 use crate::network;
 use crate::rand::RngCore;
+use crate::transaction;
 use crate::ControllerMessage;
 
 use crate::crypto::Hashable;
-use std::cmp::min;
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::io::{Read, Write};
 use std::net;
 use std::rc::Rc;
 use std::sync::mpsc;
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 pub type NodeId = usize;
 
-const MAX_DOWNLOADING_BLOCKS: usize = 16;
+// A peer exceeding the rate limit is reported at most once per window
+const FLOOD_REPORT_WINDOW: Duration = Duration::from_secs(10);
+
+// Number of inventory items announced by a peer remembered to ignore them
+// when they are announced again
+const MAX_KNOWN_INVENTORY: usize = 5_000;
 
 #[derive(Debug, Clone)]
 pub struct NodeHandle {
@@ -28,6 +36,12 @@ pub struct NodeHandle {
     command_sender: mpsc::Sender<NodeCommand>,
     state: NodeState,
     download_current: Vec<crypto::Hash32>,
+    addr: Option<net::SocketAddr>,
+    fee_filter: u64,
+    relay: bool, // whether the peer wants transactions to be announced (BIP37)
+    known_inventory: HashSet<crypto::Hash32>, // items already announced by the peer
+    latency: Option<Duration>, // last round trip time measured with a ping
+    bloom_filter: Option<bloom::BloomFilter>, // transactions of the filtered blocks (BIP37)
 }
 
 impl NodeHandle {
@@ -37,9 +51,65 @@ impl NodeHandle {
             command_sender,
             state: NodeState::CONNECTING(ConnectionState::CLOSED),
             download_current: Vec::new(),
+            addr: None,
+            fee_filter: 0,
+            relay: true,
+            known_inventory: HashSet::new(),
+            latency: None,
+            bloom_filter: None,
         }
     }
 
+    /// Returns the address of the peer this node is talking to
+    pub fn addr(&self) -> Option<net::SocketAddr> {
+        self.addr
+    }
+
+    pub fn set_addr(&mut self, addr: net::SocketAddr) {
+        self.addr = Some(addr);
+    }
+
+    /// Returns the minimum fee rate of the transactions the peer wants to
+    /// hear about, in satoshis per kB
+    pub fn fee_filter(&self) -> u64 {
+        self.fee_filter
+    }
+
+    pub fn set_fee_filter(&mut self, fee_filter: u64) {
+        self.fee_filter = fee_filter;
+    }
+
+    /// Returns whether the peer wants transactions to be announced. Bloom
+    /// filters only restrict filtered blocks, so a peer refusing them never
+    /// gets any.
+    pub fn relay(&self) -> bool {
+        self.relay
+    }
+
+    pub fn set_relay(&mut self, relay: bool) {
+        self.relay = relay;
+    }
+
+    /// Returns the last round trip time measured between a ping and its
+    /// pong, if any
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = Some(latency);
+    }
+
+    /// Returns the bloom filter loaded by the peer, if any
+    pub fn bloom_filter(&self) -> Option<&bloom::BloomFilter> {
+        self.bloom_filter.as_ref()
+    }
+
+    pub fn set_bloom_filter(&mut self, bloom_filter: bloom::BloomFilter) {
+        self.bloom_filter = Some(bloom_filter);
+    }
+
+    /// Removes the last block asked for and not delivered yet
     pub fn download_current_pop(&mut self) -> Option<crypto::Hash32> {
         self.download_current.pop()
     }
@@ -48,6 +118,12 @@ impl NodeHandle {
         self.state = NodeState::CONNECTING(ConnectionState::CLOSED);
         self.download_current = Vec::new();
         self.command_sender = command_sender;
+        self.addr = None;
+        self.fee_filter = 0;
+        self.relay = true;
+        self.known_inventory = HashSet::new();
+        self.latency = None;
+        self.bloom_filter = None;
     }
 
     pub fn send(
@@ -61,15 +137,42 @@ impl NodeHandle {
         &self.state
     }
 
-    pub fn set_state(&mut self, state: NodeState) {
+    /// Changes the state of the node, unless the state machine does not
+    /// allow it from the current one
+    pub fn set_state(&mut self, state: NodeState) -> Result<(), InvalidTransition> {
+        if !self.state.can_become(&state) {
+            log::error!(
+                "[{}] Invalid state change: {:?} => {:?}",
+                self.id,
+                self.state,
+                state
+            );
+            return Err(InvalidTransition {
+                from: self.state.clone(),
+                to: state,
+            });
+        }
         log::debug!("Update state: {:?} => {:?}", self.state, state);
         self.state = state;
+        Ok(())
     }
 
     pub fn id(&self) -> NodeId {
         self.id
     }
 
+    /// Remembers an inventory item announced by the peer. Returns whether it
+    /// had not been announced yet.
+    pub fn add_known_inventory(&mut self, hash: crypto::Hash32) -> bool {
+        if self.known_inventory.contains(&hash) {
+            return false;
+        }
+        if self.known_inventory.len() >= MAX_KNOWN_INVENTORY {
+            self.known_inventory.clear();
+        }
+        self.known_inventory.insert(hash)
+    }
+
     pub fn is_downloading(&self, hash: &crypto::Hash32) -> bool {
         if let Some(_) = self.download_current.iter().find(|&&x| x == *hash) {
             return true;
@@ -77,7 +180,9 @@ impl NodeHandle {
         false
     }
 
-    pub fn mark_downloaded(&mut self, block: &block::Block) {
+    /// Removes the block from the blocks being downloaded by the node.
+    /// Returns whether the node had been asked for it.
+    pub fn mark_downloaded(&mut self, block: &block::Block) -> bool {
         match self
             .download_current
             .iter()
@@ -85,20 +190,31 @@ impl NodeHandle {
         {
             Some(index) => {
                 log::debug!("[{}] Found {:?} at index {}", self.id, &block.hash(), index);
-                self.download_current.swap_remove(index);
+                // Keep the order of the blocks left, in which they are
+                // queued again if the node is restarted
+                self.download_current.remove(index);
+                true
+            }
+            None => {
+                log::warn!(
+                    "[{}] Block {} was not asked",
+                    self.id,
+                    hex::encode(block.hash())
+                );
+                false
             }
-            None => log::warn!(
-                "[{}] Block {} was not asked",
-                self.id,
-                hex::encode(block.hash())
-            ),
         }
     }
 
+    /// Asks the peer for the next blocks of the queue, unless it is already
+    /// downloading some. Blocks in `in_flight` are being downloaded from
+    /// another peer, and are dropped from the queue instead of being asked
+    /// twice; the ones asked here are added to it.
     pub fn download_next(
         &mut self,
         config: &Config,
         download_queue: &mut VecDeque<crypto::Hash32>,
+        in_flight: &mut HashSet<crypto::Hash32>,
     ) -> bool {
         match &self.state {
             NodeState::UPDATING_BLOCKS => {}
@@ -125,18 +241,27 @@ impl NodeHandle {
                 self.id,
                 download_queue.len()
             );
-            let count_to_download = min(MAX_DOWNLOADING_BLOCKS, download_queue.len());
+            while self.download_current.len() < config.max_downloading_blocks {
+                let hash = match download_queue.pop_front() {
+                    Some(hash) => hash,
+                    None => break,
+                };
+                if in_flight.insert(hash) {
+                    self.download_current.push(hash);
+                } else {
+                    log::debug!(
+                        "[{}] Block {} is already being downloaded",
+                        self.id,
+                        hex::encode(hash)
+                    );
+                }
+            }
 
-            if count_to_download == 0 {
+            if self.download_current.is_empty() {
                 log::debug!("[{}] Download queue is empty", self.id);
                 return false;
             }
 
-            for _ in 0..count_to_download {
-                self.download_current
-                    .push(download_queue.pop_front().unwrap());
-            }
-
             let download_current_str: Vec<String> = self
                 .download_current
                 .iter()
@@ -159,7 +284,7 @@ impl NodeHandle {
                         self.download_current
                             .iter()
                             .map(|elt| InvVect {
-                                hash_type: MSG_BLOCK,
+                                hash_type: MSG_WITNESS_BLOCK,
                                 hash: *elt,
                             })
                             .collect(),
@@ -180,16 +305,68 @@ impl NodeHandle {
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum NodeState {
+    DISCONNECTED, // no peer has been found for this slot
     CONNECTING(ConnectionState),
     UPDATING_PEERS,
     UPDATING_BLOCKS,
 }
 
+impl NodeState {
+    /// Returns whether a node may go from this state to the given one: it
+    /// connects, then asks for peers, then for blocks, and it may be
+    /// disconnected at any time
+    fn can_become(&self, state: &NodeState) -> bool {
+        match (self, state) {
+            (_, NodeState::DISCONNECTED) => true,
+            (NodeState::DISCONNECTED, NodeState::CONNECTING(_)) => true,
+            (NodeState::CONNECTING(_), NodeState::CONNECTING(_)) => true,
+            (NodeState::CONNECTING(_), NodeState::UPDATING_PEERS) => true,
+            (NodeState::UPDATING_PEERS, NodeState::UPDATING_BLOCKS) => true,
+            _ => false,
+        }
+    }
+}
+
+/// State change refused by `NodeHandle::set_state`
+#[derive(Debug, PartialEq)]
+pub struct InvalidTransition {
+    pub from: NodeState,
+    pub to: NodeState,
+}
+
 #[derive(Debug, Clone)]
 pub enum NodeCommand {
     SendMessage(message::MessageType),
     Kill,
     ConnectionClosed,
+    Misbehaving(Misbehavior),
+    Ping, // send a ping to measure the latency and keep the connection alive
+}
+
+/// Protocol violations a peer can commit. Each of them increases the
+/// ban score of the peer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Misbehavior {
+    InvalidChecksum,
+    InvalidHeader,
+    OversizedMessage,
+    InvalidBlock,
+    MessageFlood,        // messages received above the rate limit during a window
+    UnexpectedHandshake, // version or verack received out of the handshake sequence
+}
+
+impl Misbehavior {
+    /// Returns how much the ban score of the peer is increased
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidChecksum => 10,
+            Misbehavior::InvalidHeader => 20,
+            Misbehavior::OversizedMessage => 20,
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::MessageFlood => 10,
+            Misbehavior::UnexpectedHandshake => 1,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -202,9 +379,25 @@ pub struct NodeResponse {
 pub enum NodeResponseContent {
     Connected,
     Addrs(Vec<network::NetAddr>),
+    GetAddr, // the peer asks for the addresses we know
+    GetData(Vec<message::inv_base::InvVect>),
+    Inv(Vec<message::inv_base::InvVect>), // inventory announced by the peer
+    NotFound(Vec<message::inv_base::InvVect>), // items asked for which the peer does not have
     Headers(Vec<block::BlockHeader>),
+    GetHeaders(Vec<crypto::Hash32>, crypto::Hash32), // block locator and stop hash
     Block(block::Block),
+    Transaction(transaction::Transaction),
+    FeeFilter(u64), // minimum fee rate (satoshis per kB) of the transactions to announce
+    Filter(crypto::Hash32, cfilter::Filter), // compact filter of a block
+    GetCFilters(u8, u32, crypto::Hash32), // filter type, start height and stop hash
+    GetCFHeaders(u8, u32, crypto::Hash32), // filter type, start height and stop hash
+    GetCFCheckpt(u8, crypto::Hash32), // filter type and stop hash
+    LocalAddr(net::IpAddr), // our address as seen by the peer
+    Relay(bool),    // relay flag of the version of the peer
+    Latency(Duration), // round trip time between a ping and its pong
+    FilterLoad(bloom::BloomFilter), // bloom filter loaded by the peer
     ConnectionClosed,
+    Misbehavior(Misbehavior),
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -216,6 +409,70 @@ pub enum ConnectionState {
     ESTABLISHED,
 }
 
+/// Token bucket limiting the rate of the messages received from a peer
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64, // tokens added per second
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+    flood_reported: Option<Instant>, // when the last flood has been reported
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+            flood_reported: None,
+        }
+    }
+
+    /// Returns whether a flood happening at `now` must be reported, that is
+    /// whether none has been reported during the last FLOOD_REPORT_WINDOW
+    pub fn report_flood(&mut self, now: Instant) -> bool {
+        match self.flood_reported {
+            Some(reported) if now.saturating_duration_since(reported) < FLOOD_REPORT_WINDOW => {
+                false
+            }
+            _ => {
+                self.flood_reported = Some(now);
+                true
+            }
+        }
+    }
+
+    /// Takes a token for a message received at `now`. Returns false if the
+    /// bucket is empty, i.e. the message exceeds the rate limit.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Returns whether the message answers a request sent to the peer
+fn is_response(message_type: &message::MessageType) -> bool {
+    matches!(
+        message_type,
+        message::MessageType::Headers(_)
+            | message::MessageType::Block(_)
+            | message::MessageType::MerkleBlock(_)
+            | message::MessageType::NotFound(_)
+            | message::MessageType::Pong(_)
+            | message::MessageType::CFilter(_)
+            | message::MessageType::CFHeaders(_)
+            | message::MessageType::CFCheckpt(_)
+    )
+}
+
 pub enum CommandOrMessageType {
     Command(NodeCommand),
     MessageType(message::MessageType),
@@ -224,24 +481,37 @@ pub enum CommandOrMessageType {
 pub struct Node {
     node_id: usize,
     stream: net::TcpStream,
+    peer_addr: Option<net::SocketAddr>, // unknown if the peer disconnected right away
     state: ConnectionState,
     writer_receiver: mpsc::Receiver<CommandOrMessageType>,
     response_sender: mpsc::Sender<ControllerMessage>,
+    rate_limiter: RateLimiter,
+    ping_sent: Option<(u64, Instant)>, // nonce and time of the ping awaiting a pong
 }
 
 impl Node {
+    /// Creates a node talking to the peer of the stream. Only the messages
+    /// with the magic of the configuration are read from it, and the peer
+    /// is pinged then disconnected if it stays silent for too long.
     pub fn new(
         node_id: usize,
         stream: net::TcpStream,
+        config: &Config,
         command_receiver: mpsc::Receiver<NodeCommand>,
         response_sender: mpsc::Sender<ControllerMessage>,
     ) -> Self {
         let input_stream = stream.try_clone().unwrap();
+        let peer_addr = stream.peer_addr().ok();
+        // Without a timeout, a half-open connection blocks the reader forever
+        if let Err(err) = input_stream.set_read_timeout(Some(config.peer_timeout)) {
+            log::warn!("[{}] Could not set the read timeout: {}", node_id, err);
+        }
+        let magic = config.magic;
 
         let (writer_sender, writer_receiver) = mpsc::channel();
         let command_writer_sender = writer_sender.clone();
 
-        thread::spawn(move || reader(input_stream, writer_sender));
+        thread::spawn(move || reader(input_stream, magic, writer_sender));
         // thread::spawn(move || writer(output_stream, r_cw));
         thread::spawn(move || command(command_receiver, command_writer_sender));
 
@@ -249,34 +519,59 @@ impl Node {
             node_id,
             state: ConnectionState::CLOSED,
             stream,
+            peer_addr,
             writer_receiver,
             response_sender,
+            rate_limiter: RateLimiter::new(
+                config::DEFAULT_MAX_MESSAGE_RATE,
+                config::DEFAULT_MAX_MESSAGE_BURST,
+            ),
+            ping_sent: None,
         }
     }
 
     pub fn run(&mut self, config: &Config) {
         // Init connection by sending version message
         let my_addr: net::Ipv4Addr = "0.0.0.0".parse().unwrap();
-        let node_addr: net::Ipv6Addr = match self.stream.peer_addr().unwrap() {
-            net::SocketAddr::V4(addr) => addr.ip().to_ipv6_mapped(),
-            net::SocketAddr::V6(addr) => addr.ip().clone(),
+        let peer_addr = match self.peer_addr {
+            Some(addr) => addr,
+            None => {
+                log::warn!("[{}] Peer disconnected before the handshake", self.node_id);
+                self.send_response(NodeResponseContent::ConnectionClosed)
+                    .unwrap_or_default();
+                return;
+            }
         };
-        let port: u16 = self.stream.peer_addr().unwrap().port();
         let mut data = [0u8; 8];
         rand::thread_rng().fill_bytes(&mut data);
+        // Pruned nodes only serve the last blocks (BIP159)
+        let mut services = match config.prune_target_mib {
+            Some(_) => message::NODE_NETWORK_LIMITED,
+            None => message::NODE_NETWORK,
+        };
+        if config.build_cfilters {
+            services |= message::NODE_COMPACT_FILTERS;
+        }
+        let addr_from = match config.external_addr {
+            Some(ip) => network::NetAddrVersion::from_socket_addr(
+                services,
+                &net::SocketAddr::new(ip, config.port),
+            ),
+            None => network::NetAddrVersion::new(services, my_addr.to_ipv6_mapped(), 0),
+        };
         let version = message::version::MessageVersion::new(
             70013,
-            message::NODE_NETWORK,
+            services,
             SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_secs() as u64,
-            network::NetAddrVersion::new(message::NODE_NETWORK, node_addr, port),
-            network::NetAddrVersion::new(message::NODE_NETWORK, my_addr.to_ipv6_mapped(), 0),
+            network::NetAddrVersion::from_socket_addr(message::NODE_NETWORK, &peer_addr),
+            addr_from,
             u64::from_le_bytes(data),
             "/yasbit:0.1.0/".to_string(),
             0,
-            true,
+            config.relay,
         );
         log::debug!(
             "[{}]: Sending version message : {:?}",
@@ -284,10 +579,11 @@ impl Node {
             version
         );
         let message = message::Message::new(config.magic, version);
-        self.stream.write(&message.bytes()).unwrap();
+        self.stream.write_all(&message.bytes()).unwrap();
         self.stream.flush().unwrap();
 
         self.state = ConnectionState::VER_SENT;
+        self.rate_limiter = RateLimiter::new(config.max_message_rate, config.max_message_burst);
 
         // This is the writer thread, the main thread managing this node
         // It reads from reader and command and eventually send messages
@@ -295,9 +591,11 @@ impl Node {
         loop {
             let should_break = match self.writer_receiver.recv().unwrap() {
                 CommandOrMessageType::MessageType(message_type) => {
-                    self.handle_message(config, message_type)
+                    self.receive_message(config, message_type)
+                }
+                CommandOrMessageType::Command(node_command) => {
+                    self.handle_command(config, node_command)
                 }
-                CommandOrMessageType::Command(node_command) => self.handle_command(node_command),
             };
             if should_break {
                 log::info!("[{}]: Terminate thread", self.node_id);
@@ -306,11 +604,11 @@ impl Node {
         }
     }
 
-    pub fn handle_command(&mut self, node_command: NodeCommand) -> bool {
+    pub fn handle_command(&mut self, config: &Config, node_command: NodeCommand) -> bool {
         match node_command {
             NodeCommand::SendMessage(message) => {
-                log::debug!("[{}] Send message: {:?}", self.node_id, &message);
-                self.stream.write(&message.bytes()).unwrap();
+                log::debug!("[{}] Send {} message", self.node_id, message);
+                self.stream.write_all(&message.bytes()).unwrap();
                 self.stream.flush().unwrap();
                 false
             }
@@ -326,80 +624,85 @@ impl Node {
                     .unwrap();
                 false
             }
+            NodeCommand::Misbehaving(misbehavior) => {
+                log::warn!("[{}] Peer misbehaved: {:?}", self.node_id, misbehavior);
+                self.send_response(NodeResponseContent::Misbehavior(misbehavior))
+                    .unwrap();
+                false
+            }
+            NodeCommand::Ping => {
+                self.send_ping(config);
+                false
+            }
         }
     }
 
-    pub fn handle_message(&mut self, config: &Config, message_type: message::MessageType) -> bool {
-        match message_type {
-            message::MessageType::Alert(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::Version(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::Verack(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::GetAddr(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::Addr(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::Ping(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::Pong(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::GetHeaders(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::FeeFilter(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::SendHeaders(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::Inv(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::GetBlocks(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::GetData(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::NotFound(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
-            }
-            message::MessageType::Headers(mess) => {
-                // display_message(&self.node_id, &mess.command);
-                log::debug!(
-                    "[{}] Received {} message",
-                    self.node_id,
-                    std::str::from_utf8(&mess.command.name()).unwrap(),
-                );
-                mess.command.handle(self, config)
+    /// Sends a ping with a random nonce, replacing the one awaiting a pong
+    fn send_ping(&mut self, config: &Config) {
+        let nonce = rand::thread_rng().next_u64();
+        let ping = message::Message::new(config.magic, message::ping::MessagePing::new(nonce));
+        log::debug!("[{}] Sending ping message: {}", self.node_id, nonce);
+        self.stream.write_all(&ping.bytes()).unwrap();
+        self.stream.flush().unwrap();
+        self.ping_sent = Some((nonce, Instant::now()));
+    }
+
+    /// Returns the time elapsed since the ping answered by a pong with the
+    /// given nonce, or None if no such ping is awaiting a pong
+    pub fn pong_received(&mut self, nonce: u64) -> Option<Duration> {
+        match self.ping_sent {
+            Some((sent_nonce, sent_at)) if sent_nonce == nonce => {
+                self.ping_sent = None;
+                Some(sent_at.elapsed())
             }
-            message::MessageType::Block(mess) => {
-                display_message(&self.node_id, &mess.command);
-                mess.command.handle(self, config)
+            _ => None,
+        }
+    }
+
+    /// Handles a message received from the peer, unless it exceeds the rate
+    /// limit. Such messages are dropped, and the flood increases the ban
+    /// score once per window. Answers to our requests are never dropped.
+    pub fn receive_message(&mut self, config: &Config, message_type: message::MessageType) -> bool {
+        let now = Instant::now();
+        if !is_response(&message_type) && !self.rate_limiter.allow(now) {
+            log::debug!("[{}] Rate limit exceeded, drop message", self.node_id);
+            if self.rate_limiter.report_flood(now) {
+                self.send_response(NodeResponseContent::Misbehavior(Misbehavior::MessageFlood))
+                    .unwrap();
             }
+            return false;
+        }
+        self.handle_message(config, message_type)
+    }
+
+    pub fn handle_message(&mut self, config: &Config, message_type: message::MessageType) -> bool {
+        log::debug!("[{}] Received {} message", self.node_id, message_type);
+        match message_type {
+            message::MessageType::Alert(mess) => mess.command.handle(self, config),
+            message::MessageType::Version(mess) => mess.command.handle(self, config),
+            message::MessageType::Verack(mess) => mess.command.handle(self, config),
+            message::MessageType::GetAddr(mess) => mess.command.handle(self, config),
+            message::MessageType::Addr(mess) => mess.command.handle(self, config),
+            message::MessageType::Ping(mess) => mess.command.handle(self, config),
+            message::MessageType::Pong(mess) => mess.command.handle(self, config),
+            message::MessageType::GetHeaders(mess) => mess.command.handle(self, config),
+            message::MessageType::FeeFilter(mess) => mess.command.handle(self, config),
+            message::MessageType::SendHeaders(mess) => mess.command.handle(self, config),
+            message::MessageType::Inv(mess) => mess.command.handle(self, config),
+            message::MessageType::GetBlocks(mess) => mess.command.handle(self, config),
+            message::MessageType::GetData(mess) => mess.command.handle(self, config),
+            message::MessageType::NotFound(mess) => mess.command.handle(self, config),
+            message::MessageType::Headers(mess) => mess.command.handle(self, config),
+            message::MessageType::Block(mess) => mess.command.handle(self, config),
+            message::MessageType::Tx(mess) => mess.command.handle(self, config),
+            message::MessageType::GetCFilters(mess) => mess.command.handle(self, config),
+            message::MessageType::CFilter(mess) => mess.command.handle(self, config),
+            message::MessageType::GetCFHeaders(mess) => mess.command.handle(self, config),
+            message::MessageType::CFHeaders(mess) => mess.command.handle(self, config),
+            message::MessageType::GetCFCheckpt(mess) => mess.command.handle(self, config),
+            message::MessageType::CFCheckpt(mess) => mess.command.handle(self, config),
+            message::MessageType::FilterLoad(mess) => mess.command.handle(self, config),
+            message::MessageType::MerkleBlock(mess) => mess.command.handle(self, config),
         };
         false
     }
@@ -420,6 +723,21 @@ impl Node {
         self.state = state;
     }
 
+    /// Reports the misbehavior of the peer and closes the connection. The
+    /// reader then notifies the controller, which restarts the node.
+    pub fn disconnect(&mut self, misbehavior: Misbehavior) {
+        log::warn!(
+            "[{}] Disconnect peer which misbehaved: {:?}",
+            self.node_id,
+            misbehavior
+        );
+        self.send_response(NodeResponseContent::Misbehavior(misbehavior))
+            .unwrap_or_default();
+        self.stream
+            .shutdown(net::Shutdown::Both)
+            .unwrap_or_default();
+    }
+
     pub fn send_response(
         &mut self,
         content: NodeResponseContent,
@@ -448,14 +766,43 @@ fn command(
     }
 }
 
-fn reader(mut stream: net::TcpStream, t_rc: mpsc::Sender<CommandOrMessageType>) {
+fn reader(mut stream: net::TcpStream, magic: u32, t_rc: mpsc::Sender<CommandOrMessageType>) {
     let mut bytes = Vec::new();
     let mut buffer = [0 as u8; 100];
     let mut remaining_bytes = 0;
+    let mut silent = false;
     loop {
-        let received_bytes = stream.read(&mut buffer).unwrap();
+        let received_bytes = match stream.read(&mut buffer) {
+            Ok(received_bytes) => {
+                silent = false;
+                received_bytes
+            }
+            Err(err)
+                if err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                if silent {
+                    log::warn!("Remote {:?} did not answer a ping", stream.peer_addr().ok());
+                    0
+                } else {
+                    // A live peer has to answer with a pong
+                    silent = true;
+                    if t_rc
+                        .send(CommandOrMessageType::Command(NodeCommand::Ping))
+                        .is_err()
+                    {
+                        break;
+                    }
+                    continue;
+                }
+            }
+            Err(err) => {
+                log::warn!("Could not read from remote: {}", err);
+                0
+            }
+        };
         if received_bytes == 0 {
-            log::warn!("Remote {:?} closed connection", stream.peer_addr().unwrap());
+            log::warn!("Remote {:?} closed connection", stream.peer_addr().ok());
             // Send a notification to the controller so that it can
             t_rc.send(CommandOrMessageType::Command(NodeCommand::ConnectionClosed))
                 .unwrap();
@@ -475,7 +822,7 @@ fn reader(mut stream: net::TcpStream, t_rc: mpsc::Sender<CommandOrMessageType>)
             let previous_bytes = bytes.len();
             bytes.extend_from_slice(&buffer[index..(curr_mess_bytes + index)]);
 
-            match message::parse(&bytes) {
+            match message::parse(&bytes, magic) {
                 Ok((message_type, used_bytes)) => {
                     curr_mess_bytes = used_bytes - previous_bytes;
                     // Send the message to the controller
@@ -485,6 +832,34 @@ fn reader(mut stream: net::TcpStream, t_rc: mpsc::Sender<CommandOrMessageType>)
                 Err(message::ParseError::Partial(needed)) => {
                     remaining_bytes = needed;
                 }
+                Err(message::ParseError::InvalidChecksum(frame_length)) => {
+                    log::warn!("Received a message with an invalid checksum. Skip it.");
+                    // Magic, name and length are likely intact: skip the whole
+                    // frame and resume parsing with the next message
+                    curr_mess_bytes = frame_length - previous_bytes;
+                    t_rc.send(CommandOrMessageType::Command(NodeCommand::Misbehaving(
+                        Misbehavior::InvalidChecksum,
+                    )))
+                    .unwrap();
+                }
+                Err(message::ParseError::UnknownMessage(name, frame_length)) => {
+                    log::debug!("Received unsupported {} message. Skip it.", name);
+                    // Resume parsing right after the frame
+                    curr_mess_bytes = frame_length - previous_bytes;
+                }
+                Err(message::ParseError::OversizedMessage(length)) => {
+                    log::warn!("Received an oversized message ({} bytes)", length);
+                    // Skipping the frame would mean reading its whole announced
+                    // length: close the connection instead
+                    t_rc.send(CommandOrMessageType::Command(NodeCommand::Misbehaving(
+                        Misbehavior::OversizedMessage,
+                    )))
+                    .unwrap();
+                    stream.shutdown(net::Shutdown::Both).unwrap_or_default();
+                    t_rc.send(CommandOrMessageType::Command(NodeCommand::ConnectionClosed))
+                        .unwrap();
+                    return;
+                }
                 Err(err) => {
                     log::warn!(
                         "Could not parse received message: {:?}.\n Message received: {:?}",
@@ -508,11 +883,586 @@ fn reader(mut stream: net::TcpStream, t_rc: mpsc::Sender<CommandOrMessageType>)
     }
 }
 
-fn display_message<T: message::MessageCommand + std::fmt::Debug>(node_id: &NodeId, command: &T) {
-    log::debug!(
-        "[{}] Received {} message: {:?}",
-        node_id,
-        std::str::from_utf8(&command.name()).unwrap(),
-        command
-    );
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config;
+    use crate::variable_integer::VariableInteger;
+
+    /// Spawns a reader on a local connection, writes the given bytes on it
+    /// then closes the connection. Returns what the reader produced.
+    fn read_bytes(bytes: &[u8]) -> Vec<CommandOrMessageType> {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let magic = config::regtest_config().magic;
+        thread::spawn(move || reader(server, magic, sender));
+
+        client.write_all(bytes).unwrap();
+        client.shutdown(net::Shutdown::Both).unwrap();
+
+        let mut received = Vec::new();
+        loop {
+            let item = receiver.recv().unwrap();
+            let closed = match &item {
+                CommandOrMessageType::Command(NodeCommand::ConnectionClosed) => true,
+                _ => false,
+            };
+            received.push(item);
+            if closed {
+                break;
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn test_reader_skips_invalid_checksum() {
+        let config = config::regtest_config();
+
+        // A block message spanning several reads, with a corrupted payload
+        let mut bytes = message::Message::new(
+            config.magic,
+            message::block::MessageBlock::new(config.genesis_block.clone()),
+        )
+        .bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        bytes.extend_from_slice(
+            &message::Message::new(config.magic, message::ping::MessagePing::new(42)).bytes(),
+        );
+
+        let received = read_bytes(&bytes);
+        assert_eq!(received.len(), 3);
+        match &received[0] {
+            CommandOrMessageType::Command(NodeCommand::Misbehaving(
+                Misbehavior::InvalidChecksum,
+            )) => (),
+            _ => panic!("Expected an invalid checksum notification"),
+        }
+        match &received[1] {
+            CommandOrMessageType::MessageType(message::MessageType::Ping(ping)) => {
+                assert_eq!(ping.command, message::ping::MessagePing::new(42))
+            }
+            _ => panic!("Expected a ping message"),
+        }
+    }
+
+    #[test]
+    fn test_reader_skips_unknown_message() {
+        let config = config::regtest_config();
+
+        let mut unknown =
+            message::Message::new(config.magic, message::ping::MessagePing::new(0)).bytes();
+        let mut name = [0; 12];
+        name[..7].copy_from_slice(b"mempool");
+        unknown[4..16].copy_from_slice(&name);
+
+        let mut bytes =
+            message::Message::new(config.magic, message::ping::MessagePing::new(1)).bytes();
+        bytes.extend_from_slice(&unknown);
+        bytes.extend_from_slice(
+            &message::Message::new(config.magic, message::ping::MessagePing::new(2)).bytes(),
+        );
+
+        let received = read_bytes(&bytes);
+        assert_eq!(received.len(), 3);
+        for (item, nonce) in received.iter().zip(&[1, 2]) {
+            match item {
+                CommandOrMessageType::MessageType(message::MessageType::Ping(ping)) => {
+                    assert_eq!(ping.command, message::ping::MessagePing::new(*nonce))
+                }
+                _ => panic!("Expected a ping message"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reader_closes_on_oversized_message() {
+        let config = config::regtest_config();
+
+        let mut bytes =
+            message::Message::new(config.magic, message::ping::MessagePing::new(1)).bytes();
+        bytes[16..20].copy_from_slice(&(message::MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+        bytes.extend_from_slice(
+            &message::Message::new(config.magic, message::ping::MessagePing::new(2)).bytes(),
+        );
+
+        // The following ping is never parsed
+        let received = read_bytes(&bytes);
+        assert_eq!(received.len(), 2);
+        match &received[0] {
+            CommandOrMessageType::Command(NodeCommand::Misbehaving(
+                Misbehavior::OversizedMessage,
+            )) => (),
+            _ => panic!("Expected an oversized message notification"),
+        }
+    }
+
+    #[test]
+    fn test_peer_disconnected_before_run() {
+        let config = config::regtest_config();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        // Closing a socket with unread data resets the connection
+        server.write_all(b"unread").unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+        drop(client);
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert!(server.peer_addr().is_err());
+
+        let (_command_sender, command_receiver) = mpsc::channel();
+        let (response_sender, response_receiver) = mpsc::channel();
+        let mut node = Node::new(0, server, &config, command_receiver, response_sender);
+        node.run(&config);
+
+        match response_receiver.try_recv() {
+            Ok(ControllerMessage::NodeResponse(NodeResponse {
+                node_id: 0,
+                content: NodeResponseContent::ConnectionClosed,
+            })) => (),
+            _ => panic!("Expected a ConnectionClosed response"),
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(10, 5);
+        limiter.last_refill = start;
+        for _ in 0..5 {
+            assert!(limiter.allow(start));
+        }
+        assert!(!limiter.allow(start));
+        // One token every 100ms
+        assert!(limiter.allow(start + std::time::Duration::from_millis(100)));
+        assert!(!limiter.allow(start + std::time::Duration::from_millis(150)));
+        // The bucket does not fill beyond the burst
+        let later = start + std::time::Duration::from_secs(10);
+        for _ in 0..5 {
+            assert!(limiter.allow(later));
+        }
+        assert!(!limiter.allow(later));
+    }
+
+    #[test]
+    fn test_silent_peer() {
+        let mut config = config::regtest_config();
+        config.peer_timeout = Duration::from_millis(200);
+        // The peer accepts the connection then never sends anything
+        let (mut node, mut client, response_receiver) = local_node(&config);
+        let start = Instant::now();
+
+        // It is pinged once the timeout elapsed
+        match node.writer_receiver.recv().unwrap() {
+            CommandOrMessageType::Command(NodeCommand::Ping) => (),
+            _ => panic!("Expected a Ping command"),
+        }
+        assert!(start.elapsed() >= config.peer_timeout);
+        node.handle_command(&config, NodeCommand::Ping);
+        let mut buffer = [0; 100];
+        let size = client.read(&mut buffer).unwrap();
+        match message::parse(&buffer[..size], config.magic) {
+            Ok((message::MessageType::Ping(_), _)) => (),
+            _ => panic!("Expected a ping message"),
+        }
+
+        // Then disconnected as it does not answer
+        match node.writer_receiver.recv().unwrap() {
+            CommandOrMessageType::Command(NodeCommand::ConnectionClosed) => (),
+            _ => panic!("Expected a ConnectionClosed command"),
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= 2 * config.peer_timeout);
+        assert!(elapsed < Duration::from_secs(2));
+        node.handle_command(&config, NodeCommand::ConnectionClosed);
+        match response_receiver.try_recv() {
+            Ok(ControllerMessage::NodeResponse(NodeResponse {
+                node_id: 0,
+                content: NodeResponseContent::ConnectionClosed,
+            })) => (),
+            _ => panic!("Expected a ConnectionClosed response"),
+        }
+    }
+
+    #[test]
+    fn test_ping_flood() {
+        let config = config::regtest_config();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let (_command_sender, command_receiver) = mpsc::channel();
+        let (response_sender, response_receiver) = mpsc::channel();
+        let mut node = Node::new(0, server, &config, command_receiver, response_sender);
+        node.rate_limiter = RateLimiter::new(1, 10);
+
+        let mut flood = |node: &mut Node| {
+            for nonce in 0..200 {
+                let ping =
+                    message::Message::new(config.magic, message::ping::MessagePing::new(nonce));
+                node.receive_message(&config, message::MessageType::Ping(ping));
+            }
+        };
+        let mut reports = || {
+            let mut reports = 0;
+            while let Ok(ControllerMessage::NodeResponse(response)) = response_receiver.try_recv() {
+                match response.content {
+                    NodeResponseContent::Misbehavior(Misbehavior::MessageFlood) => reports += 1,
+                    _ => panic!("Unexpected response"),
+                }
+            }
+            reports
+        };
+
+        // The flood is reported once per window
+        flood(&mut node);
+        assert_eq!(reports(), 1);
+        flood(&mut node);
+        assert_eq!(reports(), 0);
+        node.rate_limiter.flood_reported = Some(Instant::now() - FLOOD_REPORT_WINDOW);
+        flood(&mut node);
+        assert_eq!(reports(), 1);
+
+        // The blocks asked for are still handled
+        let block = message::Message::new(
+            config.magic,
+            message::block::MessageBlock::new(config.genesis_block.clone()),
+        );
+        node.receive_message(&config, message::MessageType::Block(block));
+        match response_receiver.try_recv() {
+            Ok(ControllerMessage::NodeResponse(NodeResponse {
+                content: NodeResponseContent::Block(_),
+                ..
+            })) => (),
+            _ => panic!("Expected a Block response"),
+        }
+    }
+
+    #[test]
+    fn test_ping_latency() {
+        let config = config::regtest_config();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        // The peer delays its pong
+        let delay = Duration::from_millis(200);
+        let magic = config.magic;
+        thread::spawn(move || {
+            let mut bytes = Vec::new();
+            let mut buffer = [0; 100];
+            loop {
+                let size = client.read(&mut buffer).unwrap();
+                bytes.extend_from_slice(&buffer[..size]);
+                if let Ok((message::MessageType::Ping(ping), _)) = message::parse(&bytes, magic) {
+                    thread::sleep(delay);
+                    let nonce =
+                        u64::from_le_bytes(crate::utils::clone_into_array(&ping.command.bytes()));
+                    let pong = message::pong::MessagePong::new(nonce);
+                    client
+                        .write_all(&message::Message::new(magic, pong).bytes())
+                        .unwrap();
+                    thread::sleep(Duration::from_secs(5));
+                    return;
+                }
+            }
+        });
+
+        let (_command_sender, command_receiver) = mpsc::channel();
+        let (response_sender, response_receiver) = mpsc::channel();
+        let mut node = Node::new(0, server, &config, command_receiver, response_sender);
+
+        // A pong which answers no ping is ignored
+        let pong = message::Message::new(config.magic, message::pong::MessagePong::new(42));
+        node.handle_message(&config, message::MessageType::Pong(pong));
+        assert!(response_receiver.try_recv().is_err());
+
+        node.handle_command(&config, NodeCommand::Ping);
+        let pong = match node.writer_receiver.recv().unwrap() {
+            CommandOrMessageType::MessageType(pong @ message::MessageType::Pong(_)) => pong,
+            _ => panic!("Expected a pong message"),
+        };
+        node.handle_message(&config, pong.clone());
+        match response_receiver.try_recv() {
+            Ok(ControllerMessage::NodeResponse(NodeResponse {
+                node_id: 0,
+                content: NodeResponseContent::Latency(latency),
+            })) => assert!(latency >= delay),
+            _ => panic!("Expected a Latency response"),
+        }
+
+        // The same pong is not measured twice
+        node.handle_message(&config, pong);
+        assert!(response_receiver.try_recv().is_err());
+    }
+
+    /// Returns a node talking to a local peer, with the peer side of the
+    /// connection and the receiver of the responses of the node
+    fn local_node(config: &Config) -> (Node, net::TcpStream, mpsc::Receiver<ControllerMessage>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let (_command_sender, command_receiver) = mpsc::channel();
+        let (response_sender, response_receiver) = mpsc::channel();
+        let node = Node::new(0, server, config, command_receiver, response_sender);
+        (node, client, response_receiver)
+    }
+
+    /// Asserts that the node reported an unexpected handshake message then
+    /// closed the connection
+    fn assert_disconnected(
+        node: &Node,
+        mut client: net::TcpStream,
+        response_receiver: &mpsc::Receiver<ControllerMessage>,
+    ) {
+        match response_receiver.try_recv() {
+            Ok(ControllerMessage::NodeResponse(NodeResponse {
+                node_id: 0,
+                content: NodeResponseContent::Misbehavior(Misbehavior::UnexpectedHandshake),
+            })) => (),
+            _ => panic!("Expected a Misbehavior response"),
+        }
+        assert!(response_receiver.try_recv().is_err());
+        // Both the peer and the reader of the node see the connection closed
+        let mut bytes = Vec::new();
+        client.read_to_end(&mut bytes).unwrap();
+        loop {
+            match node.writer_receiver.recv().unwrap() {
+                CommandOrMessageType::Command(NodeCommand::ConnectionClosed) => break,
+                CommandOrMessageType::Command(_) => panic!("Unexpected command"),
+                CommandOrMessageType::MessageType(_) => (),
+            }
+        }
+    }
+
+    #[test]
+    fn test_duplicate_version() {
+        let config = config::regtest_config();
+        let (mut node, client, response_receiver) = local_node(&config);
+        node.set_connection_state(ConnectionState::VER_SENT);
+
+        let addr = net::Ipv4Addr::LOCALHOST.to_ipv6_mapped();
+        let version = message::version::MessageVersion::new(
+            70013,
+            message::NODE_NETWORK,
+            0,
+            network::NetAddrVersion::new(message::NODE_NETWORK, addr, 0),
+            network::NetAddrVersion::new(message::NODE_NETWORK, addr, 0),
+            0,
+            "/test/".to_string(),
+            0,
+            true,
+        );
+        let version = message::MessageType::Version(message::Message::new(config.magic, version));
+        node.handle_message(&config, version.clone());
+        assert_eq!(node.connection_state(), &ConnectionState::VER_RECEIVED);
+        while let Ok(ControllerMessage::NodeResponse(response)) = response_receiver.try_recv() {
+            match response.content {
+                NodeResponseContent::LocalAddr(_) | NodeResponseContent::Relay(_) => (),
+                _ => panic!("Unexpected response"),
+            }
+        }
+
+        node.handle_message(&config, version);
+        assert_eq!(node.connection_state(), &ConnectionState::VER_RECEIVED);
+        assert_disconnected(&node, client, &response_receiver);
+    }
+
+    #[test]
+    fn test_duplicate_verack() {
+        let config = config::regtest_config();
+        let (mut node, client, response_receiver) = local_node(&config);
+        node.set_connection_state(ConnectionState::VER_SENT);
+
+        let verack = message::MessageType::Verack(message::Message::new(
+            config.magic,
+            message::verack::MessageVerack::new(),
+        ));
+        node.handle_message(&config, verack.clone());
+        assert_eq!(node.connection_state(), &ConnectionState::VERACK_RECEIVED);
+        assert!(response_receiver.try_recv().is_err());
+
+        node.handle_message(&config, verack);
+        assert_eq!(node.connection_state(), &ConnectionState::VERACK_RECEIVED);
+        assert_disconnected(&node, client, &response_receiver);
+    }
+
+    #[test]
+    fn test_oversized_headers() {
+        let config = config::regtest_config();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let (_command_sender, command_receiver) = mpsc::channel();
+        let (response_sender, response_receiver) = mpsc::channel();
+        let mut node = Node::new(0, server, &config, command_receiver, response_sender);
+
+        // A headers message announcing 100,000 headers
+        let mut payload = VariableInteger::new(100_000).bytes();
+        payload.extend_from_slice(&config.genesis_block.header.bytes());
+        payload.push(0);
+        let headers = message::headers::MessageHeaders::from_bytes(&payload);
+        node.receive_message(
+            &config,
+            message::MessageType::Headers(message::Message::new(config.magic, headers)),
+        );
+
+        match response_receiver.try_recv() {
+            Ok(ControllerMessage::NodeResponse(NodeResponse {
+                node_id: 0,
+                content: NodeResponseContent::Misbehavior(Misbehavior::OversizedMessage),
+            })) => (),
+            _ => panic!("Expected a Misbehavior response"),
+        }
+        assert!(response_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_state_transitions() {
+        let (command_sender, _command_receiver) = mpsc::channel();
+        let mut node_handle = NodeHandle::new(0, command_sender);
+
+        // Peers must be asked for before blocks
+        assert_eq!(
+            node_handle.set_state(NodeState::UPDATING_BLOCKS),
+            Err(InvalidTransition {
+                from: NodeState::CONNECTING(ConnectionState::CLOSED),
+                to: NodeState::UPDATING_BLOCKS,
+            })
+        );
+        assert_eq!(
+            node_handle.state(),
+            &NodeState::CONNECTING(ConnectionState::CLOSED)
+        );
+
+        assert!(node_handle.set_state(NodeState::UPDATING_PEERS).is_ok());
+        assert!(node_handle
+            .set_state(NodeState::CONNECTING(ConnectionState::CLOSED))
+            .is_err());
+        assert!(node_handle.set_state(NodeState::UPDATING_BLOCKS).is_ok());
+        assert!(node_handle.set_state(NodeState::UPDATING_PEERS).is_err());
+        assert_eq!(node_handle.state(), &NodeState::UPDATING_BLOCKS);
+
+        // A disconnected node must connect again first
+        assert!(node_handle.set_state(NodeState::DISCONNECTED).is_ok());
+        assert!(node_handle.set_state(NodeState::UPDATING_PEERS).is_err());
+        assert!(node_handle
+            .set_state(NodeState::CONNECTING(ConnectionState::CLOSED))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_download_window() {
+        let mut config = config::regtest_config();
+        config.max_downloading_blocks = 4;
+        let (command_sender, _command_receiver) = mpsc::channel();
+        let mut node_handle = NodeHandle::new(0, command_sender);
+        node_handle.set_state(NodeState::UPDATING_PEERS).unwrap();
+        node_handle.set_state(NodeState::UPDATING_BLOCKS).unwrap();
+
+        let mut download_queue: VecDeque<crypto::Hash32> = (0..10u8).map(|i| [i; 32]).collect();
+        let mut in_flight = HashSet::new();
+        while !download_queue.is_empty() {
+            assert!(node_handle.download_next(&config, &mut download_queue, &mut in_flight));
+            assert!(node_handle.download_current.len() <= 4);
+            // Already downloading: nothing more is requested
+            node_handle.download_next(&config, &mut download_queue, &mut in_flight);
+            assert!(node_handle.download_current.len() <= 4);
+            while node_handle.download_current_pop().is_some() {}
+        }
+        assert!(!node_handle.download_next(&config, &mut download_queue, &mut in_flight));
+        assert_eq!(in_flight.len(), 10);
+
+        // Blocks downloaded from another peer are not asked again
+        download_queue = (9..12u8).map(|i| [i; 32]).collect();
+        assert!(node_handle.download_next(&config, &mut download_queue, &mut in_flight));
+        assert_eq!(node_handle.download_current, vec![[10; 32], [11; 32]]);
+    }
+
+    /// Returns a handle downloading the given blocks
+    fn downloading_handle(blocks: &[&block::Block]) -> (NodeHandle, mpsc::Receiver<NodeCommand>) {
+        let config = config::regtest_config();
+        let (command_sender, command_receiver) = mpsc::channel();
+        let mut node_handle = NodeHandle::new(0, command_sender);
+        node_handle.set_state(NodeState::UPDATING_PEERS).unwrap();
+        node_handle.set_state(NodeState::UPDATING_BLOCKS).unwrap();
+        let mut download_queue = blocks.iter().map(|block| block.hash()).collect();
+        assert!(node_handle.download_next(&config, &mut download_queue, &mut HashSet::new()));
+        (node_handle, command_receiver)
+    }
+
+    #[test]
+    fn test_is_downloading() {
+        let genesis = config::regtest_config().genesis_block;
+        let block = crate::storage::next_block(&genesis, 1);
+        let (mut node_handle, _command_receiver) = downloading_handle(&[&genesis, &block]);
+
+        assert!(node_handle.is_downloading(&genesis.hash()));
+        assert!(node_handle.is_downloading(&block.hash()));
+        assert!(!node_handle.is_downloading(&[0; 32]));
+        node_handle.mark_downloaded(&genesis);
+        assert!(!node_handle.is_downloading(&genesis.hash()));
+        assert!(node_handle.is_downloading(&block.hash()));
+    }
+
+    #[test]
+    fn test_download_current_pop() {
+        let genesis = config::regtest_config().genesis_block;
+        let block = crate::storage::next_block(&genesis, 1);
+        let (mut node_handle, _command_receiver) = downloading_handle(&[&genesis, &block]);
+
+        assert_eq!(node_handle.download_current_pop(), Some(block.hash()));
+        assert!(!node_handle.is_downloading(&block.hash()));
+        assert_eq!(node_handle.download_current_pop(), Some(genesis.hash()));
+        assert_eq!(node_handle.download_current_pop(), None);
+    }
+
+    #[test]
+    fn test_reset() {
+        let genesis = config::regtest_config().genesis_block;
+        let (mut node_handle, old_receiver) = downloading_handle(&[&genesis]);
+        node_handle.set_addr("10.0.0.1:18444".parse().unwrap());
+        node_handle.set_fee_filter(1000);
+        node_handle.set_relay(false);
+        assert!(node_handle.add_known_inventory(genesis.hash()));
+        assert!(!node_handle.add_known_inventory(genesis.hash()));
+
+        node_handle.send(NodeCommand::Kill).unwrap();
+        match old_receiver.try_recv() {
+            Ok(NodeCommand::SendMessage(message::MessageType::GetData(_))) => (),
+            other => panic!("Unexpected command: {:?}", other),
+        }
+        match old_receiver.try_recv() {
+            Ok(NodeCommand::Kill) => (),
+            other => panic!("Unexpected command: {:?}", other),
+        }
+
+        let (command_sender, command_receiver) = mpsc::channel();
+        node_handle.reset(command_sender);
+        assert_eq!(
+            node_handle.state(),
+            &NodeState::CONNECTING(ConnectionState::CLOSED)
+        );
+        assert!(!node_handle.is_downloading(&genesis.hash()));
+        assert_eq!(node_handle.download_current_pop(), None);
+        assert_eq!(node_handle.addr(), None);
+        assert_eq!(node_handle.fee_filter(), 0);
+        assert!(node_handle.relay());
+        assert!(node_handle.add_known_inventory(genesis.hash()));
+
+        // Commands go to the new sender only
+        node_handle.send(NodeCommand::Kill).unwrap();
+        assert!(old_receiver.try_recv().is_err());
+        match command_receiver.try_recv() {
+            Ok(NodeCommand::Kill) => (),
+            other => panic!("Unexpected command: {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,464 @@
+use crate::transaction::{Transaction, TxOutput};
+use crate::utils;
+use crate::variable_integer::VariableInteger;
+
+// "psbt" followed by 0xff
+const MAGIC: &[u8] = b"psbt\xff";
+// Key types of the records of the global map
+const GLOBAL_UNSIGNED_TX: u8 = 0x00;
+// Key types of the records of the input maps
+const IN_NON_WITNESS_UTXO: u8 = 0x00;
+const IN_WITNESS_UTXO: u8 = 0x01;
+const IN_SIGHASH_TYPE: u8 = 0x03;
+
+/// Records which are not parsed, e.g. partial signatures, kept as they are
+/// to be written back: their key, starting with the key type, and value
+pub type UnknownRecords = Vec<(Vec<u8>, Vec<u8>)>;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    BadMagic,
+    Truncated,
+    MissingUnsignedTx,
+    SignedTx,         // the transaction has scriptSigs or witnesses
+    BadRecord(u8),    // the record with this key type can not be parsed
+    DuplicateKey(u8), // two records of a map have the same key, of this key type
+    MapCount,         // the maps do not match the inputs and outputs
+}
+
+/// Fields of an input needed to sign it
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PsbtInput {
+    pub non_witness_utxo: Option<Transaction>, // transaction spent by a legacy input
+    pub witness_utxo: Option<TxOutput>,        // output spent by a witness input
+    pub sighash_type: Option<u32>,
+    pub unknown: UnknownRecords,
+}
+
+/// Fields of an output, none of which is parsed
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PsbtOutput {
+    pub unknown: UnknownRecords,
+}
+
+/// Partially signed bitcoin transaction (BIP174), exported to be signed
+/// elsewhere. Only the records needed to sign P2PKH and P2WPKH inputs are
+/// parsed, the other ones are kept unknown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Psbt {
+    pub unsigned_tx: Transaction,
+    pub unknown: UnknownRecords,
+    pub inputs: Vec<PsbtInput>,   // one per input of the transaction
+    pub outputs: Vec<PsbtOutput>, // one per output of the transaction
+}
+
+/// Appends a key-value record to the bytes of a map
+fn push_record(bytes: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    bytes.extend_from_slice(&VariableInteger::new(key.len() as u64).bytes());
+    bytes.extend_from_slice(key);
+    bytes.extend_from_slice(&VariableInteger::new(value.len() as u64).bytes());
+    bytes.extend_from_slice(value);
+}
+
+/// Appends unknown records and the separator ending a map
+fn push_unknown(bytes: &mut Vec<u8>, unknown: &[(Vec<u8>, Vec<u8>)]) {
+    for (key, value) in unknown {
+        push_record(bytes, key, value);
+    }
+    bytes.push(0x00);
+}
+
+/// Reads a variable integer, or returns None if it is truncated
+fn read_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let size = match bytes.first()? {
+        0xfd => 3,
+        0xfe => 5,
+        0xff => 9,
+        _ => 1,
+    };
+    if bytes.len() < size {
+        return None;
+    }
+    VariableInteger::from_bytes(bytes).ok()
+}
+
+/// Reads the records of the map starting at `index`, up to its separator.
+/// Returns the key and the value of each record, and the position
+/// following the map.
+fn read_map(bytes: &[u8], mut index: usize) -> Result<(UnknownRecords, usize), Error> {
+    let read_bytes = |index: &mut usize| -> Result<Vec<u8>, Error> {
+        let (len, len_size) =
+            read_varint(bytes.get(*index..).unwrap_or(&[])).ok_or(Error::Truncated)?;
+        *index += len_size;
+        let end = index.checked_add(len as usize).ok_or(Error::Truncated)?;
+        let data = bytes.get(*index..end).ok_or(Error::Truncated)?.to_vec();
+        *index = end;
+        Ok(data)
+    };
+
+    let mut records: UnknownRecords = Vec::new();
+    loop {
+        let key = read_bytes(&mut index)?;
+        // An empty key is the separator ending the map
+        let key_type = match key.first() {
+            Some(key_type) => *key_type,
+            None => return Ok((records, index)),
+        };
+        let value = read_bytes(&mut index)?;
+        if records.iter().any(|(k, _)| *k == key) {
+            return Err(Error::DuplicateKey(key_type));
+        }
+        records.push((key, value));
+    }
+}
+
+/// Returns the key type of a record whose key is the key type alone, as
+/// are the keys of the parsed records
+fn single_key_type(key: &[u8]) -> Option<u8> {
+    match key {
+        [key_type] => Some(*key_type),
+        _ => None,
+    }
+}
+
+/// Returns the length of the transaction serialized at the start of
+/// `bytes`, or None if it is truncated. Transaction::from_bytes does not
+/// check the bounds, so this is checked first.
+fn tx_len(bytes: &[u8]) -> Option<usize> {
+    let read_len = |index: &mut usize| -> Option<usize> {
+        let (len, len_size) = read_varint(bytes.get(*index..)?)?;
+        *index += len_size;
+        Some(len as usize)
+    };
+    let skip = |index: &mut usize, len: usize| -> Option<()> {
+        *index = index.checked_add(len).filter(|end| *end <= bytes.len())?;
+        Some(())
+    };
+
+    // Version, then the segwit marker and flag if any
+    let mut index = 4;
+    let segwit = bytes.get(index..(index + 2))? == [0x00, 0x01];
+    if segwit {
+        index += 2;
+    }
+    let input_count = read_len(&mut index)?;
+    for _ in 0..input_count {
+        // Outpoint, scriptSig and sequence
+        skip(&mut index, 36)?;
+        let script_len = read_len(&mut index)?;
+        skip(&mut index, script_len)?;
+        skip(&mut index, 4)?;
+    }
+    let output_count = read_len(&mut index)?;
+    for _ in 0..output_count {
+        skip(&mut index, 8)?;
+        let script_len = read_len(&mut index)?;
+        skip(&mut index, script_len)?;
+    }
+    if segwit {
+        for _ in 0..input_count {
+            let item_count = read_len(&mut index)?;
+            for _ in 0..item_count {
+                let item_len = read_len(&mut index)?;
+                skip(&mut index, item_len)?;
+            }
+        }
+    }
+    skip(&mut index, 4)?;
+    Some(index)
+}
+
+/// Parses a whole transaction from the value of a record
+fn parse_tx(value: &[u8], key_type: u8) -> Result<Transaction, Error> {
+    if tx_len(value) != Some(value.len()) {
+        return Err(Error::BadRecord(key_type));
+    }
+    let (tx, _) = Transaction::from_bytes(value);
+    Ok(tx)
+}
+
+impl Psbt {
+    /// Creates a PSBT without any input field. The transaction must not be
+    /// signed yet.
+    pub fn new(unsigned_tx: Transaction) -> Result<Self, Error> {
+        if unsigned_tx.has_witness()
+            || unsigned_tx
+                .inputs()
+                .iter()
+                .any(|input| !input.script_sig.is_empty())
+        {
+            return Err(Error::SignedTx);
+        }
+        let inputs = vec![PsbtInput::default(); unsigned_tx.inputs().len()];
+        let outputs = vec![PsbtOutput::default(); unsigned_tx.outputs().len()];
+        Ok(Psbt {
+            unsigned_tx,
+            unknown: Vec::new(),
+            inputs,
+            outputs,
+        })
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        push_record(&mut bytes, &[GLOBAL_UNSIGNED_TX], &self.unsigned_tx.bytes());
+        push_unknown(&mut bytes, &self.unknown);
+
+        for input in &self.inputs {
+            if let Some(tx) = &input.non_witness_utxo {
+                push_record(&mut bytes, &[IN_NON_WITNESS_UTXO], &tx.witness_bytes());
+            }
+            if let Some(output) = &input.witness_utxo {
+                let script = output.pubkey();
+                let mut value = output.value().to_le_bytes().to_vec();
+                value.extend_from_slice(&VariableInteger::new(script.len() as u64).bytes());
+                value.extend_from_slice(&script);
+                push_record(&mut bytes, &[IN_WITNESS_UTXO], &value);
+            }
+            if let Some(sighash_type) = input.sighash_type {
+                push_record(&mut bytes, &[IN_SIGHASH_TYPE], &sighash_type.to_le_bytes());
+            }
+            push_unknown(&mut bytes, &input.unknown);
+        }
+
+        for output in &self.outputs {
+            push_unknown(&mut bytes, &output.unknown);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(Error::BadMagic);
+        }
+        let (mut globals, mut index) = read_map(bytes, MAGIC.len())?;
+        let unsigned_tx = match globals
+            .iter()
+            .position(|(key, _)| single_key_type(key) == Some(GLOBAL_UNSIGNED_TX))
+        {
+            Some(position) => parse_tx(&globals.remove(position).1, GLOBAL_UNSIGNED_TX)?,
+            None => return Err(Error::MissingUnsignedTx),
+        };
+        let mut psbt = Psbt::new(unsigned_tx)?;
+        psbt.unknown = globals;
+
+        for input in psbt.inputs.iter_mut() {
+            let (records, next) = read_map(bytes, index)?;
+            index = next;
+            for (key, value) in records {
+                let key_type = match single_key_type(&key) {
+                    Some(key_type) => key_type,
+                    None => {
+                        input.unknown.push((key, value));
+                        continue;
+                    }
+                };
+                match key_type {
+                    IN_NON_WITNESS_UTXO => {
+                        input.non_witness_utxo = Some(parse_tx(&value, key_type)?);
+                    }
+                    IN_WITNESS_UTXO => {
+                        let amount = value.get(..8).ok_or(Error::BadRecord(key_type))?;
+                        let (script_len, script_len_size) =
+                            read_varint(&value[8..]).ok_or(Error::BadRecord(key_type))?;
+                        let script_start = 8 + script_len_size;
+                        if Some(value.len()) != script_start.checked_add(script_len as usize) {
+                            return Err(Error::BadRecord(key_type));
+                        }
+                        input.witness_utxo = Some(TxOutput::new(
+                            u64::from_le_bytes(utils::clone_into_array(amount)),
+                            value[script_start..].to_vec(),
+                        ));
+                    }
+                    IN_SIGHASH_TYPE if value.len() == 4 => {
+                        input.sighash_type =
+                            Some(u32::from_le_bytes(utils::clone_into_array(&value)));
+                    }
+                    IN_SIGHASH_TYPE => return Err(Error::BadRecord(key_type)),
+                    _ => input.unknown.push((key, value)),
+                }
+            }
+        }
+
+        for output in psbt.outputs.iter_mut() {
+            let (records, next) = read_map(bytes, index)?;
+            index = next;
+            output.unknown = records;
+        }
+        if index != bytes.len() {
+            return Err(Error::MapCount);
+        }
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::crypto::Hashable;
+    use crate::transaction::SIGHASH_ALL;
+
+    #[test]
+    fn test_psbt_round_trip() {
+        // Previous transaction paying a P2PKH and a P2WPKH output
+        let mut prev_tx = Transaction::new();
+        prev_tx.add_input([1; 32], 0, vec![0x51]);
+        let p2pkh = hex::decode("76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac").unwrap();
+        let p2wpkh = hex::decode("00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1").unwrap();
+        prev_tx.add_output(100_000, p2pkh);
+        prev_tx.add_output(200_000, p2wpkh.clone());
+
+        let mut tx = Transaction::new();
+        tx.add_input(prev_tx.hash(), 0, vec![]);
+        tx.add_input(prev_tx.hash(), 1, vec![]);
+        tx.add_output(290_000, vec![0x51]);
+
+        let mut psbt = Psbt::new(tx.clone()).unwrap();
+        assert_eq!(psbt.inputs, vec![PsbtInput::default(); 2]);
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx.clone());
+        psbt.inputs[0].sighash_type = Some(SIGHASH_ALL);
+        psbt.inputs[1].witness_utxo = Some(TxOutput::new(200_000, p2wpkh));
+
+        let bytes = psbt.bytes();
+        assert_eq!(&bytes[..5], &[0x70, 0x73, 0x62, 0x74, 0xff]);
+        // Global unsigned transaction record, then the separator
+        let tx_bytes = tx.bytes();
+        assert_eq!(&bytes[5..8], &[0x01, 0x00, tx_bytes.len() as u8]);
+        assert_eq!(&bytes[8..(8 + tx_bytes.len())], tx_bytes.as_slice());
+        assert_eq!(bytes[8 + tx_bytes.len()], 0x00);
+        // The output map is empty
+        assert_eq!(bytes.last(), Some(&0x00));
+        assert_eq!(Psbt::from_bytes(&bytes), Ok(psbt.clone()));
+
+        // Unknown records are kept, such as the partial signatures whose
+        // key holds the public key
+        let partial_sig_key = {
+            let mut key = vec![0x02];
+            key.extend_from_slice(&[0x03; 33]);
+            key
+        };
+        psbt.unknown.push((vec![0xfc, 0x01], vec![0xaa]));
+        psbt.inputs[0]
+            .unknown
+            .push((partial_sig_key.clone(), vec![0x30, 0x01]));
+        psbt.inputs[1].unknown.push((vec![0x06], vec![0xbb]));
+        psbt.outputs[0].unknown.push((vec![0x00], vec![0x02]));
+        let bytes = psbt.bytes();
+        assert_eq!(Psbt::from_bytes(&bytes), Ok(psbt.clone()));
+        assert_eq!(Psbt::from_bytes(&bytes).unwrap().bytes(), bytes);
+
+        // Records of the same key type with different keys are not
+        // duplicates
+        let mut other_sig_key = partial_sig_key;
+        other_sig_key[1] = 0x02;
+        psbt.inputs[0]
+            .unknown
+            .push((other_sig_key, vec![0x30, 0x02]));
+        assert_eq!(Psbt::from_bytes(&psbt.bytes()), Ok(psbt));
+    }
+
+    #[test]
+    fn test_psbt_errors() {
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, vec![]);
+        tx.add_output(1000, vec![0x51]);
+        let bytes = Psbt::new(tx.clone()).unwrap().bytes();
+
+        assert_eq!(Psbt::from_bytes(&bytes[1..]), Err(Error::BadMagic));
+        assert_eq!(
+            Psbt::from_bytes(&bytes[..(bytes.len() - 1)]),
+            Err(Error::Truncated)
+        );
+        let mut extra_map = bytes.clone();
+        extra_map.push(0x00);
+        assert_eq!(Psbt::from_bytes(&extra_map), Err(Error::MapCount));
+        assert_eq!(
+            Psbt::from_bytes(b"psbt\xff\x00"),
+            Err(Error::MissingUnsignedTx)
+        );
+
+        let mut duplicate = MAGIC.to_vec();
+        push_record(&mut duplicate, &[GLOBAL_UNSIGNED_TX], &tx.bytes());
+        push_record(&mut duplicate, &[GLOBAL_UNSIGNED_TX], &tx.bytes());
+        duplicate.extend_from_slice(&[0x00, 0x00, 0x00]);
+        assert_eq!(
+            Psbt::from_bytes(&duplicate),
+            Err(Error::DuplicateKey(GLOBAL_UNSIGNED_TX))
+        );
+
+        // Signed transactions can not be exported
+        let mut signed = tx.clone();
+        signed.inputs_mut()[0].script_sig = vec![0x51];
+        assert_eq!(Psbt::new(signed.clone()), Err(Error::SignedTx));
+        tx.set_witness(0, vec![vec![0x51]]);
+        assert_eq!(Psbt::new(tx), Err(Error::SignedTx));
+        let mut signed_bytes = MAGIC.to_vec();
+        push_record(&mut signed_bytes, &[GLOBAL_UNSIGNED_TX], &signed.bytes());
+        signed_bytes.extend_from_slice(&[0x00, 0x00, 0x00]);
+        assert_eq!(Psbt::from_bytes(&signed_bytes), Err(Error::SignedTx));
+    }
+
+    #[test]
+    fn test_psbt_malformed() {
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, vec![]);
+        tx.add_output(1000, vec![0x51]);
+        let psbt_with_tx = |tx_bytes: &[u8]| {
+            let mut bytes = MAGIC.to_vec();
+            push_record(&mut bytes, &[GLOBAL_UNSIGNED_TX], tx_bytes);
+            bytes.extend_from_slice(&[0x00, 0x00, 0x00]);
+            bytes
+        };
+        assert!(Psbt::from_bytes(&psbt_with_tx(&tx.bytes())).is_ok());
+
+        // Length of a record value overflowing the position
+        let mut overflow = MAGIC.to_vec();
+        overflow.extend_from_slice(&[0x01, GLOBAL_UNSIGNED_TX]);
+        overflow.extend_from_slice(&[0xff; 9]);
+        assert_eq!(Psbt::from_bytes(&overflow), Err(Error::Truncated));
+
+        // Truncated transactions, one of them announcing an input
+        let tx_bytes = tx.bytes();
+        for len in 0..tx_bytes.len() {
+            assert_eq!(
+                Psbt::from_bytes(&psbt_with_tx(&tx_bytes[..len])),
+                Err(Error::BadRecord(GLOBAL_UNSIGNED_TX))
+            );
+        }
+        assert_eq!(
+            Psbt::from_bytes(&psbt_with_tx(&[1, 0, 0, 0, 1, 0, 0, 0, 0, 0])),
+            Err(Error::BadRecord(GLOBAL_UNSIGNED_TX))
+        );
+        // Truncated witness of a previous transaction
+        let mut prev_tx = tx.clone();
+        prev_tx.set_witness(0, vec![vec![0x51; 3]]);
+        let prev_tx_bytes = prev_tx.witness_bytes();
+        let mut bytes = MAGIC.to_vec();
+        push_record(&mut bytes, &[GLOBAL_UNSIGNED_TX], &tx_bytes);
+        bytes.push(0x00);
+        push_record(
+            &mut bytes,
+            &[IN_NON_WITNESS_UTXO],
+            &prev_tx_bytes[..(prev_tx_bytes.len() - 6)],
+        );
+        bytes.extend_from_slice(&[0x00, 0x00]);
+        assert_eq!(
+            Psbt::from_bytes(&bytes),
+            Err(Error::BadRecord(IN_NON_WITNESS_UTXO))
+        );
+
+        // Length of the script of a witness output overflowing the position
+        let mut value = 1000u64.to_le_bytes().to_vec();
+        value.extend_from_slice(&[0xff; 9]);
+        let mut bytes = MAGIC.to_vec();
+        push_record(&mut bytes, &[GLOBAL_UNSIGNED_TX], &tx_bytes);
+        bytes.push(0x00);
+        push_record(&mut bytes, &[IN_WITNESS_UTXO], &value);
+        bytes.extend_from_slice(&[0x00, 0x00]);
+        assert_eq!(
+            Psbt::from_bytes(&bytes),
+            Err(Error::BadRecord(IN_WITNESS_UTXO))
+        );
+    }
+}
@@ -3,46 +3,410 @@ use std::collections::HashMap;
 use crate::crypto;
 use crate::crypto::Hashable;
 use crate::transaction::{Transaction, TxOutput};
+use crate::utils;
+
+/// Maximum number of public keys of an OP_CHECKMULTISIG
+const MAX_MULTISIG_PUBKEYS: i64 = 20;
+/// Maximum size of each executed script: scriptSig, scriptPubKey, redeem
+/// script and witness script
+const MAX_SCRIPT_SIZE: usize = 10_000;
+/// Maximum size of a stack entry
+const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+/// Maximum number of non-push opcodes executed by a script
+const MAX_OPS_PER_SCRIPT: usize = 201;
+/// Opcodes which make a script fail, e.g. OP_CAT and the other splice or
+/// bitwise opcodes (CVE-2010-5137)
+const DISABLED_OPCODES: [u8; 15] = [
+    0x7e, 0x7f, 0x80, 0x81, 0x83, 0x84, 0x85, 0x86, 0x8d, 0x8e, 0x95, 0x96, 0x97, 0x98, 0x99,
+];
+
+/// Reasons for which a script fails
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    EvalFalse,             // the script ends without a true value on the stack
+    InvalidStackOperation, // a missing entry, or an entry of the wrong kind
+    BadOpcode(u8),         // unknown opcode, or truncated push
+    DisabledOpcode(u8),
+    Verify,
+    EqualVerify,
+    SigVerifyFailed, // OP_CHECKSIGVERIFY or OP_CHECKMULTISIGVERIFY
+    PushSize,        // entry larger than MAX_SCRIPT_ELEMENT_SIZE
+    OpCount,         // more than MAX_OPS_PER_SCRIPT non-push opcodes
+    ScriptSize,      // script larger than MAX_SCRIPT_SIZE
+    MinimalData,     // non-minimal push or number (BIP62)
+    NumberOverflow,  // number larger than 4 bytes
+    PubkeyCount,
+    SigCount,
+    NullDummy,   // extra argument of OP_CHECKMULTISIG which is not empty
+    SigPushOnly, // pay to script hash scriptSig with other opcodes than pushes
+    WitnessMalleated,
+    WitnessProgramMismatch,
+    WitnessProgramWrongLength, // version 0 program of neither 20 nor 32 bytes
+    WitnessUnexpected,         // witness data on an input without witness program
+    CleanStack,                // more than one entry left by a witness script
+}
+
+/// Returns the position and the size of the data pushed by the push
+/// opcode at `index` (including OP_PUSHDATA1/2/4), or None if there is no
+/// such opcode or if the data is truncated
+pub fn push_data(script: &[u8], index: usize) -> Option<(usize, usize)> {
+    let opcode = *script.get(index)?;
+    let (start, size) = match opcode {
+        0x00..=0x4b => (index + 1, opcode as usize),
+        0x4c => (index + 2, *script.get(index + 1)? as usize),
+        0x4d => {
+            let size = utils::clone_into_array(script.get((index + 1)..(index + 3))?);
+            (index + 3, u16::from_le_bytes(size) as usize)
+        }
+        0x4e => {
+            let size = utils::clone_into_array(script.get((index + 1)..(index + 5))?);
+            (index + 5, u32::from_le_bytes(size) as usize)
+        }
+        _ => return None,
+    };
+    if start + size > script.len() {
+        return None;
+    }
+    Some((start, size))
+}
+
+/// Returns the number of signature operations of a script, counting each
+/// OP_CHECKMULTISIG(VERIFY) as the largest number of public keys. Counting
+/// stops at a truncated push, like the execution would.
+pub fn legacy_sigop_count(script: &[u8]) -> usize {
+    let mut count = 0;
+    let mut index = 0;
+    while index < script.len() {
+        let opcode = script[index];
+        match opcode {
+            0x00..=0x4e => match push_data(script, index) {
+                Some((start, size)) => {
+                    index = start + size;
+                    continue;
+                }
+                None => break,
+            },
+            0xac | 0xad => count += 1,
+            0xae | 0xaf => count += MAX_MULTISIG_PUBKEYS as usize,
+            _ => (),
+        }
+        index += 1;
+    }
+    count
+}
 
-#[derive(Debug, Clone)]
+/// Returns the pay to public key hash scriptPubKey of a public key hash:
+/// OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG
+pub fn p2pkh(pubkey_hash: crypto::Hash20) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend_from_slice(&pubkey_hash);
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+/// Returns the pay to script hash scriptPubKey of a redeem script hash:
+/// OP_HASH160 <hash> OP_EQUAL
+pub fn p2sh(script_hash: crypto::Hash20) -> Vec<u8> {
+    let mut script = vec![0xa9, 0x14];
+    script.extend_from_slice(&script_hash);
+    script.push(0x87);
+    script
+}
+
+/// Returns the pay to witness public key hash scriptPubKey of a public key
+/// hash: the version 0 witness program <hash>
+pub fn p2wpkh(pubkey_hash: crypto::Hash20) -> Vec<u8> {
+    let mut script = vec![0x00, 0x14];
+    script.extend_from_slice(&pubkey_hash);
+    script
+}
+
+/// Returns whether `data` is pushed with the smallest possible opcode
+/// (BIP62): OP_0 or OP_1NEGATE..OP_16 when possible, then a direct push,
+/// then OP_PUSHDATA1/2/4
+fn is_minimal_push(opcode: u8, data: &[u8]) -> bool {
+    match data {
+        [] => opcode == 0x00,
+        [value] if *value >= 1 && *value <= 16 => false,
+        [0x81] => false,
+        _ if data.len() <= 0x4b => opcode as usize == data.len(),
+        _ if data.len() <= 0xff => opcode == 0x4c,
+        _ if data.len() <= 0xffff => opcode == 0x4d,
+        _ => true,
+    }
+}
+
+/// Returns whether a script number has no useless trailing zero byte
+fn is_minimal_number(bytes: &[u8]) -> bool {
+    match bytes {
+        [] => true,
+        [.., last] if last & 0x7f != 0 => true,
+        // A zero byte (or a sign byte) is only needed to hold the sign bit
+        [.., before_last, _] => before_last & 0x80 != 0,
+        _ => false,
+    }
+}
+
+/// Returns whether bytes are true for the script, i.e. are not a (possibly
+/// negative) zero
+fn cast_to_bool(bytes: &[u8]) -> bool {
+    match bytes.split_last() {
+        Some((last, rest)) => rest.iter().any(|byte| *byte != 0) || (*last & 0x7f) != 0,
+        None => false,
+    }
+}
+
+/// Encodes a script number: little endian, the sign being the highest bit
+/// of the last byte. Zero is the empty array.
+fn encode_number(number: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut abs = number.unsigned_abs();
+    while abs > 0 {
+        bytes.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    let sign = if number < 0 { 0x80 } else { 0 };
+    match bytes.last_mut() {
+        Some(last) if *last & 0x80 != 0 => bytes.push(sign),
+        Some(last) => *last |= sign,
+        None => (),
+    }
+    bytes
+}
+
+/// Decodes a script number of at most 4 bytes
+fn decode_number(bytes: &[u8]) -> Option<i64> {
+    if bytes.len() > 4 {
+        return None;
+    }
+    let (last, _) = match bytes.split_last() {
+        Some(split) => split,
+        None => return Some(0),
+    };
+    let mut abs = 0i64;
+    for (i, byte) in bytes.iter().enumerate() {
+        abs |= i64::from(*byte) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        abs &= !(0x80i64 << (8 * (bytes.len() - 1)));
+        return Some(-abs);
+    }
+    Some(abs)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum StackEntry {
     Array(Vec<u8>),
-    Bool(bool),
     Number(i64),
 }
 
+impl StackEntry {
+    /// Returns the entry pushed for a boolean result, like OP_0 and OP_1
+    fn from_bool(value: bool) -> Self {
+        if value {
+            StackEntry::Array(vec![1])
+        } else {
+            StackEntry::Array(Vec::new())
+        }
+    }
+
+    /// Returns the bytes of the entry, as found on the stack of Bitcoin Core
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            StackEntry::Array(bytes) => bytes.clone(),
+            StackEntry::Number(number) => encode_number(*number),
+        }
+    }
+
+    /// Returns whether the entry is true, e.g. for OP_VERIFY
+    pub fn is_true(&self) -> bool {
+        cast_to_bool(&self.to_bytes())
+    }
+}
+
 pub struct Script {
     code: Vec<u8>,
     txin_scriptsig: Vec<u8>,
     txout_pkscript: Vec<u8>,
+    txout_value: u64,
+    witness_script_code: Option<Vec<u8>>, // Set when executing a witness program
+    redeem_script: Option<Vec<u8>>,       // Set when executing a pay to script hash redeem script
     stack: Vec<StackEntry>,
     pc: usize,
     op_map: HashMap<u8, fn(&mut Script) -> ()>,
     transaction: Box<Transaction>,
-    transaction_invalid: bool,
+    error: Option<ScriptError>, // set once the execution has failed
+    op_count: usize,
     input_index: usize,
     block_timestamp: u64,
+    require_minimal: bool, // Refuse non-minimal pushes and numbers (BIP62)
+    segwit: bool,          // Verify the witness programs (BIP141)
 }
 
 pub struct ScriptResult {
     stack: Vec<StackEntry>,
-    invalid: bool,
+    error: Option<ScriptError>,
+}
+
+impl ScriptResult {
+    /// Returns whether the script has run until its end. The value it
+    /// leaves on the stack is checked by `verify`.
+    pub fn is_valid(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Returns why the execution has failed, if it has
+    pub fn error(&self) -> Option<&ScriptError> {
+        self.error.as_ref()
+    }
+
+    /// Returns whether the input may spend the output: the script has run
+    /// until its end and left a true value on the stack
+    pub fn verify(&self) -> Result<(), ScriptError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+        match self.top() {
+            Some(top) if top.is_true() => Ok(()),
+            _ => Err(ScriptError::EvalFalse),
+        }
+    }
+
+    /// Returns the stack left by the execution
+    pub fn stack(&self) -> &[StackEntry] {
+        &self.stack
+    }
+
+    /// Returns the top of the stack left by the execution, if any
+    pub fn top(&self) -> Option<&StackEntry> {
+        self.stack.last()
+    }
+}
+
+/// Returns the script pushing `data`, with a direct push or
+/// OP_PUSHDATA1/2/4 depending on its size
+pub fn push_script(data: &[u8]) -> Vec<u8> {
+    let mut script = Vec::with_capacity(data.len() + 5);
+    if data.len() < 0x4c {
+        script.push(data.len() as u8);
+    } else if data.len() <= 0xff {
+        script.push(0x4c);
+        script.push(data.len() as u8);
+    } else if data.len() <= 0xffff {
+        script.push(0x4d);
+        script.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    } else {
+        script.push(0x4e);
+        script.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    }
+    script.extend_from_slice(data);
+    script
+}
+
+/// Returns the minimal script pushing `number`: OP_0, OP_1NEGATE, OP_1 to
+/// OP_16 or a push of its script number encoding
+pub fn push_number(number: i64) -> Vec<u8> {
+    match number {
+        0 => vec![0x00],
+        -1 => vec![0x4f],
+        1..=16 => vec![0x50 + number as u8],
+        _ => push_script(&encode_number(number)),
+    }
 }
 
 impl Script {
+    /// Marks the transaction as invalid, which halts the execution. Scripts
+    /// come from peers: a malformed one must never panic.
+    fn fail(&mut self, error: ScriptError) {
+        log::debug!("Script failed: {:?}", error);
+        self.error = Some(error);
+        // Move to the end of the code
+        self.pc = self.code.len();
+    }
+
+    /// Returns the result of the execution, with the current stack
+    fn result(&self) -> ScriptResult {
+        ScriptResult {
+            stack: self.stack.clone(),
+            error: self.error.clone(),
+        }
+    }
+
+    /// Pops the top of the stack, failing if it is empty
+    fn pop(&mut self) -> Option<StackEntry> {
+        let entry = self.stack.pop();
+        if entry.is_none() {
+            self.fail(ScriptError::InvalidStackOperation);
+        }
+        entry
+    }
+
+    /// Pops a byte array from the stack, failing if there is none
+    fn pop_array(&mut self) -> Option<Vec<u8>> {
+        match self.pop() {
+            Some(StackEntry::Array(bytes)) => Some(bytes),
+            Some(_) => {
+                self.fail(ScriptError::InvalidStackOperation);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Pops a number from the stack, failing if there is none or if the
+    /// entry is not a number of at most 4 bytes, minimally encoded if required
+    fn pop_number(&mut self) -> Option<i64> {
+        let number = match self.pop()? {
+            StackEntry::Number(number) => Ok(number),
+            StackEntry::Array(ref bytes) if self.require_minimal && !is_minimal_number(bytes) => {
+                Err(ScriptError::MinimalData)
+            }
+            StackEntry::Array(bytes) => decode_number(&bytes).ok_or(ScriptError::NumberOverflow),
+        };
+        match number {
+            Ok(number) => Some(number),
+            Err(error) => {
+                self.fail(error);
+                None
+            }
+        }
+    }
+
+    /// Pops the top of the stack, failing with the given error if it is
+    /// not true
+    fn verify_top(&mut self, error: ScriptError) {
+        if let Some(val) = self.pop() {
+            if !val.is_true() {
+                self.fail(error);
+            }
+        }
+    }
+
     fn op_push(&mut self) {
         println!("op_push");
-        let size = self.code[self.pc];
-        self.pc += 1;
-        let mut array = Vec::with_capacity(size as usize);
-        array.extend_from_slice(&self.code[self.pc..(self.pc + size as usize)]);
+        let opcode = self.code[self.pc];
+        let (start, size) = match push_data(&self.code, self.pc) {
+            Some(push) => push,
+            None => return self.fail(ScriptError::BadOpcode(opcode)),
+        };
+        if size > MAX_SCRIPT_ELEMENT_SIZE {
+            return self.fail(ScriptError::PushSize);
+        }
+        let array = self.code[start..(start + size)].to_vec();
+        if self.require_minimal && !is_minimal_push(opcode, &array) {
+            log::debug!("Non-minimal push of {} bytes", size);
+            return self.fail(ScriptError::MinimalData);
+        }
         self.stack.push(StackEntry::Array(array));
-        self.pc += size as usize;
+        self.pc = start + size;
     }
 
     fn op_dup(&mut self) {
         println!("op_dup");
-        let new = self.stack[self.stack.len() - 1].clone();
+        let new = match self.stack.last() {
+            Some(entry) => entry.clone(),
+            None => return self.fail(ScriptError::InvalidStackOperation),
+        };
         self.stack.push(new);
         self.pc += 1;
     }
@@ -50,74 +414,68 @@ impl Script {
     fn op_hash160(&mut self) {
         println!("op_hash160");
         self.pc += 1;
-        if let Some(StackEntry::Array(data)) = self.stack.pop() {
-            let h = crypto::hash20(&data);
+        if let Some(data) = self.pop_array() {
+            let h = crypto::hash160(&data);
             self.stack.push(StackEntry::Array(h.to_vec()));
-        } else {
-            panic!("Invalid stack");
         }
     }
 
     fn op_equal(&mut self) {
         println!("op_equal");
         self.pc += 1;
-        let x1 = self.stack.pop().unwrap();
-        let x2 = self.stack.pop().unwrap();
-
-        let to_add = match (x1, x2) {
-            (StackEntry::Array(ref val1), StackEntry::Array(ref val2)) if val1 == val2 => {
-                StackEntry::Bool(true)
-            }
-            (StackEntry::Bool(val1), StackEntry::Bool(val2)) if val1 == val2 => {
-                StackEntry::Bool(true)
-            }
-            _ => StackEntry::Bool(false),
+        let (x1, x2) = match (self.pop(), self.pop()) {
+            (Some(x1), Some(x2)) => (x1, x2),
+            _ => return,
         };
 
-        self.stack.push(to_add);
+        // Entries are compared byte for byte, whatever opcode pushed them
+        self.stack
+            .push(StackEntry::from_bool(x1.to_bytes() == x2.to_bytes()));
     }
 
     fn op_verify(&mut self) {
         println!("op_verify");
         self.pc += 1;
-        let val = self.stack.pop().unwrap();
-
-        self.transaction_invalid = match val {
-            StackEntry::Array(ref vect) if vect.is_empty() => true,
-            StackEntry::Bool(false) => true,
-            _ => false,
-        }
+        self.verify_top(ScriptError::Verify);
     }
 
     fn op_equalverify(&mut self) {
         println!("op_equalverify");
-        // op_equal and op_verify both increment pc
-        self.pc -= 1;
         self.op_equal();
-        self.op_verify();
+        self.verify_top(ScriptError::EqualVerify);
     }
 
     fn checksig(&self, pub_key_str: Vec<u8>, mut sig_str: Vec<u8>) -> bool {
         // Step 2
         // FIXME we assume that there is no OP_CODESEPARATOR for now
-        let sub_script = self.txout_pkscript.clone();
+        let sub_script = match &self.redeem_script {
+            Some(redeem_script) => redeem_script.clone(),
+            None => self.txout_pkscript.clone(),
+        };
 
         // FIXME Step 3/4
 
         // Step 5
-        let hashtype = sig_str.pop().unwrap() as u32;
+        let hashtype = match sig_str.pop() {
+            Some(hashtype) => hashtype as u32,
+            None => return false,
+        };
+
+        if let Some(script_code) = &self.witness_script_code {
+            return self.checksig_witness(&pub_key_str, &sig_str, script_code, hashtype);
+        }
 
         // Step 6
         let mut tx_copy = self.transaction.clone();
 
         // Step 7
-        for input in tx_copy.inputs.iter_mut() {
+        for input in tx_copy.inputs_mut().iter_mut() {
             let tx_input = &mut input.script_sig;
             tx_input.clear();
         }
 
         // Step 8
-        let input = &mut tx_copy.inputs[self.input_index];
+        let input = &mut tx_copy.inputs_mut()[self.input_index];
         input.script_sig.extend_from_slice(sub_script.as_slice());
 
         // Step 9
@@ -131,11 +489,31 @@ impl Script {
         }
     }
 
+    /// Checks the signature of a witness input, which commits to the value
+    /// of the spent output (BIP143)
+    fn checksig_witness(
+        &self,
+        pub_key_str: &[u8],
+        sig_str: &[u8],
+        script_code: &[u8],
+        hashtype: u32,
+    ) -> bool {
+        let hash = self.transaction.witness_signature_hash(
+            self.input_index,
+            script_code,
+            self.txout_value,
+            hashtype,
+        );
+        match crypto::check_signature(pub_key_str, sig_str, &hash) {
+            Ok(true) => true,
+            _ => false,
+        }
+    }
+
     fn op_checkmultisigverify(&mut self) {
         println!("op_checkmultisigverify");
-        self.pc -= 1;
         self.op_checkmultisig();
-        self.op_verify();
+        self.verify_top(ScriptError::SigVerifyFailed);
     }
 
     fn op_checkmultisig(&mut self) {
@@ -143,90 +521,80 @@ impl Script {
 
         self.pc += 1;
         // The first entry represents the number of public keys
-        if let StackEntry::Number(pubkeys_len) = self.stack.pop().unwrap() {
-            if pubkeys_len <= 0 {
-                panic!("pubkeys must be > 0");
+        let pubkeys_len = match self.pop_number() {
+            Some(pubkeys_len) if pubkeys_len > 0 && pubkeys_len <= MAX_MULTISIG_PUBKEYS => {
+                pubkeys_len
             }
+            Some(_) => return self.fail(ScriptError::PubkeyCount),
+            None => return,
+        };
+        // Each public key counts as an opcode
+        self.op_count += pubkeys_len as usize;
+        if self.op_count > MAX_OPS_PER_SCRIPT {
+            return self.fail(ScriptError::OpCount);
+        }
 
-            let mut pubkeys = Vec::with_capacity(pubkeys_len as usize);
-            let mut pubkeys_index = 0;
-            for _ in 0..pubkeys_len {
-                pubkeys.push(match self.stack.pop().unwrap() {
-                    StackEntry::Array(bytes) => bytes,
-                    _ => panic!("Wrong public key"),
-                });
+        let mut pubkeys = Vec::with_capacity(pubkeys_len as usize);
+        for _ in 0..pubkeys_len {
+            match self.pop_array() {
+                Some(bytes) => pubkeys.push(bytes),
+                None => return,
             }
-            pubkeys.reverse();
-
-            if let StackEntry::Number(sigs_len) = self.stack.pop().unwrap() {
-                let mut sigs = Vec::new();
-                for _ in 0..sigs_len {
-                    sigs.push(match self.stack.pop().unwrap() {
-                        StackEntry::Array(bytes) => bytes,
-                        _ => panic!("Wrong signature"),
-                    });
-                }
-                sigs.reverse();
-                // A bug causes CHECKMULTISIG to consume one extra argument
-                // whose contents were not checked in any way.
-                //
-                // Unfortunately this is a potential source of mutability,
-                // so optionally verify it is exactly equal to zero prior
-                // to removing it from the stack.
-                let should_panic = match self.stack.pop().unwrap() {
-                    StackEntry::Bool(false) => false,
-                    StackEntry::Array(vector) => !vector.is_empty(),
-                    _ => true,
-                };
-
-                if should_panic {
-                    panic!("There should be an unused stack element.");
-                }
-
-                for i in 0..sigs_len {
-                    while pubkeys_index < pubkeys_len {
-                        if self.checksig(
-                            sigs[i as usize].clone(),
-                            pubkeys[pubkeys_index as usize].clone(),
-                        ) {
-                            pubkeys_index += 1;
-                            break;
-                        }
-                        pubkeys_index += 1;
-                    }
-                    if pubkeys_index == pubkeys_len && i < sigs_len - 1 {
-                        self.stack.push(StackEntry::Bool(false));
-                        return;
-                    }
-                }
+        }
+        pubkeys.reverse();
 
-                self.stack.push(StackEntry::Bool(true));
-            } else {
-                panic!("Signatures number expected.");
+        let sigs_len = match self.pop_number() {
+            Some(sigs_len) if sigs_len >= 0 && sigs_len <= pubkeys_len => sigs_len,
+            Some(_) => return self.fail(ScriptError::SigCount),
+            None => return,
+        };
+        let mut sigs = Vec::new();
+        for _ in 0..sigs_len {
+            match self.pop_array() {
+                Some(bytes) => sigs.push(bytes),
+                None => return,
             }
-        } else {
-            panic!("Public keys number expected.");
         }
+        sigs.reverse();
+        // A bug causes CHECKMULTISIG to consume one extra argument
+        // whose contents were not checked in any way.
+        //
+        // Unfortunately this is a potential source of mutability,
+        // so optionally verify it is exactly equal to zero prior
+        // to removing it from the stack.
+        let unused_is_zero = match self.pop() {
+            Some(entry) => entry.to_bytes().is_empty(),
+            None => false,
+        };
+        if !unused_is_zero {
+            return self.fail(ScriptError::NullDummy);
+        }
+
+        // Each signature must match one of the public keys, in the same
+        // order, a public key being tried once
+        let mut pubkeys = pubkeys.into_iter();
+        let valid = sigs
+            .into_iter()
+            .all(|sig| pubkeys.any(|pubkey| self.checksig(pubkey, sig.clone())));
+        self.stack.push(StackEntry::from_bool(valid));
     }
 
     fn op_checksig(&mut self) {
         println!("op_checksig");
+        self.pc += 1;
         // Step 1
-        if let StackEntry::Array(pub_key_str) = self.stack.pop().unwrap() {
-            if let StackEntry::Array(sig_str) = self.stack.pop().unwrap() {
-                self.stack
-                    .push(StackEntry::Bool(self.checksig(pub_key_str, sig_str)));
+        if let Some(pub_key_str) = self.pop_array() {
+            if let Some(sig_str) = self.pop_array() {
+                let valid = self.checksig(pub_key_str, sig_str);
+                self.stack.push(StackEntry::from_bool(valid));
             }
         }
-
-        self.pc += 1;
     }
 
     fn op_checksigverify(&mut self) {
         println!("op_checksigverify");
-        self.pc -= 1;
         self.op_checksig();
-        self.op_verify();
+        self.verify_top(ScriptError::SigVerifyFailed);
     }
 
     fn op_true(&mut self) {
@@ -235,6 +603,14 @@ impl Script {
         self.pc += 1;
     }
 
+    /// OP_2..OP_16 push the number of their opcode minus 0x50
+    fn op_small_number(&mut self) {
+        let number = self.code[self.pc] as i64 - 0x50;
+        println!("op_{}", number);
+        self.stack.push(StackEntry::Number(number));
+        self.pc += 1;
+    }
+
     fn op_false(&mut self) {
         println!("op_false");
         self.stack.push(StackEntry::Array(Vec::new()));
@@ -243,12 +619,32 @@ impl Script {
 
     fn exec_next_instruction(&mut self) {
         let opcode = self.code[self.pc];
+        if DISABLED_OPCODES.contains(&opcode) {
+            return self.fail(ScriptError::DisabledOpcode(opcode));
+        }
+        // Pushes, including OP_1..OP_16, are not counted
+        if opcode > 0x60 {
+            self.op_count += 1;
+            if self.op_count > MAX_OPS_PER_SCRIPT {
+                return self.fail(ScriptError::OpCount);
+            }
+        }
         if let Some(func) = self.op_map.get(&opcode) {
             func(self);
-        } else if opcode >= 0x01 && opcode <= 0x4b {
+        } else if opcode >= 0x01 && opcode <= 0x4e {
             self.op_push();
         } else {
-            panic!("Invalid opcode {}", hex::encode([opcode]));
+            log::debug!("Invalid opcode {}", hex::encode([opcode]));
+            self.fail(ScriptError::BadOpcode(opcode));
+        }
+    }
+
+    /// Executes the code until its end or until the transaction is found
+    /// invalid
+    fn exec_code(&mut self) {
+        self.op_count = 0;
+        while !self.exec_is_finished() && self.error.is_none() {
+            self.exec_next_instruction();
         }
     }
 
@@ -265,6 +661,9 @@ impl Script {
         self.op_map.insert(0xac, Script::op_checksig);
         self.op_map.insert(0xad, Script::op_checksigverify);
         self.op_map.insert(0x51, Script::op_true);
+        for opcode in 0x52..=0x60 {
+            self.op_map.insert(opcode, Script::op_small_number);
+        }
         self.op_map.insert(0xae, Script::op_checkmultisig);
         self.op_map.insert(0xaf, Script::op_checkmultisigverify);
         self.op_map.insert(0x00, Script::op_false);
@@ -276,7 +675,7 @@ impl Script {
         tx_prev_out: Box<TxOutput>,
         block_timestamp: u64,
     ) -> Self {
-        let script_sig = (*(*tx_new).inputs[input_index]).sig();
+        let script_sig = (*(*tx_new).inputs()[input_index]).sig();
         let pk_script = (*tx_prev_out).pubkey();
         let mut code = Vec::with_capacity(script_sig.len() + pk_script.len());
         code.extend_from_slice(script_sig.as_slice());
@@ -286,16 +685,34 @@ impl Script {
             code: code,
             txin_scriptsig: script_sig,
             txout_pkscript: pk_script,
+            txout_value: tx_prev_out.value(),
+            witness_script_code: None,
+            redeem_script: None,
             stack: Vec::new(),
             pc: 0,
             op_map: HashMap::new(),
             transaction: tx_new,
-            transaction_invalid: false,
+            error: None,
+            op_count: 0,
             input_index,
             block_timestamp,
+            require_minimal: false,
+            segwit: false,
         }
     }
 
+    /// Sets whether the witness programs are verified (BIP141), which is
+    /// the case from the segwit activation height of the network
+    pub fn set_segwit(&mut self, segwit: bool) {
+        self.segwit = segwit;
+    }
+
+    /// Sets whether pushes and numbers must be minimally encoded (BIP62).
+    /// This is a policy rule, not required by default.
+    pub fn set_require_minimal(&mut self, require_minimal: bool) {
+        self.require_minimal = require_minimal;
+    }
+
     fn is_pay_to_script_hash(&self) -> bool {
         // We check that block timestamp is greater than 1333238400
         if self.block_timestamp < 1333238400 {
@@ -331,71 +748,180 @@ impl Script {
         true
     }
 
+    /// Returns the version and the program of a witness script (BIP141),
+    /// i.e. OP_0 or OP_1 to OP_16 followed by a push of 2 to 40 bytes
+    fn witness_program(&self, script: &[u8]) -> Option<(u8, Vec<u8>)> {
+        if !self.segwit || script.len() < 4 || script.len() > 42 {
+            return None;
+        }
+
+        let version = match script[0] {
+            0x00 => 0,
+            0x51..=0x60 => script[0] - 0x50,
+            _ => return None,
+        };
+        if script[1] as usize != script.len() - 2 {
+            return None;
+        }
+        Some((version, script[2..].to_vec()))
+    }
+
+    /// Validates the witness of the input against a witness program
+    /// (BIP141)
+    fn exec_witness(&mut self, version: u8, program: Vec<u8>) -> ScriptResult {
+        let invalid = |error| ScriptResult {
+            stack: Vec::new(),
+            error: Some(error),
+        };
+
+        // The scriptSig of a native witness input must be empty, and the
+        // one of a nested witness input must only push the redeem script
+        if !self.txin_scriptsig.is_empty() {
+            return invalid(ScriptError::WitnessMalleated);
+        }
+
+        // Programs of other versions are left to future soft forks
+        if version != 0 {
+            return self.result();
+        }
+        if program.len() != 20 && program.len() != 32 {
+            return invalid(ScriptError::WitnessProgramWrongLength);
+        }
+
+        let mut witness = self.transaction.witness(self.input_index).to_vec();
+        let script = if program.len() == 20 {
+            // Pay to witness public key hash: the witness is a signature and
+            // a public key, checked as in pay to public key hash
+            if witness.len() != 2 {
+                return invalid(ScriptError::WitnessProgramMismatch);
+            }
+            p2pkh(utils::clone_into_array(&program))
+        } else {
+            // Pay to witness script hash: the last item is the script
+            match witness.pop() {
+                Some(script) if crypto::single_hash32(&script)[..] == program[..] => script,
+                _ => return invalid(ScriptError::WitnessProgramMismatch),
+            }
+        };
+        if script.len() > MAX_SCRIPT_SIZE {
+            return invalid(ScriptError::ScriptSize);
+        }
+        if witness
+            .iter()
+            .any(|item| item.len() > MAX_SCRIPT_ELEMENT_SIZE)
+        {
+            return invalid(ScriptError::PushSize);
+        }
+
+        self.stack = witness.into_iter().map(StackEntry::Array).collect();
+        self.code = script.clone();
+        self.witness_script_code = Some(script);
+        self.pc = 0;
+        self.exec_code();
+
+        // Exactly one true element must be left on the stack
+        if self.error.is_none() {
+            match self.stack.last() {
+                Some(entry) if entry.is_true() => {
+                    if self.stack.len() != 1 {
+                        self.fail(ScriptError::CleanStack);
+                    }
+                }
+                _ => self.fail(ScriptError::EvalFalse),
+            }
+        }
+        self.result()
+    }
+
     pub fn exec(&mut self) -> ScriptResult {
         // Initialize execution
         self.build_op_map();
 
+        if self.txin_scriptsig.len() > MAX_SCRIPT_SIZE
+            || self.txout_pkscript.len() > MAX_SCRIPT_SIZE
+        {
+            log::debug!("Script larger than {} bytes", MAX_SCRIPT_SIZE);
+            return ScriptResult {
+                stack: Vec::new(),
+                error: Some(ScriptError::ScriptSize),
+            };
+        }
+
         self.stack.clear();
         self.pc = 0;
-        loop {
-            self.exec_next_instruction();
-            if self.exec_is_finished() || self.transaction_invalid {
-                break;
+        self.exec_code();
+
+        if self.error.is_none() {
+            if let Some((version, program)) = self.witness_program(&self.txout_pkscript) {
+                return self.exec_witness(version, program);
             }
         }
 
-        if self.transaction_invalid || !self.is_pay_to_script_hash() {
-            return ScriptResult {
-                stack: self.stack.clone(),
-                invalid: self.transaction_invalid,
-            };
+        if self.error.is_some() || !self.is_pay_to_script_hash() {
+            return self.non_witness_result();
         }
 
         // Pay to script hash => Extended validation
-        let script = self.pop_serialized_script().unwrap();
+        let script = match self.pop_serialized_script() {
+            Ok(script) if script.len() <= MAX_SCRIPT_SIZE => script,
+            Ok(_) => {
+                self.fail(ScriptError::ScriptSize);
+                return self.result();
+            }
+            Err(error) => {
+                self.fail(error);
+                return self.result();
+            }
+        };
         self.code.clear();
         self.code.extend_from_slice(&self.txin_scriptsig);
         self.code.extend_from_slice(&script);
+        self.redeem_script = Some(script.clone());
 
         // Reset stack
         self.pc = 0;
         self.stack.clear();
+        self.exec_code();
 
-        loop {
-            self.exec_next_instruction();
-            if self.exec_is_finished() || self.transaction_invalid {
-                break;
+        // Pay to script hash wrapping a witness program (BIP141)
+        if self.error.is_none() {
+            if let Some((version, program)) = self.witness_program(&script) {
+                return self.exec_witness(version, program);
             }
         }
 
-        return ScriptResult {
-            stack: self.stack.clone(),
-            invalid: self.transaction_invalid,
-        };
+        self.non_witness_result()
     }
 
-    fn pop_serialized_script(&mut self) -> Result<Vec<u8>, ()> {
-        let mut index = 0;
-        let txin_scriptsig_len = self.txin_scriptsig.len();
-        let mut opcode = self.txin_scriptsig[index];
-        let mut size = 0;
-        while index < txin_scriptsig_len {
-            opcode = self.txin_scriptsig[index];
-            index += 1;
-            // FIXME : Is it always the right size ?
-            // Should take in account push ops, and maybe others...
-            if opcode >= 0x01 && opcode <= 0x4b {
-                size = opcode as usize;
-            }
-            index += size;
+    /// Returns the result of an input without witness program, which must
+    /// not carry witness data once segwit is active (BIP141)
+    fn non_witness_result(&mut self) -> ScriptResult {
+        if self.segwit
+            && self.error.is_none()
+            && !self.transaction.witness(self.input_index).is_empty()
+        {
+            self.fail(ScriptError::WitnessUnexpected);
         }
-        if index != txin_scriptsig_len {
-            return Err(());
+        self.result()
+    }
+
+    /// Removes the serialized script of a pay to script hash input, i.e.
+    /// the last push of its scriptSig, and returns it. Fails if the
+    /// scriptSig is not only made of pushes (BIP16) or is truncated.
+    fn pop_serialized_script(&mut self) -> Result<Vec<u8>, ScriptError> {
+        let mut index = 0;
+        let mut last_push = None;
+        while index < self.txin_scriptsig.len() {
+            let (start, size) = match push_data(&self.txin_scriptsig, index) {
+                Some(push) => push,
+                None => return Err(ScriptError::SigPushOnly),
+            };
+            last_push = Some((index, start, size));
+            index = start + size;
         }
-        let start = index - size;
-        let script = self.txin_scriptsig[start..].to_vec();
-        let end = if start > 0 { start - 1 } else { start };
-        self.txin_scriptsig = self.txin_scriptsig[0..end].to_vec();
+        let (opcode_index, start, size) = last_push.ok_or(ScriptError::InvalidStackOperation)?;
+        let script = self.txin_scriptsig[start..(start + size)].to_vec();
+        self.txin_scriptsig.truncate(opcode_index);
         Ok(script)
     }
 }
@@ -404,7 +930,10 @@ impl Script {
 mod tests {
 
     use super::*;
+    use crate::transaction::SIGHASH_ALL;
     use crate::utils;
+    use rand::seq::SliceRandom;
+    use rand::Rng;
 
     fn get_script_parameters(code: Vec<u8>) -> (Box<Transaction>, usize, Box<TxOutput>) {
         let mut tx_new = Box::new(Transaction::new());
@@ -413,11 +942,23 @@ mod tests {
 
         let mut tx_prev = Transaction::new();
         tx_prev.add_output(1, vec![]);
-        let tx_prev_out = tx_prev.outputs[0].clone();
+        let tx_prev_out = tx_prev.outputs()[0].clone();
 
         (tx_new, input_index, tx_prev_out)
     }
 
+    /// Returns a script verifying the witness programs, as after the
+    /// activation of segwit
+    fn segwit_script(
+        tx_new: Box<Transaction>,
+        input_index: usize,
+        tx_prev_out: Box<TxOutput>,
+    ) -> Script {
+        let mut script = Script::new(tx_new, input_index, tx_prev_out, 1333238400);
+        script.set_segwit(true);
+        script
+    }
+
     #[test]
     fn test_script_struct() {
         let mut tx_new = Transaction::new();
@@ -431,7 +972,7 @@ mod tests {
 
         let mut tx_prev = Transaction::new();
         tx_prev.add_output(1, hex::decode("abcdef").unwrap());
-        let tx_prev_out = tx_prev.outputs[0].clone();
+        let tx_prev_out = tx_prev.outputs()[0].clone();
 
         let script = Script::new(tx_new_box, input_index, tx_prev_out, 0);
         assert_eq!(script.code, hex::decode("1234567890abcdef").unwrap());
@@ -439,17 +980,28 @@ mod tests {
         assert_eq!(script.txout_pkscript, hex::decode("abcdef").unwrap());
         assert!(script.stack.is_empty());
         assert_eq!(script.pc, 0);
-        assert!(!script.transaction_invalid);
+        assert_eq!(script.error, None);
         assert_eq!(script.input_index, input_index);
     }
 
+    #[test]
+    fn test_push_number() {
+        // Small numbers are pushed with an opcode
+        assert_eq!(push_number(0), vec![0x00]);
+        assert_eq!(push_number(-1), vec![0x4f]);
+        assert_eq!(push_number(16), vec![0x60]);
+        assert_eq!(push_number(17), vec![0x01, 0x11]);
+        assert_eq!(push_number(500), vec![0x02, 0xf4, 0x01]);
+        assert_eq!(push_number(-255), vec![0x02, 0xff, 0x80]);
+    }
+
     #[test]
     fn test_push() {
         let code = hex::decode("4930460221009805aa00cb6f80ca984584d4ca40f637fc948e3dbe159ea5c4eb6941bf4eb763022100e1cc0852d3f6eb87839edca1f90169088ed3502d8cde2f495840acac69eefc9801").unwrap();
         let (tx_new, input_index, tx_prev_out) = get_script_parameters(code);
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert_eq!(result.stack.len(), 1);
         if let StackEntry::Array(vect) = &result.stack[0] {
             assert_eq!(vect, &hex::decode("30460221009805aa00cb6f80ca984584d4ca40f637fc948e3dbe159ea5c4eb6941bf4eb763022100e1cc0852d3f6eb87839edca1f90169088ed3502d8cde2f495840acac69eefc9801").unwrap());
@@ -480,7 +1032,7 @@ mod tests {
         let (tx_new, input_index, tx_prev_out) = get_script_parameters(code);
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert_eq!(result.stack.len(), 2);
         if let StackEntry::Array(vect) = &result.stack[0] {
             assert_eq!(vect, &hex::decode("30460221009805aa00cb6f80ca984584d4ca40f637fc948e3dbe159ea5c4eb6941bf4eb763022100e1cc0852d3f6eb87839edca1f90169088ed3502d8cde2f495840acac69eefc9801").unwrap());
@@ -500,7 +1052,7 @@ mod tests {
         let (tx_new, input_index, tx_prev_out) = get_script_parameters(code);
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert_eq!(result.stack.len(), 1);
         if let StackEntry::Array(vect) = &result.stack[0] {
             assert_eq!(
@@ -519,10 +1071,10 @@ mod tests {
         let (tx_new, input_index, tx_prev_out) = get_script_parameters(code);
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert_eq!(result.stack.len(), 1);
-        if let StackEntry::Bool(value) = &result.stack[0] {
-            assert_eq!(*value, true);
+        if let StackEntry::Array(value) = &result.stack[0] {
+            assert_eq!(*value, vec![1]);
         } else {
             panic!();
         }
@@ -531,34 +1083,34 @@ mod tests {
         let (tx_new, input_index, tx_prev_out) = get_script_parameters(code);
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert_eq!(result.stack.len(), 1);
-        if let StackEntry::Bool(value) = &result.stack[0] {
-            assert_eq!(*value, false);
+        if let StackEntry::Array(value) = &result.stack[0] {
+            assert!(value.is_empty());
         } else {
             panic!();
         }
         // Test with booleans from equal
-        let code = hex::decode("0101010187010101018787").unwrap();
+        let code = hex::decode("0111011187011101118787").unwrap();
         let (tx_new, input_index, tx_prev_out) = get_script_parameters(code);
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert_eq!(result.stack.len(), 1);
-        if let StackEntry::Bool(value) = &result.stack[0] {
-            assert_eq!(*value, true);
+        if let StackEntry::Array(value) = &result.stack[0] {
+            assert_eq!(*value, vec![1]);
         } else {
             panic!();
         }
         // Test with booleans from equal
-        let code = hex::decode("0102010187010101018787").unwrap();
+        let code = hex::decode("0112011187011101118787").unwrap();
         let (tx_new, input_index, tx_prev_out) = get_script_parameters(code);
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert_eq!(result.stack.len(), 1);
-        if let StackEntry::Bool(value) = &result.stack[0] {
-            assert_eq!(*value, false);
+        if let StackEntry::Array(value) = &result.stack[0] {
+            assert!(value.is_empty());
         } else {
             panic!();
         }
@@ -566,35 +1118,35 @@ mod tests {
 
     #[test]
     fn test_verify() {
-        let code = hex::decode("010101028769").unwrap();
+        let code = hex::decode("011101128769").unwrap();
         let (tx_new, input_index, tx_prev_out) = get_script_parameters(code);
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(result.invalid);
+        assert_eq!(result.error, Some(ScriptError::Verify));
         assert!(result.stack.is_empty());
 
-        let code = hex::decode("010101018769").unwrap();
+        let code = hex::decode("011101118769").unwrap();
         let (tx_new, input_index, tx_prev_out) = get_script_parameters(code);
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert!(result.stack.is_empty());
     }
 
     #[test]
     fn test_equalverify() {
-        let code = hex::decode("0102010188").unwrap();
+        let code = hex::decode("0112011188").unwrap();
         let (tx_new, input_index, tx_prev_out) = get_script_parameters(code);
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(result.invalid);
+        assert_eq!(result.error, Some(ScriptError::EqualVerify));
         assert!(result.stack.is_empty());
 
-        let code = hex::decode("0101010188").unwrap();
+        let code = hex::decode("0111011188").unwrap();
         let (tx_new, input_index, tx_prev_out) = get_script_parameters(code);
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert!(result.stack.is_empty());
     }
 
@@ -635,16 +1187,13 @@ mod tests {
         let pkscript = hex::decode("76a91471d7dd96d9edda09180fe9d57a477b5acc9cad1188ac").unwrap();
 
         tx_prev.add_output(5_000_000_000, pkscript);
-        let tx_prev_out = tx_prev.outputs[0].clone();
+        let tx_prev_out = tx_prev.outputs()[0].clone();
 
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert_eq!(result.stack.len(), 1);
-        match result.stack[0] {
-            StackEntry::Bool(true) => (),
-            _ => panic!(),
-        }
+        assert_eq!(result.stack[0], StackEntry::Array(vec![1]));
     }
 
     #[test]
@@ -691,16 +1240,13 @@ mod tests {
         let pkscript = hex::decode("4104bb24090e128506bc3c5335cb47ae254a3919c3619df8c780511cedb5837d2360ef6d7fbeeaace93f6e0b0dcf29515684843208744ad3292e4e32ad3b1b931892ac").unwrap();
 
         tx_prev.add_output(5_000_000_000, pkscript);
-        let tx_prev_out = tx_prev.outputs[0].clone();
+        let tx_prev_out = tx_prev.outputs()[0].clone();
 
         let mut script = Script::new(tx_new.clone(), input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert_eq!(result.stack.len(), 1);
-        match result.stack[0] {
-            StackEntry::Bool(true) => (),
-            _ => panic!(),
-        }
+        assert_eq!(result.stack[0], StackEntry::Array(vec![1]));
 
         // Check second input
         let input_index = 1;
@@ -709,16 +1255,232 @@ mod tests {
         let pkscript = hex::decode("410421ca0ddad2cfae978d8863d391b068af9ed72dac32f3d4f2d9f3a09253483d0a283054a20fa9f230c1f5fd40f3df4669dd5e6a48f7dfe142f1be8df09383e072ac").unwrap();
 
         tx_prev.add_output(5_000_000_000, pkscript);
-        let tx_prev_out = tx_prev.outputs[0].clone();
+        let tx_prev_out = tx_prev.outputs()[0].clone();
 
         let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert_eq!(result.stack.len(), 1);
-        match result.stack[0] {
-            StackEntry::Bool(true) => (),
-            _ => panic!(),
+        assert_eq!(result.stack[0], StackEntry::Array(vec![1]));
+    }
+
+    #[test]
+    /// The result of OP_CHECKSIG is compared byte for byte with OP_1 and OP_0
+    fn test_checksig_equal() {
+        let (priv_key, pub_key) = crypto::generate_keypair();
+        let (other_priv_key, _) = crypto::generate_keypair();
+        // <sig> <pubkey> OP_CHECKSIG followed by the given opcodes
+        let exec = |signing_key: &[u8], opcodes: &[u8]| {
+            let mut pk_script = vec![pub_key.len() as u8];
+            pk_script.extend_from_slice(&pub_key);
+            pk_script.push(0xac);
+            pk_script.extend_from_slice(opcodes);
+
+            let mut tx_new = Transaction::new();
+            tx_new.add_input([1; 32], 0, Vec::new());
+            tx_new.add_output(90_000, vec![0x51]);
+            let hash = tx_new.signature_hash(0, &pk_script, SIGHASH_ALL);
+            let mut sig = crypto::sign(signing_key, &hash);
+            sig.push(SIGHASH_ALL as u8);
+            let mut script_sig = vec![sig.len() as u8];
+            script_sig.extend_from_slice(&sig);
+            tx_new.inputs_mut()[0].script_sig = script_sig;
+
+            let tx_prev_out = Box::new(TxOutput::new(100_000, pk_script));
+            Script::new(Box::new(tx_new), 0, tx_prev_out, 0).exec()
+        };
+        let true_entry = StackEntry::Array(vec![1]);
+        let false_entry = StackEntry::Array(Vec::new());
+
+        // A valid signature equals OP_1 and the pushed 0x01 byte
+        let result = exec(&priv_key, &[0x51, 0x87]);
+        assert_eq!(result.error, None);
+        assert_eq!(result.stack, vec![true_entry.clone()]);
+        let result = exec(&priv_key, &[0x01, 0x01, 0x87]);
+        assert_eq!(result.stack, vec![true_entry.clone()]);
+        let result = exec(&priv_key, &[0x00, 0x87]);
+        assert_eq!(result.stack, vec![false_entry.clone()]);
+
+        // An invalid one equals OP_0, which OP_VERIFY refuses
+        let result = exec(&other_priv_key, &[0x00, 0x87]);
+        assert_eq!(result.stack, vec![true_entry]);
+        let result = exec(&other_priv_key, &[0x51, 0x87]);
+        assert_eq!(result.stack, vec![false_entry]);
+        assert_eq!(
+            exec(&other_priv_key, &[0x69]).error,
+            Some(ScriptError::Verify)
+        );
+        assert_eq!(exec(&priv_key, &[0x69]).error, None);
+    }
+
+    #[test]
+    fn test_standard_scripts() {
+        // Scripts of the BIP143 test vectors
+        let pubkey_hash = utils::clone_into_array(
+            &hex::decode("1d0f172a0ecb48aee1be1f2687d2963ae33f71a1").unwrap(),
+        );
+        assert_eq!(
+            p2pkh(pubkey_hash),
+            hex::decode("76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac").unwrap()
+        );
+        assert_eq!(
+            p2wpkh(pubkey_hash),
+            hex::decode("00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1").unwrap()
+        );
+        // Pay to script hash wrapping a witness program
+        let redeem_script = p2wpkh(utils::clone_into_array(
+            &hex::decode("79091972186c449eb1ded22b78e40d009bdf0089").unwrap(),
+        ));
+        assert_eq!(
+            p2sh(crypto::hash160(&redeem_script)),
+            hex::decode("a9144733f37cf4db86fbc2efed2500b4f4e49f31202387").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_script_size() {
+        let exec = |script_sig: Vec<u8>, pk_script: Vec<u8>| {
+            let mut tx_new = Box::new(Transaction::new());
+            tx_new.add_input([0; 32], 0xffffffff, script_sig);
+            let mut tx_prev = Transaction::new();
+            tx_prev.add_output(1, pk_script);
+            Script::new(tx_new, 0, tx_prev.outputs()[0].clone(), 1333238400).exec()
+        };
+        // OP_PUSHDATA2 of `size` bytes
+        let push = |size: usize| {
+            let mut script = vec![0x4d];
+            script.extend_from_slice(&(size as u16).to_le_bytes());
+            script.extend_from_slice(&vec![0x42; size]);
+            script
+        };
+        // Script of `size` bytes: pushes of the largest entries, then OP_1s
+        let script_of_size = |size: usize| {
+            let mut script = Vec::new();
+            while script.len() + MAX_SCRIPT_ELEMENT_SIZE + 3 <= size {
+                script.extend_from_slice(&push(MAX_SCRIPT_ELEMENT_SIZE));
+            }
+            script.resize(size, 0x51);
+            script
+        };
+
+        // Empty scripts
+        let result = exec(vec![], vec![0x51]);
+        assert_eq!(result.error, None);
+        assert_eq!(result.stack, vec![StackEntry::Number(1)]);
+        assert_eq!(exec(vec![], vec![]).error, None);
+        // There is no serialized script to pop for pay to script hash
+        let p2sh_script = hex::decode("a91419a7d869032368fd1f1e26e5e73a4ad0e474960e87").unwrap();
+        assert_eq!(
+            exec(vec![], p2sh_script).error,
+            Some(ScriptError::InvalidStackOperation)
+        );
+
+        // Scripts of exactly the maximum size
+        assert_eq!(script_of_size(MAX_SCRIPT_SIZE).len(), MAX_SCRIPT_SIZE);
+        let result = exec(script_of_size(MAX_SCRIPT_SIZE), vec![0x51]);
+        assert_eq!(result.error, None);
+        assert_eq!(result.top(), Some(&StackEntry::Number(1)));
+        assert_eq!(
+            exec(vec![0x51], script_of_size(MAX_SCRIPT_SIZE)).error,
+            None
+        );
+
+        // One more byte in either script
+        assert_eq!(
+            exec(script_of_size(MAX_SCRIPT_SIZE + 1), vec![0x51]).error,
+            Some(ScriptError::ScriptSize)
+        );
+        assert_eq!(
+            exec(vec![0x51], script_of_size(MAX_SCRIPT_SIZE + 1)).error,
+            Some(ScriptError::ScriptSize)
+        );
+
+        // Redeem scripts are pushed, so are limited to the size of an entry
+        let redeem = |size: usize| {
+            let redeem_script = script_of_size(size);
+            let mut script_sig = vec![0x4d];
+            script_sig.extend_from_slice(&(redeem_script.len() as u16).to_le_bytes());
+            script_sig.extend_from_slice(&redeem_script);
+            exec(script_sig, p2sh(crypto::hash160(&redeem_script)))
+        };
+        assert_eq!(redeem(MAX_SCRIPT_ELEMENT_SIZE).error, None);
+        assert_eq!(
+            redeem(MAX_SCRIPT_ELEMENT_SIZE + 1).error,
+            Some(ScriptError::PushSize)
+        );
+        assert_eq!(
+            redeem(MAX_SCRIPT_SIZE + 1).error,
+            Some(ScriptError::ScriptSize)
+        );
+    }
+
+    #[test]
+    fn test_legacy_sigop_count() {
+        let count = |code: &str| legacy_sigop_count(&hex::decode(code).unwrap());
+        assert_eq!(count(""), 0);
+        // Pay to public key hash
+        assert_eq!(
+            count("76a914000000000000000000000000000000000000000088ac"),
+            1
+        );
+        assert_eq!(count("ad51ac"), 2);
+        // 1-of-1 multisig
+        assert_eq!(count(&format!("5121{}51ae", "02".repeat(33))), 20);
+        assert_eq!(count("af"), 20);
+        // Opcodes inside pushes do not count
+        assert_eq!(count("02acac"), 0);
+        assert_eq!(count("4c02acacac"), 1);
+        // Nor those after a truncated push
+        assert_eq!(count("ac4c05acac"), 1);
+    }
+
+    #[test]
+    fn test_require_minimal() {
+        let exec = |code: &str, require_minimal: bool| {
+            let (tx_new, input_index, tx_prev_out) =
+                get_script_parameters(hex::decode(code).unwrap());
+            let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
+            script.set_require_minimal(require_minimal);
+            script.exec()
+        };
+
+        // OP_PUSHDATA1 for a single byte
+        assert_eq!(exec("4c01ff", true).error, Some(ScriptError::MinimalData));
+        let result = exec("4c01ff", false);
+        assert_eq!(result.error, None);
+        assert_eq!(result.stack, vec![StackEntry::Array(vec![0xff])]);
+        assert_eq!(exec("01ff", true).error, None);
+        // A direct push of 5 instead of OP_5, and of nothing instead of OP_0
+        assert_eq!(exec("0105", true).error, Some(ScriptError::MinimalData));
+        assert_eq!(exec("0105", false).error, None);
+        assert_eq!(exec("4c00", true).error, Some(ScriptError::MinimalData));
+        assert_eq!(exec("00", true).error, None);
+
+        assert!(is_minimal_number(&[]));
+        assert!(is_minimal_number(&[0x01]));
+        assert!(is_minimal_number(&[0x80, 0x00]));
+        assert!(is_minimal_number(&[0xff, 0x80]));
+        assert!(!is_minimal_number(&[0x00]));
+        assert!(!is_minimal_number(&[0x80]));
+        assert!(!is_minimal_number(&[0x01, 0x00]));
+    }
+
+    #[test]
+    fn test_script_numbers() {
+        for number in &[0, 1, -1, 127, 128, -128, 255, 0x7fffffff, -0x7fffffff] {
+            assert_eq!(decode_number(&encode_number(*number)), Some(*number));
         }
+        assert_eq!(encode_number(0), Vec::<u8>::new());
+        assert_eq!(encode_number(-1), vec![0x81]);
+        assert_eq!(encode_number(128), vec![0x80, 0x00]);
+        assert_eq!(encode_number(-128), vec![0x80, 0x80]);
+        assert_eq!(decode_number(&[1, 2, 3, 4, 5]), None);
+
+        // Negative zero is false
+        assert!(!cast_to_bool(&[0x00, 0x80]));
+        assert!(cast_to_bool(&[0x80, 0x00]));
+        assert!(StackEntry::Number(-1).is_true());
+        assert!(!StackEntry::Array(vec![0, 0]).is_true());
     }
 
     #[test]
@@ -742,20 +1504,16 @@ mod tests {
             5_000_000_000,
             hex::decode("a91419a7d869032368fd1f1e26e5e73a4ad0e474960e87").unwrap(),
         );
-        let tx_prev_out = tx_prev.outputs[0].clone();
+        let tx_prev_out = tx_prev.outputs()[0].clone();
 
         let mut script = Script::new(tx_new.clone(), 0, tx_prev_out, 0);
         let result = script.exec();
-        assert!(!result.invalid);
+        assert_eq!(result.error, None);
         assert_eq!(result.stack.len(), 1);
-        match result.stack[0] {
-            StackEntry::Bool(true) => (),
-            _ => panic!(),
-        }
+        assert_eq!(result.stack[0], StackEntry::Array(vec![1]));
     }
 
     #[test]
-    #[should_panic]
     fn test_pay_to_script_hash_invalid() {
         // The following transaction is not compliant with BIP16
         // https://github.com/bitcoin/bips/blob/master/bip-0016.mediawiki
@@ -779,15 +1537,60 @@ mod tests {
             5_000_000_000,
             hex::decode("a91419a7d869032368fd1f1e26e5e73a4ad0e474960e87").unwrap(),
         );
-        let tx_prev_out = tx_prev.outputs[0].clone();
+        let tx_prev_out = tx_prev.outputs()[0].clone();
 
         let mut script = Script::new(tx_new.clone(), 0, tx_prev_out, 1333238400);
+        assert_eq!(
+            script.exec().error,
+            Some(ScriptError::InvalidStackOperation)
+        );
+    }
+
+    #[test]
+    fn test_pay_to_script_hash_truncated() {
+        let redeem_script = hex::decode(
+            "5121029b6d2c97b8b7c718c325d7be3ac30f7c9d67651bce0c929f55ee77ce58efcf8451ae",
+        )
+        .unwrap();
+        let mut tx_prev = Transaction::new();
+        tx_prev.add_output(
+            5_000_000_000,
+            hex::decode("a91419a7d869032368fd1f1e26e5e73a4ad0e474960e87").unwrap(),
+        );
+        let tx_prev_out = tx_prev.outputs()[0].clone();
+        let script_sig = |push: &[u8]| {
+            let mut tx_new = Box::new(Transaction::new());
+            let mut script_sig = push.to_vec();
+            script_sig.extend_from_slice(&redeem_script);
+            tx_new.add_input([0; 32], 1, script_sig);
+            tx_new
+        };
+
+        // The redeem script may be pushed with OP_PUSHDATA1, which is not
+        // minimal
+        let mut script = Script::new(script_sig(&[0x4c, 0x25]), 0, tx_prev_out.clone(), 0);
+        script.set_require_minimal(true);
+        assert_eq!(script.exec().error, Some(ScriptError::MinimalData));
+        let mut script = Script::new(script_sig(&[0x4c, 0x25]), 0, tx_prev_out.clone(), 0);
         let result = script.exec();
-        assert!(!result.invalid);
-        assert_eq!(result.stack.len(), 1);
-        match result.stack[0] {
-            StackEntry::Bool(true) => (),
-            _ => panic!(),
+        assert_eq!(result.error, None);
+        assert_eq!(result.stack, vec![StackEntry::Array(vec![1])]);
+        assert_eq!(script.pop_serialized_script(), Ok(redeem_script.clone()));
+        assert!(script.txin_scriptsig.is_empty());
+
+        // Pushes announcing more bytes than there are are rejected
+        for push in &[
+            &[0x26][..],
+            &[0x4c, 0x26],
+            &[0x4d, 0x25],
+            &[0x4e, 0x25, 0, 0],
+        ] {
+            let mut script = Script::new(script_sig(push), 0, tx_prev_out.clone(), 1333238400);
+            assert!(script.exec().error.is_some());
+            assert_eq!(
+                script.pop_serialized_script(),
+                Err(ScriptError::SigPushOnly)
+            );
         }
     }
 
@@ -812,14 +1615,377 @@ mod tests {
             1000000,
             hex::decode("a914e9c3dd0c07aac76179ebc76a6c78d4d67c6c160a87").unwrap(),
         );
-        let tx_prev_out = tx_prev.outputs[0].clone();
+        let tx_prev_out = tx_prev.outputs()[0].clone();
 
+        // Only the input of the transaction is known, so its signature does
+        // not match, but the redeem script is run to the end
         let mut script = Script::new(tx_new.clone(), 0, tx_prev_out, 1333238400);
         let result = script.exec();
-        assert!(!result.invalid);
-        match result.stack.last().unwrap() {
-            StackEntry::Bool(true) => (),
-            _ => panic!(),
+        assert_eq!(result.error, None);
+        assert_eq!(result.stack.last(), Some(&StackEntry::Array(vec![])));
+    }
+
+    #[test]
+    fn test_pay_to_script_hash_multisig() {
+        // 1-of-1 multisig redeem script, as the one of 40eee
+        let (priv_key, pub_key) = crypto::generate_keypair();
+        let mut redeem_script = vec![0x51];
+        redeem_script.extend_from_slice(&push_script(&pub_key));
+        redeem_script.extend_from_slice(&[0x51, 0xae]);
+        let mut pk_script = vec![0xa9, 0x14];
+        pk_script.extend_from_slice(&crypto::hash160(&redeem_script));
+        pk_script.push(0x87);
+        let tx_prev_out = Box::new(TxOutput::new(100_000, pk_script));
+
+        let mut tx_new = Transaction::new();
+        tx_new.add_input([1; 32], 0, Vec::new());
+        tx_new.add_output(90_000, vec![0x51]);
+        // The signature commits to the redeem script
+        let exec = |signed_script: &[u8]| {
+            let hash = tx_new.signature_hash(0, signed_script, SIGHASH_ALL);
+            let mut sig = crypto::sign(&priv_key, &hash);
+            sig.push(SIGHASH_ALL as u8);
+            let mut tx = tx_new.clone();
+            tx.inputs_mut()[0].script_sig =
+                [vec![0x00], push_script(&sig), push_script(&redeem_script)].concat();
+            Script::new(Box::new(tx), 0, tx_prev_out.clone(), 1333238400).exec()
+        };
+        let result = exec(&redeem_script);
+        assert_eq!(result.error, None);
+        assert_eq!(result.stack.last(), Some(&StackEntry::Array(vec![1])));
+        let result = exec(&tx_prev_out.pubkey());
+        assert_eq!(result.stack.last(), Some(&StackEntry::Array(vec![])));
+    }
+
+    #[test]
+    /// Native P2WPKH example of BIP143
+    fn test_pay_to_witness_public_key_hash() {
+        let (tx_new, _) = Transaction::from_bytes(&hex::decode("01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000").unwrap());
+        let pk_script = hex::decode("00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1").unwrap();
+
+        let tx_prev_out = Box::new(TxOutput::new(600_000_000, pk_script.clone()));
+        let mut script = segwit_script(Box::new(tx_new.clone()), 1, tx_prev_out);
+        let result = script.exec();
+        assert_eq!(result.error, None);
+        assert_eq!(result.stack, vec![StackEntry::Array(vec![1])]);
+
+        // The signature commits to the spent value
+        let tx_prev_out = Box::new(TxOutput::new(600_000_001, pk_script));
+        let mut script = segwit_script(Box::new(tx_new), 1, tx_prev_out);
+        assert_eq!(script.exec().error, Some(ScriptError::EvalFalse));
+    }
+
+    #[test]
+    /// Signature of the native P2WPKH example of BIP143
+    fn test_checksig_witness() {
+        let (tx_new, _) = Transaction::from_bytes(&hex::decode("0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000").unwrap());
+        let pub_key =
+            hex::decode("025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee6357")
+                .unwrap();
+        let sig = hex::decode("304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee").unwrap();
+        let script_code =
+            hex::decode("76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac").unwrap();
+
+        let tx_prev_out = Box::new(TxOutput::new(
+            600_000_000,
+            hex::decode("00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1").unwrap(),
+        ));
+        let script = segwit_script(Box::new(tx_new), 1, tx_prev_out);
+        assert!(script.checksig_witness(&pub_key, &sig, &script_code, 1));
+        // Legacy hashing does not verify it
+        let mut legacy_sig = sig.clone();
+        legacy_sig.push(0x01);
+        assert!(!script.checksig(pub_key.clone(), legacy_sig));
+        // Nor does another hash type
+        assert!(!script.checksig_witness(&pub_key, &sig, &script_code, 3));
+    }
+
+    #[test]
+    /// P2SH-P2WPKH example of BIP143
+    fn test_pay_to_script_hash_witness_public_key_hash() {
+        let (tx_new, _) = Transaction::from_bytes(&hex::decode("01000000000101db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a5477010000001716001479091972186c449eb1ded22b78e40d009bdf0089feffffff02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b59d0d96388ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac02473044022047ac8e878352d3ebbde1c94ce3a10d057c24175747116f8288e5d794d12d482f0220217f36a485cae903c713331d877c1f64677e3622ad4010726870540656fe9dcb012103ad1d8e89212f0b92c74d23bb710c00662ad1470198ac48c43f7d6f93a2a2687392040000").unwrap());
+        let tx_prev_out = Box::new(TxOutput::new(
+            1_000_000_000,
+            hex::decode("a9144733f37cf4db86fbc2efed2500b4f4e49f31202387").unwrap(),
+        ));
+        let exec = |tx_new: &Transaction| {
+            let mut script = segwit_script(Box::new(tx_new.clone()), 0, tx_prev_out.clone());
+            script.exec()
+        };
+        let result = exec(&tx_new);
+        assert_eq!(result.error, None);
+        assert_eq!(result.stack, vec![StackEntry::Array(vec![1])]);
+
+        // The witness is checked
+        let mut tx_bad_sig = tx_new.clone();
+        let mut witness = tx_new.witness(0).to_vec();
+        witness[0][10] ^= 1;
+        tx_bad_sig.set_witness(0, witness);
+        assert_ne!(exec(&tx_bad_sig).error, None);
+
+        // The scriptSig must only push the redeem script
+        let mut tx_malleated = tx_new.clone();
+        let mut script_sig = vec![0x00];
+        script_sig.extend_from_slice(&tx_new.inputs()[0].script_sig);
+        tx_malleated.inputs_mut()[0].script_sig = script_sig;
+        assert_eq!(
+            exec(&tx_malleated).error,
+            Some(ScriptError::WitnessMalleated)
+        );
+
+        // Before the activation of segwit, the redeem script is enough
+        let mut tx_no_witness = tx_new.clone();
+        tx_no_witness.set_witness(0, Vec::new());
+        let mut script = Script::new(Box::new(tx_no_witness), 0, tx_prev_out.clone(), 1333238400);
+        assert_eq!(script.exec().error, None);
+    }
+
+    #[test]
+    fn test_pay_to_witness_script_hash() {
+        let (priv_key, pub_key) = crypto::generate_keypair();
+        let mut witness_script = vec![pub_key.len() as u8];
+        witness_script.extend_from_slice(&pub_key);
+        witness_script.push(0xac);
+        let mut pk_script = vec![0x00, 0x20];
+        pk_script.extend_from_slice(&crypto::single_hash32(&witness_script));
+
+        let mut tx_new = Transaction::new();
+        tx_new.add_input([1; 32], 0, Vec::new());
+        tx_new.add_output(90_000, vec![0x51]);
+        let hash = tx_new.witness_signature_hash(0, &witness_script, 100_000, 1);
+        let mut sig = crypto::sign(&priv_key, &hash);
+        sig.push(0x01);
+        tx_new.set_witness(0, vec![sig.clone(), witness_script.clone()]);
+
+        let tx_prev_out = Box::new(TxOutput::new(100_000, pk_script.clone()));
+        let mut script = segwit_script(Box::new(tx_new.clone()), 0, tx_prev_out.clone());
+        assert_eq!(script.exec().error, None);
+
+        // The witness script must match the program
+        let mut other_script = witness_script.clone();
+        other_script.push(0x51);
+        tx_new.set_witness(0, vec![sig, other_script]);
+        let mut script = segwit_script(Box::new(tx_new), 0, tx_prev_out);
+        assert_eq!(
+            script.exec().error,
+            Some(ScriptError::WitnessProgramMismatch)
+        );
+    }
+
+    #[test]
+    fn test_script_errors() {
+        let exec = |code: String, require_minimal: bool| {
+            let (tx_new, input_index, tx_prev_out) =
+                get_script_parameters(hex::decode(code).unwrap());
+            let mut script = Script::new(tx_new, input_index, tx_prev_out, 0);
+            script.set_require_minimal(require_minimal);
+            script.exec()
+        };
+        let error = |code: &str| exec(code.to_string(), true).error;
+
+        assert_eq!(error("ff"), Some(ScriptError::BadOpcode(0xff)));
+        assert_eq!(error("4c"), Some(ScriptError::BadOpcode(0x4c)));
+        assert_eq!(error("01117e"), Some(ScriptError::DisabledOpcode(0x7e)));
+        assert_eq!(error("76"), Some(ScriptError::InvalidStackOperation));
+        assert_eq!(error("51a9"), Some(ScriptError::InvalidStackOperation));
+
+        // The final value is checked by verify
+        assert_eq!(exec("51".to_string(), true).verify(), Ok(()));
+        assert_eq!(
+            exec("00".to_string(), true).verify(),
+            Err(ScriptError::EvalFalse)
+        );
+        assert_eq!(
+            exec(String::new(), true).verify(),
+            Err(ScriptError::EvalFalse)
+        );
+        assert_eq!(
+            exec("76".to_string(), true).verify(),
+            Err(ScriptError::InvalidStackOperation)
+        );
+
+        // Non-push opcodes are counted
+        assert_eq!(error(&format!("0111{}", "76".repeat(201))), None);
+        assert_eq!(
+            error(&format!("0111{}", "76".repeat(202))),
+            Some(ScriptError::OpCount)
+        );
+
+        // <sig> <pubkey> OP_CHECKSIG(VERIFY)
+        assert_eq!(error("023001020202ad"), Some(ScriptError::SigVerifyFailed));
+        let result = exec("023001020202ac".to_string(), true);
+        assert_eq!(result.error, None);
+        assert_eq!(result.verify(), Err(ScriptError::EvalFalse));
+
+        // <dummy> <sig> <sig count> <pubkey> <pubkey count> OP_CHECKMULTISIG
+        let multisig = |dummy: &str, sigs: &str, pubkeys: &str, opcode: &str| {
+            format!("{}023001{}020202{}{}", dummy, sigs, pubkeys, opcode)
+        };
+        assert_eq!(
+            error(&multisig("51", "51", "51", "ae")),
+            Some(ScriptError::NullDummy)
+        );
+        assert_eq!(
+            error(&multisig("00", "51", "00", "ae")),
+            Some(ScriptError::PubkeyCount)
+        );
+        assert_eq!(
+            exec(multisig("00", "0102", "51", "ae"), false).error,
+            Some(ScriptError::SigCount)
+        );
+        assert_eq!(error("050102030405ae"), Some(ScriptError::NumberOverflow));
+        assert_eq!(
+            error(&format!("00{}", "4d0902".to_string() + &"42".repeat(521))),
+            Some(ScriptError::PushSize)
+        );
+
+        // The redeem script must be pushed by a scriptSig made of pushes
+        let mut tx_new = Box::new(Transaction::new());
+        tx_new.add_input([0; 32], 0, vec![0x51, 0x01, 0x11]);
+        let tx_prev_out = Box::new(TxOutput::new(1, p2sh(crypto::hash160(&[0x11]))));
+        let mut script = Script::new(tx_new, 0, tx_prev_out, 1333238400);
+        assert_eq!(script.exec().error, Some(ScriptError::SigPushOnly));
+
+        // Witness programs
+        let exec_witness = |script_sig: Vec<u8>, witness_script: Vec<u8>| {
+            let mut pk_script = vec![0x00, 0x20];
+            pk_script.extend_from_slice(&crypto::single_hash32(&witness_script));
+            let mut tx_new = Transaction::new();
+            tx_new.add_input([1; 32], 0, script_sig);
+            tx_new.set_witness(0, vec![witness_script]);
+            let tx_prev_out = Box::new(TxOutput::new(1, pk_script));
+            segwit_script(Box::new(tx_new), 0, tx_prev_out).exec()
+        };
+        assert_eq!(exec_witness(vec![], vec![0x51]).error, None);
+        assert_eq!(
+            exec_witness(vec![0x51], vec![0x51]).error,
+            Some(ScriptError::WitnessMalleated)
+        );
+        assert_eq!(
+            exec_witness(vec![], vec![0x51, 0x51]).error,
+            Some(ScriptError::CleanStack)
+        );
+        assert_eq!(
+            exec_witness(vec![], vec![0x00]).error,
+            Some(ScriptError::EvalFalse)
+        );
+    }
+
+    #[test]
+    fn test_pay_to_witness_script_hash_multisig() {
+        let keys: Vec<(Vec<u8>, Vec<u8>)> = (0..3).map(|_| crypto::generate_keypair()).collect();
+        // OP_2 <pubkey> <pubkey> <pubkey> OP_3 OP_CHECKMULTISIG
+        let mut witness_script = vec![0x52];
+        for (_, pub_key) in &keys {
+            witness_script.extend_from_slice(&push_script(pub_key));
+        }
+        witness_script.extend_from_slice(&[0x53, 0xae]);
+        let mut pk_script = vec![0x00, 0x20];
+        pk_script.extend_from_slice(&crypto::single_hash32(&witness_script));
+        let tx_prev_out = Box::new(TxOutput::new(100_000, pk_script));
+
+        let mut tx_new = Transaction::new();
+        tx_new.add_input([1; 32], 0, Vec::new());
+        tx_new.add_output(90_000, vec![0x51]);
+        let hash = tx_new.witness_signature_hash(0, &witness_script, 100_000, 1);
+        let sigs: Vec<Vec<u8>> = keys
+            .iter()
+            .map(|(priv_key, _)| {
+                let mut sig = crypto::sign(priv_key, &hash);
+                sig.push(0x01);
+                sig
+            })
+            .collect();
+        let exec = |sigs: &[&Vec<u8>]| {
+            let mut tx = tx_new.clone();
+            let mut witness = vec![Vec::new()];
+            witness.extend(sigs.iter().map(|sig| (*sig).clone()));
+            witness.push(witness_script.clone());
+            tx.set_witness(0, witness);
+            segwit_script(Box::new(tx), 0, tx_prev_out.clone()).exec()
+        };
+
+        assert_eq!(exec(&[&sigs[0], &sigs[2]]).verify(), Ok(()));
+        assert_eq!(exec(&[&sigs[1], &sigs[2]]).verify(), Ok(()));
+        // The signatures must be in the order of the public keys
+        assert!(exec(&[&sigs[2], &sigs[0]]).verify().is_err());
+        // A public key can not be used twice
+        assert!(exec(&[&sigs[0], &sigs[0]]).verify().is_err());
+        // The last signature must match too
+        let (other_priv_key, _) = crypto::generate_keypair();
+        let mut other_sig = crypto::sign(&other_priv_key, &hash);
+        other_sig.push(0x01);
+        assert!(exec(&[&sigs[0], &other_sig]).verify().is_err());
+    }
+
+    #[test]
+    fn test_witness_program_versions() {
+        let exec_program = |pk_script: &str, witness: Vec<Vec<u8>>| {
+            let mut tx_new = Transaction::new();
+            tx_new.add_input([1; 32], 0, Vec::new());
+            tx_new.set_witness(0, witness);
+            let tx_prev_out = Box::new(TxOutput::new(1, hex::decode(pk_script).unwrap()));
+            segwit_script(Box::new(tx_new), 0, tx_prev_out).exec()
+        };
+
+        // Version 0 programs are either 20 or 32 bytes long, other versions
+        // are not checked yet
+        let program = "42".repeat(21);
+        assert_eq!(
+            exec_program(&format!("0015{}", program), vec![vec![0x51]]).error,
+            Some(ScriptError::WitnessProgramWrongLength)
+        );
+        assert_eq!(
+            exec_program(&format!("5115{}", program), vec![vec![0x51]]).error,
+            None
+        );
+
+        // Inputs without witness program must not carry a witness
+        assert_eq!(exec_program("51", Vec::new()).error, None);
+        assert_eq!(
+            exec_program("51", vec![vec![0x51]]).error,
+            Some(ScriptError::WitnessUnexpected)
+        );
+        let mut tx_new = Transaction::new();
+        tx_new.add_input([1; 32], 0, Vec::new());
+        tx_new.set_witness(0, vec![vec![0x51]]);
+        let tx_prev_out = Box::new(TxOutput::new(1, vec![0x51]));
+        let mut script = Script::new(Box::new(tx_new), 0, tx_prev_out, 1333238400);
+        assert_eq!(script.exec().error, None);
+    }
+
+    #[test]
+    /// Scripts come from peers: executing random ones must never panic
+    fn test_random_scripts() {
+        let mut rng = rand::thread_rng();
+        let opcodes = [
+            0x00, 0x01, 0x14, 0x20, 0x4b, 0x51, 0x69, 0x76, 0x87, 0x88, 0xa9, 0xac, 0xad, 0xae,
+            0xaf, 0xff,
+        ];
+        let random_script = |rng: &mut rand::rngs::ThreadRng| -> Vec<u8> {
+            let len = rng.gen_range(0, 40);
+            (0..len)
+                .map(|_| {
+                    if rng.gen_bool(0.8) {
+                        *opcodes.choose(rng).unwrap()
+                    } else {
+                        rng.gen()
+                    }
+                })
+                .collect()
+        };
+        let prefixes: [&[u8]; 4] = [&[], &[0xa9, 0x14], &[0x00, 0x14], &[0x00, 0x20]];
+        for _ in 0..5000 {
+            let mut tx_new = Transaction::new();
+            tx_new.add_input([0; 32], 0, random_script(&mut rng));
+            tx_new.set_witness(0, vec![random_script(&mut rng), random_script(&mut rng)]);
+            // Also look like P2SH or witness outputs
+            let mut pk_script = prefixes.choose(&mut rng).unwrap().to_vec();
+            pk_script.extend(random_script(&mut rng));
+
+            let tx_prev_out = Box::new(TxOutput::new(1000, pk_script));
+            let mut script = segwit_script(Box::new(tx_new), 0, tx_prev_out);
+            script.exec();
         }
     }
 }
@@ -0,0 +1,404 @@
+use crate::block::Block;
+use crate::crypto;
+use crate::crypto::Hashable;
+use crate::merkle_tree;
+use crate::script;
+use crate::transaction::{Transaction, TxOutput};
+use crate::variable_integer::VariableInteger;
+
+// Challenge of the default signet: 1-of-2 multisig
+pub const DEFAULT_CHALLENGE: &str = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae";
+
+// Marks the push of the witness commitment which holds the signet solution
+const SIGNET_HEADER: [u8; 4] = [0xec, 0xc7, 0xda, 0xa2];
+// OP_RETURN, push of 36 bytes and the witness commitment header (BIP141)
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+const OP_0: u8 = 0x00;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+const OP_RETURN: u8 = 0x6a;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKMULTISIG: u8 = 0xae;
+const SIGHASH_ALL: u8 = 0x01;
+
+/// Splits a script into its operations: opcode and pushed data
+fn parse_script(script: &[u8]) -> Option<Vec<(u8, Vec<u8>)>> {
+    let mut ops = Vec::new();
+    let mut index = 0;
+    while index < script.len() {
+        let opcode = script[index];
+        if opcode > OP_PUSHDATA4 {
+            ops.push((opcode, Vec::new()));
+            index += 1;
+            continue;
+        }
+        let (start, size) = script::push_data(script, index)?;
+        ops.push((opcode, script[start..(start + size)].to_vec()));
+        index = start + size;
+    }
+    Some(ops)
+}
+
+/// Returns the index of the output of the coinbase holding the witness
+/// commitment. The last one is used if there are several of them.
+fn witness_commitment_index(coinbase: &Transaction) -> Option<usize> {
+    coinbase.outputs().iter().rposition(|output| {
+        let script = output.pubkey();
+        script.len() >= 38 && script[..6] == WITNESS_COMMITMENT_HEADER
+    })
+}
+
+/// Removes the signet solution from the witness commitment, leaving the
+/// signet header. Returns the solution, if any.
+fn take_solution(commitment: &[u8]) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+    let mut replacement = Vec::with_capacity(commitment.len());
+    let mut solution = None;
+    for (opcode, mut data) in parse_script(commitment)? {
+        if data.is_empty() {
+            replacement.push(opcode);
+            continue;
+        }
+        // Only the first push holding the header and some data counts
+        if solution.is_none() && data.len() > SIGNET_HEADER.len() && data[..4] == SIGNET_HEADER {
+            solution = Some(data.split_off(SIGNET_HEADER.len()));
+        }
+        replacement.extend_from_slice(&script::push_script(&data));
+    }
+    Some((replacement, solution))
+}
+
+/// Parses a signet solution: the scriptSig and the witness stack of the
+/// transaction spending the challenge
+fn parse_solution(solution: &[u8]) -> Option<(Vec<u8>, Vec<Vec<u8>>)> {
+    // The length of the variable integer is checked first, as from_bytes
+    // does not
+    let read_varint = |index: usize| -> Option<(u64, usize)> {
+        let bytes = solution.get(index..)?;
+        let size = match bytes.first()? {
+            0xfd => 3,
+            0xfe => 5,
+            0xff => 9,
+            _ => 1,
+        };
+        if bytes.len() < size {
+            return None;
+        }
+        VariableInteger::from_bytes(bytes).ok()
+    };
+    let read_bytes = |index: &mut usize| -> Option<Vec<u8>> {
+        let (len, len_size) = read_varint(*index)?;
+        *index += len_size;
+        let end = index.checked_add(len as usize)?;
+        let bytes = solution.get(*index..end)?.to_vec();
+        *index = end;
+        Some(bytes)
+    };
+
+    let mut index = 0;
+    let script_sig = read_bytes(&mut index)?;
+    let (item_count, item_count_size) = read_varint(index)?;
+    index += item_count_size;
+    let mut witness = Vec::new();
+    for _ in 0..item_count {
+        witness.push(read_bytes(&mut index)?);
+    }
+    if index != solution.len() {
+        // Extraneous data
+        return None;
+    }
+    Some((script_sig, witness))
+}
+
+/// Builds the transaction spending the challenge on behalf of the block
+/// (BIP325). Its input holds the signet solution found in the coinbase.
+fn spending_transaction(block: &Block, challenge: &[u8]) -> Option<Transaction> {
+    let coinbase = block.coinbase()?;
+    let commitment_index = witness_commitment_index(coinbase)?;
+    let commitment = &coinbase.outputs()[commitment_index];
+    let (replacement, solution) = take_solution(&commitment.pubkey())?;
+    let (script_sig, witness) = match solution {
+        Some(solution) => parse_solution(&solution)?,
+        // Allowed for trivial challenges such as OP_TRUE
+        None => (vec![], vec![]),
+    };
+
+    // The merkle root is computed without the solution, which can not sign
+    // itself
+    let mut modified_coinbase = coinbase.clone();
+    *modified_coinbase.outputs_mut()[commitment_index] =
+        TxOutput::new(commitment.value(), replacement);
+    let mut transactions = vec![Box::new(modified_coinbase)];
+    transactions.extend(block.transactions()[1..].iter().cloned());
+    let merkle_root = merkle_tree::MerkleTree::new(&transactions).root()?;
+
+    // Version, previous block, modified merkle root and time
+    let mut block_data = block.header.bytes()[..72].to_vec();
+    block_data[36..68].copy_from_slice(&crypto::hash32_to_bytes(&merkle_root));
+
+    let mut to_spend = Transaction::new();
+    to_spend.set_version(0);
+    let mut to_spend_script_sig = vec![OP_0];
+    to_spend_script_sig.extend_from_slice(&script::push_script(&block_data));
+    to_spend.add_input([0; 32], 0xffffffff, to_spend_script_sig);
+    to_spend.set_sequence(0, 0);
+    to_spend.add_output(0, challenge.to_vec());
+
+    let mut spending = Transaction::new();
+    spending.set_version(0);
+    spending.add_input(to_spend.hash(), 0, script_sig);
+    spending.set_sequence(0, 0);
+    spending.set_witness(0, witness);
+    spending.add_output(0, vec![OP_RETURN]);
+    Some(spending)
+}
+
+/// Checks a signature of the spending transaction against a public key
+fn check_signature(spending: &Transaction, challenge: &[u8], pub_key: &[u8], sig: &[u8]) -> bool {
+    match sig.split_last() {
+        Some((&SIGHASH_ALL, der)) => {
+            let hash = spending.signature_hash(0, challenge, SIGHASH_ALL as u32);
+            crypto::check_signature(pub_key, der, &hash).unwrap_or(false)
+        }
+        // Other hash types are not supported
+        _ => false,
+    }
+}
+
+/// Returns whether the scriptSig satisfies the challenge. Only the usual
+/// challenges are supported: OP_TRUE, pay to public key and bare multisig.
+fn check_challenge(spending: &Transaction, challenge: &[u8]) -> bool {
+    if spending.has_witness() {
+        log::warn!("Witness signet challenges are not supported");
+        return false;
+    }
+    let (script_sig, challenge_ops) = match (
+        parse_script(&spending.inputs()[0].script_sig),
+        parse_script(challenge),
+    ) {
+        (Some(script_sig), Some(challenge_ops)) => (script_sig, challenge_ops),
+        _ => return false,
+    };
+    // The scriptSig must only push data
+    if script_sig
+        .iter()
+        .any(|(opcode, _)| *opcode != OP_0 && *opcode > OP_PUSHDATA4)
+    {
+        return false;
+    }
+    let pushes: Vec<&Vec<u8>> = script_sig.iter().map(|(_, data)| data).collect();
+
+    match challenge_ops.as_slice() {
+        [(OP_1, _)] => true,
+        [(_, pub_key), (OP_CHECKSIG, _)] if !pub_key.is_empty() => {
+            pushes.len() == 1 && check_signature(spending, challenge, pub_key, pushes[0])
+        }
+        [(m, _), keys @ .., (n, _), (OP_CHECKMULTISIG, _)]
+            if (OP_1..=OP_16).contains(m) && (OP_1..=OP_16).contains(n) =>
+        {
+            let required = (m - OP_1 + 1) as usize;
+            let pub_keys: Vec<&Vec<u8>> = keys.iter().map(|(_, data)| data).collect();
+            if pub_keys.len() != (n - OP_1 + 1) as usize || pub_keys.iter().any(|k| k.is_empty()) {
+                return false;
+            }
+            // Extra element consumed by OP_CHECKMULTISIG, which must be empty
+            if pushes.len() != required + 1 || !pushes[0].is_empty() {
+                return false;
+            }
+            // Signatures must be in the same order as the public keys
+            let mut keys = pub_keys.iter();
+            pushes[1..]
+                .iter()
+                .all(|sig| keys.any(|pub_key| check_signature(spending, challenge, pub_key, sig)))
+        }
+        _ => {
+            log::warn!("Unsupported signet challenge {}", hex::encode(challenge));
+            false
+        }
+    }
+}
+
+/// Returns whether the block is signed according to the challenge of the
+/// signet (BIP325)
+pub fn check_block_solution(block: &Block, challenge: &[u8]) -> bool {
+    match spending_transaction(block, challenge) {
+        Some(spending) => check_challenge(&spending, challenge),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Returns a block whose coinbase commits to the given signet solution
+    fn signet_block(solution: &[u8]) -> Block {
+        let mut signet_data = SIGNET_HEADER.to_vec();
+        signet_data.extend_from_slice(solution);
+        let mut commitment = WITNESS_COMMITMENT_HEADER.to_vec();
+        commitment.extend_from_slice(&[0; 32]);
+        commitment.extend_from_slice(&script::push_script(&signet_data));
+
+        let mut coinbase = Transaction::new();
+        coinbase.add_input([0; 32], 0xffffffff, vec![0x51]);
+        coinbase.add_output(5_000_000_000, vec![0x51]);
+        coinbase.add_output(0, commitment);
+        Block::new(
+            0x20000000,
+            [1; 32],
+            1598918400,
+            0,
+            0x1e0377ae,
+            Box::new(coinbase),
+        )
+    }
+
+    /// Signs the block with the given keys, as a signet miner would do
+    fn sign_block(challenge: &[u8], priv_keys: &[&[u8]]) -> Block {
+        let unsigned = signet_block(&[]);
+        let spending = spending_transaction(&unsigned, challenge).unwrap();
+        let hash = spending.signature_hash(0, challenge, SIGHASH_ALL as u32);
+
+        let mut script_sig = vec![OP_0];
+        for priv_key in priv_keys {
+            let mut sig = crypto::sign(priv_key, &hash);
+            sig.push(SIGHASH_ALL);
+            script_sig.extend_from_slice(&script::push_script(&sig));
+        }
+        let mut solution = VariableInteger::new(script_sig.len() as u64).bytes();
+        solution.extend_from_slice(&script_sig);
+        solution.push(0x00); // empty witness
+        signet_block(&solution)
+    }
+
+    #[test]
+    fn test_signet_solution() {
+        let (priv_key, pub_key) = crypto::generate_keypair();
+        let (other_priv_key, other_pub_key) = crypto::generate_keypair();
+        // Same shape as the default challenge
+        let mut challenge = vec![OP_1];
+        challenge.extend_from_slice(&script::push_script(&pub_key));
+        challenge.extend_from_slice(&script::push_script(&other_pub_key));
+        challenge.extend_from_slice(&[0x52, OP_CHECKMULTISIG]);
+
+        let block = sign_block(&challenge, &[&priv_key]);
+        assert!(check_block_solution(&block, &challenge));
+        assert!(block.check_merkle_root());
+
+        let block = sign_block(&challenge, &[&other_priv_key]);
+        assert!(check_block_solution(&block, &challenge));
+
+        // Signed by an unknown key
+        let (unknown_priv_key, _) = crypto::generate_keypair();
+        let block = sign_block(&challenge, &[&unknown_priv_key]);
+        assert!(!check_block_solution(&block, &challenge));
+
+        // Signed for another challenge
+        let block = sign_block(&challenge, &[&priv_key]);
+        let default_challenge = hex::decode(DEFAULT_CHALLENGE).unwrap();
+        assert!(!check_block_solution(&block, &default_challenge));
+
+        // Not signed
+        assert!(!check_block_solution(&signet_block(&[]), &challenge));
+        assert!(check_block_solution(&signet_block(&[]), &[OP_1]));
+
+        // Extraneous data after the solution
+        let block = sign_block(&challenge, &[&priv_key]);
+        let mut coinbase = block.coinbase().unwrap().clone();
+        let mut commitment = coinbase.outputs()[1].pubkey();
+        commitment[38] += 1; // push one more byte after the solution
+        commitment.push(0x00);
+        *coinbase.outputs_mut()[1] = TxOutput::new(0, commitment);
+        let block = Block::new(
+            0x20000000,
+            [1; 32],
+            1598918400,
+            0,
+            0x1e0377ae,
+            Box::new(coinbase),
+        );
+        assert!(!check_block_solution(&block, &challenge));
+    }
+
+    #[test]
+    /// Block 1 of the default signet, also used by the signet functional
+    /// test of Bitcoin Core
+    fn test_default_signet_block() {
+        let bytes = hex::decode(concat!(
+            "00000020f61eee3b63a380a477a063af32b2bbc97c9ff9f01f2c4225e973988108000000",
+            "f575c83235984e7dc4afc1f30944c170462e84437ab6f2d52e16878a79e4678bd1914d5f",
+            "ae77031eccf40700010100000000010100000000000000000000000000000000000000",
+            "00000000000000000000000000ffffffff025151feffffff0200f2052a01000000160014",
+            "9243f727dd5343293eb83174324019ec16c2630f0000000000000000776a24aa21a9ede2",
+            "f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf94c4fecc7da",
+            "a2490047304402205e423a8754336ca99dbe16509b877ef1bf98d008836c725005b3c787",
+            "c41ebe46022047246e4467ad7cc7f1ad98662afcaf14c115e0095a227c7b05c5182591c2",
+            "3e7e01000120000000000000000000000000000000000000000000000000000000000000",
+            "000000000000",
+        ))
+        .unwrap();
+        let block = Block::from_bytes(&bytes);
+        assert_eq!(
+            hex::encode(block.hash()),
+            "00000086d6b2636cb2a392d45edc4ec544a10024d30141c9adf4bfd9de533b53"
+        );
+        let challenge = hex::decode(DEFAULT_CHALLENGE).unwrap();
+        assert!(check_block_solution(&block, &challenge));
+
+        // The solution signs the header without the nonce
+        let mut header = bytes.clone();
+        header[76] ^= 1;
+        assert!(check_block_solution(
+            &Block::from_bytes(&header),
+            &challenge
+        ));
+        let mut time = bytes;
+        time[68] ^= 1;
+        assert!(!check_block_solution(&Block::from_bytes(&time), &challenge));
+    }
+
+    #[test]
+    fn test_take_solution() {
+        let mut commitment = WITNESS_COMMITMENT_HEADER.to_vec();
+        commitment.extend_from_slice(&[0; 32]);
+        let mut signet_data = SIGNET_HEADER.to_vec();
+        signet_data.extend_from_slice(&[1, 2, 3]);
+        let mut script = commitment.clone();
+        script.extend_from_slice(&script::push_script(&signet_data));
+
+        let (replacement, solution) = take_solution(&script).unwrap();
+        assert_eq!(solution, Some(vec![1, 2, 3]));
+        let mut expected = commitment.clone();
+        expected.extend_from_slice(&script::push_script(&SIGNET_HEADER));
+        assert_eq!(replacement, expected);
+
+        // The header alone is not a solution
+        assert_eq!(take_solution(&expected).unwrap(), (expected.clone(), None));
+        // Truncated push
+        assert_eq!(take_solution(&[0x05, 0x01]), None);
+    }
+
+    #[test]
+    fn test_parse_solution() {
+        // Empty scriptSig and a witness of one item
+        assert_eq!(
+            parse_solution(&[0x00, 0x01, 0x02, 0xaa, 0xbb]),
+            Some((vec![], vec![vec![0xaa, 0xbb]]))
+        );
+        // Missing or truncated witness item count
+        assert_eq!(parse_solution(&[0x00]), None);
+        assert_eq!(parse_solution(&[0x00, 0xfd, 0x01]), None);
+        // Truncated or overflowing scriptSig length
+        assert_eq!(parse_solution(&[]), None);
+        assert_eq!(parse_solution(&[0xff, 0xff, 0xff]), None);
+        assert_eq!(parse_solution(&[0xff; 9]), None);
+        // Extraneous data
+        assert_eq!(parse_solution(&[0x00, 0x00, 0x00]), None);
+
+        // A block committing to a truncated solution is not valid
+        let block = signet_block(&[0x00]);
+        assert!(!check_block_solution(&block, &[OP_1]));
+    }
+}
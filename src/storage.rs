@@ -1,35 +1,111 @@
 use crate::block::{Block, BlockHeader};
+use crate::cfilter;
 use crate::crypto::{Hash32, Hashable};
+use crate::transaction::TxOutput;
+use crate::utils;
 use bincode;
-use rocksdb::DB;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use rocksdb::{Direction, IteratorMode, DB};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs::{read_dir, File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
 use std::marker::{Send, Sync};
 use std::path;
+use std::sync::Mutex;
 
 #[derive(Debug)]
 pub enum Error {
     DBOperation,
     AlreadyExists,
     FileOperation,
+    UnknownParent,
+    Deserialization,
+    Compression,
+    Pruned,                  // a block to disconnect or connect is not available anymore
+    IncompatibleVersion(u8), // the storage was written with another format
 }
 
+/// Storage of blocks and chain state. Every accessor takes `&self` so that
+/// a single `Storage` can be shared between threads behind an `Arc`.
 pub struct Storage {
     blocks: DB,
     transactions: DB,
     chain: DB,
-    current_file: FilePos,
+    current_file: Mutex<FilePos>, // also serializes writers
+    blocks_file_path: String,
+    compress_blocks: bool,
+    max_block_file_size: u64,
+    build_cfilters: bool,
+    max_reorg_depth: u64,
+    prune_target: Option<u64>, // in bytes
+}
+
+impl std::fmt::Debug for Storage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Storage")
+            .field("blocks_file_path", &self.blocks_file_path)
+            .finish()
+    }
 }
 
 const BLOCK_PREFIX: char = 'b';
+/// A new block file is started once the current one reaches this size
+pub const DEFAULT_MAX_BLOCK_FILE_SIZE: u64 = 128 * 1024 * 1024;
+/// Branches forking deeper below the tip are never switched to
+pub const DEFAULT_MAX_REORG_DEPTH: u64 = 100;
+/// Number of blocks below the tip never pruned automatically, as peers may
+/// still request them (BIP159)
+pub const MIN_BLOCKS_TO_KEEP: u64 = 288;
+// Key of the active tip in the chain db
+const TIP_KEY: &[u8] = b"tip";
+// Hashes of the blocks of the active chain by height, in the chain db
+const HEIGHT_PREFIX: char = 'h';
+// Key of the version of the format of the records in the chain db
+const VERSION_KEY: &[u8] = b"version";
+/// Version of the format of the records, bumped on any change to them.
+/// Storages written before it was recorded have the format of version 1.
+pub const STORAGE_VERSION: u8 = 2;
+const SPEND_PREFIX: char = 's';
+// Unspent outputs of the active chain
+const OUTPUT_PREFIX: char = 'o';
+// Outputs spent by the blocks of the active chain, to disconnect them
+const UNDO_PREFIX: char = 'u';
+// Compact filters of the blocks, only stored when filters are built
+const FILTER_PREFIX: char = 'f';
+// Headers of the compact filters, only stored when the filters of all the
+// previous blocks have been built
+const FILTER_HEADER_PREFIX: char = 'c';
+// Size and highest block of each block file, in the chain db
+const BLOCK_FILE_PREFIX: char = 'F';
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct FilePosRecord {
     name: String,
     pos: u64,
+    size: u64,        // number of bytes written at pos
+    compressed: bool, // whether the block has been deflated
+}
+
+/// Bounds of a block file, so that it can be pruned without going through
+/// the block index
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct BlockFileRecord {
+    size: u64,
+    max_height: u64, // height of the highest block of the file
+    pruned: bool,    // whether the file has been deleted
+}
+
+/// Key of the record of a block file in the chain db
+fn block_file_key(name: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + name.len());
+    key.push(BLOCK_FILE_PREFIX as u8);
+    key.extend_from_slice(name.as_bytes());
+    key
 }
 
 struct FilePos {
@@ -39,17 +115,196 @@ struct FilePos {
 }
 
 impl FilePos {
-    pub fn write(&mut self, bytes: &[u8]) -> Result<u64, Error> {
+    /// Appends bytes to the file, deflating them first if `compress` is set.
+    /// Returns the position and the size of what has been written.
+    pub fn write(&mut self, bytes: &[u8], compress: bool) -> Result<(u64, u64), Error> {
+        let compressed;
+        let bytes = if compress {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            compressed = match encoder.write_all(bytes).and_then(|_| encoder.finish()) {
+                Ok(compressed) => compressed,
+                Err(_) => return Err(Error::Compression),
+            };
+            &compressed
+        } else {
+            bytes
+        };
+
         let orig_pos = self.pos;
         if let Err(_) = self.file.write_all(bytes) {
             return Err(Error::FileOperation);
         }
         self.pos += (bytes.len() as u64);
-        Ok(orig_pos)
+        Ok((orig_pos, bytes.len() as u64))
+    }
+}
+
+/// Unspent output. Coinbase outputs also record the height of their block,
+/// as they can only be spent once mature.
+#[derive(Clone, Serialize, Deserialize)]
+struct OutputRecord {
+    value: u64,
+    script_pub_key: Vec<u8>,
+    coinbase_height: Option<u64>,
+}
+
+impl OutputRecord {
+    fn into_output(self) -> (TxOutput, Option<u64>) {
+        (
+            TxOutput::new(self.value, self.script_pub_key),
+            self.coinbase_height,
+        )
     }
 }
 
+/// Outputs spent by a block, restored when it is disconnected
 #[derive(Serialize, Deserialize)]
+struct UndoRecord {
+    spent: Vec<((Hash32, u32), OutputRecord)>,
+}
+
+/// Transaction spending an outpoint, and the block holding it
+#[derive(Serialize, Deserialize)]
+struct SpendRecord {
+    txid: Hash32,
+    block_hash: Hash32,
+}
+
+/// Key of an outpoint in the transactions db
+fn outpoint_key(prefix: char, outpoint: (Hash32, u32)) -> Vec<u8> {
+    let (txid, vout) = outpoint;
+    let mut key = Vec::with_capacity(37);
+    key.push(prefix as u8);
+    key.extend_from_slice(&txid);
+    key.extend_from_slice(&vout.to_le_bytes());
+    key
+}
+
+/// Key of an outpoint in the spends index of the transactions db
+fn spend_key(outpoint: (Hash32, u32)) -> Vec<u8> {
+    outpoint_key(SPEND_PREFIX, outpoint)
+}
+
+/// Key of the compact filter of a block in the transactions db
+fn filter_key(hash: Hash32) -> Vec<u8> {
+    let mut key = Vec::with_capacity(33);
+    key.push(FILTER_PREFIX as u8);
+    key.extend_from_slice(&hash);
+    key
+}
+
+/// Key of the hash of the block of the active chain at a height in the
+/// chain db, in big endian so that the keys are sorted by height
+fn height_key(height: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(9);
+    key.push(HEIGHT_PREFIX as u8);
+    key.extend_from_slice(&height.to_be_bytes());
+    key
+}
+
+/// Key of the header of the compact filter of a block in the transactions db
+fn filter_header_key(hash: Hash32) -> Vec<u8> {
+    let mut key = Vec::with_capacity(33);
+    key.push(FILTER_HEADER_PREFIX as u8);
+    key.extend_from_slice(&hash);
+    key
+}
+
+/// Key of the undo data of a block in the transactions db
+fn undo_key(hash: Hash32) -> Vec<u8> {
+    let mut key = Vec::with_capacity(33);
+    key.push(UNDO_PREFIX as u8);
+    key.extend_from_slice(&hash);
+    key
+}
+
+/// Unspent outputs as seen from a block: those of the active chain, once
+/// the blocks of the active chain which are not its ancestors have been
+/// disconnected and those of its branch connected
+pub struct UtxoView<'a> {
+    storage: &'a Storage,
+    changes: HashMap<(Hash32, u32), Option<OutputRecord>>, // None once spent
+    disconnected: Vec<Block>,                              // the tip first
+    connected: Vec<(Block, u64, UndoRecord)>,              // with their height
+}
+
+impl<'a> UtxoView<'a> {
+    fn new(storage: &'a Storage) -> Self {
+        UtxoView {
+            storage,
+            changes: HashMap::new(),
+            disconnected: Vec::new(),
+            connected: Vec::new(),
+        }
+    }
+
+    fn get_record(&self, outpoint: (Hash32, u32)) -> Result<Option<OutputRecord>, Error> {
+        match self.changes.get(&outpoint) {
+            Some(record) => Ok(record.clone()),
+            None => self.storage.get_output_record(outpoint),
+        }
+    }
+
+    /// Returns an unspent output, with the height of its block if it is a
+    /// coinbase output
+    pub fn get(&self, outpoint: (Hash32, u32)) -> Result<Option<(TxOutput, Option<u64>)>, Error> {
+        Ok(self.get_record(outpoint)?.map(OutputRecord::into_output))
+    }
+
+    /// Spends the outputs spent by a block, and adds the ones it creates
+    fn connect(&mut self, block: Block, height: u64) -> Result<(), Error> {
+        let mut undo = UndoRecord { spent: Vec::new() };
+        for tx in block.transactions() {
+            let txid = tx.hash();
+            let coinbase_height = if tx.is_coinbase() {
+                Some(height)
+            } else {
+                for input in tx.inputs() {
+                    let outpoint = input.prev_output();
+                    match self.get_record(outpoint)? {
+                        Some(record) => undo.spent.push((outpoint, record)),
+                        // Blocks are not validated at ValidationLevel::None
+                        None => log::debug!("Output spent by {} is unknown", hex::encode(txid)),
+                    }
+                    self.changes.insert(outpoint, None);
+                }
+                None
+            };
+            for (vout, output) in tx.outputs().iter().enumerate() {
+                let script_pub_key = output.pubkey();
+                if script_pub_key.first() == Some(&0x6a) {
+                    // OP_RETURN outputs can not be spent
+                    continue;
+                }
+                let record = OutputRecord {
+                    value: output.value(),
+                    script_pub_key,
+                    coinbase_height,
+                };
+                self.changes.insert((txid, vout as u32), Some(record));
+            }
+        }
+        self.connected.push((block, height, undo));
+        Ok(())
+    }
+
+    /// Restores the outputs spent by a block, then removes the ones it
+    /// creates, some of them being spent by the block itself
+    fn disconnect(&mut self, block: Block, undo: UndoRecord) {
+        for (outpoint, record) in undo.spent {
+            self.changes.insert(outpoint, Some(record));
+        }
+        for tx in block.transactions() {
+            let txid = tx.hash();
+            for vout in 0..tx.outputs().len() {
+                self.changes.insert((txid, vout as u32), None);
+            }
+        }
+        self.disconnected.push(block);
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct BlockIndexRecord {
     header: BlockHeader,
     height: u64,
@@ -57,6 +312,35 @@ struct BlockIndexRecord {
     location: FilePosRecord,
 }
 
+fn block_file_name(number: u32) -> String {
+    format!("blk{:05}.dat", number)
+}
+
+fn open_block_file(blocks_path: &str, block_fname: String) -> Result<FilePos, Error> {
+    let block_path: path::PathBuf = [blocks_path, &block_fname].iter().collect();
+    // Blocks are appended to existing files, which must be kept
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(block_path)
+    {
+        Ok(file) => file,
+        Err(_) => return Err(Error::FileOperation),
+    };
+    let pos = match file.seek(io::SeekFrom::End(0)) {
+        Ok(pos) => pos,
+        Err(_) => return Err(Error::FileOperation),
+    };
+
+    Ok(FilePos {
+        name: block_fname,
+        file,
+        pos,
+    })
+}
+
 fn get_last_block_file_pos(blocks_path: &str) -> FilePos {
     let mut entries = read_dir(blocks_path)
         .unwrap()
@@ -67,65 +351,119 @@ fn get_last_block_file_pos(blocks_path: &str) -> FilePos {
     // ordering is required the entries should be explicitly sorted.
     entries.sort();
 
-    if entries.len() > 0 {
-        let block_fname = entries.pop().unwrap();
-        let block_path: path::PathBuf = [blocks_path, block_fname.to_str().unwrap()]
-            .iter()
-            .collect();
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(block_path)
-            .unwrap();
-        let pos = file.metadata().unwrap().len();
-        file.seek(io::SeekFrom::Start(pos)).unwrap();
-
-        FilePos {
-            name: block_fname.into_string().unwrap(),
-            file,
-            pos,
-        }
-    } else {
-        let block_fname = "blk00001.dat";
-        let block_path: path::PathBuf = [blocks_path, block_fname].iter().collect();
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(block_path)
-            .unwrap();
-        let pos = file.metadata().unwrap().len();
-
-        FilePos {
-            name: block_fname.to_string(),
-            file,
-            pos,
-        }
-    }
+    let block_fname = match entries.pop() {
+        Some(block_fname) => block_fname.into_string().unwrap(),
+        None => block_file_name(1),
+    };
+    open_block_file(blocks_path, block_fname).unwrap()
 }
 
 impl Storage {
+    /// Opens the storage, or creates it if it does not exist yet. A storage
+    /// written with another format version is refused.
     pub fn new(
         blocks_path: &str,
         transactions_path: &str,
         chain_path: &str,
         blocks_file_path: &str,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         let current_file = get_last_block_file_pos(blocks_file_path);
         log::info!(
             "Current block file is {} offset {}",
             current_file.name,
             current_file.pos
         );
-        Storage {
+        let storage = Storage {
             blocks: DB::open_default(blocks_path).unwrap(),
             transactions: DB::open_default(transactions_path).unwrap(),
             chain: DB::open_default(chain_path).unwrap(),
-            current_file,
+            current_file: Mutex::new(current_file),
+            blocks_file_path: blocks_file_path.to_string(),
+            compress_blocks: false,
+            max_block_file_size: DEFAULT_MAX_BLOCK_FILE_SIZE,
+            build_cfilters: false,
+            max_reorg_depth: DEFAULT_MAX_REORG_DEPTH,
+            prune_target: None,
+        };
+        storage.check_version()?;
+        Ok(storage)
+    }
+
+    /// Checks that the storage has been written with the current format,
+    /// and records it if the storage is new
+    fn check_version(&self) -> Result<(), Error> {
+        match self.chain.get(VERSION_KEY) {
+            Err(_) => Err(Error::DBOperation),
+            Ok(Some(version)) if version == [STORAGE_VERSION] => Ok(()),
+            Ok(Some(version)) => Err(Error::IncompatibleVersion(
+                version.first().copied().unwrap_or(0),
+            )),
+            Ok(None) => match self.chain.get_pinned(TIP_KEY) {
+                Err(_) => Err(Error::DBOperation),
+                Ok(Some(_)) => Err(Error::IncompatibleVersion(1)),
+                Ok(None) => match self.chain.put(VERSION_KEY, [STORAGE_VERSION]) {
+                    Ok(()) => Ok(()),
+                    Err(_) => Err(Error::DBOperation),
+                },
+            },
         }
     }
 
-    pub fn store_block(&mut self, block: &Block) -> Result<(), Error> {
+    /// Sets the size in bytes of the block files above which the oldest
+    /// ones are deleted by `prune_to_target`
+    pub fn set_prune_target(&mut self, prune_target: Option<u64>) {
+        self.prune_target = prune_target;
+    }
+
+    /// Sets the maximum number of blocks of the active chain which may be
+    /// disconnected to switch to another branch
+    pub fn set_max_reorg_depth(&mut self, max_reorg_depth: u64) {
+        self.max_reorg_depth = max_reorg_depth;
+    }
+
+    /// Sets whether the basic compact filter (BIP158) of each block stored
+    /// from now on is built
+    pub fn set_build_cfilters(&mut self, build_cfilters: bool) {
+        self.build_cfilters = build_cfilters;
+    }
+
+    /// Sets the size from which a new block file is started
+    pub fn set_max_block_file_size(&mut self, max_block_file_size: u64) {
+        self.max_block_file_size = max_block_file_size;
+    }
+
+    /// Starts a new block file if the current one is full
+    fn rotate_block_file(&self, current_file: &mut FilePos) -> Result<bool, Error> {
+        if current_file.pos < self.max_block_file_size {
+            return Ok(false);
+        }
+        let number: u32 = match current_file
+            .name
+            .trim_start_matches("blk")
+            .trim_end_matches(".dat")
+            .parse()
+        {
+            Ok(number) => number,
+            Err(_) => return Err(Error::FileOperation),
+        };
+        if let Err(_) = current_file.file.flush() {
+            return Err(Error::FileOperation);
+        }
+        *current_file = open_block_file(&self.blocks_file_path, block_file_name(number + 1))?;
+        log::info!("Current block file is now {}", current_file.name);
+        Ok(true)
+    }
+
+    /// Sets whether blocks written from now on are compressed. Blocks
+    /// already stored are read back whatever their format.
+    pub fn set_compress_blocks(&mut self, compress_blocks: bool) {
+        self.compress_blocks = compress_blocks;
+    }
+
+    pub fn store_block(&self, block: &Block) -> Result<(), Error> {
+        // Only one block is stored at a time
+        let mut current_file = self.current_file.lock().unwrap();
+
         // Check existence in blocks db
         let mut key = Vec::with_capacity(33);
         key.extend_from_slice(&block.hash());
@@ -135,34 +473,681 @@ impl Storage {
             _ => (),
         };
 
+        let height = self.next_height(block)?;
+
         // Write to current block file
+        let new_file = self.rotate_block_file(&mut current_file)?;
         log::info!(
             "Writing block {} in file {} offset {}",
             hex::encode(block.hash()),
-            self.current_file.name,
-            self.current_file.pos
+            current_file.name,
+            current_file.pos
         );
-        let pos = self.current_file.write(&block.bytes())?;
+        let (pos, size) = current_file.write(&block.bytes(), self.compress_blocks)?;
+        let mut file_record = self.get_block_file(&current_file.name)?.unwrap_or_default();
+        file_record.size = current_file.pos;
+        file_record.max_height = file_record.max_height.max(height);
+        self.put_block_file(&current_file.name, &file_record)?;
         let location = FilePosRecord {
-            name: self.current_file.name.clone(),
+            name: current_file.name.clone(),
             pos,
+            size,
+            compressed: self.compress_blocks,
         };
 
         let block_index_record = BlockIndexRecord {
-            header: block.header.clone(), // FIXME
-            height: 0,                    // TODO
-            tx_number: (block.transactions.len() as u64),
+            header: block.header.clone(),
+            height,
+            tx_number: (block.transaction_count() as u64),
             location,
         };
 
         // Store block index record
-        self.blocks
-            .put(&key, bincode::serialize(&block_index_record).unwrap());
+        if let Err(_) = self
+            .blocks
+            .put(&key, bincode::serialize(&block_index_record).unwrap())
+        {
+            return Err(Error::DBOperation);
+        }
+
+        // Update the active tip if the block extends the chain
+        let extends_tip = match self.tip()? {
+            Some((tip_hash, tip_height)) if height > tip_height => {
+                let prev = (block.header.hash_prev_block(), height - 1);
+                let depth = self.reorg_depth((tip_hash, tip_height), prev)?;
+                if depth > self.max_reorg_depth {
+                    log::warn!(
+                        "Refusing to switch to block {} forking {} blocks below the tip",
+                        hex::encode(block.hash()),
+                        depth
+                    );
+                }
+                depth <= self.max_reorg_depth
+            }
+            Some(_) => false,
+            None => true,
+        };
+        if extends_tip {
+            log::debug!("New tip {} at height {}", hex::encode(block.hash()), height);
+            let mut view = self.branch_view(block.header.hash_prev_block(), &mut current_file)?;
+            view.connect(block.clone(), height)?;
+            self.write_view(view)?;
+            if let Err(_) = self.chain.put(TIP_KEY, &block.hash()) {
+                return Err(Error::DBOperation);
+            }
+        }
+
+        // The block files only grow by one when a new one is started
+        if new_file {
+            if let Err(err) = self.prune_files_to_target(&current_file) {
+                log::warn!("Error occurred while pruning block files: {:?}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the unspent outputs as seen from a stored block, whose
+    /// transactions may spend them
+    pub fn utxo_view(&self, hash: Hash32) -> Result<UtxoView<'_>, Error> {
+        let mut current_file = self.current_file.lock().unwrap();
+        self.branch_view(hash, &mut current_file)
+    }
+
+    /// Builds the view of a block by disconnecting the blocks of the active
+    /// chain down to the fork point of its branch, then connecting the
+    /// blocks of its branch up to it
+    fn branch_view(&self, hash: Hash32, current_file: &mut FilePos) -> Result<UtxoView<'_>, Error> {
+        let mut view = UtxoView::new(self);
+        let (mut tip, mut tip_height) = match self.tip()? {
+            Some(tip) => tip,
+            None => return Ok(view),
+        };
+        let (mut other, mut other_height) = match self.get_block_index(hash)? {
+            Some(record) => (hash, record.height),
+            None => return Err(Error::UnknownParent),
+        };
+        let mut branch = Vec::new();
+        while tip != other {
+            if tip_height >= other_height {
+                let block = match self.read_block(tip, current_file)? {
+                    Some(block) => block,
+                    None => return Err(Error::Pruned),
+                };
+                let undo = self.get_undo(tip)?;
+                tip = block.header.hash_prev_block();
+                tip_height -= 1;
+                view.disconnect(block, undo);
+            } else {
+                branch.push((other, other_height));
+                other = match self.get_block_index(other)? {
+                    Some(record) => record.header.hash_prev_block(),
+                    None => return Err(Error::UnknownParent),
+                };
+                other_height -= 1;
+            }
+        }
+        for (hash, height) in branch.into_iter().rev() {
+            match self.read_block(hash, current_file)? {
+                Some(block) => view.connect(block, height)?,
+                None => return Err(Error::Pruned),
+            }
+        }
+        Ok(view)
+    }
+
+    /// Writes the changes of a view to the unspent outputs, and the undo
+    /// data and indexes of the blocks it has disconnected and connected
+    fn write_view(&self, view: UtxoView<'_>) -> Result<(), Error> {
+        for block in &view.disconnected {
+            if let Err(_) = self.transactions.delete(undo_key(block.hash())) {
+                return Err(Error::DBOperation);
+            }
+            self.unindex_spends(block)?;
+            if let Some(height) = self.height(block.hash())? {
+                if let Err(_) = self.chain.delete(height_key(height)) {
+                    return Err(Error::DBOperation);
+                }
+            }
+        }
+        for (block, height, undo) in &view.connected {
+            if let Err(_) = self
+                .transactions
+                .put(undo_key(block.hash()), bincode::serialize(undo).unwrap())
+            {
+                return Err(Error::DBOperation);
+            }
+            if let Err(_) = self.chain.put(height_key(*height), &block.hash()) {
+                return Err(Error::DBOperation);
+            }
+            self.index_spends(block)?;
+            if self.build_cfilters {
+                self.store_filter(block, *height, undo)?;
+            }
+        }
+        for (outpoint, record) in view.changes {
+            let key = outpoint_key(OUTPUT_PREFIX, outpoint);
+            let result = match record {
+                Some(record) => self
+                    .transactions
+                    .put(key, bincode::serialize(&record).unwrap()),
+                None => self.transactions.delete(key),
+            };
+            if let Err(_) = result {
+                return Err(Error::DBOperation);
+            }
+        }
+        Ok(())
+    }
+
+    fn get_undo(&self, hash: Hash32) -> Result<UndoRecord, Error> {
+        match self.transactions.get_pinned(undo_key(hash)) {
+            Err(_) => Err(Error::DBOperation),
+            Ok(None) => Err(Error::Deserialization),
+            Ok(Some(bytes)) => match bincode::deserialize(&bytes) {
+                Ok(record) => Ok(record),
+                Err(_) => Err(Error::Deserialization),
+            },
+        }
+    }
+
+    fn get_output_record(&self, outpoint: (Hash32, u32)) -> Result<Option<OutputRecord>, Error> {
+        match self
+            .transactions
+            .get_pinned(outpoint_key(OUTPUT_PREFIX, outpoint))
+        {
+            Err(_) => Err(Error::DBOperation),
+            Ok(None) => Ok(None),
+            Ok(Some(bytes)) => match bincode::deserialize(&bytes) {
+                Ok(record) => Ok(Some(record)),
+                Err(_) => Err(Error::Deserialization),
+            },
+        }
+    }
+
+    /// Returns an unspent output of the active chain, with the height of its
+    /// block if it is a coinbase output
+    pub fn get_output(
+        &self,
+        outpoint: (Hash32, u32),
+    ) -> Result<Option<(TxOutput, Option<u64>)>, Error> {
+        Ok(self
+            .get_output_record(outpoint)?
+            .map(OutputRecord::into_output))
+    }
+
+    /// Builds and stores the basic filter of a block, from the scripts of
+    /// its outputs and of the outputs it spends, and its header if the one
+    /// of the previous block is known
+    fn store_filter(&self, block: &Block, height: u64, undo: &UndoRecord) -> Result<(), Error> {
+        let prev_scripts: Vec<Vec<u8>> = undo
+            .spent
+            .iter()
+            .map(|(_, record)| record.script_pub_key.clone())
+            .filter(|script| !script.is_empty())
+            .collect();
+
+        let filter = cfilter::Filter::basic(block, &prev_scripts);
+        if let Err(_) = self
+            .transactions
+            .put(filter_key(block.hash()), filter.bytes())
+        {
+            return Err(Error::DBOperation);
+        }
+
+        let prev_header = match height {
+            0 => Some([0; 32]),
+            _ => self.get_filter_header(block.header.hash_prev_block())?,
+        };
+        if let Some(prev_header) = prev_header {
+            if let Err(_) = self
+                .transactions
+                .put(filter_header_key(block.hash()), filter.header(&prev_header))
+            {
+                return Err(Error::DBOperation);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the header of the basic compact filter of a block, if the
+    /// filters of the block and of all its ancestors have been built
+    pub fn get_filter_header(&self, hash: Hash32) -> Result<Option<Hash32>, Error> {
+        match self.transactions.get_pinned(filter_header_key(hash)) {
+            Err(_) => Err(Error::DBOperation),
+            Ok(None) => Ok(None),
+            Ok(Some(bytes)) if bytes.len() == 32 => Ok(Some(utils::clone_into_array(&bytes))),
+            Ok(Some(_)) => Err(Error::Deserialization),
+        }
+    }
+
+    /// Returns the basic compact filter of a block, if it has been built
+    pub fn get_filter(&self, hash: Hash32) -> Result<Option<cfilter::Filter>, Error> {
+        match self.transactions.get_pinned(filter_key(hash)) {
+            Err(_) => Err(Error::DBOperation),
+            Ok(None) => Ok(None),
+            Ok(Some(bytes)) => match cfilter::Filter::from_bytes(&bytes) {
+                Ok(filter) => Ok(Some(filter)),
+                Err(_) => Err(Error::Deserialization),
+            },
+        }
+    }
+
+    /// Returns the hashes of the blocks from `start_height` up to `stop_hash`,
+    /// in ascending order
+    pub fn blocks_range(&self, start_height: u64, stop_hash: Hash32) -> Result<Vec<Hash32>, Error> {
+        let mut hashes = Vec::new();
+        let mut hash = stop_hash;
+        loop {
+            let record = match self.get_block_index(hash)? {
+                Some(record) => record,
+                None => return Err(Error::UnknownParent),
+            };
+            if record.height < start_height {
+                break;
+            }
+            hashes.push(hash);
+            if record.height == 0 {
+                break;
+            }
+            hash = record.header.hash_prev_block();
+        }
+        hashes.reverse();
+        Ok(hashes)
+    }
+
+    /// Records the outpoints spent by the transactions of a block of the
+    /// active chain
+    fn index_spends(&self, block: &Block) -> Result<(), Error> {
+        // The coinbase does not spend any outpoint
+        for tx in block.transactions().iter().skip(1) {
+            let record = SpendRecord {
+                txid: tx.hash(),
+                block_hash: block.hash(),
+            };
+            let value = bincode::serialize(&record).unwrap();
+            for input in tx.inputs() {
+                if let Err(_) = self
+                    .transactions
+                    .put(spend_key(input.prev_output()), &value)
+                {
+                    return Err(Error::DBOperation);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the outpoints spent by the transactions of a block which is
+    /// disconnected from the active chain
+    fn unindex_spends(&self, block: &Block) -> Result<(), Error> {
+        for tx in block.transactions().iter().skip(1) {
+            for input in tx.inputs() {
+                if let Err(_) = self.transactions.delete(spend_key(input.prev_output())) {
+                    return Err(Error::DBOperation);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the transaction of the active chain which spends the given
+    /// outpoint, if any
+    pub fn spending_tx(&self, outpoint: (Hash32, u32)) -> Result<Option<Hash32>, Error> {
+        match self.transactions.get_pinned(spend_key(outpoint)) {
+            Err(_) => Err(Error::DBOperation),
+            Ok(None) => Ok(None),
+            Ok(Some(bytes)) => match bincode::deserialize::<SpendRecord>(&bytes) {
+                Ok(record) => Ok(Some(record.txid)),
+                Err(_) => Err(Error::Deserialization),
+            },
+        }
+    }
+
+    /// Returns the height of the given block, computed from the height of
+    /// its parent which must already be stored
+    pub fn next_height(&self, block: &Block) -> Result<u64, Error> {
+        let hash_prev_block = block.header.hash_prev_block();
+        if hash_prev_block == [0; 32] {
+            // Genesis block
+            return Ok(0);
+        }
+        match self.get_block_index(hash_prev_block)? {
+            Some(record) => Ok(record.height + 1),
+            None => Err(Error::UnknownParent),
+        }
+    }
+
+    /// Returns the number of blocks of the active chain, ending with `tip`,
+    /// to disconnect to switch to the branch ending with `other`. Both are
+    /// given with their height. Gives up once the maximum depth is exceeded.
+    fn reorg_depth(&self, tip: (Hash32, u64), other: (Hash32, u64)) -> Result<u64, Error> {
+        let (mut hash, mut height) = tip;
+        let (mut other_hash, mut other_height) = other;
+        let prev = |hash: Hash32| match self.get_block_index(hash)? {
+            Some(record) => Ok(record.header.hash_prev_block()),
+            None => Err(Error::UnknownParent),
+        };
+        while hash != other_hash && tip.1 - height <= self.max_reorg_depth {
+            if height >= other_height {
+                hash = prev(hash)?;
+                height -= 1;
+            } else {
+                other_hash = prev(other_hash)?;
+                other_height -= 1;
+            }
+        }
+        Ok(tip.1 - height)
+    }
+
+    fn get_block_index(&self, hash: Hash32) -> Result<Option<BlockIndexRecord>, Error> {
+        match self.blocks.get_pinned(&hash) {
+            Err(_) => Err(Error::DBOperation),
+            Ok(None) => Ok(None),
+            Ok(Some(bytes)) => match bincode::deserialize(&bytes) {
+                Ok(record) => Ok(Some(record)),
+                Err(_) => Err(Error::Deserialization),
+            },
+        }
+    }
+
+    /// Returns the hash and the height of the last block of the active chain
+    pub fn tip(&self) -> Result<Option<(Hash32, u64)>, Error> {
+        let hash: Hash32 = match self.chain.get_pinned(TIP_KEY) {
+            Err(_) => return Err(Error::DBOperation),
+            Ok(None) => return Ok(None),
+            Ok(Some(bytes)) if bytes.len() == 32 => utils::clone_into_array(&bytes),
+            Ok(Some(_)) => return Err(Error::Deserialization),
+        };
+        match self.get_block_index(hash)? {
+            Some(record) => Ok(Some((hash, record.height))),
+            None => Err(Error::Deserialization),
+        }
+    }
+
+    /// Returns the hash of the block of the active chain at a height
+    pub fn active_hash(&self, height: u64) -> Result<Option<Hash32>, Error> {
+        match self.chain.get_pinned(height_key(height)) {
+            Err(_) => Err(Error::DBOperation),
+            Ok(None) => Ok(None),
+            Ok(Some(bytes)) if bytes.len() == 32 => Ok(Some(utils::clone_into_array(&bytes))),
+            Ok(Some(_)) => Err(Error::Deserialization),
+        }
+    }
+
+    /// Returns the hash of the block of the active chain at a height, which
+    /// must not be above the tip
+    fn expect_active_hash(&self, height: u64) -> Result<Hash32, Error> {
+        match self.active_hash(height)? {
+            Some(hash) => Ok(hash),
+            None => Err(Error::UnknownParent),
+        }
+    }
+
+    /// Returns the hashes of the blocks of the active chain, from the
+    /// genesis block to the tip
+    pub fn active_chain(&self) -> Result<Vec<Hash32>, Error> {
+        let tip_height = match self.tip()? {
+            Some((_, height)) => height,
+            None => return Ok(vec![]),
+        };
+        (0..=tip_height)
+            .map(|height| self.expect_active_hash(height))
+            .collect()
+    }
+
+    fn get_block_file(&self, name: &str) -> Result<Option<BlockFileRecord>, Error> {
+        match self.chain.get_pinned(block_file_key(name)) {
+            Err(_) => Err(Error::DBOperation),
+            Ok(None) => Ok(None),
+            Ok(Some(bytes)) => match bincode::deserialize(&bytes) {
+                Ok(record) => Ok(Some(record)),
+                Err(_) => Err(Error::Deserialization),
+            },
+        }
+    }
+
+    fn put_block_file(&self, name: &str, record: &BlockFileRecord) -> Result<(), Error> {
+        match self
+            .chain
+            .put(block_file_key(name), bincode::serialize(record).unwrap())
+        {
+            Ok(()) => Ok(()),
+            Err(_) => Err(Error::DBOperation),
+        }
+    }
+
+    /// Returns the records of the block files, pruned ones included
+    fn block_files(&self) -> Result<Vec<(String, BlockFileRecord)>, Error> {
+        let prefix = [BLOCK_FILE_PREFIX as u8];
+        let mut files = Vec::new();
+        for (key, value) in self
+            .chain
+            .iterator(IteratorMode::From(&prefix, Direction::Forward))
+            .take_while(|(key, _)| key.starts_with(&prefix))
+        {
+            let name = match String::from_utf8(key[1..].to_vec()) {
+                Ok(name) => name,
+                Err(_) => return Err(Error::Deserialization),
+            };
+            match bincode::deserialize(&value) {
+                Ok(record) => files.push((name, record)),
+                Err(_) => return Err(Error::Deserialization),
+            }
+        }
+        Ok(files)
+    }
+
+    /// Deletes the block files only holding blocks below the given height,
+    /// the current one excepted. The headers of their blocks stay in the
+    /// index. Returns the number of bytes reclaimed.
+    pub fn prune_below(&self, height: u64) -> Result<u64, Error> {
+        // Block files can not be written meanwhile
+        let current_file = self.current_file.lock().unwrap();
+        self.prune_files_below(height, &current_file)
+    }
+
+    /// Prunes the block files, `current_file` being locked by the caller
+    fn prune_files_below(&self, height: u64, current_file: &FilePos) -> Result<u64, Error> {
+        let mut reclaimed = 0;
+        for (name, mut record) in self.block_files()? {
+            if record.pruned || record.max_height >= height || name == current_file.name {
+                continue;
+            }
+            let block_path: path::PathBuf = [&self.blocks_file_path, &name].iter().collect();
+            if let Err(_) = std::fs::remove_file(&block_path) {
+                return Err(Error::FileOperation);
+            }
+            record.pruned = true;
+            self.put_block_file(&name, &record)?;
+            log::info!("Pruned block file {} ({} bytes)", name, record.size);
+            reclaimed += record.size;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Returns the size in bytes of the block files
+    pub fn block_files_size(&self) -> Result<u64, Error> {
+        Ok(self
+            .block_files()?
+            .iter()
+            .filter(|(_, record)| !record.pruned)
+            .map(|(_, record)| record.size)
+            .sum())
+    }
+
+    /// Once the block files exceed the prune target, deletes those only
+    /// holding blocks more than `MIN_BLOCKS_TO_KEEP` below the tip. Returns
+    /// the number of bytes reclaimed. This is done whenever a new block
+    /// file is started.
+    pub fn prune_to_target(&self) -> Result<u64, Error> {
+        let current_file = self.current_file.lock().unwrap();
+        self.prune_files_to_target(&current_file)
+    }
+
+    fn prune_files_to_target(&self, current_file: &FilePos) -> Result<u64, Error> {
+        let prune_target = match self.prune_target {
+            Some(prune_target) => prune_target,
+            None => return Ok(0),
+        };
+        if self.block_files_size()? <= prune_target {
+            return Ok(0);
+        }
+        match self.tip()? {
+            Some((_, height)) if height > MIN_BLOCKS_TO_KEEP => {
+                self.prune_files_below(height - MIN_BLOCKS_TO_KEEP, current_file)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Returns the locator of the active chain sent in getheaders messages:
+    /// the hashes of the last ten blocks from the tip, then of exponentially
+    /// distant ones, down to the genesis block
+    pub fn block_locator(&self) -> Result<Vec<Hash32>, Error> {
+        let mut locator = Vec::new();
+        let (tip, mut height) = match self.tip()? {
+            Some(tip) => tip,
+            None => return Ok(locator),
+        };
+        locator.push(tip);
+        let mut step = 1;
+        while height > 0 {
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+            locator.push(self.expect_active_hash(height)?);
+        }
+        Ok(locator)
+    }
+
+    /// Returns the last block of the locator found on the active chain, or
+    /// the genesis block if there is none, and the headers following it up
+    /// to `stop_hash`, at most `max` of them
+    pub fn headers_after(
+        &self,
+        locator: &[Hash32],
+        stop_hash: Hash32,
+        max: usize,
+    ) -> Result<(Hash32, Vec<BlockHeader>), Error> {
+        let tip_height = match self.tip()? {
+            Some((_, height)) => height,
+            None => return Err(Error::UnknownParent),
+        };
+        let mut fork_height = 0;
+        for hash in locator {
+            if let Some(height) = self.height(*hash)? {
+                if self.active_hash(height)? == Some(*hash) {
+                    fork_height = height;
+                    break;
+                }
+            }
+        }
+        let fork = self.expect_active_hash(fork_height)?;
+
+        let mut headers = Vec::new();
+        for height in (fork_height + 1..=tip_height).take(max) {
+            let hash = self.expect_active_hash(height)?;
+            match self.get_block_index(hash)? {
+                Some(record) => headers.push(record.header),
+                None => return Err(Error::UnknownParent),
+            }
+            if hash == stop_hash {
+                break;
+            }
+        }
+        Ok((fork, headers))
+    }
+
+    /// Returns the height of a stored block
+    pub fn height(&self, hash: Hash32) -> Result<Option<u64>, Error> {
+        Ok(self.get_block_index(hash)?.map(|record| record.height))
+    }
+
+    /// Returns the timestamps of a stored block and of its ancestors, the
+    /// most recent first, at most `count` of them
+    pub fn timestamps(&self, hash: Hash32, count: usize) -> Result<Vec<u64>, Error> {
+        let mut timestamps = Vec::with_capacity(count);
+        let mut hash = hash;
+        while timestamps.len() < count {
+            match self.get_block_index(hash)? {
+                Some(record) => {
+                    timestamps.push(record.header.timestamp());
+                    hash = record.header.hash_prev_block();
+                }
+                None => break,
+            }
+        }
+        Ok(timestamps)
+    }
+
+    /// Reads a stored block from its block file. Pruned blocks are not
+    /// available anymore.
+    pub fn get_block(&self, hash: Hash32) -> Result<Option<Block>, Error> {
+        let mut current_file = self.current_file.lock().unwrap();
+        self.read_block(hash, &mut current_file)
+    }
+
+    /// Reads a stored block, `current_file` being locked by the caller
+    fn read_block(&self, hash: Hash32, current_file: &mut FilePos) -> Result<Option<Block>, Error> {
+        let location = match self.get_block_index(hash)? {
+            Some(record) => record.location,
+            None => return Ok(None),
+        };
+        match self.get_block_file(&location.name)? {
+            Some(file) if file.pruned => return Ok(None),
+            _ => (),
+        }
+        if location.name == current_file.name {
+            // Make sure everything has been written
+            if let Err(_) = current_file.file.flush() {
+                return Err(Error::FileOperation);
+            }
+        }
+
+        let block_path: path::PathBuf = [&self.blocks_file_path, &location.name].iter().collect();
+        let mut file = match File::open(block_path) {
+            Ok(file) => file,
+            Err(_) => return Err(Error::FileOperation),
+        };
+        let mut bytes = vec![0; location.size as usize];
+        if let Err(_) = file
+            .seek(io::SeekFrom::Start(location.pos))
+            .and_then(|_| file.read_exact(&mut bytes))
+        {
+            return Err(Error::FileOperation);
+        }
+
+        if location.compressed {
+            let mut decompressed = Vec::new();
+            if let Err(_) = DeflateDecoder::new(&bytes[..]).read_to_end(&mut decompressed) {
+                return Err(Error::Compression);
+            }
+            bytes = decompressed;
+        }
+        Ok(Some(Block::from_bytes(&bytes)))
+    }
 
+    /// Flushes the three dbs and syncs the current block file to disk, so
+    /// that nothing is lost and the next start does not replay the logs
+    pub fn flush(&self) -> Result<(), Error> {
+        for db in [&self.blocks, &self.transactions, &self.chain].iter() {
+            if let Err(_) = db.flush() {
+                return Err(Error::DBOperation);
+            }
+        }
+        let mut current_file = self.current_file.lock().unwrap();
+        if let Err(_) = current_file
+            .file
+            .flush()
+            .and_then(|_| current_file.file.sync_all())
+        {
+            return Err(Error::FileOperation);
+        }
         Ok(())
     }
 
-    pub fn has_block(&mut self, hash: Hash32) -> Result<bool, Error> {
+    pub fn has_block(&self, hash: Hash32) -> Result<bool, Error> {
         let mut key = Vec::with_capacity(33);
         key.extend_from_slice(&hash);
         match self.blocks.get_pinned(&key) {
@@ -172,3 +1157,688 @@ impl Storage {
         }
     }
 }
+
+#[cfg(test)]
+pub fn temp_storage_root(name: &str) -> path::PathBuf {
+    let mut root = std::env::temp_dir();
+    root.push(format!("yasbit_{}_{}", name, std::process::id()));
+    root
+}
+
+/// Creates an empty storage in a temporary directory
+#[cfg(test)]
+pub fn temp_storage(name: &str) -> Storage {
+    let root = temp_storage_root(name);
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("blocks")).unwrap();
+    reopen_temp_storage(name)
+}
+
+/// Opens again a storage created with `temp_storage`
+#[cfg(test)]
+pub fn reopen_temp_storage(name: &str) -> Storage {
+    open_temp_storage(name).unwrap()
+}
+
+#[cfg(test)]
+fn open_temp_storage(name: &str) -> Result<Storage, Error> {
+    let root = temp_storage_root(name);
+    let path = |name: &str| root.join(name).to_str().unwrap().to_string();
+    Storage::new(
+        &path("blocks.db"),
+        &path("transactions.db"),
+        &path("chain.db"),
+        &path("blocks"),
+    )
+}
+
+/// Returns a valid regtest block on top of the given one, `time` seconds
+/// after it
+#[cfg(test)]
+pub fn next_block(prev: &Block, time: u32) -> Block {
+    let mut coinbase = crate::transaction::Transaction::new();
+    coinbase.add_input([0; 32], 0xffffffff, time.to_le_bytes().to_vec());
+    // Nothing is claimed, so that the block is valid whatever its height
+    coinbase.add_output(0, vec![]);
+    let time = prev.header.time() + time;
+    let mut block = Block::new(1, prev.hash(), time, 0, 0x207fffff, Box::new(coinbase));
+    block.mine();
+    block
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::config;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_tip_and_heights() {
+        let config = config::regtest_config();
+        let block1 = next_block(&config.genesis_block, 1);
+        let block2 = next_block(&block1, 2);
+        {
+            let storage = temp_storage("storage_tip");
+            assert_eq!(storage.tip().unwrap(), None);
+
+            storage.store_block(&config.genesis_block).unwrap();
+            assert_eq!(
+                storage.tip().unwrap(),
+                Some((config.genesis_block.hash(), 0))
+            );
+
+            // Parent must be stored first
+            match storage.store_block(&block2) {
+                Err(Error::UnknownParent) => (),
+                _ => panic!("Block without parent should not be stored"),
+            }
+
+            storage.store_block(&block1).unwrap();
+            assert_eq!(storage.tip().unwrap(), Some((block1.hash(), 1)));
+            assert_eq!(storage.height(block1.hash()).unwrap(), Some(1));
+            assert_eq!(
+                storage.active_chain().unwrap(),
+                vec![config.genesis_block.hash(), block1.hash()]
+            );
+        }
+
+        // The tip survives a restart
+        let storage = reopen_temp_storage("storage_tip");
+        assert_eq!(storage.tip().unwrap(), Some((block1.hash(), 1)));
+        assert_eq!(storage.height(block2.hash()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_flush() {
+        let config = config::regtest_config();
+        let block1 = next_block(&config.genesis_block, 1);
+        let block2 = next_block(&block1, 2);
+        {
+            let storage = temp_storage("storage_flush");
+            storage.store_block(&config.genesis_block).unwrap();
+            storage.store_block(&block1).unwrap();
+            storage.store_block(&block2).unwrap();
+            storage.flush().unwrap();
+        }
+
+        let storage = reopen_temp_storage("storage_flush");
+        for block in [&config.genesis_block, &block1, &block2].iter() {
+            assert!(storage.has_block(block.hash()).unwrap());
+            assert_eq!(
+                storage.get_block(block.hash()).unwrap().as_ref(),
+                Some(*block)
+            );
+        }
+        assert_eq!(storage.tip().unwrap(), Some((block2.hash(), 2)));
+    }
+
+    #[test]
+    fn test_storage_version() {
+        let config = config::regtest_config();
+        let storage = temp_storage("storage_version");
+        storage.store_block(&config.genesis_block).unwrap();
+        drop(storage);
+        // The version is recorded when the storage is created
+        let storage = reopen_temp_storage("storage_version");
+        assert!(storage.has_block(config.genesis_block.hash()).unwrap());
+
+        // Storages written with an older or a newer format are refused
+        for version in [0, STORAGE_VERSION + 1].iter() {
+            let name = format!("storage_version_{}", version);
+            let storage = temp_storage(&name);
+            storage.chain.put(VERSION_KEY, [*version]).unwrap();
+            drop(storage);
+            assert!(matches!(
+                open_temp_storage(&name),
+                Err(Error::IncompatibleVersion(v)) if v == *version
+            ));
+        }
+
+        // Storages holding blocks without any version have the first format
+        let storage = temp_storage("storage_version_none");
+        storage.store_block(&config.genesis_block).unwrap();
+        storage.chain.delete(VERSION_KEY).unwrap();
+        drop(storage);
+        assert!(matches!(
+            open_temp_storage("storage_version_none"),
+            Err(Error::IncompatibleVersion(1))
+        ));
+    }
+
+    #[test]
+    fn test_block_index_record() {
+        let config = config::regtest_config();
+        let block1 = next_block(&config.genesis_block, 1);
+        let record = BlockIndexRecord {
+            header: block1.header.clone(),
+            height: 1,
+            tx_number: 1,
+            location: FilePosRecord {
+                name: block_file_name(1),
+                pos: 293,
+                size: 213,
+                compressed: false,
+            },
+        };
+        let bytes = bincode::serialize(&record).unwrap();
+        assert_eq!(
+            bincode::deserialize::<BlockIndexRecord>(&bytes).unwrap(),
+            record
+        );
+
+        // Records are read back from the blocks db as they were written
+        let storage = temp_storage("storage_block_index");
+        storage.store_block(&config.genesis_block).unwrap();
+        storage.store_block(&block1).unwrap();
+        let stored = storage.get_block_index(block1.hash()).unwrap().unwrap();
+        assert_eq!(stored.header, block1.header);
+        assert_eq!(stored.height, 1);
+        assert_eq!(stored.tx_number, 1);
+        assert_eq!(stored.location.name, block_file_name(1));
+        assert_eq!(storage.get_block_index([1; 32]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_headers_after() {
+        let config = config::regtest_config();
+        let storage = temp_storage("storage_headers_after");
+        let mut chain = vec![config.genesis_block.clone()];
+        for time in 1..6 {
+            let block = next_block(chain.last().unwrap(), time);
+            chain.push(block);
+        }
+        for block in &chain {
+            storage.store_block(block).unwrap();
+        }
+        let headers = |range: std::ops::Range<usize>| -> Vec<BlockHeader> {
+            chain[range]
+                .iter()
+                .map(|block| block.header.clone())
+                .collect()
+        };
+
+        // The first hash of the locator on the active chain is the fork point
+        let locator = [[1; 32], chain[2].hash(), chain[0].hash()];
+        assert_eq!(
+            storage.headers_after(&locator, [0; 32], 2000).unwrap(),
+            (chain[2].hash(), headers(3..6))
+        );
+        assert_eq!(
+            storage
+                .headers_after(&locator, chain[4].hash(), 2000)
+                .unwrap(),
+            (chain[2].hash(), headers(3..5))
+        );
+        assert_eq!(
+            storage.headers_after(&locator, [0; 32], 1).unwrap(),
+            (chain[2].hash(), headers(3..4))
+        );
+        assert_eq!(
+            storage
+                .headers_after(&[chain[5].hash()], [0; 32], 2000)
+                .unwrap(),
+            (chain[5].hash(), vec![])
+        );
+        // Without any known block, headers are sent from the genesis block
+        assert_eq!(
+            storage.headers_after(&[[1; 32]], [0; 32], 2000).unwrap(),
+            (chain[0].hash(), headers(1..6))
+        );
+    }
+
+    #[test]
+    fn test_block_locator() {
+        let config = config::regtest_config();
+        let storage = temp_storage("storage_locator");
+        assert_eq!(storage.block_locator().unwrap(), Vec::<Hash32>::new());
+
+        let mut chain = vec![config.genesis_block.clone()];
+        for time in 1..20 {
+            let block = next_block(chain.last().unwrap(), time);
+            chain.push(block);
+        }
+        for block in &chain {
+            storage.store_block(block).unwrap();
+        }
+        let heights = [19, 18, 17, 16, 15, 14, 13, 12, 11, 10, 8, 4, 0];
+        assert_eq!(
+            storage.block_locator().unwrap(),
+            heights
+                .iter()
+                .map(|height| chain[*height].hash())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let config = config::regtest_config();
+        let block1 = next_block(&config.genesis_block, 1);
+        {
+            let mut storage = temp_storage("storage_compression");
+            storage.store_block(&config.genesis_block).unwrap();
+            storage.set_compress_blocks(true);
+            storage.store_block(&block1).unwrap();
+
+            assert_eq!(
+                storage.get_block(config.genesis_block.hash()).unwrap(),
+                Some(config.genesis_block.clone())
+            );
+            assert_eq!(
+                storage.get_block(block1.hash()).unwrap(),
+                Some(block1.clone())
+            );
+            assert_eq!(storage.get_block([1; 32]).unwrap(), None);
+        }
+
+        // Both formats are still readable after a restart
+        let storage = reopen_temp_storage("storage_compression");
+        assert_eq!(
+            storage.get_block(config.genesis_block.hash()).unwrap(),
+            Some(config.genesis_block.clone())
+        );
+        assert_eq!(storage.get_block(block1.hash()).unwrap(), Some(block1));
+    }
+
+    #[test]
+    fn test_block_file_rotation() {
+        let config = config::regtest_config();
+        let mut blocks = vec![config.genesis_block.clone()];
+        for time in 1..5 {
+            let block = next_block(blocks.last().unwrap(), time);
+            blocks.push(block);
+        }
+        {
+            let mut storage = temp_storage("storage_rotation");
+            // Every block is larger than the maximum size
+            storage.set_max_block_file_size(100);
+            for block in &blocks {
+                storage.store_block(block).unwrap();
+            }
+            assert_eq!(storage.current_file.lock().unwrap().name, "blk00005.dat");
+            for block in &blocks {
+                assert_eq!(
+                    storage.get_block(block.hash()).unwrap().as_ref(),
+                    Some(block)
+                );
+            }
+        }
+
+        // The last file is picked up after a restart
+        let mut storage = reopen_temp_storage("storage_rotation");
+        assert_eq!(storage.current_file.lock().unwrap().name, "blk00005.dat");
+        storage.set_max_block_file_size(100);
+        let block = next_block(blocks.last().unwrap(), 5);
+        storage.store_block(&block).unwrap();
+        assert_eq!(storage.current_file.lock().unwrap().name, "blk00006.dat");
+        for block in blocks.iter().chain(std::iter::once(&block)) {
+            assert_eq!(
+                storage.get_block(block.hash()).unwrap().as_ref(),
+                Some(block)
+            );
+        }
+    }
+
+    #[test]
+    fn test_prune_below() {
+        let config = config::regtest_config();
+        let mut blocks = vec![config.genesis_block.clone()];
+        for time in 1..6 {
+            let block = next_block(blocks.last().unwrap(), time);
+            blocks.push(block);
+        }
+        {
+            let mut storage = temp_storage("storage_prune");
+            // One block per file
+            storage.set_max_block_file_size(100);
+            for block in &blocks {
+                storage.store_block(block).unwrap();
+            }
+            let size = storage.block_files_size().unwrap();
+            let pruned_size: u64 = blocks[..3].iter().map(|block| block.size() as u64).sum();
+
+            assert_eq!(storage.prune_below(3).unwrap(), pruned_size);
+            assert_eq!(storage.block_files_size().unwrap(), size - pruned_size);
+            assert_eq!(storage.prune_below(3).unwrap(), 0);
+            for (height, block) in blocks.iter().enumerate() {
+                let expected = if height < 3 { None } else { Some(block) };
+                assert_eq!(storage.get_block(block.hash()).unwrap().as_ref(), expected);
+                // The headers are kept
+                assert!(storage.has_block(block.hash()).unwrap());
+                assert_eq!(storage.height(block.hash()).unwrap(), Some(height as u64));
+            }
+            assert_eq!(
+                storage.active_chain().unwrap(),
+                blocks.iter().map(|block| block.hash()).collect::<Vec<_>>()
+            );
+
+            // The current file is never pruned
+            let pruned_size = (blocks[3].size() + blocks[4].size()) as u64;
+            assert_eq!(storage.prune_below(100).unwrap(), pruned_size);
+            assert!(storage.get_block(blocks[5].hash()).unwrap().is_some());
+        }
+
+        // Blocks are still stored after a restart
+        let mut storage = reopen_temp_storage("storage_prune");
+        storage.set_max_block_file_size(100);
+        let block = next_block(blocks.last().unwrap(), 6);
+        storage.store_block(&block).unwrap();
+        assert_eq!(storage.get_block(block.hash()).unwrap(), Some(block));
+        assert_eq!(storage.get_block(blocks[0].hash()).unwrap(), None);
+
+        // Nothing is pruned below the target, nor close to the tip
+        storage.set_prune_target(Some(0));
+        assert_eq!(storage.prune_to_target().unwrap(), 0);
+        assert!(storage.get_block(blocks[5].hash()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_prune_on_new_file() {
+        let config = config::regtest_config();
+        let mut storage = temp_storage("storage_prune_on_new_file");
+        // One block per file
+        storage.set_max_block_file_size(100);
+        storage.set_prune_target(Some(0));
+        let mut blocks = vec![config.genesis_block.clone()];
+        storage.store_block(&blocks[0]).unwrap();
+        for time in 1..(MIN_BLOCKS_TO_KEEP as u32 + 3) {
+            let block = next_block(blocks.last().unwrap(), time);
+            storage.store_block(&block).unwrap();
+            blocks.push(block);
+        }
+
+        // The files of the blocks too far below the tip have been deleted
+        // as new files were started
+        let tip_height = blocks.len() as u64 - 1;
+        for (height, block) in blocks.iter().enumerate() {
+            let kept = height as u64 >= tip_height - MIN_BLOCKS_TO_KEEP;
+            assert_eq!(storage.get_block(block.hash()).unwrap().is_some(), kept);
+        }
+        let kept_size: u64 = blocks[(tip_height - MIN_BLOCKS_TO_KEEP) as usize..]
+            .iter()
+            .map(|block| block.size() as u64)
+            .sum();
+        assert_eq!(storage.block_files_size().unwrap(), kept_size);
+    }
+
+    #[test]
+    fn test_concurrent_access() {
+        let config = config::regtest_config();
+        let storage = Arc::new(temp_storage("storage_concurrent"));
+        storage.store_block(&config.genesis_block).unwrap();
+
+        // Two chains forking from the genesis block, stored concurrently
+        let writers: Vec<_> = (0..2u32)
+            .map(|fork| {
+                let storage = Arc::clone(&storage);
+                let genesis = config.genesis_block.clone();
+                thread::spawn(move || {
+                    let mut prev = genesis;
+                    for i in 0..20 {
+                        let block = next_block(&prev, fork * 100 + i + 1);
+                        storage.store_block(&block).unwrap();
+                        assert_eq!(
+                            storage.get_block(block.hash()).unwrap().as_ref(),
+                            Some(&block)
+                        );
+                        prev = block;
+                    }
+                    prev
+                })
+            })
+            .collect();
+        let reader = {
+            let storage = Arc::clone(&storage);
+            let genesis = config.genesis_block.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    assert_eq!(
+                        storage.get_block(genesis.hash()).unwrap(),
+                        Some(genesis.clone())
+                    );
+                    let (_, height) = storage.tip().unwrap().unwrap();
+                    assert!(height <= 20);
+                }
+            })
+        };
+
+        let tips: Vec<Block> = writers.into_iter().map(|w| w.join().unwrap()).collect();
+        reader.join().unwrap();
+        for tip in &tips {
+            assert_eq!(storage.height(tip.hash()).unwrap(), Some(20));
+            assert_eq!(storage.get_block(tip.hash()).unwrap().as_ref(), Some(tip));
+        }
+        assert_eq!(storage.tip().unwrap().unwrap().1, 20);
+    }
+
+    #[test]
+    fn test_spending_tx() {
+        let config = config::regtest_config();
+        let block1 = next_block(&config.genesis_block, 1);
+        let coinbase1 = block1.coinbase().unwrap().hash();
+        let mut spend = crate::transaction::Transaction::new();
+        spend.add_input(coinbase1, 0, vec![]);
+        spend.add_output(4_000_000_000, vec![]);
+        let mut block2 = next_block(&block1, 2);
+        block2.add_tx(Box::new(spend.clone())).unwrap();
+        {
+            let storage = temp_storage("storage_spends");
+            for block in &[&config.genesis_block, &block1, &block2] {
+                storage.store_block(block).unwrap();
+            }
+            assert_eq!(
+                storage.spending_tx((coinbase1, 0)).unwrap(),
+                Some(spend.hash())
+            );
+            assert_eq!(storage.spending_tx((coinbase1, 1)).unwrap(), None);
+            assert_eq!(storage.spending_tx((spend.hash(), 0)).unwrap(), None);
+        }
+
+        // The index survives a restart
+        let storage = reopen_temp_storage("storage_spends");
+        assert_eq!(
+            storage.spending_tx((coinbase1, 0)).unwrap(),
+            Some(spend.hash())
+        );
+
+        // It follows the active chain when switching branches
+        let mut respend = crate::transaction::Transaction::new();
+        respend.add_input(coinbase1, 0, vec![]);
+        respend.add_output(3_000_000_000, vec![]);
+        let mut block2b = next_block(&block1, 3);
+        block2b.add_tx(Box::new(respend.clone())).unwrap();
+        let block3b = next_block(&block2b, 3);
+        storage.store_block(&block2b).unwrap();
+        assert_eq!(
+            storage.spending_tx((coinbase1, 0)).unwrap(),
+            Some(spend.hash())
+        );
+        storage.store_block(&block3b).unwrap();
+        assert_eq!(
+            storage.spending_tx((coinbase1, 0)).unwrap(),
+            Some(respend.hash())
+        );
+        let block3 = next_block(&block2, 4);
+        let block4 = next_block(&block3, 4);
+        storage.store_block(&block3).unwrap();
+        storage.store_block(&block4).unwrap();
+        assert_eq!(storage.tip().unwrap(), Some((block4.hash(), 4)));
+        assert_eq!(
+            storage.spending_tx((coinbase1, 0)).unwrap(),
+            Some(spend.hash())
+        );
+
+        // Outpoints spent only by a disconnected block are not spent anymore
+        let mut spend2 = crate::transaction::Transaction::new();
+        spend2.add_input(spend.hash(), 0, vec![]);
+        spend2.add_output(0, vec![]);
+        let mut block5 = next_block(&block4, 5);
+        block5.add_tx(Box::new(spend2)).unwrap();
+        storage.store_block(&block5).unwrap();
+        assert!(storage.spending_tx((spend.hash(), 0)).unwrap().is_some());
+        let block5b = next_block(&block4, 6);
+        let block6b = next_block(&block5b, 6);
+        storage.store_block(&block5b).unwrap();
+        storage.store_block(&block6b).unwrap();
+        assert_eq!(storage.spending_tx((spend.hash(), 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_utxo_set() {
+        let config = config::regtest_config();
+        let block1 = next_block(&config.genesis_block, 1);
+        let coinbase1 = block1.coinbase().unwrap().hash();
+        let mut spend = crate::transaction::Transaction::new();
+        spend.add_input(coinbase1, 0, vec![]);
+        spend.add_output(0, vec![0x51]);
+        let mut block2 = next_block(&block1, 2);
+        block2.add_tx(Box::new(spend.clone())).unwrap();
+        let coinbase2 = block2.coinbase().unwrap().hash();
+
+        let storage = temp_storage("storage_utxo_set");
+        for block in &[&config.genesis_block, &block1, &block2] {
+            storage.store_block(block).unwrap();
+        }
+        // Spent outputs are removed, coinbase outputs keep their height
+        assert_eq!(storage.get_output((coinbase1, 0)).unwrap(), None);
+        assert_eq!(
+            storage.get_output((spend.hash(), 0)).unwrap(),
+            Some((TxOutput::new(0, vec![0x51]), None))
+        );
+        assert_eq!(
+            storage.get_output((coinbase2, 0)).unwrap(),
+            Some((TxOutput::new(0, vec![]), Some(2)))
+        );
+
+        // Switching to another branch restores the outputs spent by the
+        // disconnected block, and removes the ones it created
+        let block2b = next_block(&block1, 3);
+        let block3b = next_block(&block2b, 3);
+        storage.store_block(&block2b).unwrap();
+        assert_eq!(storage.tip().unwrap(), Some((block2.hash(), 2)));
+        storage.store_block(&block3b).unwrap();
+        assert_eq!(storage.tip().unwrap(), Some((block3b.hash(), 3)));
+        assert_eq!(
+            storage.get_output((coinbase1, 0)).unwrap(),
+            Some((TxOutput::new(0, vec![]), Some(1)))
+        );
+        assert_eq!(storage.get_output((spend.hash(), 0)).unwrap(), None);
+        assert_eq!(storage.get_output((coinbase2, 0)).unwrap(), None);
+        let coinbase2b = block2b.coinbase().unwrap().hash();
+        assert!(storage.get_output((coinbase2b, 0)).unwrap().is_some());
+
+        // The other branch still sees its own outputs
+        let view = storage.utxo_view(block2.hash()).unwrap();
+        assert_eq!(view.get((coinbase1, 0)).unwrap(), None);
+        assert!(view.get((spend.hash(), 0)).unwrap().is_some());
+        assert_eq!(view.get((coinbase2b, 0)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_max_reorg_depth() {
+        let config = config::regtest_config();
+        let storage = temp_storage("storage_reorg_depth");
+        storage.store_block(&config.genesis_block).unwrap();
+        let mut chain = vec![config.genesis_block.clone()];
+        for time in 1..=210 {
+            let block = next_block(chain.last().unwrap(), time);
+            storage.store_block(&block).unwrap();
+            chain.push(block);
+        }
+        let tip = (chain[210].hash(), 210);
+        assert_eq!(storage.tip().unwrap(), Some(tip));
+
+        // A longer branch forking 200 blocks below the tip is refused
+        let mut branch = chain[10].clone();
+        for time in 1000..1202 {
+            branch = next_block(&branch, time);
+            storage.store_block(&branch).unwrap();
+        }
+        assert_eq!(storage.height(branch.hash()).unwrap(), Some(212));
+        assert_eq!(storage.tip().unwrap(), Some(tip));
+
+        // A shallow one is switched to
+        let block = next_block(&chain[209], 2000);
+        storage.store_block(&block).unwrap();
+        let block = next_block(&block, 2001);
+        storage.store_block(&block).unwrap();
+        assert_eq!(storage.tip().unwrap(), Some((block.hash(), 211)));
+    }
+
+    #[test]
+    fn test_block_filters() {
+        let config = config::regtest_config();
+        let script1 = vec![0x76, 0xa9, 0x14, 1, 1, 1, 0x88, 0xac];
+        let script2 = vec![0x76, 0xa9, 0x14, 2, 2, 2, 0x88, 0xac];
+        let mut coinbase = crate::transaction::Transaction::new();
+        coinbase.add_input([0; 32], 0xffffffff, vec![0x51]);
+        coinbase.add_output(5_000_000_000, script1.clone());
+        let block1 = Block::new(
+            1,
+            config.genesis_block.hash(),
+            1,
+            0,
+            0x207fffff,
+            Box::new(coinbase.clone()),
+        );
+        let mut spend = crate::transaction::Transaction::new();
+        spend.add_input(coinbase.hash(), 0, vec![]);
+        spend.add_output(4_000_000_000, script2.clone());
+        spend.add_output(0, vec![0x6a, 0x01, 0x02]);
+        let mut block2 = next_block(&block1, 2);
+        block2.add_tx(Box::new(spend)).unwrap();
+
+        let mut storage = temp_storage("storage_filters");
+        storage.store_block(&config.genesis_block).unwrap();
+        assert_eq!(
+            storage.get_filter(config.genesis_block.hash()).unwrap(),
+            None
+        );
+        storage.set_build_cfilters(true);
+        storage.store_block(&block1).unwrap();
+        storage.store_block(&block2).unwrap();
+
+        let filter1 = storage.get_filter(block1.hash()).unwrap().unwrap();
+        assert!(filter1.matches(&block1.hash(), &script1).unwrap());
+        // The filter of the genesis block has not been built
+        assert_eq!(storage.get_filter_header(block1.hash()).unwrap(), None);
+        assert!(!filter1.matches(&block1.hash(), &script2).unwrap());
+        // The spent script and the new output are part of the filter
+        let filter2 = storage.get_filter(block2.hash()).unwrap().unwrap();
+        assert_eq!(filter2, cfilter::Filter::basic(&block2, &[script1.clone()]));
+        assert!(filter2.matches(&block2.hash(), &script1).unwrap());
+        assert!(filter2.matches(&block2.hash(), &script2).unwrap());
+        assert!(!filter2
+            .matches(&block2.hash(), &[0x6a, 0x01, 0x02])
+            .unwrap());
+
+        assert_eq!(
+            storage.blocks_range(1, block2.hash()).unwrap(),
+            vec![block1.hash(), block2.hash()]
+        );
+    }
+
+    #[test]
+    fn test_filter_headers() {
+        let config = config::regtest_config();
+        let genesis = &config.genesis_block;
+        let block1 = next_block(genesis, 1);
+        let mut storage = temp_storage("storage_filter_headers");
+        storage.set_build_cfilters(true);
+        storage.store_block(genesis).unwrap();
+        storage.store_block(&block1).unwrap();
+
+        // The headers chain from a zero header before the genesis block
+        let genesis_header = cfilter::Filter::basic(genesis, &[]).header(&[0; 32]);
+        assert_eq!(
+            storage.get_filter_header(genesis.hash()).unwrap(),
+            Some(genesis_header)
+        );
+        assert_eq!(
+            storage.get_filter_header(block1.hash()).unwrap(),
+            Some(cfilter::Filter::basic(&block1, &[]).header(&genesis_header))
+        );
+    }
+}
@@ -1,18 +1,58 @@
 extern crate hex;
 
-use crate::crypto::{bytes_to_hash32, hash32, hash32_to_bytes, Hash32, Hashable};
+use crate::crypto::{bytes_to_hash32, hash32, hash32_to_bytes, Hash32, Hashable, Hasher32};
 use crate::utils;
 use crate::variable_integer::VariableInteger;
+use std::cell::Cell;
+
+pub const SIGHASH_ALL: u32 = 0x01;
+pub const SIGHASH_NONE: u32 = 0x02;
+pub const SIGHASH_SINGLE: u32 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+/// Weight of a byte outside of the witness data, which weighs 1 (BIP141)
+pub const WITNESS_SCALE_FACTOR: usize = 4;
+/// Heaviest transaction relayed to peers. This is a policy, not a consensus
+/// rule: heavier ones are still valid in blocks.
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+/// Largest input sequence number signaling that the transaction may be
+/// replaced (BIP125)
+pub const MAX_BIP125_RBF_SEQUENCE: u32 = 0xfffffffd;
+/// Largest amount of satoshis, which no value nor sum of values may exceed
+pub const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+/// Returns the sum of the values, or None if it, or one of them, exceeds
+/// MAX_MONEY
+pub fn money_sum<I: IntoIterator<Item = u64>>(values: I) -> Option<u64> {
+    values.into_iter().try_fold(0u64, |total, value| {
+        let total = total.checked_add(value)?;
+        if value > MAX_MONEY || total > MAX_MONEY {
+            return None;
+        }
+        Some(total)
+    })
+}
 
 /// A transaction is represented here
 /// See https://en.bitcoin.it/wiki/Transactions
-// FIXME Support flag and witnesses
 #[derive(Debug, Clone, PartialEq)]
 pub struct Transaction {
     version: u32,
-    pub inputs: Vec<Box<TxInput>>,
-    pub outputs: Vec<Box<TxOutput>>,
+    inputs: Vec<Box<TxInput>>,
+    outputs: Vec<Box<TxOutput>>,
+    witnesses: Vec<Vec<Vec<u8>>>, // one stack per input, empty without witness (BIP144)
     lock_time: u32,
+    txid: TxidCache,
+}
+
+/// Memoized txid. It is not part of the transaction value: two transactions
+/// are equal whether their txid has been computed or not.
+#[derive(Debug, Clone, Default)]
+struct TxidCache(Cell<Option<Hash32>>);
+
+impl PartialEq for TxidCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -38,10 +78,25 @@ impl TxInput {
         bytes
     }
 
+    /// Returns the length of `bytes`
+    fn size(&self) -> usize {
+        let script_sig_len = self.script_sig.len();
+        32 + 4 + VariableInteger::new(script_sig_len as u64).length() + script_sig_len + 4
+    }
+
     pub fn sig(&self) -> Vec<u8> {
         self.script_sig.clone()
     }
 
+    /// Returns the txid and the index of the output spent by this input
+    pub fn prev_output(&self) -> (Hash32, u32) {
+        (self.tx, self.index)
+    }
+
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
     fn from_bytes(bytes: &[u8]) -> (Self, usize) {
         let mut index = 0;
         let mut next_size = 32;
@@ -96,10 +151,28 @@ impl TxOutput {
         bytes
     }
 
+    /// Returns the length of `bytes`
+    fn size(&self) -> usize {
+        let script_len = self.script_pub_key.len();
+        8 + VariableInteger::new(script_len as u64).length() + script_len
+    }
+
+    pub fn new(value: u64, script_pub_key: Vec<u8>) -> Self {
+        TxOutput {
+            value,
+            script_pub_key,
+        }
+    }
+
     pub fn pubkey(&self) -> Vec<u8> {
         self.script_pub_key.clone()
     }
 
+    /// Returns the value of the output, in satoshis
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
     fn from_bytes(bytes: &[u8]) -> (Self, usize) {
         let mut index = 0;
         let mut next_size = 8;
@@ -130,7 +203,9 @@ impl Transaction {
             version: 1,
             inputs: Vec::new(),
             outputs: Vec::new(),
+            witnesses: Vec::new(),
             lock_time: 0,
+            txid: TxidCache::default(),
         }
     }
 
@@ -142,7 +217,7 @@ impl Transaction {
             script_sig,
             sequence: 0xffffffff,
         };
-        self.inputs.push(Box::new(tx_input));
+        self.inputs_mut().push(Box::new(tx_input));
     }
 
     /// Adds an output to the transaction
@@ -151,10 +226,57 @@ impl Transaction {
             value,
             script_pub_key,
         };
-        self.outputs.push(Box::new(tx_output));
+        self.outputs_mut().push(Box::new(tx_output));
+    }
+
+    pub fn set_version(&mut self, version: u32) {
+        self.txid.0.set(None);
+        self.version = version;
+    }
+
+    /// Sets the sequence number of an input
+    pub fn set_sequence(&mut self, input: usize, sequence: u32) {
+        self.inputs_mut()[input].sequence = sequence;
+    }
+
+    pub fn inputs(&self) -> &[Box<TxInput>] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[Box<TxOutput>] {
+        &self.outputs
+    }
+
+    /// Gives a mutable access to the inputs, invalidating the cached txid
+    pub fn inputs_mut(&mut self) -> &mut Vec<Box<TxInput>> {
+        self.txid.0.set(None);
+        &mut self.inputs
+    }
+
+    /// Gives a mutable access to the outputs, invalidating the cached txid
+    pub fn outputs_mut(&mut self) -> &mut Vec<Box<TxOutput>> {
+        self.txid.0.set(None);
+        &mut self.outputs
+    }
+
+    /// Sets the witness stack of an input
+    pub fn set_witness(&mut self, input: usize, witness: Vec<Vec<u8>>) {
+        self.witnesses.resize(self.inputs.len(), Vec::new());
+        self.witnesses[input] = witness;
+    }
+
+    /// Returns the witness stack of an input, empty if it has none
+    pub fn witness(&self, input: usize) -> &[Vec<u8>] {
+        self.witnesses.get(input).map(Vec::as_slice).unwrap_or(&[])
     }
 
-    /// Returns a bytes vector representing the transaction
+    /// Returns whether the transaction carries witness data
+    pub fn has_witness(&self) -> bool {
+        self.witnesses.iter().any(|witness| !witness.is_empty())
+    }
+
+    /// Returns a bytes vector representing the transaction, without its
+    /// witness data. This is what the txid is computed from.
     pub fn bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.version.to_le_bytes());
@@ -172,6 +294,99 @@ impl Transaction {
         bytes
     }
 
+    /// Returns the size of the transaction without its witness data, i.e.
+    /// the length of `bytes`
+    pub fn size(&self) -> usize {
+        let varint_len = |len: usize| VariableInteger::new(len as u64).length();
+        4 + varint_len(self.inputs.len())
+            + self.inputs.iter().map(|input| input.size()).sum::<usize>()
+            + varint_len(self.outputs.len())
+            + self
+                .outputs
+                .iter()
+                .map(|output| output.size())
+                .sum::<usize>()
+            + 4
+    }
+
+    /// Returns the size of the transaction with its witness data, i.e. the
+    /// length of `witness_bytes`
+    pub fn witness_size(&self) -> usize {
+        if !self.has_witness() {
+            return self.size();
+        }
+        let varint_len = |len: usize| VariableInteger::new(len as u64).length();
+        let witness_size: usize = (0..self.inputs.len())
+            .map(|input| {
+                let witness = self.witness(input);
+                varint_len(witness.len())
+                    + witness
+                        .iter()
+                        .map(|item| varint_len(item.len()) + item.len())
+                        .sum::<usize>()
+            })
+            .sum();
+        // Marker and flag, then the witnesses
+        self.size() + 2 + witness_size
+    }
+
+    /// Returns whether this is the coinbase transaction of a block, whose
+    /// single input does not spend any outpoint
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].prev_output() == ([0; 32], 0xffffffff)
+    }
+
+    /// Returns whether the transaction opts in to be replaced by a
+    /// conflicting one paying a higher fee (BIP125)
+    pub fn signals_rbf(&self) -> bool {
+        self.inputs
+            .iter()
+            .any(|input| input.sequence <= MAX_BIP125_RBF_SEQUENCE)
+    }
+
+    /// Returns the sum of the values of the outputs, or None if it exceeds
+    /// MAX_MONEY
+    pub fn output_value(&self) -> Option<u64> {
+        money_sum(self.outputs.iter().map(|output| output.value()))
+    }
+
+    /// Returns the weight of the transaction, its witness data weighing
+    /// less than the rest (BIP141)
+    pub fn weight(&self) -> usize {
+        self.size() * (WITNESS_SCALE_FACTOR - 1) + self.witness_size()
+    }
+
+    /// Returns whether the transaction is light enough to be relayed
+    pub fn is_standard_size(&self) -> bool {
+        self.is_coinbase() || self.weight() <= MAX_STANDARD_TX_WEIGHT
+    }
+
+    /// Returns a bytes vector representing the transaction with its witness
+    /// data (BIP144). Same as `bytes` if there is no witness.
+    pub fn witness_bytes(&self) -> Vec<u8> {
+        if !self.has_witness() {
+            return self.bytes();
+        }
+
+        let legacy = self.bytes();
+        let lock_time_index = legacy.len() - 4;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&legacy[..4]); // version
+        bytes.extend_from_slice(&[0x00, 0x01]); // marker and flag
+        bytes.extend_from_slice(&legacy[4..lock_time_index]);
+        for input in 0..self.inputs.len() {
+            let empty = Vec::new();
+            let witness = self.witnesses.get(input).unwrap_or(&empty);
+            bytes.extend_from_slice(&VariableInteger::new(witness.len() as u64).bytes());
+            for item in witness {
+                bytes.extend_from_slice(&VariableInteger::new(item.len() as u64).bytes());
+                bytes.extend_from_slice(item);
+            }
+        }
+        bytes.extend_from_slice(&legacy[lock_time_index..]);
+        bytes
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> (Self, usize) {
         let mut index = 0;
         let mut next_size = 4;
@@ -180,6 +395,12 @@ impl Transaction {
             u32::from_le_bytes(utils::clone_into_array(&bytes[index..(index + next_size)]));
         index += next_size;
 
+        // A transaction can not have zero inputs: 0x00 is the segwit marker
+        let segwit = bytes[index] == 0x00 && bytes[index + 1] == 0x01;
+        if segwit {
+            index += 2;
+        }
+
         let (tx_in_count, tx_in_count_size) = VariableInteger::from_bytes(&bytes[index..]).unwrap();
         index += tx_in_count_size;
 
@@ -201,6 +422,25 @@ impl Transaction {
             outputs.push(Box::new(output));
         }
 
+        let mut witnesses = Vec::new();
+        if segwit {
+            for _ in 0..tx_in_count {
+                let (item_count, item_count_size) =
+                    VariableInteger::from_bytes(&bytes[index..]).unwrap();
+                index += item_count_size;
+
+                let mut witness = Vec::new();
+                for _ in 0..item_count {
+                    let (item_len, item_len_size) =
+                        VariableInteger::from_bytes(&bytes[index..]).unwrap();
+                    index += item_len_size;
+                    witness.push(Vec::from(&bytes[index..(index + (item_len as usize))]));
+                    index += item_len as usize;
+                }
+                witnesses.push(witness);
+            }
+        }
+
         next_size = 4;
         let lock_time =
             u32::from_le_bytes(utils::clone_into_array(&bytes[index..(index + next_size)]));
@@ -211,22 +451,131 @@ impl Transaction {
                 version,
                 inputs,
                 outputs,
+                witnesses,
                 lock_time,
+                txid: TxidCache::default(),
             },
             index,
         )
     }
 }
 
-impl Hashable for Transaction {
-    /// Returns the hash representing the transaction
-    fn hash(&self) -> Hash32 {
+impl Transaction {
+    /// Returns the hash signed by the signature of an input spending an
+    /// output locked by `script_code`. Only the legacy algorithm with
+    /// SIGHASH_ALL semantics is implemented.
+    pub fn signature_hash(&self, input: usize, script_code: &[u8], hash_type: u32) -> Hash32 {
+        let mut tx_copy = self.clone();
+        for (index, tx_input) in tx_copy.inputs_mut().iter_mut().enumerate() {
+            tx_input.script_sig = if index == input {
+                script_code.to_vec()
+            } else {
+                Vec::new()
+            };
+        }
+        let mut bytes = tx_copy.bytes();
+        bytes.extend_from_slice(&hash_type.to_le_bytes());
+        hash32(&bytes)
+    }
+
+    /// Returns the hash signed by the signature of a segwit v0 input
+    /// spending an output of `value` satoshis locked by `script_code`
+    /// (BIP143).
+    pub fn witness_signature_hash(
+        &self,
+        input: usize,
+        script_code: &[u8],
+        value: u64,
+        hash_type: u32,
+    ) -> Hash32 {
+        let anyone_can_pay = hash_type & SIGHASH_ANYONECANPAY != 0;
+        let base_type = hash_type & 0x1f;
+
+        let mut hash_prevouts = [0; 32];
+        if !anyone_can_pay {
+            let mut prevouts = Hasher32::new();
+            for tx_input in self.inputs.iter() {
+                prevouts.update(&hash32_to_bytes(&tx_input.tx));
+                prevouts.update(&tx_input.index.to_le_bytes());
+            }
+            hash_prevouts = prevouts.finalize();
+        }
+
+        let mut hash_sequence = [0; 32];
+        if !anyone_can_pay && base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+            let mut sequences = Hasher32::new();
+            for tx_input in self.inputs.iter() {
+                sequences.update(&tx_input.sequence.to_le_bytes());
+            }
+            hash_sequence = sequences.finalize();
+        }
+
+        let mut hash_outputs = [0; 32];
+        if base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+            let mut outputs = Hasher32::new();
+            for output in self.outputs.iter() {
+                outputs.update(&output.bytes());
+            }
+            hash_outputs = outputs.finalize();
+        } else if base_type == SIGHASH_SINGLE && input < self.outputs.len() {
+            hash_outputs = hash32(&self.outputs[input].bytes());
+        }
+
+        let tx_input = &self.inputs[input];
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&hash_prevouts);
+        bytes.extend_from_slice(&hash_sequence);
+        bytes.extend_from_slice(&hash32_to_bytes(&tx_input.tx));
+        bytes.extend_from_slice(&tx_input.index.to_le_bytes());
+        bytes.extend_from_slice(&VariableInteger::new(script_code.len() as u64).bytes());
+        bytes.extend_from_slice(script_code);
+        bytes.extend_from_slice(&value.to_le_bytes());
+        bytes.extend_from_slice(&tx_input.sequence.to_le_bytes());
+        bytes.extend_from_slice(&hash_outputs);
+        bytes.extend_from_slice(&self.lock_time.to_le_bytes());
+        bytes.extend_from_slice(&hash_type.to_le_bytes());
+        hash32(&bytes)
+    }
+
+    /// Returns the hash of the transaction with its witness data (BIP141).
+    /// It is the txid for transactions without witness.
+    pub fn wtxid(&self) -> Hash32 {
+        let mut hash = hash32(&self.witness_bytes());
+        hash.reverse();
+        hash
+    }
+
+    /// Returns the txid of the transaction with every scriptSig emptied, so
+    /// that the variants malleated through their scriptSigs share it
+    pub fn normalized_txid(&self) -> Hash32 {
+        let mut normalized = self.clone();
+        for input in normalized.inputs_mut().iter_mut() {
+            input.script_sig.clear();
+        }
+        normalized.hash()
+    }
+
+    fn compute_txid(&self) -> Hash32 {
         let mut hash = hash32(self.bytes().as_slice());
         hash.reverse();
         hash
     }
 }
 
+impl Hashable for Transaction {
+    /// Returns the hash representing the transaction. It is computed once
+    /// then cached until the transaction is modified.
+    fn hash(&self) -> Hash32 {
+        if let Some(txid) = self.txid.0.get() {
+            return txid;
+        }
+        let txid = self.compute_txid();
+        self.txid.0.set(Some(txid));
+        txid
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -249,6 +598,8 @@ mod tests {
 
         let (deserialized, _size) = Transaction::from_bytes(&tx.bytes());
         assert_eq!(tx, deserialized);
+        assert_eq!(tx.size(), tx.bytes().len());
+        assert!(tx.is_coinbase());
     }
 
     #[test]
@@ -279,4 +630,236 @@ mod tests {
         let (deserialized, _size) = Transaction::from_bytes(&tx.bytes());
         assert_eq!(tx, deserialized);
     }
+
+    #[test]
+    fn witness_serialization() {
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, vec![]);
+        tx.add_input([2; 32], 1, vec![]);
+        tx.add_output(
+            1000,
+            hex::decode("00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1").unwrap(),
+        );
+        let legacy = tx.bytes();
+        let txid = tx.hash();
+        assert_eq!(tx.witness_bytes(), legacy);
+
+        tx.set_witness(1, vec![vec![0x30, 0x44], vec![0x02, 0x79]]);
+        assert!(tx.has_witness());
+        // The witness does not change the txid
+        assert_eq!(tx.bytes(), legacy);
+        assert_eq!(tx.hash(), txid);
+
+        let bytes = tx.witness_bytes();
+        assert_eq!(&bytes[4..6], &[0x00, 0x01]);
+        // Empty stack for the first input, then two items
+        assert_eq!(
+            &bytes[(bytes.len() - 4 - 8)..(bytes.len() - 4)],
+            &[0x00, 0x02, 0x02, 0x30, 0x44, 0x02, 0x02, 0x79]
+        );
+
+        let (deserialized, size) = Transaction::from_bytes(&bytes);
+        assert_eq!(size, bytes.len());
+        assert_eq!(deserialized, tx);
+        assert_eq!(tx.size(), legacy.len());
+        assert_eq!(tx.witness_size(), bytes.len());
+
+        assert_eq!(tx.hash(), txid);
+        assert_ne!(tx.wtxid(), txid);
+        tx.set_witness(1, Vec::new());
+        assert_eq!(tx.wtxid(), txid);
+    }
+
+    #[test]
+    /// Native P2WPKH example of BIP143
+    fn witness_signature_hash() {
+        let (tx, _) = Transaction::from_bytes(&hex::decode("0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000").unwrap());
+        let script_code =
+            hex::decode("76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac").unwrap();
+        assert_eq!(
+            "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670",
+            hex::encode(tx.witness_signature_hash(1, &script_code, 600_000_000, SIGHASH_ALL))
+        );
+    }
+
+    #[test]
+    fn witness_signature_hash_types() {
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, Vec::new());
+        tx.add_input([2; 32], 1, Vec::new());
+        tx.add_output(1000, vec![0x51]);
+        tx.add_output(2000, vec![0x52]);
+        let script_code = vec![0x51];
+        let hash = |tx: &Transaction, hash_type| {
+            tx.witness_signature_hash(0, &script_code, 5000, hash_type)
+        };
+        let all_types = [
+            SIGHASH_ALL,
+            SIGHASH_NONE,
+            SIGHASH_SINGLE,
+            SIGHASH_ALL | SIGHASH_ANYONECANPAY,
+            SIGHASH_NONE | SIGHASH_ANYONECANPAY,
+            SIGHASH_SINGLE | SIGHASH_ANYONECANPAY,
+        ];
+        let hashes: Vec<Hash32> = all_types.iter().map(|t| hash(&tx, *t)).collect();
+
+        // Changing the second output only matters to SIGHASH_ALL
+        let mut other_output = tx.clone();
+        other_output.outputs_mut()[1] = Box::new(TxOutput::new(3000, vec![0x52]));
+        for (index, hash_type) in all_types.iter().enumerate() {
+            let base_type = hash_type & 0x1f;
+            assert_eq!(
+                hash(&other_output, *hash_type) == hashes[index],
+                base_type != SIGHASH_ALL
+            );
+        }
+
+        // Changing the other input only matters without SIGHASH_ANYONECANPAY
+        let mut other_input = tx.clone();
+        other_input.inputs_mut().pop();
+        other_input.add_input([3; 32], 1, Vec::new());
+        for (index, hash_type) in all_types.iter().enumerate() {
+            assert_eq!(
+                hash(&other_input, *hash_type) == hashes[index],
+                hash_type & SIGHASH_ANYONECANPAY != 0
+            );
+        }
+    }
+
+    #[test]
+    fn cached_txid() {
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, vec![0x51]);
+        assert_eq!(tx.txid.0.get(), None);
+
+        let txid = tx.hash();
+        assert_eq!(tx.txid.0.get(), Some(txid));
+        assert_eq!(txid, tx.compute_txid());
+        assert_eq!(tx.hash(), txid);
+        // The cache does not take part in the comparison
+        assert_eq!(tx, Transaction::from_bytes(&tx.bytes()).0);
+
+        // Any mutation invalidates the cache
+        tx.add_output(1000, vec![0x51]);
+        assert_eq!(tx.txid.0.get(), None);
+        assert_ne!(tx.hash(), txid);
+        assert_eq!(tx.hash(), tx.compute_txid());
+
+        let txid = tx.hash();
+        tx.inputs_mut()[0].script_sig.push(0x51);
+        assert_ne!(tx.hash(), txid);
+        assert_eq!(tx.hash(), tx.compute_txid());
+    }
+
+    #[test]
+    fn size() {
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, vec![0x51; 0xfd]);
+        tx.add_output(1000, vec![0x51; 0x10000]);
+        tx.add_output(1000, Vec::new());
+        assert_eq!(tx.size(), tx.bytes().len());
+        assert_eq!(tx.witness_size(), tx.witness_bytes().len());
+
+        tx.set_witness(0, vec![vec![0x30; 0xfd], Vec::new()]);
+        assert_eq!(tx.size(), tx.bytes().len());
+        assert_eq!(tx.witness_size(), tx.witness_bytes().len());
+    }
+
+    #[test]
+    fn standard_size() {
+        let tx_with_script_len = |len: usize| {
+            let mut tx = Transaction::new();
+            tx.add_input([1; 32], 0, Vec::new());
+            tx.add_output(1000, vec![0x51; len]);
+            tx
+        };
+        // Version, input, output with a 5-byte script length and lock time
+        let max_size = MAX_STANDARD_TX_WEIGHT / WITNESS_SCALE_FACTOR;
+        let len = max_size - (4 + 1 + 41 + 1 + 8 + 5 + 4);
+        let tx = tx_with_script_len(len);
+        assert_eq!(tx.size(), max_size);
+        assert_eq!(tx.weight(), MAX_STANDARD_TX_WEIGHT);
+        assert!(tx.is_standard_size());
+        assert!(!tx_with_script_len(len + 1).is_standard_size());
+
+        // The witness counts too, but a quarter of the other bytes, so that
+        // the transaction may be larger than `max_size`
+        let mut tx = tx_with_script_len(len - 4);
+        tx.set_witness(0, vec![vec![0x30; 12]]);
+        assert_eq!(tx.weight(), MAX_STANDARD_TX_WEIGHT);
+        assert!(tx.witness_size() > max_size);
+        assert!(tx.is_standard_size());
+        tx.set_witness(0, vec![vec![0x30; 13]]);
+        assert!(!tx.is_standard_size());
+
+        // Coinbases are never relayed but are not limited either
+        let mut coinbase = Transaction::new();
+        coinbase.add_input([0; 32], 0xffffffff, Vec::new());
+        coinbase.add_output(1000, vec![0x51; len + 1]);
+        assert!(coinbase.is_standard_size());
+    }
+
+    #[test]
+    fn signals_rbf() {
+        let mut tx = Transaction::new();
+        assert!(!tx.signals_rbf());
+        tx.add_input([1; 32], 0, Vec::new());
+        tx.add_input([2; 32], 0, Vec::new());
+        assert_eq!(tx.inputs[0].sequence(), 0xffffffff);
+        assert!(!tx.signals_rbf());
+
+        // Final but with lock time enabled
+        tx.set_sequence(1, 0xfffffffe);
+        assert!(!tx.signals_rbf());
+
+        // A single input is enough
+        for &sequence in [MAX_BIP125_RBF_SEQUENCE, 1, 0].iter() {
+            tx.set_sequence(1, sequence);
+            assert_eq!(tx.inputs[1].sequence(), sequence);
+            assert!(tx.signals_rbf());
+        }
+
+        // The signal survives serialization
+        assert!(Transaction::from_bytes(&tx.bytes()).0.signals_rbf());
+    }
+
+    #[test]
+    fn normalized_txid() {
+        let sig = vec![0x30; 71];
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, [vec![71], sig.clone()].concat());
+        tx.add_output(1000, vec![0x51]);
+
+        // Same signature pushed with OP_PUSHDATA1
+        let mut malleated = tx.clone();
+        malleated.inputs_mut()[0].script_sig = [vec![0x4c, 71], sig].concat();
+        assert_ne!(tx.hash(), malleated.hash());
+        assert_eq!(tx.normalized_txid(), malleated.normalized_txid());
+
+        // Other changes are not hidden
+        let mut other = tx.clone();
+        other.outputs_mut()[0] = Box::new(TxOutput {
+            value: 999,
+            script_pub_key: vec![0x51],
+        });
+        assert_ne!(tx.normalized_txid(), other.normalized_txid());
+    }
+
+    #[test]
+    fn test_money_sum() {
+        assert_eq!(money_sum(vec![]), Some(0));
+        assert_eq!(money_sum(vec![MAX_MONEY - 1, 1]), Some(MAX_MONEY));
+        assert_eq!(money_sum(vec![MAX_MONEY, 1]), None);
+        assert_eq!(money_sum(vec![MAX_MONEY + 1]), None);
+        // Would wrap around to 9 without the checks
+        assert_eq!(money_sum(vec![u64::MAX - 10, 20]), None);
+
+        let mut tx = Transaction::new();
+        tx.add_input([1; 32], 0, Vec::new());
+        tx.add_output(1000, vec![0x51]);
+        tx.add_output(2000, vec![0x51]);
+        assert_eq!(tx.output_value(), Some(3000));
+        tx.add_output(u64::MAX - 2000, vec![0x51]);
+        assert_eq!(tx.output_value(), None);
+    }
 }
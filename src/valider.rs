@@ -1,9 +1,12 @@
 use crate::block;
+use crate::config;
+use crate::config::ValidationLevel;
 use crate::crypto;
 use crate::crypto::Hashable;
 use crate::storage::Storage;
 use crate::ControllerMessage;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::thread;
@@ -13,120 +16,971 @@ pub enum Message {
     Wait(Vec<crypto::Hash32>),
     Validate(block::Block),
     Timeout(crypto::Hash32),
+    Forget(Vec<crypto::Hash32>), // blocks found invalid, and their descendants
 }
 
 pub enum ValiderMessage {
     Timeout(crypto::Hash32),
+    Invalid(crypto::Hash32, block::BlockValidationError),
+    Validated(crypto::Hash32, u64), // stored block and its height
+    Reorg(crypto::Hash32, crypto::Hash32), // previous and new tip
 }
 
-pub fn timeout(sender: mpsc::Sender<Message>, hash: crypto::Hash32) {
+// Delay after which a block which has not been received is requested again
+const BLOCK_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+// Delay after which a block too far in the future is validated again
+const FUTURE_BLOCK_DELAY: time::Duration = time::Duration::from_secs(60);
+
+/// Sends a Timeout message for the given block after BLOCK_TIMEOUT, unless
+/// `cancel` has been set in the meantime (i.e. the block has arrived)
+pub fn timeout(sender: mpsc::Sender<Message>, hash: crypto::Hash32, cancel: Arc<AtomicBool>) {
     log::debug!("timeout launched for hash {:?}", hash);
-    thread::sleep(time::Duration::from_secs(2));
+    thread::sleep(BLOCK_TIMEOUT);
+    if cancel.load(Ordering::SeqCst) {
+        return;
+    }
     log::debug!("timeout end for hash {:?}", hash);
-    sender.send(Message::Timeout(hash)).unwrap();
+    sender.send(Message::Timeout(hash)).unwrap_or_default();
+}
+
+/// Spawns a timeout for the given block. Returns its cancel flag.
+fn spawn_timeout(sender: &mpsc::Sender<Message>, hash: crypto::Hash32) -> Arc<AtomicBool> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let sender_timeout = sender.clone();
+    let cancel_timeout = Arc::clone(&cancel);
+    thread::spawn(move || timeout(sender_timeout, hash, cancel_timeout));
+    cancel
+}
+
+/// Sends a block too far in the future back to the valider after
+/// FUTURE_BLOCK_DELAY, as it may have become acceptable in the meantime
+fn spawn_deferred(sender: &mpsc::Sender<Message>, block: block::Block) {
+    let sender_deferred = sender.clone();
+    thread::spawn(move || {
+        thread::sleep(FUTURE_BLOCK_DELAY);
+        sender_deferred
+            .send(Message::Validate(block))
+            .unwrap_or_default();
+    });
+}
+
+/// Appends hashes to the waiting list, skipping blocks which have already
+/// been validated and stored (e.g. before a restart)
+fn extend_waiting(
+    storage: &Storage,
+    waiting: &mut VecDeque<crypto::Hash32>,
+    hashes: Vec<crypto::Hash32>,
+) {
+    log::debug!(
+        "Waiting list, currently {} hashes, add {} hashes",
+        waiting.len(),
+        hashes.len()
+    );
+    waiting.extend(
+        hashes
+            .into_iter()
+            .filter(|hash| match storage.has_block(*hash) {
+                Ok(stored) => !stored,
+                Err(err) => {
+                    log::warn!("Storage error: {:?}", err);
+                    true
+                }
+            }),
+    );
+    log::debug!(
+        "Waiting list updated. Size {}. Head: {:?}..",
+        waiting.len(),
+        waiting
+            .iter()
+            .take(10)
+            .map(|hash| format!("{:?}", hash))
+            .collect::<Vec<String>>()
+    );
+}
+
+/// Removes blocks found invalid from the waiting list and the available
+/// blocks, and remembers them so that they are not validated anymore
+fn forget(
+    waiting: &mut VecDeque<crypto::Hash32>,
+    available: &mut HashMap<crypto::Hash32, block::Block>,
+    invalid: &mut HashSet<crypto::Hash32>,
+    hashes: Vec<crypto::Hash32>,
+) {
+    for hash in hashes {
+        available.remove(&hash);
+        invalid.insert(hash);
+    }
+    waiting.retain(|hash| !invalid.contains(hash));
+}
+
+/// Returns whether the assumed valid block is part of the given hashes to
+/// validate, and has not been stored yet
+fn leads_to_assume_valid(
+    config: &config::Config,
+    storage: &Storage,
+    hashes: &[crypto::Hash32],
+) -> bool {
+    match config.assume_valid {
+        Some(hash) => hashes.contains(&hash) && !storage.has_block(hash).unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Runs the checks of the configured validation level on a block at the
+/// time `now`. Scripts are not run if the block is assumed valid.
+fn check_block(
+    config: &config::Config,
+    storage: &Storage,
+    block: &block::Block,
+    assume_valid: bool,
+    now: u64,
+) -> Result<(), block::BlockValidationError> {
+    if config.validation_level == ValidationLevel::None {
+        return Ok(());
+    }
+
+    // The parent is known once the blocks are validated in order
+    let height = match storage.next_height(block) {
+        Ok(height) => height,
+        Err(err) => {
+            log::warn!("Storage error: {:?}", err);
+            return Err(block::BlockValidationError::UnknownParent);
+        }
+    };
+    let prev_timestamps =
+        match storage.timestamps(block.header.hash_prev_block(), block::MEDIAN_TIME_SPAN) {
+            Ok(timestamps) => timestamps,
+            Err(err) => {
+                log::warn!("Storage error: {:?}", err);
+                return Err(block::BlockValidationError::UnknownParent);
+            }
+        };
+    // The outputs spent by a block of another branch are the ones of its
+    // branch
+    let view = match storage.utxo_view(block.header.hash_prev_block()) {
+        Ok(view) => view,
+        Err(err) => {
+            log::warn!("Storage error: {:?}", err);
+            return Err(block::BlockValidationError::UnknownParent);
+        }
+    };
+    let utxo = |outpoint| match view.get(outpoint) {
+        Ok(output) => output,
+        Err(err) => {
+            log::warn!("Storage error: {:?}", err);
+            None
+        }
+    };
+    let check_scripts = config.validation_level == ValidationLevel::Full && !assume_valid;
+    block::validate_block(
+        block,
+        &prev_timestamps,
+        height,
+        &utxo,
+        config,
+        check_scripts,
+        now,
+    )
+}
+
+/// Tells the controller that a block has been stored, and whether it has
+/// moved the tip onto another branch
+fn report_stored(
+    storage: &Storage,
+    controller_sender: &mpsc::Sender<ControllerMessage>,
+    block: &block::Block,
+    old_tip: Option<(crypto::Hash32, u64)>,
+) {
+    let hash = block.hash();
+    if let Ok(height) = storage.next_height(block) {
+        controller_sender
+            .send(ControllerMessage::ValiderResponse(
+                ValiderMessage::Validated(hash, height),
+            ))
+            .unwrap_or_default();
+    }
+    if let (Some((old_hash, _)), Ok(Some((new_hash, _)))) = (old_tip, storage.tip()) {
+        if new_hash == hash && block.header.hash_prev_block() != old_hash {
+            controller_sender
+                .send(ControllerMessage::ValiderResponse(ValiderMessage::Reorg(
+                    old_hash, new_hash,
+                )))
+                .unwrap_or_default();
+        }
+    }
 }
 
 pub fn run(
-    mut storage: Storage,
+    config: config::Config,
+    storage: Arc<Storage>,
     sender: mpsc::Sender<Message>,
     receiver: mpsc::Receiver<Message>,
     controller_sender: mpsc::Sender<ControllerMessage>,
+) {
+    run_with_clock(
+        config,
+        storage,
+        sender,
+        receiver,
+        controller_sender,
+        block::unix_time,
+    )
+}
+
+/// Validates the blocks like `run`, `clock` giving the current time
+fn run_with_clock(
+    config: config::Config,
+    storage: Arc<Storage>,
+    sender: mpsc::Sender<Message>,
+    receiver: mpsc::Receiver<Message>,
+    controller_sender: mpsc::Sender<ControllerMessage>,
+    clock: fn() -> u64,
 ) {
     let mut available: HashMap<crypto::Hash32, block::Block> = HashMap::new();
     let mut waiting = VecDeque::new();
-
-    match receiver.recv().unwrap() {
-        Message::Wait(hashes) => {
-            log::debug!(
-                "Waiting list, currently {} hashes, add {} hashes",
-                waiting.len(),
-                hashes.len()
-            );
-            waiting.extend(hashes);
-            log::debug!(
-                "Waiting list updated. Size {}. Head: {:?}..",
-                waiting.len(),
-                waiting
-                    .iter()
-                    .take(10)
-                    .map(|hash| format!("{:?}", hash))
-                    .collect::<Vec<String>>()
-            );
-        }
-        _ => log::error!("Should have received a Wait message first."),
-    }
+    // Blocks too far in the future, which come back after FUTURE_BLOCK_DELAY
+    let mut deferred = HashSet::new();
+    // Blocks found invalid, which are neither waited for nor validated
+    let mut invalid = HashSet::new();
+    // Whether the assumed valid block is in the waiting list, so that the
+    // blocks before it are its ancestors
+    let mut assume_valid_waiting = false;
 
     // This never ends
     loop {
-        let next = waiting.pop_front().unwrap();
+        let next = match waiting.pop_front() {
+            Some(next) => next,
+            None => {
+                // Every known block is validated (e.g. the tip is reached):
+                // wait for new headers, keeping the blocks received meanwhile
+                log::debug!("Waiting list is empty");
+                match receiver.recv() {
+                    Ok(Message::Wait(hashes)) => {
+                        assume_valid_waiting |= leads_to_assume_valid(&config, &storage, &hashes);
+                        extend_waiting(&storage, &mut waiting, hashes)
+                    }
+                    Ok(Message::Validate(block)) => {
+                        if !invalid.contains(&block.hash()) {
+                            available.insert(block.hash(), block);
+                        }
+                    }
+                    Ok(Message::Timeout(_)) => (), // block validated since then
+                    Ok(Message::Forget(hashes)) => {
+                        forget(&mut waiting, &mut available, &mut invalid, hashes)
+                    }
+                    Err(_) => return,
+                }
+                continue;
+            }
+        };
         log::info!("Next block to validate is {}", hex::encode(next));
 
         if !available.contains_key(&next) {
             log::info!("Waiting for block {}.", hex::encode(next));
             // Launch timeout
-            let sender_timeout = sender.clone();
-            let sender_hash = next.clone();
-            thread::spawn(move || timeout(sender_timeout, sender_hash));
+            let mut cancel_timeout = spawn_timeout(&sender, next);
 
-            while !available.contains_key(&next) {
+            while !available.contains_key(&next) && !invalid.contains(&next) {
                 loop {
                     match receiver.recv().unwrap() {
                         Message::Wait(hashes) => {
-                            log::debug!(
-                                "Waiting list, currently {} hashes, add {} hashes",
-                                waiting.len(),
-                                hashes.len()
-                            );
-                            waiting.extend(hashes);
-                            log::debug!(
-                                "Waiting list updated. Size {}. Head: {:?}..",
-                                waiting.len(),
-                                waiting
-                                    .iter()
-                                    .take(10)
-                                    .map(|hash| format!("{:?}", hash))
-                                    .collect::<Vec<String>>()
-                            );
+                            assume_valid_waiting |=
+                                leads_to_assume_valid(&config, &storage, &hashes);
+                            extend_waiting(&storage, &mut waiting, hashes);
                         }
+                        Message::Validate(block) if invalid.contains(&block.hash()) => (),
                         Message::Validate(block) => {
                             log::info!("Block {} is available", hex::encode(block.hash()));
                             available.insert(block.hash(), block);
                             break; // Tests again if now the block is available
                         }
+                        Message::Forget(hashes) => {
+                            forget(&mut waiting, &mut available, &mut invalid, hashes);
+                            break; // Tests again if the block is still waited for
+                        }
                         Message::Timeout(hash) => {
                             log::debug!("Timeout for block {:?}", hash);
                             if hash == next {
-                                log::error!(
-                                    "Could not retrieve block {}. Ask another node...",
-                                    hex::encode(hash)
-                                );
-                                controller_sender.send(ControllerMessage::ValiderResponse(
-                                    ValiderMessage::Timeout(hash),
-                                ));
+                                if !deferred.contains(&hash) {
+                                    log::error!(
+                                        "Could not retrieve block {}. Ask another node...",
+                                        hex::encode(hash)
+                                    );
+                                    controller_sender.send(ControllerMessage::ValiderResponse(
+                                        ValiderMessage::Timeout(hash),
+                                    ));
+                                }
                                 // Relaunch timeout
-                                let sender_timeout = sender.clone();
-                                let sender_hash = hash.clone();
-                                thread::spawn(move || timeout(sender_timeout, sender_hash));
+                                cancel_timeout = spawn_timeout(&sender, hash);
                             }
                         }
                     }
                 }
             }
+            // The block has arrived, or is not waited for anymore
+            cancel_timeout.store(true, Ordering::SeqCst);
+            if invalid.contains(&next) {
+                continue;
+            }
         }
 
         // next is available
         log::info!("Validate {}", hex::encode(next));
         let block = available.remove(&next).unwrap();
+        deferred.remove(&next);
 
         // Validate block
+        let result = check_block(&config, &storage, &block, assume_valid_waiting, clock());
+        if result == Err(block::BlockValidationError::TimeTooNew) {
+            // Not invalid: the block is only early
+            log::info!(
+                "Block {} is too far in the future, validate it later",
+                hex::encode(next)
+            );
+            deferred.insert(next);
+            spawn_deferred(&sender, block);
+            waiting.push_front(next);
+            continue;
+        }
+        if let Err(reason) = result {
+            log::warn!("Block {} is invalid: {:?}", hex::encode(next), reason);
+            controller_sender
+                .send(ControllerMessage::ValiderResponse(ValiderMessage::Invalid(
+                    next,
+                    reason.clone(),
+                )))
+                .unwrap();
+            if reason.is_malleation() {
+                // Wait for a valid copy of this block, downloaded from
+                // another node
+                waiting.push_front(next);
+            } else {
+                invalid.insert(next);
+            }
+            continue;
+        }
 
         // Store block
-        if let Err(err) = storage.store_block(&block) {
-            log::warn!(
+        let old_tip = storage.tip().unwrap_or(None);
+        match storage.store_block(&block) {
+            Ok(()) => {
+                if config.assume_valid == Some(next) {
+                    log::info!("Assumed valid block {} reached", hex::encode(next));
+                    assume_valid_waiting = false;
+                }
+                report_stored(&storage, &controller_sender, &block, old_tip);
+            }
+            Err(err) => log::warn!(
                 "Error occurred while storing block {}: {:?}",
                 hex::encode(block.hash()),
                 err
-            );
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::script::ScriptError;
+    use crate::storage;
+    use crate::transaction::{Transaction, SIGHASH_ALL};
+
+    // Time of the clock of the validers of the tests, after the blocks built
+    // on top of the regtest genesis block
+    const NOW: u64 = 1_300_000_000;
+
+    /// A valider running on a new storage holding the genesis block, whose
+    /// clock is stopped at NOW
+    struct TestValider {
+        storage: Arc<Storage>,
+        sender: mpsc::Sender<Message>,
+        receiver: mpsc::Receiver<ControllerMessage>,
+    }
+
+    impl TestValider {
+        fn spawn(name: &str, config: config::Config) -> Self {
+            let storage = Arc::new(storage::temp_storage(name));
+            storage.store_block(&config.genesis_block).unwrap();
+            let (sender, receiver) = mpsc::channel();
+            let (controller_sender, controller_receiver) = mpsc::channel();
+            let sender_timeout = sender.clone();
+            let valider_storage = Arc::clone(&storage);
+            thread::spawn(move || {
+                run_with_clock(
+                    config,
+                    valider_storage,
+                    sender_timeout,
+                    receiver,
+                    controller_sender,
+                    || NOW,
+                )
+            });
+            TestValider {
+                storage,
+                sender,
+                receiver: controller_receiver,
+            }
+        }
+
+        fn send(&self, message: Message) {
+            self.sender.send(message).unwrap();
+        }
+
+        /// Waits for the given blocks, then sends them
+        fn send_blocks(&self, blocks: &[block::Block]) {
+            self.send(Message::Wait(blocks.iter().map(|b| b.hash()).collect()));
+            for block in blocks {
+                self.send(Message::Validate(block.clone()));
+            }
         }
+
+        fn next_response(&self) -> ValiderMessage {
+            match self.receiver.recv().unwrap() {
+                ControllerMessage::ValiderResponse(response) => response,
+                _ => panic!("Expected a valider response"),
+            }
+        }
+
+        /// Returns the outcome of the validation of the given block, the
+        /// blocks before it being validated
+        fn outcome(&self, hash: crypto::Hash32) -> Result<(), block::BlockValidationError> {
+            loop {
+                match self.next_response() {
+                    ValiderMessage::Validated(validated, _) if validated == hash => return Ok(()),
+                    ValiderMessage::Invalid(invalid, reason) if invalid == hash => {
+                        return Err(reason)
+                    }
+                    ValiderMessage::Validated(..) => (),
+                    _ => panic!("Expected a Validated or Invalid message"),
+                }
+            }
+        }
+    }
+
+    /// Validates the given blocks on top of the regtest genesis block, and
+    /// returns the outcome of the last one
+    fn validate_last(
+        name: &str,
+        config: config::Config,
+        blocks: Vec<block::Block>,
+    ) -> Result<(), block::BlockValidationError> {
+        let valider = TestValider::spawn(name, config);
+        valider.send_blocks(&blocks);
+        valider.outcome(blocks.last().unwrap().hash())
+    }
+
+    #[test]
+    fn test_invalid_merkle_root() {
+        let config = config::regtest_config();
+        let mut block = storage::next_block(&config.genesis_block, 1);
+        block.add_tx(Box::new(Transaction::new())).unwrap();
+
+        assert_eq!(
+            validate_last("valider_invalid_merkle_root", config, vec![block]),
+            Err(block::BlockValidationError::BadMerkleRoot)
+        );
+    }
+
+    #[test]
+    fn test_double_spend_is_invalid() {
+        let config = config::regtest_config();
+        let genesis = config.genesis_block.clone();
+        let valider = TestValider::spawn("valider_double_spend", config);
+
+        // Both transactions spend the same prevout
+        let mut block = storage::next_block(&genesis, 1);
+        for value in 1..3 {
+            let mut tx = Transaction::new();
+            tx.add_input([1; 32], 0, vec![]);
+            tx.add_output(value, vec![]);
+            block.add_tx(Box::new(tx)).unwrap();
+        }
+        block.update_merkle_root();
+        block.mine();
+        assert!(block.check_merkle_root());
+        valider.send_blocks(&[block.clone()]);
+
+        assert_eq!(
+            valider.outcome(block.hash()),
+            Err(block::BlockValidationError::DoubleSpend)
+        );
+        assert_eq!(valider.storage.tip().unwrap(), Some((genesis.hash(), 0)));
+    }
+
+    #[test]
+    fn test_invalid_block_is_forgotten() {
+        let config = config::regtest_config();
+        let genesis = config.genesis_block.clone();
+        let valider = TestValider::spawn("valider_invalid_forgotten", config);
+
+        // Both transactions spend the same prevout
+        let mut bad = storage::next_block(&genesis, 1);
+        for value in 1..3 {
+            let mut tx = Transaction::new();
+            tx.add_input([1; 32], 0, vec![]);
+            tx.add_output(value, vec![]);
+            bad.add_tx(Box::new(tx)).unwrap();
+        }
+        bad.update_merkle_root();
+        let child = storage::next_block(&bad, 2);
+        let sibling = storage::next_block(&genesis, 3);
+        valider.send(Message::Wait(vec![
+            bad.hash(),
+            child.hash(),
+            sibling.hash(),
+        ]));
+        valider.send(Message::Validate(bad.clone()));
+        match valider.next_response() {
+            ValiderMessage::Invalid(hash, reason) => {
+                assert_eq!(hash, bad.hash());
+                assert!(!reason.is_malleation());
+            }
+            _ => panic!("Expected an Invalid message"),
+        }
+
+        // Neither the block nor its child are waited for anymore
+        valider.send(Message::Forget(vec![bad.hash(), child.hash()]));
+        valider.send(Message::Validate(bad));
+        valider.send(Message::Validate(sibling.clone()));
+        assert_eq!(valider.outcome(sibling.hash()), Ok(()));
+    }
+
+    #[test]
+    fn test_resume_above_stored_tip() {
+        let config = config::regtest_config();
+        let block1 = storage::next_block(&config.genesis_block, 1);
+        let block2 = storage::next_block(&block1, 2);
+        {
+            let storage = storage::temp_storage("valider_resume");
+            storage.store_block(&config.genesis_block).unwrap();
+            storage.store_block(&block1).unwrap();
+        }
+
+        // Restart
+        let storage = Arc::new(storage::reopen_temp_storage("valider_resume"));
+        assert_eq!(storage.tip().unwrap(), Some((block1.hash(), 1)));
+        let (sender, receiver) = mpsc::channel();
+        let (controller_sender, controller_receiver) = mpsc::channel();
+        let sender_timeout = sender.clone();
+        let valider_config = config.clone();
+        thread::spawn(move || {
+            run(
+                valider_config,
+                storage,
+                sender_timeout,
+                receiver,
+                controller_sender,
+            )
+        });
+
+        sender
+            .send(Message::Wait(vec![
+                config.genesis_block.hash(),
+                block1.hash(),
+                block2.hash(),
+            ]))
+            .unwrap();
+
+        // Only the block above the tip is requested
+        match controller_receiver
+            .recv_timeout(time::Duration::from_secs(5))
+            .unwrap()
+        {
+            ControllerMessage::ValiderResponse(ValiderMessage::Timeout(hash)) => {
+                assert_eq!(hash, block2.hash())
+            }
+            _ => panic!("Expected a Timeout message"),
+        }
+    }
+
+    #[test]
+    fn test_cancelled_timeout() {
+        let (sender, receiver) = mpsc::channel();
+        let cancel = spawn_timeout(&sender, [1; 32]);
+        let fired = spawn_timeout(&sender, [2; 32]);
+
+        // The first block is delivered before the timeout
+        cancel.store(true, Ordering::SeqCst);
+
+        match receiver.recv_timeout(BLOCK_TIMEOUT * 2) {
+            Ok(Message::Timeout(hash)) => assert_eq!(hash, [2; 32]),
+            _ => panic!("Expected a Timeout message"),
+        }
+        assert!(!fired.load(Ordering::SeqCst));
+        assert!(receiver.recv_timeout(BLOCK_TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn test_future_block_is_deferred() {
+        let config = config_with(ValidationLevel::HeadersOnly);
+        let genesis = config.genesis_block.clone();
+        let valider = TestValider::spawn("valider_future_block", config);
+
+        let block1 = storage::next_block(&genesis, 1);
+        let too_new = NOW + block::MAX_FUTURE_BLOCK_TIME + 1 - block1.header.timestamp();
+        let future = storage::next_block(&block1, too_new as u32);
+        valider.send_blocks(&[block1.clone(), future.clone()]);
+        assert_eq!(valider.outcome(block1.hash()), Ok(()));
+
+        // The future block is neither invalid nor downloaded again: only
+        // the next block is reported once it is forgotten
+        valider.send(Message::Timeout(future.hash()));
+        valider.send(Message::Forget(vec![future.hash()]));
+        let block2 = storage::next_block(&block1, 2);
+        valider.send_blocks(&[block2.clone()]);
+        assert_eq!(valider.outcome(block2.hash()), Ok(()));
+        assert_eq!(valider.storage.tip().unwrap(), Some((block2.hash(), 2)));
+    }
+
+    #[test]
+    fn test_bad_bip34_height_is_invalid() {
+        let config = config::regtest_config();
+        let genesis = config.genesis_block.clone();
+        let valider = TestValider::spawn("valider_bip34", config);
+
+        // Heights are only enforced from 500 on regtest: build the chain
+        // up to 499 with any coinbase
+        let mut blocks = vec![storage::next_block(&genesis, 1)];
+        for time in 2..500 {
+            let block = storage::next_block(blocks.last().unwrap(), time);
+            blocks.push(block);
+        }
+        let prev = blocks.last().unwrap().clone();
+        let bad_block = storage::next_block(&prev, 500);
+        blocks.push(bad_block.clone());
+        valider.send_blocks(&blocks);
+
+        assert_eq!(
+            valider.outcome(bad_block.hash()),
+            Err(block::BlockValidationError::BadBip34Height)
+        );
+        assert_eq!(valider.storage.tip().unwrap(), Some((prev.hash(), 499)));
+    }
+
+    #[test]
+    fn test_reorg_reported() {
+        let config = config::regtest_config();
+        let genesis = config.genesis_block.clone();
+        let valider = TestValider::spawn("valider_reorg", config);
+
+        // A branch of two blocks overtakes the first block
+        let a1 = storage::next_block(&genesis, 1);
+        let b1 = storage::next_block(&genesis, 2);
+        let b2 = storage::next_block(&b1, 3);
+        valider.send_blocks(&[a1.clone(), b1.clone(), b2.clone()]);
+
+        for (block, expected_height) in &[(&a1, 1), (&b1, 1), (&b2, 2)] {
+            match valider.next_response() {
+                ValiderMessage::Validated(hash, height) => {
+                    assert_eq!((hash, height), (block.hash(), *expected_height))
+                }
+                _ => panic!("Expected a Validated message"),
+            }
+        }
+        match valider.next_response() {
+            ValiderMessage::Reorg(old_tip, new_tip) => {
+                assert_eq!((old_tip, new_tip), (a1.hash(), b2.hash()))
+            }
+            _ => panic!("Expected a Reorg message"),
+        }
+    }
+
+    fn config_with(validation_level: ValidationLevel) -> config::Config {
+        let mut config = config::regtest_config();
+        config.validation_level = validation_level;
+        config
+    }
+
+    /// A key locking outputs in pay to public key scripts
+    struct P2pk {
+        priv_key: Vec<u8>,
+        pk_script: Vec<u8>,
+    }
+
+    impl P2pk {
+        fn new() -> Self {
+            let (priv_key, pub_key) = crypto::generate_keypair();
+            let mut pk_script = vec![pub_key.len() as u8];
+            pk_script.extend_from_slice(&pub_key);
+            pk_script.push(0xac); // OP_CHECKSIG
+            P2pk {
+                priv_key,
+                pk_script,
+            }
+        }
+
+        /// Returns a block `time` seconds after `prev`, whose coinbase
+        /// shares the subsidy between `count` outputs paid to the key
+        fn coinbase_block(&self, prev: &block::Block, time: u32, count: usize) -> block::Block {
+            let mut coinbase = Transaction::new();
+            coinbase.add_input([0; 32], 0xffffffff, time.to_le_bytes().to_vec());
+            for _ in 0..count {
+                coinbase.add_output(5_000_000_000 / count as u64, self.pk_script.clone());
+            }
+            let time = prev.header.time() + time;
+            let mut block =
+                block::Block::new(1, prev.hash(), time, 0, 0x207fffff, Box::new(coinbase));
+            block.mine();
+            block
+        }
+
+        /// Returns a block spending an output paid to the key. Its signature
+        /// is made for `signed` instead of the signature hash, if given.
+        fn spending_block(
+            &self,
+            prev: &block::Block,
+            time: u32,
+            prev_output: (crypto::Hash32, u32),
+            signed: Option<crypto::Hash32>,
+        ) -> block::Block {
+            let mut tx = Transaction::new();
+            tx.add_input(prev_output.0, prev_output.1, vec![]);
+            tx.add_output(1_000_000_000, vec![0x51]);
+            let sig_hash = tx.signature_hash(0, &self.pk_script, SIGHASH_ALL);
+            let mut sig = crypto::sign(&self.priv_key, &signed.unwrap_or(sig_hash));
+            sig.push(SIGHASH_ALL as u8);
+            let mut script_sig = vec![sig.len() as u8];
+            script_sig.extend_from_slice(&sig);
+            tx.inputs_mut()[0].script_sig = script_sig;
+
+            let mut block = storage::next_block(prev, time);
+            block.add_tx(Box::new(tx)).unwrap();
+            block.update_merkle_root();
+            block.mine();
+            block
+        }
+    }
+
+    /// Returns `prev` followed by the blocks after which the outputs of its
+    /// coinbase can be spent
+    fn maturing(prev: &block::Block) -> Vec<block::Block> {
+        let mut blocks = vec![prev.clone()];
+        for time in 1..block::COINBASE_MATURITY as u32 {
+            let block = storage::next_block(blocks.last().unwrap(), time);
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    /// Returns the given blocks followed by `block`
+    fn followed_by(blocks: &[block::Block], block: block::Block) -> Vec<block::Block> {
+        let mut blocks = blocks.to_vec();
+        blocks.push(block);
+        blocks
+    }
+
+    #[test]
+    fn test_validation_levels() {
+        let genesis = config::regtest_config().genesis_block;
+        let key = P2pk::new();
+        let block1 = key.coinbase_block(&genesis, 1, 1);
+        let coinbase = block1.transactions()[0].hash();
+        let mature = maturing(&block1);
+        let last = mature.last().unwrap();
+        let good = key.spending_block(last, 2, (coinbase, 0), None);
+        let bad = key.spending_block(last, 2, (coinbase, 0), Some([1; 32]));
+        let unknown = key.spending_block(last, 2, ([1; 32], 0), None);
+        let immature = key.spending_block(&block1, 2, (coinbase, 0), None);
+
+        assert_eq!(
+            validate_last(
+                "valider_full_good",
+                config_with(ValidationLevel::Full),
+                followed_by(&mature, good)
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            validate_last(
+                "valider_full_immature",
+                config_with(ValidationLevel::Full),
+                vec![block1.clone(), immature]
+            ),
+            Err(block::BlockValidationError::ImmatureCoinbase)
+        );
+        assert_eq!(
+            validate_last(
+                "valider_full_bad",
+                config_with(ValidationLevel::Full),
+                followed_by(&mature, bad.clone())
+            ),
+            Err(block::BlockValidationError::BadScript(
+                ScriptError::EvalFalse
+            ))
+        );
+        assert_eq!(
+            validate_last(
+                "valider_full_unknown",
+                config_with(ValidationLevel::Full),
+                followed_by(&mature, unknown)
+            ),
+            Err(block::BlockValidationError::UnknownOutput)
+        );
+        // Scripts are assumed valid
+        assert_eq!(
+            validate_last(
+                "valider_headers_bad",
+                config_with(ValidationLevel::HeadersOnly),
+                followed_by(&mature, bad)
+            ),
+            Ok(())
+        );
+
+        // The proof of work is still checked
+        let coinbase2 = storage::next_block(&block1, 2).transactions()[0].clone();
+        let unmined = (0..)
+            .map(|nonce| {
+                block::Block::new(1, block1.hash(), 2, nonce, 0x207fffff, coinbase2.clone())
+            })
+            .find(|block| !block.header.check_proof_of_work())
+            .unwrap();
+        assert_eq!(
+            validate_last(
+                "valider_headers_unmined",
+                config_with(ValidationLevel::HeadersOnly),
+                vec![block1.clone(), unmined.clone()]
+            ),
+            Err(block::BlockValidationError::BadProofOfWork)
+        );
+        // As is the size of the coinbase scriptSig
+        let mut short_coinbase = Transaction::new();
+        short_coinbase.add_input([0; 32], 0xffffffff, vec![0x51]);
+        short_coinbase.add_output(5_000_000_000, vec![]);
+        let time = block1.header.time() + 1;
+        let mut short = block::Block::new(
+            1,
+            block1.hash(),
+            time,
+            0,
+            0x207fffff,
+            Box::new(short_coinbase),
+        );
+        short.mine();
+        assert_eq!(
+            validate_last(
+                "valider_headers_short_coinbase",
+                config_with(ValidationLevel::HeadersOnly),
+                vec![block1.clone(), short]
+            ),
+            Err(block::BlockValidationError::BadCoinbaseLength)
+        );
+        assert_eq!(
+            validate_last(
+                "valider_none_unmined",
+                config_with(ValidationLevel::None),
+                vec![block1, unmined]
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_spent_output_is_unknown() {
+        let genesis = config::regtest_config().genesis_block;
+        let key = P2pk::new();
+        let block1 = key.coinbase_block(&genesis, 1, 1);
+        let coinbase = block1.transactions()[0].hash();
+        let mature = maturing(&block1);
+        let spend = key.spending_block(mature.last().unwrap(), 2, (coinbase, 0), None);
+        // The same output is spent again by the next block
+        let respend = key.spending_block(&spend, 3, (coinbase, 0), None);
+
+        assert_eq!(
+            validate_last(
+                "valider_respend",
+                config_with(ValidationLevel::Full),
+                followed_by(&followed_by(&mature, spend), respend)
+            ),
+            Err(block::BlockValidationError::UnknownOutput)
+        );
+    }
+
+    #[test]
+    fn test_assume_valid() {
+        let genesis = config::regtest_config().genesis_block;
+        let key = P2pk::new();
+        let block1 = key.coinbase_block(&genesis, 1, 2);
+        let coinbase = block1.transactions()[0].hash();
+        let mature = maturing(&block1);
+        // Both blocks have an invalid signature
+        let block2 = key.spending_block(mature.last().unwrap(), 2, (coinbase, 0), Some([1; 32]));
+        let block3 = key.spending_block(&block2, 3, (coinbase, 1), Some([1; 32]));
+        let blocks = followed_by(&followed_by(&mature, block2.clone()), block3.clone());
+
+        // The scripts of the assumed valid block and of its ancestors are
+        // not checked, unlike those of the next blocks
+        let mut config = config_with(ValidationLevel::Full);
+        config.assume_valid = Some(block2.hash());
+        assert_eq!(
+            validate_last("valider_assume_valid", config, blocks.clone()),
+            Err(block::BlockValidationError::BadScript(
+                ScriptError::EvalFalse
+            ))
+        );
+
+        let mut config = config_with(ValidationLevel::Full);
+        config.assume_valid = Some(block3.hash());
+        assert_eq!(
+            validate_last("valider_assume_valid_tip", config, blocks.clone()),
+            Ok(())
+        );
+
+        // Nothing is skipped if the assumed valid block is not downloaded
+        let mut config = config_with(ValidationLevel::Full);
+        config.assume_valid = Some([1; 32]);
+        assert_eq!(
+            validate_last(
+                "valider_assume_valid_unknown",
+                config,
+                blocks[..(blocks.len() - 1)].to_vec()
+            ),
+            Err(block::BlockValidationError::BadScript(
+                ScriptError::EvalFalse
+            ))
+        );
+    }
+
+    #[test]
+    fn test_empty_waiting_list() {
+        let storage = Arc::new(storage::temp_storage("valider_empty_waiting"));
+        let config = config::regtest_config();
+        let genesis = config.genesis_block.clone();
+        storage.store_block(&genesis).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let (controller_sender, controller_receiver) = mpsc::channel();
+        let sender_timeout = sender.clone();
+        thread::spawn(move || run(config, storage, sender_timeout, receiver, controller_sender));
+
+        let block1 = storage::next_block(&genesis, 1);
+        let block2 = storage::next_block(&block1, 2);
+        let block3 = storage::next_block(&block2, 3);
+        let next_validated = || match controller_receiver
+            .recv_timeout(time::Duration::from_secs(5))
+            .unwrap()
+        {
+            ControllerMessage::ValiderResponse(ValiderMessage::Validated(hash, height)) => {
+                (hash, height)
+            }
+            _ => panic!("Expected a Validated message"),
+        };
+
+        // The waiting list is drained
+        sender.send(Message::Wait(vec![block1.hash()])).unwrap();
+        sender.send(Message::Validate(block1.clone())).unwrap();
+        assert_eq!(next_validated(), (block1.hash(), 1));
+
+        // More hashes are sent once the tip is reached, the block before its
+        // hash
+        thread::sleep(time::Duration::from_millis(100));
+        sender.send(Message::Validate(block2.clone())).unwrap();
+        sender.send(Message::Wait(vec![block2.hash()])).unwrap();
+        assert_eq!(next_validated(), (block2.hash(), 2));
+
+        thread::sleep(time::Duration::from_millis(100));
+        sender.send(Message::Wait(vec![block3.hash()])).unwrap();
+        sender.send(Message::Validate(block3.clone())).unwrap();
+        assert_eq!(next_validated(), (block3.hash(), 3));
     }
 }
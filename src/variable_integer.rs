@@ -44,6 +44,20 @@ impl VariableInteger {
         }
     }
 
+    /// Returns the number of bytes of the encoded integer, i.e. the length
+    /// of `bytes` without allocating it
+    pub fn length(&self) -> usize {
+        if self.integer < 0xFD {
+            1
+        } else if self.integer <= 0xFFFF {
+            3
+        } else if self.integer <= 0xFFFFFFFF {
+            5
+        } else {
+            9
+        }
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Result<(u64, usize), Box<dyn Error>> {
         let first_byte = bytes[0] as u64;
         let mut end_index = 0;
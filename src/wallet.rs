@@ -0,0 +1,174 @@
+use crate::crypto;
+use crate::crypto::{Hash20, Hash32, Hashable};
+use crate::storage;
+use crate::storage::Storage;
+use std::collections::{HashMap, HashSet};
+
+/// An unspent output paying one of the watched hashes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Utxo {
+    pub tx: Hash32,
+    pub index: u32,
+    pub value: u64,
+    pub pubkey_hash: Hash20,
+}
+
+/// Watch-only wallet tracking the outputs paying a set of public key hashes
+#[derive(Default)]
+pub struct Wallet {
+    watched: HashSet<Hash20>,
+    utxos: HashMap<(Hash32, u32), Utxo>,
+}
+
+/// Returns the public key hash an output script pays to, for P2PKH, P2WPKH
+/// and P2PK scripts
+pub fn script_pubkey_hash(script: &[u8]) -> Option<Hash20> {
+    let mut hash = [0; 20];
+    match script {
+        // OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG
+        [0x76, 0xa9, 0x14, .., 0x88, 0xac] if script.len() == 25 => {
+            hash.copy_from_slice(&script[3..23]);
+            Some(hash)
+        }
+        // OP_0 <20 bytes>
+        [0x00, 0x14, ..] if script.len() == 22 => {
+            hash.copy_from_slice(&script[2..22]);
+            Some(hash)
+        }
+        // <compressed or uncompressed public key> OP_CHECKSIG
+        [size, .., 0xac] if (*size == 33 || *size == 65) && script.len() == *size as usize + 2 => {
+            Some(crypto::hash160(&script[1..(script.len() - 1)]))
+        }
+        _ => None,
+    }
+}
+
+impl Wallet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn watch(&mut self, pubkey_hash: Hash20) {
+        self.watched.insert(pubkey_hash);
+    }
+
+    /// Walks the active chain from the genesis block and records the
+    /// outputs paying the watched hashes which have not been spent since
+    pub fn scan(&mut self, storage: &Storage) -> Result<(), storage::Error> {
+        self.utxos.clear();
+        for hash in storage.active_chain()? {
+            let block = match storage.get_block(hash)? {
+                Some(block) => block,
+                None => return Err(storage::Error::DBOperation),
+            };
+            for tx in block.transactions() {
+                for input in tx.inputs() {
+                    self.utxos.remove(&input.prev_output());
+                }
+
+                let txid = tx.hash();
+                for (index, output) in tx.outputs().iter().enumerate() {
+                    let pubkey_hash = match script_pubkey_hash(&output.pubkey()) {
+                        Some(hash) if self.watched.contains(&hash) => hash,
+                        _ => continue,
+                    };
+                    let utxo = Utxo {
+                        tx: txid,
+                        index: index as u32,
+                        value: output.value(),
+                        pubkey_hash,
+                    };
+                    self.utxos.insert((txid, index as u32), utxo);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn utxos(&self) -> Vec<&Utxo> {
+        self.utxos.values().collect()
+    }
+
+    /// Returns the sum of the unspent outputs, in satoshis
+    pub fn balance(&self) -> u64 {
+        self.utxos.values().map(|utxo| utxo.value).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::block::Block;
+    use crate::config;
+    use crate::script::{p2pkh, p2wpkh};
+    use crate::transaction::Transaction;
+
+    fn block_paying(prev: &Block, time: u32, txs: Vec<Transaction>) -> Block {
+        let mut txs = txs.into_iter();
+        let mut block = Block::new(
+            1,
+            prev.hash(),
+            time,
+            0,
+            0x207fffff,
+            Box::new(txs.next().unwrap()),
+        );
+        for tx in txs {
+            block.add_tx(Box::new(tx)).unwrap();
+        }
+        block
+    }
+
+    #[test]
+    fn test_script_pubkey_hash() {
+        let hash = [7; 20];
+        assert_eq!(script_pubkey_hash(&p2pkh(hash)), Some(hash));
+        assert_eq!(script_pubkey_hash(&p2wpkh(hash)), Some(hash));
+
+        let config = config::regtest_config();
+        let genesis_script = config.genesis_block.transactions()[0].outputs()[0].pubkey();
+        assert_eq!(
+            script_pubkey_hash(&genesis_script),
+            Some(crypto::hash160(&genesis_script[1..66]))
+        );
+        assert_eq!(script_pubkey_hash(&[0x51]), None);
+    }
+
+    #[test]
+    fn test_balance() {
+        let config = config::regtest_config();
+        let storage = storage::temp_storage("wallet_balance");
+        storage.store_block(&config.genesis_block).unwrap();
+
+        let watched = [1; 20];
+        let other = [2; 20];
+        let mut coinbase = Transaction::new();
+        coinbase.add_input([0; 32], 0xffffffff, vec![0x51]);
+        coinbase.add_output(5_000_000_000, p2pkh(watched));
+        coinbase.add_output(1000, p2pkh(other));
+        let block1 = block_paying(&config.genesis_block, 1, vec![coinbase.clone()]);
+        storage.store_block(&block1).unwrap();
+
+        let mut wallet = Wallet::new();
+        wallet.watch(watched);
+        wallet.scan(&storage).unwrap();
+        assert_eq!(wallet.balance(), 5_000_000_000);
+        assert_eq!(wallet.utxos().len(), 1);
+        assert_eq!(wallet.utxos()[0].tx, coinbase.hash());
+
+        // Spend the output: 1 BTC back to the wallet, the rest elsewhere
+        let mut coinbase2 = Transaction::new();
+        coinbase2.add_input([0; 32], 0xffffffff, vec![0x52]);
+        coinbase2.add_output(5_000_000_000, p2pkh(other));
+        let mut spend = Transaction::new();
+        spend.add_input(coinbase.hash(), 0, vec![]);
+        spend.add_output(100_000_000, p2pkh(watched));
+        spend.add_output(4_900_000_000, p2pkh(other));
+        let block2 = block_paying(&block1, 2, vec![coinbase2, spend]);
+        storage.store_block(&block2).unwrap();
+
+        wallet.scan(&storage).unwrap();
+        assert_eq!(wallet.balance(), 100_000_000);
+    }
+}
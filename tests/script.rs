@@ -0,0 +1,51 @@
+use yasbit::script::{Script, StackEntry};
+use yasbit::transaction::{Transaction, TxOutput};
+
+fn exec(script_sig: &str, pubkey_script: &str) -> yasbit::script::ScriptResult {
+    let mut tx = Transaction::new();
+    tx.add_input([0; 32], 0xffffffff, hex::decode(script_sig).unwrap());
+    let prev_out = TxOutput::new(1, hex::decode(pubkey_script).unwrap());
+    Script::new(Box::new(tx), 0, Box::new(prev_out), 0).exec()
+}
+
+#[test]
+fn test_valid_script() {
+    // <0x123456> <0x123456> OP_EQUAL
+    let result = exec("03123456", "0312345687");
+    assert!(result.is_valid());
+    assert_eq!(result.stack().len(), 1);
+    match result.top() {
+        Some(StackEntry::Array(top)) => assert_eq!(top, &vec![1]),
+        top => panic!("Unexpected top of stack {:?}", top),
+    }
+}
+
+#[test]
+fn test_stack() {
+    // <0x12> <0x3456>
+    let result = exec("0112", "023456");
+    assert!(result.is_valid());
+    match result.stack() {
+        [StackEntry::Array(first), StackEntry::Array(second)] => {
+            assert_eq!(first, &vec![0x12]);
+            assert_eq!(second, &vec![0x34, 0x56]);
+        }
+        stack => panic!("Unexpected stack {:?}", stack),
+    }
+    match result.top() {
+        Some(StackEntry::Array(top)) => assert_eq!(top, &vec![0x34, 0x56]),
+        top => panic!("Unexpected top of stack {:?}", top),
+    }
+}
+
+#[test]
+fn test_invalid_script() {
+    // OP_0 OP_VERIFY
+    let result = exec("", "0069");
+    assert!(!result.is_valid());
+    assert!(result.top().is_none());
+
+    // OP_EQUAL on an empty stack
+    let result = exec("", "87");
+    assert!(!result.is_valid());
+}
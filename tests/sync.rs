@@ -0,0 +1,149 @@
+use std::io::{Read, Write};
+use std::net;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+use yasbit::block::Block;
+use yasbit::config::{Config, Network};
+use yasbit::crypto::Hashable;
+use yasbit::message;
+use yasbit::message::{Message, MessageCommand, MessageType};
+use yasbit::transaction::Transaction;
+use yasbit::SyncEvent;
+
+/// Returns the regtest block following the genesis block
+fn next_block(genesis: &Block) -> Block {
+    let mut coinbase = Transaction::new();
+    // BIP34 height, which is not required yet on regtest
+    coinbase.add_input([0; 32], 0xffffffff, vec![0x51, 0x00]);
+    coinbase.add_output(5_000_000_000, vec![0x51]);
+    let mut block = Block::new(
+        1,
+        genesis.hash(),
+        1296688603,
+        0,
+        0x207fffff,
+        Box::new(coinbase),
+    );
+    block.mine();
+    block
+}
+
+/// Plays the role of a peer holding the given block on top of the genesis
+/// block: answers the handshake, getaddr, getheaders and getdata messages
+fn mock_peer(listener: net::TcpListener, magic: u32, block: Block) {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut bytes = Vec::new();
+    loop {
+        let mut buffer = [0; 4096];
+        let size = stream.read(&mut buffer).unwrap();
+        if size == 0 {
+            return;
+        }
+        bytes.extend_from_slice(&buffer[..size]);
+
+        loop {
+            let (message_type, size) = match message::parse(&bytes, magic) {
+                Ok(parsed) => parsed,
+                Err(message::ParseError::UnknownMessage(_, size)) => {
+                    bytes.drain(..size);
+                    continue;
+                }
+                Err(_) => break,
+            };
+            bytes.drain(..size);
+
+            let replies = match message_type {
+                // The node is answered with a version like its own
+                MessageType::Version(version) => vec![
+                    Message::new(magic, version.command).bytes(),
+                    Message::new(magic, message::verack::MessageVerack::new()).bytes(),
+                ],
+                MessageType::GetAddr(_) => {
+                    vec![Message::new(magic, message::addr::MessageAddr::new(vec![])).bytes()]
+                }
+                MessageType::GetHeaders(_) => {
+                    // A single header, without transaction count
+                    let mut headers = vec![1];
+                    headers.extend_from_slice(&block.header.bytes());
+                    headers.push(0);
+                    vec![Message::new(
+                        magic,
+                        message::headers::MessageHeaders::from_bytes(&headers),
+                    )
+                    .bytes()]
+                }
+                MessageType::GetData(_) => {
+                    vec![
+                        Message::new(magic, message::block::MessageBlock::new(block.clone()))
+                            .bytes(),
+                    ]
+                }
+                _ => vec![],
+            };
+            for reply in replies {
+                stream.write_all(&reply).unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sync_one_block() {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer_addr = listener.local_addr().unwrap();
+    let data_dir = std::env::temp_dir().join(format!("yasbit_sync_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    let mut config = Config::builder()
+        .network(Network::Regtest)
+        .data_dir(data_dir.clone())
+        .peer_count(1)
+        .fixed_peer(peer_addr)
+        .connect_only(true)
+        .build()
+        .unwrap();
+    // The mock peer accepts a single connection, so the node must not
+    // reconnect to it to hear about transactions
+    config.relay = false;
+    let block = next_block(&config.genesis_block);
+    let hash = block.hash();
+
+    let magic = config.magic;
+    let peer_block = block.clone();
+    thread::spawn(move || mock_peer(listener, magic, peer_block));
+    let (event_sender, event_receiver) = mpsc::channel();
+    thread::spawn(move || yasbit::run_with_subscriber(config, event_sender));
+
+    let mut events = Vec::new();
+    loop {
+        match event_receiver.recv_timeout(time::Duration::from_secs(30)) {
+            Ok(SyncEvent::BlockValidated(validated, height)) if validated == hash => {
+                assert_eq!(height, 1);
+                break;
+            }
+            Ok(event) => events.push(event),
+            Err(err) => panic!("Block not validated ({:?}), events: {:?}", err, events),
+        }
+    }
+    assert_eq!(
+        events,
+        vec![
+            SyncEvent::PeerConnected(0),
+            SyncEvent::SyncNodeChosen(0),
+            SyncEvent::HeadersReceived(0, 1),
+        ]
+    );
+
+    // The block has been written after the genesis block
+    let mut block_file = Vec::new();
+    std::fs::File::open(data_dir.join("blocks").join("blk00001.dat"))
+        .unwrap()
+        .read_to_end(&mut block_file)
+        .unwrap();
+    let block_bytes = block.bytes();
+    assert!(block_file
+        .windows(block_bytes.len())
+        .any(|window| window == &block_bytes[..]));
+
+    let _ = std::fs::remove_dir_all(&data_dir);
+}